@@ -8,7 +8,9 @@ use nockvm::interpreter::Context;
 use nockvm::jets::cold::Cold;
 use nockvm::jets::hot::{HotEntry, URBIT_HOT_STATE};
 use nockvm::mem::NockStack;
+use nockvm::profile::JetProfile;
 use nockvm::trace::TraceInfo;
+use tracing::warn;
 
 /// Command line arguments
 #[derive(Parser, Debug, Clone)]
@@ -42,8 +44,17 @@ pub async fn run(cli: HoonCli, hot_state: &[HotEntry]) -> Result<(), Box<dyn std
         None
     };
     let mut context: Context = init_context(Some(hot_state), trace_info);
+    if cli.boot.trace || cli.boot.jet_profile {
+        context.jet_profile = Some(JetProfile::new("jet-profile.json".into()));
+    }
 
-    save_generator(&mut context, &cli.nock_script, cli.dep_dir, cli.out_dir).await
+    let result = save_generator(&mut context, &cli.nock_script, cli.dep_dir, cli.out_dir).await;
+    if let Some(profile) = &mut context.jet_profile {
+        if let Err(e) = profile.write() {
+            warn!("Could not write jet profile: {e}");
+        }
+    }
+    result
 }
 
 /// Initializes a nockvm interpreter Context with default settings
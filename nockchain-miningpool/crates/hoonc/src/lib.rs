@@ -210,7 +210,6 @@ pub async fn initialize_hoonc_(
         Some(data_dir),
     )
     .await?;
-    nockapp.add_io_driver(nockapp::file_driver()).await;
     nockapp.add_io_driver(nockapp::exit_driver()).await;
 
     let mut slab = NounSlab::new();
@@ -289,6 +288,14 @@ pub async fn initialize_hoonc_(
         format!("{}/{}", canonicalize_and_string(&parent_dir), OUT_JAM_NAME)
     };
     debug!("Output path: {:?}", out_path_string);
+    // The kernel only writes the output file, so confine the file driver to its directory
+    let out_dir = Path::new(&out_path_string)
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| PathBuf::from("."));
+    nockapp
+        .add_io_driver(nockapp::file_driver_in(out_dir))
+        .await;
     let out_path = Atom::from_value(&mut slab, out_path_string.clone())?.as_noun();
 
     let arbitrary_noun = if arbitrary { D(0) } else { D(1) };
@@ -0,0 +1,273 @@
+//! Derive macros for `nockapp::noun::codec`.
+//!
+//! See `nockapp::noun::codec` for the noun shapes the derives produce and accept.
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{
+    parse_macro_input, parse_quote, Attribute, Data, DeriveInput, Error, Fields, Generics, Ident,
+    LitStr,
+};
+
+#[proc_macro_derive(NounEncode, attributes(noun))]
+pub fn derive_noun_encode(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_encode(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+#[proc_macro_derive(NounDecode, attributes(noun))]
+pub fn derive_noun_decode(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_decode(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+/// Read `#[noun(tag = "...")]`
+fn tag_attr(attrs: &[Attribute]) -> syn::Result<Option<String>> {
+    let mut tag = None;
+    for attr in attrs.iter().filter(|attr| attr.path().is_ident("noun")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("tag") {
+                let value: LitStr = meta.value()?.parse()?;
+                tag = Some(value.value());
+                Ok(())
+            } else {
+                Err(meta.error("unsupported noun attribute, expected `tag`"))
+            }
+        })?;
+    }
+    Ok(tag)
+}
+
+/// `SetMiningKey` -> `set-mining-key`
+fn kebab_case(ident: &Ident) -> String {
+    let mut kebab = String::new();
+    for (i, c) in ident.to_string().chars().enumerate() {
+        if c.is_uppercase() {
+            if i > 0 {
+                kebab.push('-');
+            }
+            kebab.extend(c.to_lowercase());
+        } else if c == '_' {
+            kebab.push('-');
+        } else {
+            kebab.push(c);
+        }
+    }
+    kebab
+}
+
+fn add_bounds(generics: &Generics, bound: TokenStream2) -> Generics {
+    let mut generics = generics.clone();
+    for param in generics.type_params_mut() {
+        param.bounds.push(parse_quote!(#bound));
+    }
+    generics
+}
+
+/// Bindings for each field, for patterns and constructors
+fn field_bindings(fields: &Fields) -> Vec<Ident> {
+    (0..fields.len())
+        .map(|i| format_ident!("__f{}", i))
+        .collect()
+}
+
+/// `Self { a: __f0, .. }`, `Self(__f0, ..)` or `Self`
+fn construct(path: TokenStream2, fields: &Fields, bindings: &[Ident]) -> TokenStream2 {
+    match fields {
+        Fields::Named(named) => {
+            let names = named.named.iter().map(|field| &field.ident);
+            quote!(#path { #(#names: #bindings),* })
+        }
+        Fields::Unnamed(_) => quote!(#path(#(#bindings),*)),
+        Fields::Unit => path,
+    }
+}
+
+/// Encode the bound fields as `~`, the lone field, or a right-nested tuple
+fn encode_fields(bindings: &[Ident]) -> TokenStream2 {
+    let Some((last, init)) = bindings.split_last() else {
+        return quote!(::nockapp::noun::codec::null());
+    };
+    let init = init.iter().rev();
+    quote!({
+        let mut __noun = ::nockapp::noun::codec::NounEncode::to_noun(#last, __slab);
+        #(
+            let __head = ::nockapp::noun::codec::NounEncode::to_noun(#init, __slab);
+            __noun = ::nockapp::noun::codec::cell(__slab, __head, __noun);
+        )*
+        __noun
+    })
+}
+
+/// Decode `__noun` into the field bindings, naming each field in errors
+fn decode_fields(context: &str, fields: &Fields, bindings: &[Ident]) -> TokenStream2 {
+    let names: Vec<String> = fields
+        .iter()
+        .enumerate()
+        .map(|(i, field)| match &field.ident {
+            Some(ident) => format!("{}.{}", context, ident),
+            None => format!("{}.{}", context, i),
+        })
+        .collect();
+    let Some((last, init)) = bindings.split_last() else {
+        return quote!(::nockapp::noun::codec::expect_null(&__noun)?;);
+    };
+    let last_name = names.last().expect("one name per binding");
+    let init_names = &names[..init.len()];
+    quote! {
+        let __rest = __noun;
+        #(
+            let (__head, __rest) = ::nockapp::noun::codec::split(&__rest)?;
+            let #init = ::nockapp::noun::codec::field(&__head, #init_names)?;
+        )*
+        let #last = ::nockapp::noun::codec::field(&__rest, #last_name)?;
+    }
+}
+
+fn expand_encode(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    let container_tag = tag_attr(&input.attrs)?;
+    let generics = add_bounds(&input.generics, quote!(::nockapp::noun::codec::NounEncode));
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    let body = match &input.data {
+        Data::Struct(data) => {
+            let bindings = field_bindings(&data.fields);
+            let pattern = construct(quote!(Self), &data.fields, &bindings);
+            let encoded = encode_fields(&bindings);
+            quote! {
+                let #pattern = self;
+                #encoded
+            }
+        }
+        Data::Enum(data) => {
+            let mut arms = Vec::new();
+            for variant in &data.variants {
+                let tag = match tag_attr(&variant.attrs)? {
+                    Some(tag) => tag,
+                    None => kebab_case(&variant.ident),
+                };
+                let ident = &variant.ident;
+                let bindings = field_bindings(&variant.fields);
+                let pattern = construct(quote!(Self::#ident), &variant.fields, &bindings);
+                let arm = if bindings.is_empty() {
+                    quote!(#pattern => ::nockapp::noun::codec::tag(__slab, #tag))
+                } else {
+                    let encoded = encode_fields(&bindings);
+                    quote!(#pattern => {
+                        let __body = #encoded;
+                        let __tag = ::nockapp::noun::codec::tag(__slab, #tag);
+                        ::nockapp::noun::codec::cell(__slab, __tag, __body)
+                    })
+                };
+                arms.push(arm);
+            }
+            quote!(match self { #(#arms,)* })
+        }
+        Data::Union(_) => {
+            return Err(Error::new_spanned(
+                &input.ident, "NounEncode cannot be derived for unions",
+            ))
+        }
+    };
+    let body = match container_tag {
+        Some(tag) => quote!({
+            let __body = { #body };
+            let __tag = ::nockapp::noun::codec::tag(__slab, #tag);
+            ::nockapp::noun::codec::cell(__slab, __tag, __body)
+        }),
+        None => quote!({ #body }),
+    };
+
+    Ok(quote! {
+        impl #impl_generics ::nockapp::noun::codec::NounEncode for #name #ty_generics #where_clause {
+            fn to_noun(
+                &self,
+                __slab: &mut ::nockapp::noun::slab::NounSlab,
+            ) -> ::nockapp::Noun #body
+        }
+    })
+}
+
+fn expand_decode(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    let container_tag = tag_attr(&input.attrs)?;
+    let generics = add_bounds(&input.generics, quote!(::nockapp::noun::codec::NounDecode));
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    let body = match &input.data {
+        Data::Struct(data) => {
+            let bindings = field_bindings(&data.fields);
+            let decoded = decode_fields(&name.to_string(), &data.fields, &bindings);
+            let value = construct(quote!(Self), &data.fields, &bindings);
+            quote! {
+                #decoded
+                Ok(#value)
+            }
+        }
+        Data::Enum(data) => {
+            let mut unit_arms = Vec::new();
+            let mut cell_arms = Vec::new();
+            for variant in &data.variants {
+                let tag = match tag_attr(&variant.attrs)? {
+                    Some(tag) => tag,
+                    None => kebab_case(&variant.ident),
+                };
+                let ident = &variant.ident;
+                let bindings = field_bindings(&variant.fields);
+                let value = construct(quote!(Self::#ident), &variant.fields, &bindings);
+                if bindings.is_empty() {
+                    unit_arms.push(quote! {
+                        if ::nockapp::noun::codec::tag_eq(__tag, #tag) {
+                            return Ok(#value);
+                        }
+                    });
+                } else {
+                    let context = format!("{}::{}", name, ident);
+                    let decoded = decode_fields(&context, &variant.fields, &bindings);
+                    cell_arms.push(quote! {
+                        if ::nockapp::noun::codec::tag_eq(__tag, #tag) {
+                            let __noun = __body;
+                            #decoded
+                            return Ok(#value);
+                        }
+                    });
+                }
+            }
+            quote! {
+                if let Ok(__tag) = __noun.as_atom() {
+                    #(#unit_arms)*
+                    return Err(::nockapp::noun::codec::unknown_tag(__tag));
+                }
+                let (__head, __body) = ::nockapp::noun::codec::split(&__noun)?;
+                let __tag = ::nockapp::noun::codec::expect_atom(&__head)?;
+                #(#cell_arms)*
+                Err(::nockapp::noun::codec::unknown_tag(__tag))
+            }
+        }
+        Data::Union(_) => {
+            return Err(Error::new_spanned(
+                &input.ident, "NounDecode cannot be derived for unions",
+            ))
+        }
+    };
+    let unwrap_tag = match container_tag {
+        Some(tag) => quote!(let __noun = ::nockapp::noun::codec::expect_tag(noun, #tag)?;),
+        None => quote!(let __noun = *noun;),
+    };
+
+    Ok(quote! {
+        impl #impl_generics ::nockapp::noun::codec::NounDecode for #name #ty_generics #where_clause {
+            fn from_noun(
+                noun: &::nockapp::Noun,
+            ) -> ::std::result::Result<Self, ::nockapp::noun::codec::NounDecodeError> {
+                #unwrap_tag
+                #body
+            }
+        }
+    })
+}
@@ -0,0 +1,167 @@
+use std::path::{Path, PathBuf};
+
+use clap::{Args, Parser, Subcommand};
+use nockapp::inspect::{
+    attribute, diff, mass, parse_axis, render, slot, InspectError, InspectedFile,
+};
+use nockapp::utils::NOCK_STACK_SIZE_HUGE;
+use nockvm::mem::NockStack;
+use nockvm::noun::Noun;
+
+/// Look inside jams, checkpoints and exported state
+#[derive(Parser, Debug)]
+#[command(name = "noun-inspect")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print and validate the header, and summarize the noun
+    Info { file: PathBuf },
+    /// Print the noun
+    Show {
+        file: PathBuf,
+        #[command(flatten)]
+        at: At,
+        #[arg(long, default_value_t = 6, help = "How many cells deep to print")]
+        depth: usize,
+        #[arg(
+            long,
+            default_value_t = 32,
+            help = "How many items of a tuple or list to print"
+        )]
+        width: usize,
+    },
+    /// Attribute the size of the noun to its subtrees
+    Sizes {
+        file: PathBuf,
+        #[command(flatten)]
+        at: At,
+        #[arg(
+            long,
+            default_value_t = 4,
+            help = "How many levels of subtrees to report"
+        )]
+        depth: usize,
+    },
+    /// List the axes where two nouns differ
+    Diff {
+        left: PathBuf,
+        right: PathBuf,
+        #[command(flatten)]
+        at: At,
+        #[arg(long, default_value_t = 20, help = "Stop after this many differences")]
+        max: usize,
+        #[arg(
+            long,
+            default_value_t = 3,
+            help = "How many cells deep to print each side"
+        )]
+        depth: usize,
+    },
+}
+
+#[derive(Args, Debug)]
+struct At {
+    #[arg(
+        long,
+        help = "Look at the noun at this axis. Repeat to keep descending from the last axis, e.g. --axis 7 --axis 6"
+    )]
+    axis: Vec<String>,
+}
+
+fn main() {
+    let cli = Cli::parse();
+    // The stack only takes memory as the noun fills it, so reserving a lot costs nothing
+    let mut stack = NockStack::new(NOCK_STACK_SIZE_HUGE, 0);
+    if let Err(e) = run(&mut stack, cli.command) {
+        eprintln!("noun-inspect: {e}");
+        std::process::exit(1);
+    }
+}
+
+fn run(stack: &mut NockStack, command: Command) -> Result<(), InspectError> {
+    match command {
+        Command::Info { file } => {
+            let inspected = InspectedFile::read(&file)?;
+            println!("{}: {}", file.display(), inspected.kind);
+            for (name, value) in &inspected.header {
+                println!("  {name}: {value}");
+            }
+            match &inspected.invalid {
+                Some(why) => println!("  INVALID: {why}"),
+                None => println!("  valid"),
+            }
+            if let Some(why) = &inspected.missing {
+                println!("  no noun: {why}");
+                return Ok(());
+            }
+            let noun = inspected.cue(stack, &file)?;
+            let noun_mass = mass(noun);
+            println!(
+                "  noun: {} cells, {} indirect atoms, {} bytes in memory",
+                noun_mass.cells,
+                noun_mass.indirect_atoms,
+                noun_mass.bytes()
+            );
+        }
+        Command::Show {
+            file,
+            at,
+            depth,
+            width,
+        } => {
+            let noun = load(stack, &file, &at)?;
+            println!("{}", render(noun, depth, width));
+        }
+        Command::Sizes { file, at, depth } => {
+            let noun = load(stack, &file, &at)?;
+            let total = mass(noun).bytes().max(1);
+            println!("{:>24} {:>14} {:>7}", "axis", "bytes", "share");
+            for subtree in attribute(noun, depth) {
+                let bytes = subtree.mass.bytes();
+                println!(
+                    "{:>24} {:>14} {:>6.2}%",
+                    format!("{}{}", "  ".repeat(subtree.depth), subtree.axis),
+                    bytes,
+                    bytes as f64 * 100.0 / total as f64
+                );
+            }
+        }
+        Command::Diff {
+            left,
+            right,
+            at,
+            max,
+            depth,
+        } => {
+            let left = load(stack, &left, &at)?;
+            let right = load(stack, &right, &at)?;
+            let differences = diff(left, right, max);
+            if differences.is_empty() {
+                println!("same");
+            }
+            for difference in differences {
+                println!("axis {}:", difference.axis);
+                println!("  - {}", render(difference.left, depth, 8));
+                println!("  + {}", render(difference.right, depth, 8));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Cue the noun in `file` and descend to the axes in `at`
+fn load(stack: &mut NockStack, file: &Path, at: &At) -> Result<Noun, InspectError> {
+    let inspected = InspectedFile::read(file)?;
+    if let Some(why) = &inspected.invalid {
+        eprintln!("noun-inspect: warning: {}: {why}", file.display());
+    }
+    let mut noun = inspected.cue(stack, file)?;
+    for axis in &at.axis {
+        noun = slot(noun, &parse_axis(axis)?)?;
+    }
+    Ok(noun)
+}
@@ -1,16 +1,25 @@
-use nockvm::noun::{IndirectAtom, Noun, D, NO, T, YES};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::UNIX_EPOCH;
+
+use nockvm::noun::{Atom, Noun, D, NO, T, YES};
 use nockvm_macros::tas;
-use tracing::{debug, error};
+use tokio::io::AsyncWriteExt;
+use tracing::{debug, error, warn};
 
 use crate::nockapp::driver::{make_driver, IODriverFn};
 use crate::nockapp::wire::{Wire, WireRepr};
+use crate::nockapp::NockAppError;
 use crate::noun::slab::NounSlab;
-use crate::noun::FromAtom;
-use crate::AtomExt;
+use crate::{AtomExt, Bytes};
 
 pub enum FileWire {
     Read,
     Write,
+    Append,
+    Delete,
+    List,
+    Stat,
 }
 
 impl Wire for FileWire {
@@ -18,161 +27,503 @@ impl Wire for FileWire {
     const SOURCE: &'static str = "file";
 
     fn to_wire(&self) -> crate::nockapp::wire::WireRepr {
-        let tags = match self {
-            FileWire::Read => vec!["read".into()],
-            FileWire::Write => vec!["write".into()],
+        WireRepr::new(
+            FileWire::SOURCE,
+            FileWire::VERSION,
+            vec![self.operation().into()],
+        )
+    }
+}
+
+impl FileWire {
+    /// The effect and poke tag of the operation
+    pub fn operation(&self) -> &'static str {
+        match self {
+            FileWire::Read => "read",
+            FileWire::Write => "write",
+            FileWire::Append => "append",
+            FileWire::Delete => "delete",
+            FileWire::List => "list",
+            FileWire::Stat => "stat",
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum FileError {
+    #[error("Path escapes the file driver root: {0}")]
+    Escape(String),
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+impl FileError {
+    /// Tag of the `error` noun reported to the kernel
+    fn tag(&self) -> u64 {
+        match self {
+            FileError::Escape(_) => tas!(b"escape"),
+            FileError::Io(e) => match e.kind() {
+                std::io::ErrorKind::NotFound => tas!(b"not-found"),
+                std::io::ErrorKind::PermissionDenied => tas!(b"denied"),
+                std::io::ErrorKind::AlreadyExists => tas!(b"exists"),
+                _ => tas!(b"io"),
+            },
+        }
+    }
+}
+
+/// Metadata reported for `%stat`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileStat {
+    pub dir: bool,
+    pub size: u64,
+    /// Modification time, in seconds since the Unix epoch
+    pub mtime: u64,
+}
+
+/// Filesystem access confined to a root directory.
+///
+/// Kernel paths are relative to the root, or absolute paths inside it. `..` may not climb
+/// above the root, and symlinks may not lead out of it.
+pub struct FileSandbox {
+    root: PathBuf,
+}
+
+impl FileSandbox {
+    /// Sandbox rooted at `root`, which is created if it doesn't exist
+    pub async fn new(root: impl AsRef<Path>) -> std::io::Result<Self> {
+        tokio::fs::create_dir_all(root.as_ref()).await?;
+        Ok(FileSandbox {
+            root: tokio::fs::canonicalize(root.as_ref()).await?,
+        })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Resolve a kernel path to a path inside the root
+    pub async fn resolve(&self, path: &str) -> Result<PathBuf, FileError> {
+        let escape = || FileError::Escape(path.to_string());
+        let requested = Path::new(path);
+        let relative = if requested.is_absolute() {
+            requested.strip_prefix(&self.root).map_err(|_| escape())?
+        } else {
+            requested
+        };
+
+        let mut resolved = self.root.clone();
+        let mut depth = 0usize;
+        for component in relative.components() {
+            match component {
+                Component::Normal(part) => {
+                    resolved.push(part);
+                    depth += 1;
+                }
+                Component::CurDir => {}
+                Component::ParentDir => {
+                    if depth == 0 {
+                        return Err(escape());
+                    }
+                    resolved.pop();
+                    depth -= 1;
+                }
+                Component::RootDir | Component::Prefix(_) => return Err(escape()),
+            }
+        }
+
+        // The nearest existing ancestor must still be inside the root once symlinks are followed
+        let mut existing = resolved.as_path();
+        while tokio::fs::symlink_metadata(existing).await.is_err() {
+            existing = existing.parent().ok_or_else(escape)?;
+        }
+        if !tokio::fs::canonicalize(existing)
+            .await?
+            .starts_with(&self.root)
+        {
+            return Err(escape());
+        }
+        Ok(resolved)
+    }
+
+    pub async fn read(&self, path: &str) -> Result<Vec<u8>, FileError> {
+        Ok(tokio::fs::read(self.resolve(path).await?).await?)
+    }
+
+    /// Replace the file at `path` atomically: `contents` are written to a temporary file
+    /// next to it, which is then renamed over it
+    pub async fn write(&self, path: &str, contents: &[u8]) -> Result<(), FileError> {
+        static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+        let target = self.resolve(path).await?;
+        let (Some(parent), Some(name)) = (target.parent(), target.file_name()) else {
+            return Err(FileError::Escape(path.to_string()));
         };
-        WireRepr::new(FileWire::SOURCE, FileWire::VERSION, tags)
+        tokio::fs::create_dir_all(parent).await?;
+        let temp = parent.join(format!(
+            ".{}.tmp-{}-{}",
+            name.to_string_lossy(),
+            std::process::id(),
+            TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let written = async {
+            let mut file = tokio::fs::File::create(&temp).await?;
+            file.write_all(contents).await?;
+            file.sync_all().await?;
+            tokio::fs::rename(&temp, &target).await
+        }
+        .await;
+        if let Err(e) = written {
+            let _ = tokio::fs::remove_file(&temp).await;
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    pub async fn append(&self, path: &str, contents: &[u8]) -> Result<(), FileError> {
+        let target = self.resolve(path).await?;
+        if let Some(parent) = target.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&target)
+            .await?;
+        file.write_all(contents).await?;
+        file.sync_data().await?;
+        Ok(())
+    }
+
+    /// Delete a file or an empty directory. The root itself can't be deleted.
+    pub async fn delete(&self, path: &str) -> Result<(), FileError> {
+        let target = self.resolve(path).await?;
+        if target == self.root {
+            return Err(FileError::Escape(path.to_string()));
+        }
+        if tokio::fs::symlink_metadata(&target).await?.is_dir() {
+            tokio::fs::remove_dir(&target).await?;
+        } else {
+            tokio::fs::remove_file(&target).await?;
+        }
+        Ok(())
+    }
+
+    /// Names of the entries of a directory, and whether each is a directory, sorted by name
+    pub async fn list(&self, path: &str) -> Result<Vec<(String, bool)>, FileError> {
+        let mut entries = tokio::fs::read_dir(self.resolve(path).await?).await?;
+        let mut listing = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let dir = entry.file_type().await?.is_dir();
+            listing.push((entry.file_name().to_string_lossy().into_owned(), dir));
+        }
+        listing.sort();
+        Ok(listing)
     }
+
+    pub async fn stat(&self, path: &str) -> Result<FileStat, FileError> {
+        let metadata = tokio::fs::metadata(self.resolve(path).await?).await?;
+        let mtime = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        Ok(FileStat {
+            dir: metadata.is_dir(),
+            size: metadata.len(),
+            mtime,
+        })
+    }
+}
+
+/// File IO driver rooted at the current directory
+pub fn file() -> IODriverFn {
+    file_in(".")
 }
 
 /// File IO Driver
 ///
+/// Paths are confined to `root`, see [FileSandbox]. Failures are reported as
+/// `error=[tag=?(%escape %not-found %denied %exists %io) msg=@t]`.
+///
 /// ## Effects
 /// `[%file %read path=@t]`
 /// results in poke
-/// `[%file %read ~]` on read error
-/// or
-/// `[%file %read ~ contents=@]` on read success
+/// `[%file %read path=@t (each contents=@ error)]`
 ///
-///  `[%file %write path=@t contents=@]`
-///  results in file written to disk and poke
-///  `[%file %write path=@t contents=@ success=?]`
-pub fn file() -> IODriverFn {
+/// `[%file %write path=@t contents=@]`
+/// results in the file being replaced atomically and poke
+/// `[%file %write path=@t contents=@ success=?]`
+/// which keeps the flag the prebuilt hoonc and wallet kernels expect. The error is logged.
+///
+/// `[%file %append path=@t contents=@]`
+/// results in poke
+/// `[%file %append path=@t (each ~ error)]`
+///
+/// `[%file %delete path=@t]` deletes a file or empty directory and results in poke
+/// `[%file %delete path=@t (each ~ error)]`
+///
+/// `[%file %list path=@t]`
+/// results in poke
+/// `[%file %list path=@t (each (list [name=@t dir=?]) error)]`
+///
+/// `[%file %stat path=@t]`
+/// results in poke
+/// `[%file %stat path=@t (each [dir=? size=@ud mtime=@ud] error)]`
+/// with `mtime` in seconds since the Unix epoch
+pub fn file_in(root: impl Into<PathBuf>) -> IODriverFn {
+    let root = root.into();
     make_driver(|handle| async move {
-        loop {
-            let effect_res = handle.next_effect().await;
-            let slab = match effect_res {
-                Ok(slab) => slab,
-                Err(e) => {
-                    error!("Error receiving effect: {:?}", e);
-                    continue;
-                }
-            };
+        let sandbox = FileSandbox::new(&root).await.map_err(|e| {
+            error!("file driver: can't use root {}: {}", root.display(), e);
+            NockAppError::IoError(e)
+        })?;
+        debug!("file driver: rooted at {}", sandbox.root().display());
 
+        let mut effects = handle.subscribe_effects(&["file"]);
+        while let Some(slab) = effects.recv().await {
             let Ok(effect_cell) = unsafe { slab.root() }.as_cell() else {
                 continue;
             };
-
-            if !unsafe { effect_cell.head().raw_equals(&D(tas!(b"file"))) } {
-                continue;
-            }
-
             let Ok(file_cell) = effect_cell.tail().as_cell() else {
                 continue;
             };
-
-            let (operation, path_atom) = match file_cell.head().as_direct() {
-                Ok(tag) if tag.data() == tas!(b"read") => ("read", file_cell.tail().as_atom().ok()),
-                Ok(tag) if tag.data() == tas!(b"write") => {
-                    let Ok(write_cell) = file_cell.tail().as_cell() else {
-                        continue;
-                    };
-                    ("write", write_cell.head().as_atom().ok())
-                }
-                _ => continue,
+            let Ok(operation) = file_cell.head().as_direct() else {
+                continue;
+            };
+            // Every operation starts with a path, which may be followed by contents
+            let (path_noun, contents_noun) = match file_cell.tail().as_cell() {
+                Ok(cell) => (cell.head(), Some(cell.tail())),
+                Err(_) => (file_cell.tail(), None),
+            };
+            let Ok(path_atom) = path_noun.as_atom() else {
+                continue;
+            };
+            let Ok(path) = path_atom.into_string() else {
+                warn!("file driver: path is not valid UTF-8");
+                continue;
             };
 
-            match (operation, path_atom) {
-                ("read", Some(path_atom)) => {
-                    let path = String::from_utf8(Vec::from(path_atom.as_ne_bytes()))?;
-                    match tokio::fs::read(&path).await {
+            let mut poke_slab = NounSlab::new();
+            let path_noun = poke_slab.copy_into(path_noun);
+            let (wire, result) = match operation.data() {
+                tag if tag == tas!(b"read") => {
+                    let result = match sandbox.read(&path).await {
+                        Ok(contents) if contents.is_empty() => Ok(D(0)),
                         Ok(contents) => {
-                            let mut poke_slab = NounSlab::new();
-                            let contents_atom = unsafe {
-                                IndirectAtom::new_raw_bytes_ref(&mut poke_slab, &contents)
-                                    .normalize_as_atom()
-                            };
-                            let contents_noun = Noun::from_atom(contents_atom);
-                            let poke_noun = T(
-                                &mut poke_slab,
-                                &[D(tas!(b"file")), D(tas!(b"read")), D(0), contents_noun],
-                            );
-                            poke_slab.set_root(poke_noun);
-                            let wire = FileWire::Read.to_wire();
-                            handle.poke(wire, poke_slab).await?;
+                            Ok(Atom::from_bytes(&mut poke_slab, &Bytes::from(contents)).as_noun())
                         }
-                        Err(_) => {
-                            let mut poke_slab = NounSlab::new();
-                            let poke_noun =
-                                T(&mut poke_slab, &[D(tas!(b"file")), D(tas!(b"read")), D(0)]);
-                            poke_slab.set_root(poke_noun);
-                            let wire = FileWire::Read.to_wire();
-                            handle.poke(wire, poke_slab).await?;
-                        }
-                    }
-                }
-                ("write", Some(path_atom)) => {
-                    let Ok(write_cell) = file_cell.tail().as_cell() else {
-                        continue;
+                        Err(e) => Err(e),
                     };
-                    let Ok(contents_atom) = write_cell.tail().as_atom() else {
+                    (FileWire::Read, result)
+                }
+                tag if tag == tas!(b"write") => {
+                    let Some(contents_atom) = contents_noun.and_then(|c| c.as_atom().ok()) else {
                         continue;
                     };
-                    let path = path_atom.into_string()?;
-                    let contents = contents_atom.as_ne_bytes();
+                    let contents = atom_bytes(&contents_atom);
                     debug!("file driver: writing {} bytes to: {}", contents.len(), path);
-
-                    // Create parent directories if they don't exist
-                    if let Some(parent) = std::path::Path::new(&path).parent() {
-                        if let Err(e) = tokio::fs::create_dir_all(parent).await {
-                            error!("file driver: error creating directories: {}", e);
-                            let mut poke_slab = NounSlab::new();
-                            let poke_noun = T(
-                                &mut poke_slab,
-                                &[
-                                    D(tas!(b"file")),
-                                    D(tas!(b"write")),
-                                    path_atom.as_noun(),
-                                    contents_atom.as_noun(),
-                                    NO,
-                                ],
-                            );
-                            poke_slab.set_root(poke_noun);
-                            let wire = FileWire::Write.to_wire();
-                            handle.poke(wire, poke_slab).await?;
-                            continue;
-                        }
-                    }
-
-                    match tokio::fs::write(&path, contents).await {
-                        Ok(_) => {
-                            let mut poke_slab = NounSlab::new();
-                            let poke_noun = T(
-                                &mut poke_slab,
-                                &[
-                                    D(tas!(b"file")),
-                                    D(tas!(b"write")),
-                                    path_atom.as_noun(),
-                                    contents_atom.as_noun(),
-                                    YES,
-                                ],
-                            );
-                            poke_slab.set_root(poke_noun);
-                            let wire = FileWire::Write.to_wire();
-                            handle.poke(wire, poke_slab).await?;
-                        }
+                    let success = match sandbox.write(&path, contents).await {
+                        Ok(()) => YES,
                         Err(e) => {
-                            error!("file driver: error writing to path: {}", e);
-                            let mut poke_slab = NounSlab::new();
-                            let poke_noun = T(
-                                &mut poke_slab,
-                                &[
-                                    D(tas!(b"file")),
-                                    D(tas!(b"write")),
-                                    path_atom.as_noun(),
-                                    contents_atom.as_noun(),
-                                    NO,
-                                ],
-                            );
-                            poke_slab.set_root(poke_noun);
-                            let wire = FileWire::Write.to_wire();
-                            handle.poke(wire, poke_slab).await?;
+                            error!("file driver: error writing to {}: {}", path, e);
+                            NO
                         }
-                    }
+                    };
+                    let contents_noun = poke_slab.copy_into(contents_atom.as_noun());
+                    let poke_noun = T(
+                        &mut poke_slab,
+                        &[D(tas!(b"file")), D(tas!(b"write")), path_noun, contents_noun, success],
+                    );
+                    poke_slab.set_root(poke_noun);
+                    handle.poke(FileWire::Write.to_wire(), poke_slab).await?;
+                    continue;
+                }
+                tag if tag == tas!(b"append") => {
+                    let Some(contents_atom) = contents_noun.and_then(|c| c.as_atom().ok()) else {
+                        continue;
+                    };
+                    let result = sandbox
+                        .append(&path, atom_bytes(&contents_atom))
+                        .await
+                        .map(|()| D(0));
+                    (FileWire::Append, result)
+                }
+                tag if tag == tas!(b"delete") => {
+                    (FileWire::Delete, sandbox.delete(&path).await.map(|()| D(0)))
+                }
+                tag if tag == tas!(b"list") => {
+                    let result = match sandbox.list(&path).await {
+                        Ok(listing) => {
+                            let mut list = D(0);
+                            for (name, dir) in listing.into_iter().rev() {
+                                let Ok(name) = Atom::from_value(&mut poke_slab, name) else {
+                                    continue;
+                                };
+                                let entry = T(
+                                    &mut poke_slab,
+                                    &[name.as_noun(), if dir { YES } else { NO }],
+                                );
+                                list = T(&mut poke_slab, &[entry, list]);
+                            }
+                            Ok(list)
+                        }
+                        Err(e) => Err(e),
+                    };
+                    (FileWire::List, result)
+                }
+                tag if tag == tas!(b"stat") => {
+                    let result = sandbox.stat(&path).await.map(|stat| {
+                        let size = Atom::new(&mut poke_slab, stat.size).as_noun();
+                        let mtime = Atom::new(&mut poke_slab, stat.mtime).as_noun();
+                        T(
+                            &mut poke_slab,
+                            &[if stat.dir { YES } else { NO }, size, mtime],
+                        )
+                    });
+                    (FileWire::Stat, result)
                 }
                 _ => continue,
-            }
+            };
+
+            let result = match result {
+                Ok(value) => T(&mut poke_slab, &[YES, value]),
+                Err(e) => {
+                    debug!(
+                        "file driver: {} failed on {}: {}",
+                        wire.operation(),
+                        path,
+                        e
+                    );
+                    let error = error_noun(&mut poke_slab, &e);
+                    T(&mut poke_slab, &[NO, error])
+                }
+            };
+            let operation = Atom::from_value(&mut poke_slab, wire.operation())?.as_noun();
+            let poke_noun = T(
+                &mut poke_slab,
+                &[D(tas!(b"file")), operation, path_noun, result],
+            );
+            poke_slab.set_root(poke_noun);
+            handle.poke(wire.to_wire(), poke_slab).await?;
         }
+        Ok(())
     })
 }
+
+/// The bytes of an atom, without the padding of its last word
+fn atom_bytes(atom: &Atom) -> &[u8] {
+    &atom.as_ne_bytes()[..atom.bit_size().div_ceil(8)]
+}
+
+fn error_noun(slab: &mut NounSlab, error: &FileError) -> Noun {
+    let msg = Atom::from_value(&mut *slab, error.to_string())
+        .map(|msg| msg.as_noun())
+        .unwrap_or(D(0));
+    T(slab, &[D(error.tag()), msg])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    #[cfg_attr(miri, ignore)]
+    async fn test_sandbox_rejects_escapes() {
+        let dir = tempfile::tempdir().expect("tempdir");
+        let sandbox = FileSandbox::new(dir.path().join("root"))
+            .await
+            .expect("sandbox");
+        let root = sandbox.root().to_path_buf();
+
+        assert_eq!(
+            sandbox.resolve("a/b.txt").await.expect("relative"),
+            root.join("a/b.txt")
+        );
+        assert_eq!(
+            sandbox.resolve("a/../b.txt").await.expect("dotdot"),
+            root.join("b.txt")
+        );
+        let absolute = root.join("c.txt");
+        assert_eq!(
+            sandbox
+                .resolve(absolute.to_str().expect("utf-8"))
+                .await
+                .expect("absolute"),
+            absolute
+        );
+
+        for path in ["../outside", "a/../../outside", "/etc/passwd"] {
+            assert!(
+                matches!(sandbox.resolve(path).await, Err(FileError::Escape(_))),
+                "{path} should escape"
+            );
+        }
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(dir.path(), root.join("link")).expect("symlink");
+            std::os::unix::fs::symlink(dir.path().join("gone"), root.join("dangling"))
+                .expect("symlink");
+            assert!(matches!(
+                sandbox.resolve("link/x").await,
+                Err(FileError::Escape(_))
+            ));
+            assert!(sandbox.resolve("dangling").await.is_err());
+        }
+    }
+
+    #[tokio::test]
+    #[cfg_attr(miri, ignore)]
+    async fn test_sandbox_operations() {
+        let dir = tempfile::tempdir().expect("tempdir");
+        let sandbox = FileSandbox::new(dir.path()).await.expect("sandbox");
+
+        sandbox.write("logs/a.log", b"one").await.expect("write");
+        sandbox
+            .write("logs/a.log", b"two")
+            .await
+            .expect("overwrite");
+        sandbox
+            .append("logs/a.log", b"three")
+            .await
+            .expect("append");
+        assert_eq!(sandbox.read("logs/a.log").await.expect("read"), b"twothree");
+
+        sandbox.write("logs/b.log", b"").await.expect("write");
+        sandbox.write("logs/old/c.log", b"c").await.expect("write");
+        // Temporary files from atomic writes don't linger
+        assert_eq!(
+            sandbox.list("logs").await.expect("list"),
+            vec![
+                ("a.log".to_string(), false),
+                ("b.log".to_string(), false),
+                ("old".to_string(), true),
+            ]
+        );
+
+        let stat = sandbox.stat("logs/a.log").await.expect("stat");
+        assert!(!stat.dir);
+        assert_eq!(stat.size, 8);
+        assert!(stat.mtime > 0);
+
+        let FileError::Io(e) = sandbox.read("missing").await.expect_err("missing") else {
+            panic!("expected an IO error");
+        };
+        assert_eq!(e.kind(), std::io::ErrorKind::NotFound);
+        assert!(sandbox.delete("logs/old").await.is_err());
+        sandbox.delete("logs/old/c.log").await.expect("delete file");
+        sandbox.delete("logs/old").await.expect("delete empty dir");
+        assert!(matches!(
+            sandbox.delete(".").await,
+            Err(FileError::Escape(_))
+        ));
+        assert_eq!(sandbox.list("logs").await.expect("list").len(), 2);
+    }
+
+    #[test]
+    fn test_atom_bytes_drops_padding() {
+        let mut slab = NounSlab::new();
+        let atom = Atom::from_value(&mut slab, "abc").expect("atom");
+        assert_eq!(atom_bytes(&atom), b"abc");
+    }
+}
@@ -23,7 +23,20 @@ pub struct AcmeManager {
 }
 
 impl AcmeManager {
+    /// ACME against Let's Encrypt production
     pub async fn new(domain: String, email: String, cache_dir: PathBuf) -> Result<Self> {
+        Self::with_directory(domain, email, cache_dir, LetsEncrypt::Production.url()).await
+    }
+
+    /// ACME against the directory at `directory_url`, e.g. Let's Encrypt staging or Pebble.
+    /// The account in `cache_dir` belongs to the directory it was created with, so use a
+    /// separate `cache_dir` per directory.
+    pub async fn with_directory(
+        domain: String,
+        email: String,
+        cache_dir: PathBuf,
+        directory_url: &str,
+    ) -> Result<Self> {
         // Install default crypto provider for rustls
         let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
 
@@ -38,14 +51,17 @@ impl AcmeManager {
             info!("Loaded existing ACME account");
             account
         } else {
-            info!("Creating new ACME account for {}", email);
+            info!(
+                "Creating new ACME account for {} at {}",
+                email, directory_url
+            );
             let (account, credentials) = Account::create(
                 &NewAccount {
                     contact: &[&format!("mailto:{}", email)],
                     terms_of_service_agreed: true,
                     only_return_existing: false,
                 },
-                LetsEncrypt::Production.url(),
+                directory_url,
                 None,
             )
             .await?;
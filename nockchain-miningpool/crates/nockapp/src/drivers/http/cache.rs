@@ -0,0 +1,422 @@
+//! Response cache for the HTTP driver.
+//!
+//! Responses are cached per [CacheKey]: method, path, query and the values of a
+//! configurable set of request headers (by default `hx-request`, so HTMX fragments and
+//! full pages are cached apart). Requests with credentials (`authorization` or `cookie`)
+//! or their own `cache-control: no-store`, `no-cache` or `private` bypass the cache, since
+//! their responses may be meant for them alone. Each entry has its own TTL, taken from the
+//! kernel's `cache-control: max-age=N` header when it sets one and the configured default
+//! otherwise. `no-store`, `no-cache` and `private` responses aren't cached. The cache is
+//! bounded by entry count and total body size, evicting the least recently used entries
+//! first.
+//!
+//! The kernel invalidates entries with an `[%uncache prefixes=(list @t)]` effect, which
+//! drops every entry whose path starts with one of `prefixes`, or every entry if the list
+//! is empty.
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::time::{Duration, Instant};
+
+use axum::body::Body;
+use axum::http::{HeaderMap, Method, StatusCode, Uri};
+use axum::response::Response;
+use tracing::debug;
+
+use super::http::HttpError;
+use crate::Bytes;
+
+pub const DEFAULT_CACHE_MAX_ENTRIES: usize = 1024;
+pub const DEFAULT_CACHE_MAX_BYTES: usize = 64 * 1024 * 1024;
+pub const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(60);
+/// Request headers which carry credentials, so the response may be for this client only
+const CREDENTIAL_HEADERS: [&str; 2] = ["authorization", "cookie"];
+
+/// Limits and keying for a [ResponseCache]
+#[derive(Clone, Debug)]
+pub struct CacheConfig {
+    /// TTL for responses which don't set `max-age`
+    pub default_ttl: Duration,
+    pub max_entries: usize,
+    /// Bound on the total size of the cached bodies
+    pub max_bytes: usize,
+    /// Request headers whose values are part of the key, lowercase
+    pub vary_headers: Vec<String>,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            default_ttl: DEFAULT_CACHE_TTL,
+            max_entries: DEFAULT_CACHE_MAX_ENTRIES,
+            max_bytes: DEFAULT_CACHE_MAX_BYTES,
+            vary_headers: vec!["hx-request".to_string()],
+        }
+    }
+}
+
+impl CacheConfig {
+    /// Read `EXPIRE_CACHE` (default TTL in seconds), `HTTP_CACHE_MAX_ENTRIES`,
+    /// `HTTP_CACHE_MAX_BYTES` and `HTTP_CACHE_VARY` (comma-separated header names)
+    pub fn from_env() -> Self {
+        let mut config = CacheConfig::default();
+        let parse = |name: &str| env::var(name).ok().and_then(|s| s.parse::<u64>().ok());
+        if let Some(default_ttl) = parse("EXPIRE_CACHE") {
+            config.default_ttl = Duration::from_secs(default_ttl);
+        }
+        if let Some(max_entries) = parse("HTTP_CACHE_MAX_ENTRIES") {
+            config.max_entries = max_entries as usize;
+        }
+        if let Some(max_bytes) = parse("HTTP_CACHE_MAX_BYTES") {
+            config.max_bytes = max_bytes as usize;
+        }
+        if let Ok(vary) = env::var("HTTP_CACHE_VARY") {
+            config.vary_headers = vary
+                .split(',')
+                .map(|name| name.trim().to_ascii_lowercase())
+                .filter(|name| !name.is_empty())
+                .collect();
+        }
+        config
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    method: String,
+    path: String,
+    query: Option<String>,
+    vary: Vec<(String, String)>,
+}
+
+impl CacheKey {
+    /// The key for a request, or `None` if it mustn't be served from or stored in the cache
+    pub fn new(
+        method: &Method,
+        uri: &Uri,
+        headers: &HeaderMap,
+        vary_headers: &[String],
+    ) -> Option<Self> {
+        if CREDENTIAL_HEADERS
+            .iter()
+            .any(|name| headers.contains_key(*name))
+        {
+            return None;
+        }
+        let bypass = headers
+            .get_all("cache-control")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .any(forbids_caching);
+        if bypass {
+            return None;
+        }
+        let vary = vary_headers
+            .iter()
+            .filter_map(|name| {
+                let value = headers.get(name.as_str())?.to_str().ok()?;
+                Some((name.clone(), value.to_string()))
+            })
+            .collect();
+        Some(CacheKey {
+            method: method.to_string(),
+            path: uri.path().to_string(),
+            query: uri.query().map(str::to_string),
+            vary,
+        })
+    }
+}
+
+/// Whether a `cache-control` value has `no-store`, `no-cache` or `private`
+fn forbids_caching(cache_control: &str) -> bool {
+    cache_control.split(',').map(str::trim).any(|directive| {
+        directive.eq_ignore_ascii_case("no-store")
+            || directive.eq_ignore_ascii_case("no-cache")
+            || directive.eq_ignore_ascii_case("private")
+    })
+}
+
+#[derive(Clone)]
+pub struct CachedResponse {
+    status: StatusCode,
+    headers: Vec<(String, String)>,
+    body: Option<Bytes>,
+}
+
+impl CachedResponse {
+    pub fn new(status: StatusCode, headers: Vec<(String, String)>, body: Option<Bytes>) -> Self {
+        Self {
+            status,
+            headers,
+            body,
+        }
+    }
+
+    pub fn to_response(&self) -> Result<Response<Body>, HttpError> {
+        let mut res = Response::builder().status(self.status);
+        for (k, v) in &self.headers {
+            res = res.header(k, v);
+        }
+        let body = self
+            .body
+            .as_ref()
+            .map(|b| Body::from(b.clone()))
+            .unwrap_or_else(|| Body::empty());
+        res.body(body).map_err(HttpError::ResponseBuildError)
+    }
+
+    fn size(&self) -> usize {
+        self.body.as_ref().map_or(0, |body| body.len())
+    }
+
+    /// How long this response may be cached for, or `None` if it mustn't be
+    fn ttl(&self, default_ttl: Duration) -> Option<Duration> {
+        let Some((_, cache_control)) = self
+            .headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("cache-control"))
+        else {
+            return Some(default_ttl);
+        };
+        if forbids_caching(cache_control) {
+            return None;
+        }
+        let mut ttl = default_ttl;
+        for directive in cache_control.split(',').map(str::trim) {
+            let directive = directive.to_ascii_lowercase();
+            if let Some(max_age) = directive.strip_prefix("max-age=") {
+                match max_age.parse::<u64>() {
+                    Ok(0) => return None,
+                    Ok(secs) => ttl = Duration::from_secs(secs),
+                    Err(_) => {}
+                }
+            }
+        }
+        Some(ttl)
+    }
+}
+
+struct CacheEntry {
+    response: CachedResponse,
+    expires: Instant,
+    last_used: u64,
+}
+
+/// Keyed response cache with per-entry TTLs and LRU eviction
+pub struct ResponseCache {
+    config: CacheConfig,
+    entries: HashMap<CacheKey, CacheEntry>,
+    /// Last use -> key, oldest first
+    lru: BTreeMap<u64, CacheKey>,
+    clock: u64,
+    bytes: usize,
+}
+
+impl ResponseCache {
+    pub fn new(config: CacheConfig) -> Self {
+        ResponseCache {
+            config,
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+            clock: 0,
+            bytes: 0,
+        }
+    }
+
+    pub fn config(&self) -> &CacheConfig {
+        &self.config
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&mut self, key: &CacheKey) -> Option<CachedResponse> {
+        let expired = self.entries.get(key)?.expires <= Instant::now();
+        if expired {
+            debug!("Cached response for {} expired", key.path);
+            self.remove(key);
+            return None;
+        }
+        self.clock += 1;
+        let entry = self.entries.get_mut(key)?;
+        self.lru.remove(&entry.last_used);
+        entry.last_used = self.clock;
+        self.lru.insert(self.clock, key.clone());
+        Some(entry.response.clone())
+    }
+
+    /// Cache `response` under `key`, unless its `cache-control` forbids it or it is too
+    /// large to ever fit. Returns whether it was cached.
+    pub fn insert(&mut self, key: CacheKey, response: CachedResponse) -> bool {
+        let Some(ttl) = response.ttl(self.config.default_ttl) else {
+            return false;
+        };
+        let size = response.size();
+        if self.config.max_entries == 0 || size > self.config.max_bytes {
+            return false;
+        }
+        self.remove(&key);
+        while self.entries.len() >= self.config.max_entries
+            || self.bytes + size > self.config.max_bytes
+        {
+            let Some((_, oldest)) = self.lru.pop_first() else {
+                break;
+            };
+            debug!("Evicting cached response for {}", oldest.path);
+            if let Some(entry) = self.entries.remove(&oldest) {
+                self.bytes -= entry.response.size();
+            }
+        }
+        self.clock += 1;
+        self.lru.insert(self.clock, key.clone());
+        self.bytes += size;
+        self.entries.insert(
+            key,
+            CacheEntry {
+                response,
+                expires: Instant::now() + ttl,
+                last_used: self.clock,
+            },
+        );
+        true
+    }
+
+    /// Drop the entries whose path starts with one of `prefixes`, or every entry if
+    /// `prefixes` is empty. Returns how many were dropped.
+    pub fn invalidate(&mut self, prefixes: &[String]) -> usize {
+        let keys: Vec<CacheKey> = self
+            .entries
+            .keys()
+            .filter(|key| {
+                prefixes.is_empty()
+                    || prefixes
+                        .iter()
+                        .any(|prefix| key.path.starts_with(prefix.as_str()))
+            })
+            .cloned()
+            .collect();
+        for key in &keys {
+            self.remove(key);
+        }
+        keys.len()
+    }
+
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.lru.remove(&entry.last_used);
+            self.bytes -= entry.response.size();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key_with(uri: &str, headers: &[(&'static str, &str)]) -> Option<CacheKey> {
+        let mut header_map = HeaderMap::new();
+        for (name, value) in headers {
+            header_map.append(*name, value.parse().expect("header value"));
+        }
+        let uri: Uri = uri.parse().expect("uri");
+        CacheKey::new(&Method::GET, &uri, &header_map, &["hx-request".to_string()])
+    }
+
+    fn key(uri: &str, htmx: bool) -> CacheKey {
+        let headers: &[(&'static str, &str)] = if htmx { &[("hx-request", "true")] } else { &[] };
+        key_with(uri, headers).expect("cacheable request")
+    }
+
+    fn response(body: &str, headers: &[(&str, &str)]) -> CachedResponse {
+        CachedResponse::new(
+            StatusCode::OK,
+            headers
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            Some(Bytes::from(body.to_string())),
+        )
+    }
+
+    fn body(cached: CachedResponse) -> Bytes {
+        cached.body.expect("body")
+    }
+
+    #[test]
+    fn test_cache_keys_and_invalidation() {
+        let mut cache = ResponseCache::new(CacheConfig::default());
+        assert!(cache.insert(key("/blocks?page=1", false), response("page 1", &[])));
+        assert!(cache.insert(key("/blocks?page=2", false), response("page 2", &[])));
+        assert!(cache.insert(key("/blocks?page=1", true), response("fragment", &[])));
+        assert!(cache.insert(key("/txs", false), response("txs", &[])));
+        assert!(!cache.insert(
+            key("/live", false),
+            response("live", &[("Cache-Control", "no-store")])
+        ));
+
+        let page_1 = cache.get(&key("/blocks?page=1", false)).expect("cached");
+        assert_eq!(body(page_1), "page 1");
+        let fragment = cache.get(&key("/blocks?page=1", true)).expect("cached");
+        assert_eq!(body(fragment), "fragment");
+        assert!(cache.get(&key("/live", false)).is_none());
+
+        assert_eq!(cache.invalidate(&["/blocks".to_string()]), 3);
+        assert!(cache.get(&key("/blocks?page=2", false)).is_none());
+        assert!(cache.get(&key("/txs", false)).is_some());
+        assert_eq!(cache.invalidate(&[]), 1);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_cache_ttl_and_lru_eviction() {
+        let mut cache = ResponseCache::new(CacheConfig {
+            max_entries: 2,
+            max_bytes: 10,
+            ..CacheConfig::default()
+        });
+        assert!(cache.insert(key("/a", false), response("aaaa", &[])));
+        assert!(cache.insert(key("/b", false), response("bbbb", &[])));
+        // Using /a makes /b the least recently used
+        assert!(cache.get(&key("/a", false)).is_some());
+        assert!(cache.insert(key("/c", false), response("cccc", &[])));
+        assert!(cache.get(&key("/b", false)).is_none());
+        assert!(cache.get(&key("/a", false)).is_some());
+        assert!(cache.get(&key("/c", false)).is_some());
+
+        // The byte bound evicts too, and a body over it is never cached
+        assert!(cache.insert(key("/d", false), response("dddddddd", &[])));
+        assert_eq!(cache.len(), 1);
+        assert!(!cache.insert(key("/e", false), response("eeeeeeeeeee", &[])));
+
+        assert!(!cache.insert(
+            key("/f", false),
+            response("f", &[("cache-control", "public, max-age=0")])
+        ));
+        cache
+            .entries
+            .get_mut(&key("/d", false))
+            .expect("entry")
+            .expires = Instant::now();
+        assert!(cache.get(&key("/d", false)).is_none());
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_cache_bypassed_for_private_requests() {
+        assert!(key_with("/blocks", &[("authorization", "Bearer secret")]).is_none());
+        assert!(key_with("/blocks", &[("cookie", "session=1")]).is_none());
+        assert!(key_with("/blocks", &[("cache-control", "max-age=0, No-Store")]).is_none());
+        assert!(key_with("/blocks", &[("cache-control", "private")]).is_none());
+        assert!(key_with("/blocks", &[("cache-control", "max-age=60")]).is_some());
+
+        // Responses without max-age expire after the default TTL
+        let mut cache = ResponseCache::new(CacheConfig::default());
+        assert!(cache.insert(key("/blocks", false), response("blocks", &[])));
+        let expires = cache.entries[&key("/blocks", false)].expires;
+        assert!(expires <= Instant::now() + DEFAULT_CACHE_TTL);
+    }
+}
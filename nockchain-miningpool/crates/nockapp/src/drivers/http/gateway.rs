@@ -0,0 +1,483 @@
+//! JSON-RPC gateway for peeks and pokes.
+//!
+//! Serves JSON-RPC 2.0 on `POST /rpc`. Requests must carry `Authorization: Bearer <token>`.
+//! The methods are:
+//!
+//! - `peek`, with params `{"path": [<noun>, ...]}`. The result is the peek result noun, or an
+//!   error if the peek failed.
+//! - `poke`, with params `{"cause": <noun>}`. The result is `{"ack": true}` or
+//!   `{"ack": false}` if the kernel nacked.
+//!
+//! Each method has an allow-list of tags. A peek is allowed if the first element of its path
+//! is in the list, a poke if the head of its cause is. `*` allows everything, and a method
+//! with an empty list is disabled.
+//!
+//! Nouns are written in JSON as:
+//!
+//! - a non-negative integer: that atom, e.g. `42`
+//! - a string: the cord (or `@tas`) with those UTF-8 bytes, e.g. `"blocks"` for `%blocks`
+//! - `{"atom": "<decimal>"}`: an atom too large for a JSON number
+//! - `{"hex": "<bytes>"}`: an atom made of the hex-encoded bytes, first byte least
+//!   significant, as with a cord
+//! - `null`: `~`, i.e. `0`
+//! - an array of two or more nouns: the cell `[a b c]`, nested to the right
+//!
+//! Results use the same schema: cells become arrays flattened along the right, atoms below
+//! 2^53 become numbers and larger atoms `{"hex": ...}`. Atoms are never turned back into
+//! strings, since there is no telling a cord from a number.
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use axum::body::Bytes as BodyBytes;
+use axum::extract::State;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use axum::{serve, Router};
+use either::{Left, Right};
+use ibig::UBig;
+use nockvm::noun::{Atom, Noun, D, T};
+use serde_json::{json, Map, Value};
+use tokio::net::TcpListener;
+use tracing::{debug, info, warn};
+
+use crate::drivers::http::http::HttpError;
+use crate::nockapp::driver::{make_driver, IODriverFn, NockAppHandle, PokeResult};
+use crate::nockapp::wire::Wire;
+use crate::noun::slab::NounSlab;
+use crate::{AtomExt, Bytes};
+
+/// Wildcard allow-list entry
+pub const ALLOW_ALL: &str = "*";
+/// Largest integer a JSON number holds exactly
+const MAX_JSON_INTEGER: u64 = (1 << 53) - 1;
+/// Deepest noun converted to or from JSON, so a hostile request can't exhaust the stack
+const MAX_DEPTH: usize = 512;
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const KERNEL_ERROR: i64 = -32000;
+const FORBIDDEN: i64 = -32003;
+
+pub enum GatewayWire {
+    Poke,
+}
+
+impl Wire for GatewayWire {
+    const VERSION: u64 = 1;
+    const SOURCE: &'static str = "gateway";
+}
+
+/// Configuration for the [gateway] driver
+#[derive(Clone, Debug)]
+pub struct GatewayConfig {
+    pub bind: SocketAddr,
+    /// Bearer token every request must present
+    pub token: String,
+    /// Tags allowed at the head of peek paths
+    pub peeks: Vec<String>,
+    /// Tags allowed at the head of poke causes
+    pub pokes: Vec<String>,
+}
+
+impl GatewayConfig {
+    /// A gateway on `bind` with every method disabled
+    pub fn new(bind: SocketAddr, token: String) -> Self {
+        GatewayConfig {
+            bind,
+            token,
+            peeks: Vec::new(),
+            pokes: Vec::new(),
+        }
+    }
+
+    pub fn allow_peek(mut self, tag: impl Into<String>) -> Self {
+        self.peeks.push(tag.into());
+        self
+    }
+
+    pub fn allow_poke(mut self, tag: impl Into<String>) -> Self {
+        self.pokes.push(tag.into());
+        self
+    }
+}
+
+struct GatewayState {
+    handle: NockAppHandle,
+    config: GatewayConfig,
+}
+
+/// JSON-RPC error, sent back in the `error` member
+#[derive(Debug)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        RpcError {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+/// JSON-RPC gateway IO driver
+pub fn gateway(config: GatewayConfig) -> IODriverFn {
+    make_driver(move |handle| async move {
+        if config.token.is_empty() {
+            return Err(HttpError::MissingAuthToken.into());
+        }
+        let listener = TcpListener::bind(config.bind)
+            .await
+            .map_err(HttpError::BindError)?;
+        let addr = listener
+            .local_addr()
+            .map_err(|_| HttpError::LocalAddrError)?;
+        info!(
+            "JSON-RPC gateway listening on http://{}/rpc (peeks: {:?}, pokes: {:?})",
+            addr, config.peeks, config.pokes
+        );
+        let state = Arc::new(GatewayState { handle, config });
+        let app = Router::new()
+            .route("/rpc", post(rpc_handler))
+            .with_state(state);
+        serve(listener, app.into_make_service())
+            .await
+            .map_err(|e| HttpError::ServeError(e.to_string()))?;
+        Ok(())
+    })
+}
+
+async fn rpc_handler(
+    State(state): State<Arc<GatewayState>>,
+    headers: HeaderMap,
+    body: BodyBytes,
+) -> Response {
+    if !authorized(&headers, &state.config.token) {
+        warn!("gateway: rejecting request with missing or wrong token");
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    let reply = match serde_json::from_slice::<Value>(&body) {
+        Ok(request) => dispatch(&state, request).await,
+        Err(e) => rpc_reply(Value::Null, Err(RpcError::new(PARSE_ERROR, e.to_string()))),
+    };
+    (
+        [(header::CONTENT_TYPE, "application/json")],
+        reply.to_string(),
+    )
+        .into_response()
+}
+
+fn authorized(headers: &HeaderMap, token: &str) -> bool {
+    let Some(presented) = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+    else {
+        return false;
+    };
+    // Compare in constant time so the token can't be guessed byte by byte
+    presented.len() == token.len()
+        && presented
+            .bytes()
+            .zip(token.bytes())
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+async fn dispatch(state: &GatewayState, request: Value) -> Value {
+    let id = request.get("id").cloned().unwrap_or(Value::Null);
+    if request.get("jsonrpc").and_then(Value::as_str) != Some("2.0") {
+        return rpc_reply(
+            id,
+            Err(RpcError::new(INVALID_REQUEST, "expected jsonrpc 2.0")),
+        );
+    }
+    let params = request.get("params").cloned().unwrap_or(Value::Null);
+    let result = match request.get("method").and_then(Value::as_str) {
+        Some("peek") => peek(state, &params).await,
+        Some("poke") => poke(state, &params).await,
+        Some(method) => Err(RpcError::new(
+            METHOD_NOT_FOUND,
+            format!("unknown method {}", method),
+        )),
+        None => Err(RpcError::new(INVALID_REQUEST, "missing method")),
+    };
+    rpc_reply(id, result)
+}
+
+fn rpc_reply(id: Value, result: Result<Value, RpcError>) -> Value {
+    match result {
+        Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+        Err(e) => {
+            debug!("gateway: request {} failed: {:?}", id, e);
+            json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": {"code": e.code, "message": e.message},
+            })
+        }
+    }
+}
+
+async fn peek(state: &GatewayState, params: &Value) -> Result<Value, RpcError> {
+    let Some(path) = params.get("path").and_then(Value::as_array) else {
+        return Err(RpcError::new(INVALID_PARAMS, "expected params.path array"));
+    };
+    check_allowed("peek", &state.config.peeks, path.first())?;
+    let mut slab = NounSlab::new();
+    let mut list = D(0);
+    for element in path.iter().rev() {
+        let element = json_to_noun(&mut slab, element, 0)?;
+        list = T(&mut slab, &[element, list]);
+    }
+    slab.set_root(list);
+    match state.handle.peek(slab).await {
+        Ok(Some(result)) => noun_to_json(unsafe { *result.root() }, 0),
+        Ok(None) => Err(RpcError::new(KERNEL_ERROR, "peek failed")),
+        Err(e) => Err(RpcError::new(KERNEL_ERROR, e.to_string())),
+    }
+}
+
+async fn poke(state: &GatewayState, params: &Value) -> Result<Value, RpcError> {
+    let Some(cause) = params.get("cause") else {
+        return Err(RpcError::new(INVALID_PARAMS, "expected params.cause"));
+    };
+    check_allowed(
+        "poke",
+        &state.config.pokes,
+        cause.as_array().and_then(|c| c.first()),
+    )?;
+    let mut slab = NounSlab::new();
+    let noun = json_to_noun(&mut slab, cause, 0)?;
+    slab.set_root(noun);
+    match state.handle.poke(GatewayWire::Poke.to_wire(), slab).await {
+        Ok(PokeResult::Ack) => Ok(json!({"ack": true})),
+        Ok(PokeResult::Nack) => Ok(json!({"ack": false})),
+        Err(e) => Err(RpcError::new(KERNEL_ERROR, e.to_string())),
+    }
+}
+
+/// Check the leading tag of a request against a method's allow-list
+fn check_allowed(method: &str, allowed: &[String], tag: Option<&Value>) -> Result<(), RpcError> {
+    if allowed.iter().any(|allowed| allowed == ALLOW_ALL) {
+        return Ok(());
+    }
+    match tag.and_then(Value::as_str) {
+        Some(tag) if allowed.iter().any(|allowed| allowed == tag) => Ok(()),
+        Some(tag) => Err(RpcError::new(
+            FORBIDDEN,
+            format!("{} %{} is not allowed", method, tag),
+        )),
+        None => Err(RpcError::new(
+            FORBIDDEN,
+            format!("{} must start with an allowed tag", method),
+        )),
+    }
+}
+
+/// Build a noun from its JSON form, as described in the module docs
+fn json_to_noun(slab: &mut NounSlab, value: &Value, depth: usize) -> Result<Noun, RpcError> {
+    if depth > MAX_DEPTH {
+        return Err(RpcError::new(INVALID_PARAMS, "noun is too deep"));
+    }
+    let invalid = |what: &str| RpcError::new(INVALID_PARAMS, format!("invalid noun: {}", what));
+    match value {
+        Value::Null => Ok(D(0)),
+        Value::Number(number) => {
+            let value = number
+                .as_u64()
+                .ok_or_else(|| invalid("numbers must be non-negative integers"))?;
+            Ok(Atom::new(slab, value).as_noun())
+        }
+        Value::String(cord) => {
+            Ok(Atom::from_bytes(slab, &Bytes::copy_from_slice(cord.as_bytes())).as_noun())
+        }
+        Value::Array(elements) => {
+            let Some((last, init)) = elements.split_last().filter(|(_, init)| !init.is_empty())
+            else {
+                return Err(invalid("cells need at least two elements"));
+            };
+            let mut tail = json_to_noun(slab, last, depth + 1)?;
+            for element in init.iter().rev() {
+                let head = json_to_noun(slab, element, depth + 1)?;
+                tail = T(slab, &[head, tail]);
+            }
+            Ok(tail)
+        }
+        Value::Object(object) => json_object_to_atom(slab, object).ok_or_else(|| {
+            invalid("objects must be {\"atom\": \"<decimal>\"} or {\"hex\": \"<bytes>\"}")
+        }),
+        Value::Bool(_) => Err(invalid("booleans have no noun form")),
+    }
+}
+
+fn json_object_to_atom(slab: &mut NounSlab, object: &Map<String, Value>) -> Option<Noun> {
+    if object.len() != 1 {
+        return None;
+    }
+    if let Some(decimal) = object.get("atom").and_then(Value::as_str) {
+        let big = UBig::from_str_radix(decimal, 10).ok()?;
+        return Some(Atom::from_ubig(slab, &big).as_noun());
+    }
+    let bytes = decode_hex(object.get("hex").and_then(Value::as_str)?)?;
+    Some(Atom::from_bytes(slab, &Bytes::from(bytes)).as_noun())
+}
+
+/// Render a noun in the JSON form described in the module docs
+fn noun_to_json(noun: Noun, depth: usize) -> Result<Value, RpcError> {
+    if depth > MAX_DEPTH {
+        return Err(RpcError::new(KERNEL_ERROR, "result is too deep to render"));
+    }
+    match noun.as_either_atom_cell() {
+        Left(atom) => Ok(atom_to_json(atom)),
+        Right(mut cell) => {
+            let mut elements = Vec::new();
+            loop {
+                elements.push(noun_to_json(cell.head(), depth + 1)?);
+                match cell.tail().as_either_atom_cell() {
+                    Left(atom) => {
+                        elements.push(atom_to_json(atom));
+                        break;
+                    }
+                    Right(tail) => cell = tail,
+                }
+            }
+            Ok(Value::Array(elements))
+        }
+    }
+}
+
+fn atom_to_json(atom: Atom) -> Value {
+    match atom.as_u64() {
+        Ok(value) if value <= MAX_JSON_INTEGER => json!(value),
+        _ => {
+            let mut bytes = atom.to_le_bytes();
+            while bytes.last() == Some(&0) {
+                bytes.pop();
+            }
+            json!({"hex": encode_hex(&bytes)})
+        }
+    }
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use nockvm_macros::tas;
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::nockapp::driver::{test_handle, IOAction};
+
+    fn to_noun_and_back(value: Value) -> Value {
+        let mut slab = NounSlab::new();
+        let noun = json_to_noun(&mut slab, &value, 0).expect("json_to_noun");
+        noun_to_json(noun, 0).expect("noun_to_json")
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_noun_json_schema() {
+        let mut slab = NounSlab::new();
+        let noun = json_to_noun(&mut slab, &json!(["blocks", 5, null]), 0).expect("noun");
+        let cell = noun.as_cell().expect("cell");
+        assert!(unsafe { cell.head().raw_equals(&D(tas!(b"blocks"))) });
+        let tail = cell.tail().as_cell().expect("tail");
+        assert!(unsafe { tail.head().raw_equals(&D(5)) });
+        assert!(unsafe { tail.tail().raw_equals(&D(0)) });
+
+        assert_eq!(
+            to_noun_and_back(json!([1, [2, 3], 4])),
+            json!([1, [2, 3], 4])
+        );
+        let big = json!({"hex": "0102030405060708090a"});
+        assert_eq!(to_noun_and_back(big.clone()), big);
+        assert_eq!(
+            to_noun_and_back(json!({"atom": "18446744073709551616"})),
+            json!({"hex": "000000000000000001"})
+        );
+
+        for invalid in [json!(-1), json!(true), json!([1]), json!({"hex": "abc"})] {
+            assert!(json_to_noun(&mut slab, &invalid, 0).is_err());
+        }
+    }
+
+    fn test_state(config: GatewayConfig) -> (GatewayState, mpsc::Receiver<IOAction>) {
+        let (handle, rx_io) = test_handle(8);
+        (GatewayState { handle, config }, rx_io)
+    }
+
+    #[tokio::test]
+    #[cfg_attr(miri, ignore)]
+    async fn test_gateway_dispatch() {
+        let config = GatewayConfig::new("127.0.0.1:0".parse().expect("addr"), "secret".into())
+            .allow_peek("heavy");
+        let (state, mut rx_io) = test_state(config);
+
+        let mut headers = HeaderMap::new();
+        assert!(!authorized(&headers, "secret"));
+        headers.insert(
+            header::AUTHORIZATION,
+            "Bearer secret".parse().expect("header"),
+        );
+        assert!(authorized(&headers, "secret"));
+        assert!(!authorized(&headers, "secrets"));
+
+        // Pokes aren't allowed at all
+        let reply = dispatch(
+            &state,
+            json!({"jsonrpc": "2.0", "id": 1, "method": "poke", "params": {"cause": ["fact", 0]}}),
+        )
+        .await;
+        assert_eq!(reply["error"]["code"], json!(FORBIDDEN));
+
+        let kernel = tokio::spawn(async move {
+            let Some(IOAction::Peek {
+                path,
+                result_channel,
+            }) = rx_io.recv().await
+            else {
+                panic!("expected a peek");
+            };
+            let path = unsafe { *path.root() };
+            assert!(unsafe {
+                path.as_cell()
+                    .expect("path")
+                    .head()
+                    .raw_equals(&D(tas!(b"heavy")))
+            });
+            let mut result = NounSlab::new();
+            let noun = T(&mut result, &[D(0), D(0), D(42)]);
+            result.set_root(noun);
+            let _ = result_channel.send(Some(result));
+        });
+        let reply = dispatch(
+            &state,
+            json!({"jsonrpc": "2.0", "id": 2, "method": "peek", "params": {"path": ["heavy"]}}),
+        )
+        .await;
+        kernel.await.expect("kernel task");
+        assert_eq!(
+            reply,
+            json!({"jsonrpc": "2.0", "id": 2, "result": [0, 0, 42]})
+        );
+    }
+}
@@ -1,16 +1,19 @@
 use std::collections::HashMap;
 use std::env;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use axum::body::Body;
-use axum::extract::{Path, State};
+use axum::extract::{FromRef, Path, State};
 use axum::http::{HeaderMap, Method, StatusCode, Uri};
 use axum::response::Response;
 use axum::routing::get;
 use axum::{serve, Router};
 use axum_server::tls_rustls::RustlsConfig;
+use instant_acme::LetsEncrypt;
 use nockvm::noun::{Atom, D, T};
 use nockvm_macros::tas;
 use tokio::select;
@@ -19,16 +22,22 @@ use tower_http::services::ServeDir;
 use tracing::{debug, error, info, warn};
 
 use crate::drivers::http::acme::AcmeManager;
-use crate::nockapp::driver::{make_driver, IODriverFn, PokeResult};
+use crate::drivers::http::cache::{CacheConfig, CacheKey, CachedResponse, ResponseCache};
+use crate::drivers::http::push::{
+    forward_push_effects, sse_handler, ws_handler, PushConfig, PushHub,
+};
+use crate::nockapp::driver::{make_driver, IODriverFn, NockAppHandle, PokeResult};
 use crate::nockapp::wire::{Wire, WireRepr};
 use crate::nockapp::NockAppError;
 use crate::noun::slab::NounSlab;
-use crate::{AtomExt, Bytes};
+use crate::{AtomExt, Bytes, NounExt};
 
 #[derive(Debug, thiserror::Error)]
 pub enum HttpError {
     #[error("Failed to bind TCP listener: {0}")]
     BindError(#[from] std::io::Error),
+    #[error("No auth token configured")]
+    MissingAuthToken,
     #[error("Failed to get local address")]
     LocalAddrError,
     #[error("Failed to serve HTTP: {0}")]
@@ -59,12 +68,22 @@ pub enum HttpError {
     EnvError(#[from] env::VarError),
     #[error("Noun processing error: {0}")]
     NounError(#[from] nockvm::noun::Error),
+    #[error("Missing configuration: {0}")]
+    MissingConfig(&'static str),
+    #[error("Invalid bind address: {0}")]
+    InvalidBindAddress(String),
+    #[error("Failed to load TLS certificate or key: {0}")]
+    Tls(std::io::Error),
+    #[error("Push channel name is longer than {0} bytes")]
+    PushChannelNameTooLong(usize),
+    #[error("Already at the limit of {0} push channels")]
+    TooManyPushChannels(usize),
 }
 
 impl From<HttpError> for NockAppError {
     fn from(err: HttpError) -> Self {
         match err {
-            HttpError::BindError(io_err) => NockAppError::IoError(io_err),
+            HttpError::BindError(io_err) | HttpError::Tls(io_err) => NockAppError::IoError(io_err),
             HttpError::EffectError(nock_err) => nock_err,
             HttpError::Utf8Error(utf8_err) => NockAppError::FromUtf8Error(utf8_err),
             _ => NockAppError::OtherError,
@@ -72,6 +91,9 @@ impl From<HttpError> for NockAppError {
     }
 }
 
+/// How long a request may wait for the kernel's final response before it fails
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(120);
+
 type Responder = oneshot::Sender<Result<Response, StatusCode>>;
 #[derive(Debug)]
 struct RequestMessage {
@@ -91,6 +113,8 @@ struct ResponseBuilder {
 
 pub enum HttpWire {
     Request,
+    /// A WebSocket client message, see [`super::push`]
+    Push,
 }
 
 impl Wire for HttpWire {
@@ -100,6 +124,7 @@ impl Wire for HttpWire {
     fn to_wire(&self) -> WireRepr {
         let tags = match self {
             HttpWire::Request => vec!["req".into()],
+            HttpWire::Push => vec!["push".into()],
         };
         WireRepr::new(HttpWire::SOURCE, HttpWire::VERSION, tags)
     }
@@ -111,46 +136,17 @@ fn get_id() -> u64 {
     COUNTER.fetch_add(1, Ordering::Relaxed)
 }
 
-#[derive(Clone)]
-struct CachedResponse {
-    status: StatusCode,
-    headers: Vec<(String, String)>,
-    body: Option<Bytes>,
-    timestamp: Instant,
-}
-
-impl CachedResponse {
-    fn new(status: StatusCode, headers: Vec<(String, String)>, body: Option<Bytes>) -> Self {
-        Self {
-            status,
-            headers,
-            body,
-            timestamp: Instant::now(),
-        }
-    }
-
-    fn is_expired(&self, max_age: Duration) -> bool {
-        self.timestamp.elapsed() > max_age
-    }
-
-    fn to_response(&self) -> Result<Response<Body>, HttpError> {
-        let mut res = Response::builder().status(self.status);
-        for (k, v) in &self.headers {
-            res = res.header(k, v);
-        }
-        let body = self
-            .body
-            .as_ref()
-            .map(|b| Body::from(b.clone()))
-            .unwrap_or_else(|| Body::empty());
-        res.body(body).map_err(HttpError::ResponseBuildError)
-    }
-}
-
 #[derive(Clone)]
 struct AppState {
     sender: Arc<RwLock<tokio::sync::mpsc::Sender<RequestMessage>>>,
     challenges: Option<Arc<RwLock<HashMap<String, String>>>>,
+    push: Arc<PushHub>,
+}
+
+impl FromRef<AppState> for Arc<PushHub> {
+    fn from_ref(state: &AppState) -> Self {
+        state.push.clone()
+    }
 }
 
 /// ACME challenge handler for Let's Encrypt HTTP-01 validation
@@ -169,139 +165,219 @@ async fn acme_challenge_handler(
     Err(StatusCode::NOT_FOUND)
 }
 
-/// HTTP IO driver with support for automatic HTTPS via Let's Encrypt
-pub fn http() -> IODriverFn {
-    make_driver(move |handle| async move {
-        let (tx, mut rx) = tokio::sync::mpsc::channel::<RequestMessage>(10);
+/// How the HTTP driver listens and where its TLS certificate comes from
+#[derive(Clone, Debug)]
+pub struct HttpConfig {
+    /// Plain HTTP, which also answers ACME HTTP-01 challenges
+    pub http_bind: SocketAddr,
+    /// HTTPS, used unless `tls` is [HttpTls::None]
+    pub https_bind: SocketAddr,
+    pub tls: HttpTls,
+    /// Directory of static files to serve at `/static`
+    pub web_dir: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug)]
+pub enum HttpTls {
+    /// Plain HTTP only
+    None,
+    /// An operator-provided certificate chain and private key, as PEM files
+    Pem { cert: PathBuf, key: PathBuf },
+    /// A certificate issued and renewed over ACME
+    Acme(AcmeConfig),
+}
 
-        // Domain to bind to for HTTPS
-        let domain = env::var("HTTPS_DOMAIN").unwrap_or_else(|_| "localhost".to_string());
-        // Directory to serve static files from
-        let web_dir = env::var("WEB_DIR").ok();
+#[derive(Clone, Debug)]
+pub struct AcmeConfig {
+    pub domain: String,
+    pub email: String,
+    /// Where the ACME account, certificate and key are kept
+    pub cache_dir: PathBuf,
+    /// ACME directory, Let's Encrypt production by default. Point it at a local stand-in
+    /// such as Pebble (`https://localhost:14000/dir`) for testing; its root certificate has to
+    /// be trusted, e.g. through `SSL_CERT_FILE`.
+    pub directory_url: String,
+}
+
+impl HttpConfig {
+    /// Plain HTTP on `127.0.0.1:8080`
+    pub fn local() -> Self {
+        HttpConfig {
+            http_bind: SocketAddr::from(([127, 0, 0, 1], 8080)),
+            https_bind: SocketAddr::from(([127, 0, 0, 1], 8443)),
+            tls: HttpTls::None,
+            web_dir: None,
+        }
+    }
+
+    /// Configuration from the environment:
+    ///
+    /// - `HTTPS_DOMAIN`: domain to serve. Without TLS settings, a local domain (`localhost`,
+    ///   `127.*`, `192.168.*`, `*.local`) serves plain HTTP on `127.0.0.1:8080` and any
+    ///   other uses ACME on `0.0.0.0:80` and `0.0.0.0:443`.
+    /// - `HTTP_BIND`, `HTTPS_BIND`: listen addresses, overriding the above
+    /// - `TLS_CERT_PATH`, `TLS_KEY_PATH`: PEM certificate chain and key to serve HTTPS with
+    /// - `ACME_ENABLED`: `true` or `false` to force ACME on or off
+    /// - `ACME_EMAIL`, `ACME_CACHE_DIR`, `ACME_DIRECTORY_URL`: ACME account settings
+    /// - `WEB_DIR`: static files to serve at `/static`
+    pub fn from_env() -> Result<Self, HttpError> {
+        Self::from_vars(|name| env::var(name).ok())
+    }
 
-        // Check if we're running locally
+    /// [HttpConfig::from_env], reading variables with `var`
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Self, HttpError> {
+        let domain = var("HTTPS_DOMAIN").unwrap_or_else(|| "localhost".to_string());
         let is_local = domain == "localhost"
             || domain.starts_with("127.")
             || domain.starts_with("192.168.")
             || domain.ends_with(".local");
-
-        info!(
-            "HTTP Driver starting - Domain: {}, Local mode: {}",
-            domain, is_local
-        );
-
-        let (app_state, acme_manager_opt) = if is_local {
-            debug!("Running in local mode on domain: {}", domain);
-            (
-                AppState {
-                    sender: Arc::new(RwLock::new(tx.clone())),
-                    challenges: None,
-                },
-                None,
-            )
-        } else {
-            // Email to use for ACME account
-            let email = env::var("ACME_EMAIL").map_err(HttpError::EnvError)?;
-            // Directory to store ACME challenge responses
-            let cache_dir = env::var("ACME_CACHE_DIR")
-                .map(|s| s.into())
-                .unwrap_or_else(|_| crate::system_data_dir().join("acme"));
-            info!("HTTPS enabled with domain: {}, email: {}", domain, email);
-            info!("Setting up Let's Encrypt for domain: {}", domain);
-            let acme_manager = AcmeManager::new(domain.clone(), email.clone(), cache_dir.clone())
-                .await
-                .map_err(HttpError::AcmeError)?;
-
-            let challenges = acme_manager.get_challenge_handler();
-
-            (
-                AppState {
-                    sender: Arc::new(RwLock::new(tx.clone())),
-                    challenges: Some(challenges),
-                },
-                Some(acme_manager),
-            )
+        let acme_enabled = match var("ACME_ENABLED") {
+            Some(enabled) => matches!(enabled.as_str(), "1" | "true" | "yes" | "on"),
+            None => !is_local,
         };
 
-        let app = if is_local {
-            // For local development, just use the main handler + static file serving
-            let mut router = Router::new().route("/favicon.ico", get(favicon_handler));
+        let tls = match (var("TLS_CERT_PATH"), var("TLS_KEY_PATH")) {
+            (Some(cert), Some(key)) => HttpTls::Pem {
+                cert: cert.into(),
+                key: key.into(),
+            },
+            (None, None) if acme_enabled => HttpTls::Acme(AcmeConfig {
+                email: var("ACME_EMAIL").ok_or(HttpError::MissingConfig("ACME_EMAIL"))?,
+                cache_dir: var("ACME_CACHE_DIR")
+                    .map(PathBuf::from)
+                    .unwrap_or_else(|| crate::system_data_dir().join("acme")),
+                directory_url: var("ACME_DIRECTORY_URL")
+                    .unwrap_or_else(|| LetsEncrypt::Production.url().to_string()),
+                domain,
+            }),
+            (None, None) => HttpTls::None,
+            (Some(_), None) => return Err(HttpError::MissingConfig("TLS_KEY_PATH")),
+            (None, Some(_)) => return Err(HttpError::MissingConfig("TLS_CERT_PATH")),
+        };
 
-            if let Some(web_dir_path) = &web_dir {
-                info!(
-                    "Static file serving enabled from directory: {} at /static/*",
-                    web_dir_path
-                );
-                let serve_dir = ServeDir::new(web_dir_path);
-                router = router
-                    .nest_service("/static", serve_dir)
-                    .fallback(nockvm_handler);
-            } else {
-                router = router.fallback(nockvm_handler);
-            }
-            router.with_state(app_state.clone())
+        let mut config = if is_local && matches!(tls, HttpTls::None) {
+            HttpConfig::local()
         } else {
-            // For production, include ACME challenge handler
-            let mut router = Router::new()
-                .route("/favicon.ico", get(favicon_handler))
-                .route(
-                    "/.well-known/acme-challenge/{token}",
-                    get(acme_challenge_handler),
-                );
-
-            if let Some(web_dir_path) = &web_dir {
-                info!(
-                    "Static file serving enabled from directory: {} at /static/*",
-                    web_dir_path
-                );
-                let serve_dir = ServeDir::new(web_dir_path);
-                router = router
-                    .nest_service("/static", serve_dir)
-                    .fallback(nockvm_handler);
-            } else {
-                router = router.fallback(nockvm_handler);
+            HttpConfig {
+                http_bind: SocketAddr::from(([0, 0, 0, 0], 80)),
+                https_bind: SocketAddr::from(([0, 0, 0, 0], 443)),
+                tls,
+                web_dir: None,
             }
-            router.with_state(app_state.clone())
         };
+        let bind = |name: &'static str| -> Result<Option<SocketAddr>, HttpError> {
+            var(name)
+                .map(|addr| {
+                    addr.parse()
+                        .map_err(|_| HttpError::InvalidBindAddress(addr))
+                })
+                .transpose()
+        };
+        if let Some(http_bind) = bind("HTTP_BIND")? {
+            config.http_bind = http_bind;
+        }
+        if let Some(https_bind) = bind("HTTPS_BIND")? {
+            config.https_bind = https_bind;
+        }
+        config.web_dir = var("WEB_DIR").map(PathBuf::from);
+        Ok(config)
+    }
+}
 
-        if is_local {
-            // Local development: just run HTTP on port 8080
-            let http_listener = tokio::net::TcpListener::bind("127.0.0.1:8080")
-                .await
-                .map_err(HttpError::BindError)?;
-            let http_addr = http_listener
-                .local_addr()
-                .map_err(|_| HttpError::LocalAddrError)?;
-            info!("Local HTTP server listening on http://{}", http_addr);
+/// HTTP IO driver configured from the environment, see [HttpConfig::from_env]
+pub fn http() -> IODriverFn {
+    make_driver(move |handle| async move {
+        let config = HttpConfig::from_env()?;
+        serve_http(handle, config).await
+    })
+}
 
-            tokio::spawn(async move {
-                if let Err(e) = serve(http_listener, app.into_make_service()).await {
-                    error!("HTTP server error: {}", e);
-                }
-            });
-        } else {
-            // Production: Start HTTP server first for ACME challenges
-            info!("Starting HTTP server for ACME challenges");
-            let http_app = app.clone();
-            let http_listener = tokio::net::TcpListener::bind("0.0.0.0:80")
-                .await
-                .map_err(HttpError::BindError)?;
-            let http_addr = http_listener
-                .local_addr()
-                .map_err(|_| HttpError::LocalAddrError)?;
-            info!("HTTP server listening on {} for ACME challenges", http_addr);
-            tokio::spawn(async move {
-                if let Err(e) = serve(http_listener, http_app.into_make_service()).await {
-                    error!("HTTP server error: {}", e);
-                }
-            });
+/// HTTP IO driver with explicit configuration
+pub fn http_with_config(config: HttpConfig) -> IODriverFn {
+    make_driver(move |handle| serve_http(handle, config))
+}
+
+async fn serve_http(handle: NockAppHandle, config: HttpConfig) -> Result<(), NockAppError> {
+    let handle = Arc::new(handle);
+    let (tx, mut rx) = tokio::sync::mpsc::channel::<RequestMessage>(10);
+    let push = Arc::new(PushHub::new(handle.clone(), PushConfig::from_env()));
+    tokio::spawn(forward_push_effects(push.clone()));
+
+    let acme_manager = match &config.tls {
+        HttpTls::Acme(acme) => {
+            info!(
+                "Setting up ACME for domain {} with directory {}",
+                acme.domain, acme.directory_url
+            );
+            let acme_manager = AcmeManager::with_directory(
+                acme.domain.clone(),
+                acme.email.clone(),
+                acme.cache_dir.clone(),
+                &acme.directory_url,
+            )
+            .await
+            .map_err(HttpError::AcmeError)?;
+            Some(acme_manager)
+        }
+        HttpTls::None | HttpTls::Pem { .. } => None,
+    };
+    let app_state = AppState {
+        sender: Arc::new(RwLock::new(tx.clone())),
+        challenges: acme_manager
+            .as_ref()
+            .map(AcmeManager::get_challenge_handler),
+        push: push.clone(),
+    };
 
-            // Give the HTTP server a moment to start
-            tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+    let mut router = Router::new()
+        .route("/favicon.ico", get(favicon_handler))
+        .route("/push/sse/{channel}", get(sse_handler))
+        .route("/push/ws/{channel}", get(ws_handler));
+    if acme_manager.is_some() {
+        router = router.route(
+            "/.well-known/acme-challenge/{token}",
+            get(acme_challenge_handler),
+        );
+    }
+    if let Some(web_dir) = &config.web_dir {
+        info!(
+            "Static file serving enabled from directory: {} at /static/*",
+            web_dir.display()
+        );
+        router = router.nest_service("/static", ServeDir::new(web_dir));
+    }
+    let app = router
+        .fallback(nockvm_handler)
+        .with_state(app_state.clone());
+
+    let http_listener = tokio::net::TcpListener::bind(config.http_bind)
+        .await
+        .map_err(HttpError::BindError)?;
+    let http_addr = http_listener
+        .local_addr()
+        .map_err(|_| HttpError::LocalAddrError)?;
+    info!("HTTP server listening on http://{}", http_addr);
+    let http_app = app.clone();
+    tokio::spawn(async move {
+        if let Err(e) = serve(http_listener, http_app.into_make_service()).await {
+            error!("HTTP server error: {}", e);
+        }
+    });
 
-            // Start certificate generation in background - don't block main loop
-            let acme_manager = acme_manager_opt.unwrap();
+    match &config.tls {
+        HttpTls::None => {}
+        HttpTls::Pem { cert, key } => {
+            let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+            let rustls_config = RustlsConfig::from_pem_file(cert, key)
+                .await
+                .map_err(HttpError::Tls)?;
+            spawn_https(config.https_bind, rustls_config, app.clone()).await?;
+        }
+        HttpTls::Acme(_) => {
+            let acme_manager = acme_manager.expect("ACME manager for ACME TLS");
+            let https_bind = config.https_bind;
             let app_for_https = app.clone();
+            // Issuing a certificate can take minutes, so don't hold up the request loop
             tokio::spawn(async move {
                 match tokio::time::timeout(
                     tokio::time::Duration::from_secs(300), // 5 minute timeout
@@ -312,24 +388,9 @@ pub fn http() -> IODriverFn {
                     Ok(Ok(tls_config)) => {
                         info!("Successfully got certificate, starting HTTPS server");
                         let rustls_config = RustlsConfig::from_config(Arc::new(tls_config));
-
-                        match tokio::net::TcpListener::bind("0.0.0.0:443").await {
-                            Ok(https_listener) => {
-                                let https_addr = https_listener.local_addr().unwrap();
-                                info!("HTTPS server listening on {}", https_addr);
-                                let std_listener =
-                                    std::net::TcpListener::from(https_listener.into_std().unwrap());
-                                if let Err(e) =
-                                    axum_server::from_tcp_rustls(std_listener, rustls_config)
-                                        .serve(app_for_https.into_make_service())
-                                        .await
-                                {
-                                    error!("HTTPS server error: {}", e);
-                                }
-                            }
-                            Err(e) => {
-                                error!("Failed to bind HTTPS listener: {}", e);
-                            }
+                        if let Err(e) = spawn_https(https_bind, rustls_config, app_for_https).await
+                        {
+                            error!("Failed to start HTTPS server: {}", e);
                         }
                     }
                     Ok(Err(e)) => {
@@ -343,325 +404,329 @@ pub fn http() -> IODriverFn {
                 }
             });
         }
+    }
 
-        let channel_map = RwLock::new(HashMap::<u64, Responder>::new());
-        let regular_cache = Arc::new(RwLock::new(Option::<CachedResponse>::None));
-        let htmx_cache = Arc::new(RwLock::new(Option::<CachedResponse>::None));
-
-        // Parse cache expiration from environment variable, default to never expire
-        let cache_duration = env::var("EXPIRE_CACHE")
-            .ok()
-            .and_then(|s| s.parse::<u64>().ok())
-            .map(Duration::from_secs);
+    let channel_map = RwLock::new(HashMap::<u64, Responder>::new());
+    let mut cache = ResponseCache::new(CacheConfig::from_env());
+    // Cache key of each GET waiting on the kernel, with when it arrived. A %cache or
+    // %h-cache leaves the key in place, so keys are also dropped after REQUEST_TIMEOUT.
+    let mut cache_keys = HashMap::<u64, (CacheKey, Instant)>::new();
+    let mut expiry = tokio::time::interval(REQUEST_TIMEOUT);
+    info!(
+        "Response cache default TTL: {} seconds",
+        cache.config().default_ttl.as_secs()
+    );
 
-        if let Some(cache_duration) = cache_duration {
-            info!(
-                "Cache expiration enabled: {} seconds",
-                cache_duration.as_secs()
-            );
-
-            let _regular_cache_invalidation_handle = {
-                let regular_cache = Arc::clone(&regular_cache);
-                tokio::spawn(async move {
-                    let mut interval = tokio::time::interval(cache_duration);
-                    loop {
-                        interval.tick().await;
-                        debug!("invalidating regular response cache");
-                        *regular_cache.write().await = None;
+    loop {
+        select! {
+            msg = rx.recv() => {
+                let msg = match msg {
+                    Some(msg) => msg,
+                    None => {
+                        warn!("HTTP request channel closed, recreating channel");
+                        let (new_tx, new_rx) = tokio::sync::mpsc::channel::<RequestMessage>(10);
+                        rx = new_rx;
+
+                        // Update the sender in the existing shared app_state
+                        *app_state.sender.write().await = new_tx;
+                        continue;
                     }
-                })
-            };
-
-            let _htmx_cache_invalidation_handle = {
-                let htmx_cache = Arc::clone(&htmx_cache);
-                tokio::spawn(async move {
-                    let mut interval = tokio::time::interval(cache_duration);
-                    loop {
-                        interval.tick().await;
-                        debug!("invalidating htmx response cache");
-                        *htmx_cache.write().await = None;
+                };
+                info!("Processing request {} {} with id: {}", msg.method, msg.uri, msg.id);
+                debug!("headers: {:?}", msg.headers);
+                if let Some(ref body) = msg.body {
+                    match String::from_utf8(body.to_vec()) {
+                        Ok(body_str) => debug!("body as string: {}", body_str),
+                        Err(_) => debug!("body (non-UTF8): {:?}", body),
                     }
-                })
-            };
-        } else {
-            info!("Cache expiration disabled - cache will persist until restart");
-        }
-
-        loop {
-            select! {
-                msg = rx.recv() => {
-                    let msg = match msg {
-                        Some(msg) => msg,
-                        None => {
-                            warn!("HTTP request channel closed, recreating channel");
-                            let (new_tx, new_rx) = tokio::sync::mpsc::channel::<RequestMessage>(10);
-                            rx = new_rx;
-
-                            // Update the sender in the existing shared app_state
-                            *app_state.sender.write().await = new_tx;
-                            continue;
-                        }
-                    };
-                    info!("Processing request {} {} with id: {}", msg.method, msg.uri, msg.id);
-                    debug!("headers: {:?}", msg.headers);
-                    if let Some(ref body) = msg.body {
-                        match String::from_utf8(body.to_vec()) {
-                            Ok(body_str) => debug!("body as string: {}", body_str),
-                            Err(_) => debug!("body (non-UTF8): {:?}", body),
-                        }
-                    } else {
-                        debug!("body: None");
-                    }
-
-                    let request_result = async {
-                        if msg.method == Method::GET {
-                            let is_htmx = msg.headers.contains_key("hx-request");
-                            let cache_to_use = if is_htmx { &htmx_cache } else { &regular_cache };
+                } else {
+                    debug!("body: None");
+                }
 
-                            let cache_read = cache_to_use.read().await;
-                            if let Some(cached) = &*cache_read {
-                                // Check expiration only if cache_duration is set
-                                let should_serve = if let Some(cache_duration) = cache_duration {
-                                    !cached.is_expired(cache_duration)
-                                } else {
-                                    true // Never expire if no duration set
-                                };
-
-                                if should_serve {
-                                    let cache_type = if is_htmx { "HTMX" } else { "regular" };
-                                    info!("serving cached {} response for {}", cache_type, msg.uri);
-                                    let cached_response = cached.to_response()?;
-                                    let _ = msg.resp.send(Ok(cached_response));
-                                    return Ok(());
-                                }
+                let request_result = async {
+                    if msg.method == Method::GET {
+                        let key = CacheKey::new(&msg.method, &msg.uri, &msg.headers, &cache.config().vary_headers);
+                        if let Some(key) = key {
+                            if let Some(cached) = cache.get(&key) {
+                                info!("serving cached response for {}", msg.uri);
+                                let cached_response = cached.to_response()?;
+                                let _ = msg.resp.send(Ok(cached_response));
+                                return Ok(());
                             }
-                            drop(cache_read);
+                            cache_keys.insert(msg.id, (key, Instant::now()));
                         }
+                    }
 
-                        channel_map.write().await.insert(msg.id, msg.resp);
-                        let mut slab = NounSlab::new();
-
-                        let id = Atom::from_value(&mut slab, msg.id)
+                    channel_map.write().await.insert(msg.id, msg.resp);
+                    let mut slab = NounSlab::new();
+
+                    let id = Atom::from_value(&mut slab, msg.id)
+                        .map_err(|e| HttpError::AtomCreationError(e.to_string()))?;
+                    let uri = Atom::from_value(&mut slab, msg.uri.to_string())
+                        .map_err(|e| HttpError::AtomCreationError(e.to_string()))?;
+                    let method = Atom::from_value(&mut slab, msg.method.to_string())
+                        .map_err(|e| HttpError::AtomCreationError(e.to_string()))?;
+
+                    let mut headers = D(0);
+                    for (k, v) in msg.headers {
+                        let key = k.ok_or(HttpError::InvalidHeaderName)?.as_str().to_string();
+                        let val = v.to_str().map_err(HttpError::InvalidHeaderValue)?.to_string();
+                        let k_atom = Atom::from_value(&mut slab, key)
                             .map_err(|e| HttpError::AtomCreationError(e.to_string()))?;
-                        let uri = Atom::from_value(&mut slab, msg.uri.to_string())
-                            .map_err(|e| HttpError::AtomCreationError(e.to_string()))?;
-                        let method = Atom::from_value(&mut slab, msg.method.to_string())
+                        let v_atom = Atom::from_value(&mut slab, val)
                             .map_err(|e| HttpError::AtomCreationError(e.to_string()))?;
+                        let header_cell = T(&mut slab, &[k_atom.as_noun(), v_atom.as_noun()]);
+                        headers = T(&mut slab, &[header_cell, headers]);
+                    }
 
-                        let mut headers = D(0);
-                        for (k, v) in msg.headers {
-                            let key = k.ok_or(HttpError::InvalidHeaderName)?.as_str().to_string();
-                            let val = v.to_str().map_err(HttpError::InvalidHeaderValue)?.to_string();
-                            let k_atom = Atom::from_value(&mut slab, key)
-                                .map_err(|e| HttpError::AtomCreationError(e.to_string()))?;
-                            let v_atom = Atom::from_value(&mut slab, val)
-                                .map_err(|e| HttpError::AtomCreationError(e.to_string()))?;
-                            let header_cell = T(&mut slab, &[k_atom.as_noun(), v_atom.as_noun()]);
-                            headers = T(&mut slab, &[header_cell, headers]);
+                    let body: crate::Noun = {
+                        if let Some(bod) = msg.body {
+                            let ato = Atom::from_bytes(&mut slab, &bod).as_noun();
+                            let len: u64 = bod.len().try_into().map_err(|_| HttpError::BodyLengthConversion)?;
+                            T(&mut slab, &[D(0), D(len), ato])
+                        } else {
+                            D(0)
                         }
+                    };
 
-                        let body: crate::Noun = {
-                            if let Some(bod) = msg.body {
-                                let ato = Atom::from_bytes(&mut slab, &bod).as_noun();
-                                let len: u64 = bod.len().try_into().map_err(|_| HttpError::BodyLengthConversion)?;
-                                T(&mut slab, &[D(0), D(len), ato])
-                            } else {
-                                D(0)
-                            }
-                        };
-
-                        let poke = T(
-                            &mut slab,
-                            &[D(tas!(b"req")), id.as_noun(), uri.as_noun(), method.as_noun(), headers, body],
-                        );
-                        debug!("poking kernel with request for {}", msg.uri);
-                        slab.set_root(poke);
-
-                        let wire = HttpWire::Request.to_wire();
-                        let poke_result = handle.poke(wire, slab).await?;
-                        debug!("poke result for {}: {:?}", msg.uri, poke_result);
-
-                        if let PokeResult::Nack = poke_result {
-                            error!("Kernel nacked the request for {}", msg.uri);
-                            let resp_tx = channel_map.write().await.remove(&msg.id)
-                                .ok_or(HttpError::ResponseChannelNotFound(msg.id))?;
-                            let _ = resp_tx.send(Err(StatusCode::BAD_REQUEST));
-                        }
+                    let poke = T(
+                        &mut slab,
+                        &[D(tas!(b"req")), id.as_noun(), uri.as_noun(), method.as_noun(), headers, body],
+                    );
+                    debug!("poking kernel with request for {}", msg.uri);
+                    slab.set_root(poke);
+
+                    let wire = HttpWire::Request.to_wire();
+                    let poke_result = handle.poke(wire, slab).await?;
+                    debug!("poke result for {}: {:?}", msg.uri, poke_result);
+
+                    if let PokeResult::Nack = poke_result {
+                        error!("Kernel nacked the request for {}", msg.uri);
+                        cache_keys.remove(&msg.id);
+                        let resp_tx = channel_map.write().await.remove(&msg.id)
+                            .ok_or(HttpError::ResponseChannelNotFound(msg.id))?;
+                        let _ = resp_tx.send(Err(StatusCode::BAD_REQUEST));
+                    }
 
-                        Ok::<(), HttpError>(())
-                    }.await;
+                    Ok::<(), HttpError>(())
+                }.await;
 
-                    if let Err(e) = request_result {
-                        error!("Error processing HTTP request: {}", e);
-                        // Try to send error response if we still have the channel
-                        if let Some(resp_tx) = channel_map.write().await.remove(&msg.id) {
-                            let _ = resp_tx.send(Err(StatusCode::INTERNAL_SERVER_ERROR));
-                        }
+                if let Err(e) = request_result {
+                    error!("Error processing HTTP request: {}", e);
+                    cache_keys.remove(&msg.id);
+                    // Try to send error response if we still have the channel
+                    if let Some(resp_tx) = channel_map.write().await.remove(&msg.id) {
+                        let _ = resp_tx.send(Err(StatusCode::INTERNAL_SERVER_ERROR));
                     }
                 }
-                effect = handle.next_effect() => {
-                    let effect_result = async {
-                        let slab = match effect {
-                            Ok(slab) => {
-                                info!("received effect from kernel");
-                                slab
-                            }
-                            Err(e) => {
-                                error!("Error receiving effect in HTTP driver: {:?}", e);
-                                return Ok(());
-                            }
-                        };
-                        let effect = unsafe { slab.root() };
-                        let res_list = effect.as_cell()?;
-
-                        let head_tag = res_list.head().as_atom()?;
-                        let tag_val = head_tag.as_u64().map_err(|e| HttpError::AtomCreationError(e.to_string()))?;
-                        if tag_val != tas!(b"res") && tag_val != tas!(b"cache") && tag_val != tas!(b"htmx") && tag_val != tas!(b"h-cache") {
-                            info!("http: not an HTTP response effect, skipping. Got tag: {:?}", head_tag);
+            }
+            _ = expiry.tick() => {
+                // The handler has given up on these requests by now
+                let before = cache_keys.len();
+                cache_keys.retain(|_, (_, arrived)| arrived.elapsed() < REQUEST_TIMEOUT);
+                if cache_keys.len() < before {
+                    debug!("expired {} cache keys of unfinished requests", before - cache_keys.len());
+                }
+                channel_map.write().await.retain(|_, resp| !resp.is_closed());
+            }
+            effect = handle.next_effect() => {
+                let effect_result = async {
+                    let slab = match effect {
+                        Ok(slab) => {
+                            info!("received effect from kernel");
+                            slab
+                        }
+                        Err(e) => {
+                            error!("Error receiving effect in HTTP driver: {:?}", e);
                             return Ok(());
                         }
+                    };
+                    let effect = unsafe { slab.root() };
+                    let res_list = effect.as_cell()?;
+
+                    let head_tag = res_list.head().as_atom()?;
+                    let tag_val = head_tag.as_u64().map_err(|e| HttpError::AtomCreationError(e.to_string()))?;
+                    if tag_val == tas!(b"uncache") {
+                        let mut prefixes = Vec::new();
+                        for prefix in res_list.tail().list_iter() {
+                            let prefix = prefix.as_atom()?.into_string()
+                                .map_err(|e| HttpError::AtomCreationError(e.to_string()))?;
+                            prefixes.push(prefix);
+                        }
+                        let dropped = cache.invalidate(&prefixes);
+                        info!("invalidated {} cached responses for {:?}", dropped, prefixes);
+                        return Ok(());
+                    }
+                    if tag_val != tas!(b"res") && tag_val != tas!(b"cache") && tag_val != tas!(b"htmx") && tag_val != tas!(b"h-cache") {
+                        info!("http: not an HTTP response effect, skipping. Got tag: {:?}", head_tag);
+                        return Ok(());
+                    }
 
-                        info!("processing HTTP response effect");
-                        let mut res = res_list.tail().as_cell()?;
-                        let id = res.head().as_atom()?.as_u64()
-                            .map_err(|e| HttpError::AtomCreationError(e.to_string()))?;
-                        debug!("HTTP response for request id: {}", id);
-
-                        res = res.tail().as_cell()?;
-                        let status_code = res
-                            .head()
-                            .as_atom()?
-                            .direct()
-                            .expect("not a valid status code!")
-                            .data();
-                        debug!("HTTP response status code: {}", status_code);
-
-                        let mut header_list = res.tail().as_cell()?.head();
-                        let mut header_vec: Vec<(String, String)> = Vec::new();
-                        loop {
-                            if header_list.is_atom() {
-                                break;
-                            } else {
-                                let header = header_list.as_cell()?.head().as_cell()?;
-                                let key_vec = header.head().as_atom()?;
-                                let val_vec = header.tail().as_atom()?;
-
-                                if let Ok(key) = key_vec.to_bytes_until_nul() {
-                                    if let Ok(val) = val_vec.to_bytes_until_nul() {
-                                        let key_str = String::from_utf8(key)?;
-                                        let val_str = String::from_utf8(val)?;
-                                        debug!("HTTP response header: {}: {}", key_str, val_str);
-                                        header_vec.push((key_str, val_str));
-                                        header_list = header_list.as_cell()?.tail();
-                                    } else {
-                                        break;
-                                    }
+                    info!("processing HTTP response effect");
+                    let mut res = res_list.tail().as_cell()?;
+                    let id = res.head().as_atom()?.as_u64()
+                        .map_err(|e| HttpError::AtomCreationError(e.to_string()))?;
+                    debug!("HTTP response for request id: {}", id);
+
+                    res = res.tail().as_cell()?;
+                    let status_code = res
+                        .head()
+                        .as_atom()?
+                        .direct()
+                        .expect("not a valid status code!")
+                        .data();
+                    debug!("HTTP response status code: {}", status_code);
+
+                    let mut header_list = res.tail().as_cell()?.head();
+                    let mut header_vec: Vec<(String, String)> = Vec::new();
+                    loop {
+                        if header_list.is_atom() {
+                            break;
+                        } else {
+                            let header = header_list.as_cell()?.head().as_cell()?;
+                            let key_vec = header.head().as_atom()?;
+                            let val_vec = header.tail().as_atom()?;
+
+                            if let Ok(key) = key_vec.to_bytes_until_nul() {
+                                if let Ok(val) = val_vec.to_bytes_until_nul() {
+                                    let key_str = String::from_utf8(key)?;
+                                    let val_str = String::from_utf8(val)?;
+                                    debug!("HTTP response header: {}: {}", key_str, val_str);
+                                    header_vec.push((key_str, val_str));
+                                    header_list = header_list.as_cell()?.tail();
                                 } else {
                                     break;
                                 }
+                            } else {
+                                break;
                             }
                         }
+                    }
 
-                        let maybe_body = res.tail().as_cell()?.tail();
-
-                        let body: Option<Bytes> = {
-                            if maybe_body.is_cell() {
-                                let body_octs = maybe_body.as_cell()?.tail().as_cell()?;
-                                let body_len = body_octs
-                                    .head()
-                                    .as_atom()?
-                                    .direct()
-                                    .expect("body len")
-                                    .data();
-                                let len: usize = body_len.try_into().map_err(|_| HttpError::BodyLengthConversion)?;
-                                let mut body_vec: Vec<u8> = vec![0; len];
-                                let body_atom = body_octs.tail().as_atom()?;
-
-                                // Use lossy conversion to handle invalid UTF-8 gracefully
-                                let body_bytes = match body_atom.to_bytes_until_nul() {
-                                    Ok(bytes) => bytes,
-                                    Err(e) => {
-                                        error!("Failed to convert body atom to bytes: {}", e);
-                                        // Try to get raw bytes from the atom instead
-                                        let raw_bytes = body_atom.to_ne_bytes();
-                                        let raw_size = std::cmp::min(len, raw_bytes.len());
-                                        raw_bytes[..raw_size].to_vec()
-                                    }
-                                };
-
-                                body_vec.copy_from_slice(&body_bytes[..std::cmp::min(body_bytes.len(), len)]);
-                                let bytes = Bytes::from(body_vec);
-
-                                // Log the response body as string if possible, using lossy conversion
-                                let body_str = String::from_utf8_lossy(&bytes);
-                                debug!("HTTP response body as string: {}", body_str);
-                                if body_str.contains('\u{FFFD}') {
-                                    warn!("Note: Response body contained invalid UTF-8 sequences (replaced with)");
+                    let maybe_body = res.tail().as_cell()?.tail();
+
+                    let body: Option<Bytes> = {
+                        if maybe_body.is_cell() {
+                            let body_octs = maybe_body.as_cell()?.tail().as_cell()?;
+                            let body_len = body_octs
+                                .head()
+                                .as_atom()?
+                                .direct()
+                                .expect("body len")
+                                .data();
+                            let len: usize = body_len.try_into().map_err(|_| HttpError::BodyLengthConversion)?;
+                            let mut body_vec: Vec<u8> = vec![0; len];
+                            let body_atom = body_octs.tail().as_atom()?;
+
+                            // Use lossy conversion to handle invalid UTF-8 gracefully
+                            let body_bytes = match body_atom.to_bytes_until_nul() {
+                                Ok(bytes) => bytes,
+                                Err(e) => {
+                                    error!("Failed to convert body atom to bytes: {}", e);
+                                    // Try to get raw bytes from the atom instead
+                                    let raw_bytes = body_atom.to_ne_bytes();
+                                    let raw_size = std::cmp::min(len, raw_bytes.len());
+                                    raw_bytes[..raw_size].to_vec()
                                 }
+                            };
 
-                                Some(bytes)
-                            } else {
-                                debug!("HTTP response has no body");
-                                None
+                            body_vec.copy_from_slice(&body_bytes[..std::cmp::min(body_bytes.len(), len)]);
+                            let bytes = Bytes::from(body_vec);
+
+                            // Log the response body as string if possible, using lossy conversion
+                            let body_str = String::from_utf8_lossy(&bytes);
+                            debug!("HTTP response body as string: {}", body_str);
+                            if body_str.contains('\u{FFFD}') {
+                                warn!("Note: Response body contained invalid UTF-8 sequences (replaced with)");
                             }
-                        };
 
-                        let resp = if let Ok(status) = StatusCode::from_u16(status_code as u16) {
-                            debug!("Building HTTP response with status: {}", status);
-                            let res_builder = ResponseBuilder {
-                                status_code: status,
-                                headers: header_vec.clone(),
-                                body: body.clone(),
-                            };
+                            Some(bytes)
+                        } else {
+                            debug!("HTTP response has no body");
+                            None
+                        }
+                    };
 
-                            let mut res = Response::builder().status(res_builder.status_code);
+                    let resp = if let Ok(status) = StatusCode::from_u16(status_code as u16) {
+                        debug!("Building HTTP response with status: {}", status);
+                        let res_builder = ResponseBuilder {
+                            status_code: status,
+                            headers: header_vec.clone(),
+                            body: body.clone(),
+                        };
 
-                            for (k, v) in &res_builder.headers {
-                                res = res.header(k, v);
-                            }
+                        let mut res = Response::builder().status(res_builder.status_code);
 
-                            let response_body = res_builder.body
-                                .map(Body::from)
-                                .unwrap_or_else(|| Body::empty());
+                        for (k, v) in &res_builder.headers {
+                            res = res.header(k, v);
+                        }
 
-                            let response = res.body(response_body).map_err(HttpError::ResponseBuildError)?;
+                        let response_body = res_builder.body
+                            .map(Body::from)
+                            .unwrap_or_else(|| Body::empty());
 
-                            // Cache logic - determine which cache to use based on effect type
-                            if status == StatusCode::OK {
-                                let cached_response = CachedResponse::new(status, header_vec.clone(), body.clone());
-                                if tag_val == tas!(b"htmx") || tag_val == tas!(b"h-cache") {
-                                    info!("caching HTMX response (htmx or h-cache effect)");
-                                    *htmx_cache.write().await = Some(cached_response);
-                                } else {
-                                    info!("caching regular response (res or cache effect)");
-                                    *regular_cache.write().await = Some(cached_response);
-                                }
-                            }
+                        let response = res.body(response_body).map_err(HttpError::ResponseBuildError)?;
 
-                            Ok(response)
+                        // A %res or %htmx is the last response for its request, a %cache or
+                        // %h-cache may be followed by one
+                        let key = if tag_val == tas!(b"res") || tag_val == tas!(b"htmx") {
+                            cache_keys.remove(&id).map(|(key, _)| key)
                         } else {
-                            error!("http: not a valid status code: {}", status_code);
-                            error!("http: res: {:?}", res);
-                            Err(StatusCode::INTERNAL_SERVER_ERROR)
+                            cache_keys.get(&id).map(|(key, _)| key.clone())
                         };
-
-                        if tag_val == tas!(b"res") || tag_val == tas!(b"htmx") {
-                            let resp_tx = channel_map.write().await.remove(&id)
-                                .ok_or(HttpError::ResponseChannelNotFound(id))?;
-                            debug!("Sending response back to client for request id: {}", id);
-                            let _ = resp_tx.send(resp);
+                        if let (Some(key), StatusCode::OK) = (key, status) {
+                            let cached_response = CachedResponse::new(status, header_vec.clone(), body.clone());
+                            if cache.insert(key, cached_response) {
+                                debug!("cached response for request id: {}", id);
+                            }
                         }
 
-                        Ok::<(), HttpError>(())
-                    }.await;
+                        Ok(response)
+                    } else {
+                        error!("http: not a valid status code: {}", status_code);
+                        error!("http: res: {:?}", res);
+                        Err(StatusCode::INTERNAL_SERVER_ERROR)
+                    };
 
-                    if let Err(e) = effect_result {
-                        error!("Error processing HTTP effect: {}", e);
+                    if tag_val == tas!(b"res") || tag_val == tas!(b"htmx") {
+                        let resp_tx = channel_map.write().await.remove(&id)
+                            .ok_or(HttpError::ResponseChannelNotFound(id))?;
+                        debug!("Sending response back to client for request id: {}", id);
+                        let _ = resp_tx.send(resp);
                     }
+
+                    Ok::<(), HttpError>(())
+                }.await;
+
+                if let Err(e) = effect_result {
+                    error!("Error processing HTTP effect: {}", e);
                 }
             }
         }
-    })
+    }
+}
+
+/// Bind `bind` and serve `app` over HTTPS on it in the background
+async fn spawn_https(
+    bind: SocketAddr,
+    rustls_config: RustlsConfig,
+    app: Router,
+) -> Result<(), HttpError> {
+    let https_listener = tokio::net::TcpListener::bind(bind).await?;
+    let https_addr = https_listener
+        .local_addr()
+        .map_err(|_| HttpError::LocalAddrError)?;
+    let std_listener = https_listener.into_std()?;
+    info!("HTTPS server listening on https://{}", https_addr);
+    tokio::spawn(async move {
+        if let Err(e) = axum_server::from_tcp_rustls(std_listener, rustls_config)
+            .serve(app.into_make_service())
+            .await
+        {
+            error!("HTTPS server error: {}", e);
+        }
+    });
+    Ok(())
 }
 
 async fn nockvm_handler(
@@ -745,8 +810,12 @@ async fn nockvm_handler(
     }
 
     // Await the response
-    match resp_rx.await {
-        Ok(result) => {
+    match tokio::time::timeout(REQUEST_TIMEOUT, resp_rx).await {
+        Err(_) => {
+            error!("Timed out waiting for the response to {}", uri);
+            Err(StatusCode::GATEWAY_TIMEOUT)
+        }
+        Ok(Ok(result)) => {
             debug!(
                 "Received response for {}: {:?}",
                 uri,
@@ -754,7 +823,7 @@ async fn nockvm_handler(
             );
             result
         }
-        Err(e) => {
+        Ok(Err(e)) => {
             error!("Failed to receive response for {}: {}", uri, e);
             Err(StatusCode::INTERNAL_SERVER_ERROR)
         }
@@ -774,3 +843,65 @@ async fn favicon_handler() -> Response {
         .body(Body::from(svg))
         .unwrap()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(vars: &[(&str, &str)]) -> Result<HttpConfig, HttpError> {
+        HttpConfig::from_vars(|name| {
+            vars.iter()
+                .find(|(var, _)| *var == name)
+                .map(|(_, value)| value.to_string())
+        })
+    }
+
+    #[test]
+    fn test_http_config_from_vars() {
+        let local = config(&[]).expect("local config");
+        assert_eq!(local.http_bind, SocketAddr::from(([127, 0, 0, 1], 8080)));
+        assert!(matches!(local.tls, HttpTls::None));
+
+        let acme = config(&[
+            ("HTTPS_DOMAIN", "pool.example.com"),
+            ("ACME_EMAIL", "ops@example.com"),
+            ("ACME_DIRECTORY_URL", "https://localhost:14000/dir"),
+            ("HTTP_BIND", "127.0.0.1:5002"),
+        ])
+        .expect("ACME config");
+        assert_eq!(acme.http_bind, SocketAddr::from(([127, 0, 0, 1], 5002)));
+        assert_eq!(acme.https_bind, SocketAddr::from(([0, 0, 0, 0], 443)));
+        let HttpTls::Acme(acme) = acme.tls else {
+            panic!("expected ACME");
+        };
+        assert_eq!(acme.domain, "pool.example.com");
+        assert_eq!(acme.directory_url, "https://localhost:14000/dir");
+
+        let pem = config(&[
+            ("TLS_CERT_PATH", "/etc/pool/cert.pem"),
+            ("TLS_KEY_PATH", "/etc/pool/key.pem"),
+            ("HTTPS_BIND", "[::]:8443"),
+        ])
+        .expect("PEM config");
+        assert!(matches!(pem.tls, HttpTls::Pem { .. }));
+        assert_eq!(pem.https_bind, "[::]:8443".parse().expect("addr"));
+
+        let acme_off = config(&[("HTTPS_DOMAIN", "pool.example.com"), ("ACME_ENABLED", "false")])
+            .expect("plain config");
+        assert!(matches!(acme_off.tls, HttpTls::None));
+        assert_eq!(acme_off.http_bind, SocketAddr::from(([0, 0, 0, 0], 80)));
+
+        assert!(matches!(
+            config(&[("HTTPS_DOMAIN", "pool.example.com")]),
+            Err(HttpError::MissingConfig("ACME_EMAIL"))
+        ));
+        assert!(matches!(
+            config(&[("TLS_CERT_PATH", "cert.pem")]),
+            Err(HttpError::MissingConfig("TLS_KEY_PATH"))
+        ));
+        assert!(matches!(
+            config(&[("HTTP_BIND", "nowhere")]),
+            Err(HttpError::InvalidBindAddress(_))
+        ));
+    }
+}
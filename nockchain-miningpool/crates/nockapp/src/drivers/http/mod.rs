@@ -1,5 +1,9 @@
 pub mod acme;
+pub mod cache;
+pub mod gateway;
 pub mod http;
+pub mod push;
 
 pub use acme::AcmeManager;
-pub use http::http;
+pub use gateway::{gateway, GatewayConfig};
+pub use http::{http, http_with_config, AcmeConfig, HttpConfig, HttpTls};
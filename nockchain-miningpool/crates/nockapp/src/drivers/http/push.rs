@@ -0,0 +1,519 @@
+//! Server push for the HTTP driver.
+//!
+//! Browsers subscribe to a named channel with server-sent events on
+//! `GET /push/sse/{channel}` or a WebSocket on `GET /push/ws/{channel}`. The kernel pushes
+//! to every subscriber of a channel with an `[%http-push channel=@t data=@t]` effect.
+//!
+//! Text messages a WebSocket client sends are poked into the kernel on the `/http/push`
+//! wire as `[%push channel=@t data=@t]`, at most [PushConfig::pokes_per_sec] a second per
+//! connection. Browsers may only open a WebSocket from an origin in
+//! [PushConfig::allowed_origins], or from the same origin when that is empty.
+//!
+//! A channel is forgotten once its last subscriber goes. Channel names are capped at
+//! [MAX_CHANNEL_NAME_LEN] bytes and the hub at [MAX_CHANNELS] channels, so clients can't
+//! grow it without bound.
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Path, State};
+use axum::http::header::{HOST, ORIGIN};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::Response;
+use futures::stream::{self, Stream};
+use nockvm::noun::{Atom, D, T};
+use nockvm_macros::tas;
+use tokio::select;
+use tokio::sync::broadcast;
+use tracing::{debug, info, warn};
+
+use crate::drivers::http::http::{HttpError, HttpWire};
+use crate::nockapp::driver::{NockAppHandle, PokeResult};
+use crate::nockapp::wire::Wire;
+use crate::noun::slab::NounSlab;
+use crate::AtomExt;
+
+/// Messages buffered per channel for slow subscribers before they start missing some
+const CHANNEL_CAPACITY: usize = 256;
+/// Most channels with subscribers at once
+pub const MAX_CHANNELS: usize = 1024;
+/// Longest channel name, in bytes
+pub const MAX_CHANNEL_NAME_LEN: usize = 128;
+/// Default for [PushConfig::pokes_per_sec]
+pub const DEFAULT_PUSH_POKES_PER_SEC: u32 = 10;
+
+type Channels = Arc<Mutex<HashMap<String, broadcast::Sender<String>>>>;
+
+/// Who may open push WebSockets, and how often they may poke the kernel
+#[derive(Clone, Debug)]
+pub struct PushConfig {
+    /// Origins browsers may open WebSockets from, e.g. `https://pool.example`. When empty,
+    /// only pages served from the same host may.
+    pub allowed_origins: Vec<String>,
+    /// Messages a WebSocket may poke into the kernel per second; extra ones are dropped
+    pub pokes_per_sec: u32,
+}
+
+impl Default for PushConfig {
+    fn default() -> Self {
+        PushConfig {
+            allowed_origins: Vec::new(),
+            pokes_per_sec: DEFAULT_PUSH_POKES_PER_SEC,
+        }
+    }
+}
+
+impl PushConfig {
+    /// Read `HTTP_PUSH_ALLOWED_ORIGINS` (comma-separated origins) and
+    /// `HTTP_PUSH_POKES_PER_SEC`
+    pub fn from_env() -> Self {
+        let mut config = PushConfig::default();
+        if let Ok(origins) = env::var("HTTP_PUSH_ALLOWED_ORIGINS") {
+            config.allowed_origins = origins
+                .split(',')
+                .map(|origin| origin.trim().trim_end_matches('/').to_string())
+                .filter(|origin| !origin.is_empty())
+                .collect();
+        }
+        if let Some(pokes_per_sec) = env::var("HTTP_PUSH_POKES_PER_SEC")
+            .ok()
+            .and_then(|s| s.parse::<u32>().ok())
+        {
+            config.pokes_per_sec = pokes_per_sec;
+        }
+        config
+    }
+}
+
+/// Push channels, created when first subscribed to
+pub struct PushHub {
+    channels: Channels,
+    handle: Arc<NockAppHandle>,
+    config: PushConfig,
+}
+
+impl PushHub {
+    pub fn new(handle: Arc<NockAppHandle>, config: PushConfig) -> Self {
+        PushHub {
+            channels: Arc::new(Mutex::new(HashMap::new())),
+            handle,
+            config,
+        }
+    }
+
+    /// Whether a WebSocket upgrade with these headers may go ahead. Requests without an
+    /// `Origin` don't come from a browser page, so there is no cross-site page to guard
+    /// against.
+    fn origin_allowed(&self, headers: &HeaderMap) -> bool {
+        let Some(origin) = headers.get(ORIGIN) else {
+            return true;
+        };
+        let Ok(origin) = origin.to_str() else {
+            return false;
+        };
+        if self.config.allowed_origins.is_empty() {
+            let host = headers.get(HOST).and_then(|host| host.to_str().ok());
+            host.is_some() && origin.split_once("://").map(|(_, authority)| authority) == host
+        } else {
+            self.config
+                .allowed_origins
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(origin))
+        }
+    }
+
+    pub fn subscribe(&self, channel: &str) -> Result<Subscription, HttpError> {
+        if channel.len() > MAX_CHANNEL_NAME_LEN {
+            return Err(HttpError::PushChannelNameTooLong(MAX_CHANNEL_NAME_LEN));
+        }
+        let mut channels = self.channels.lock().expect("push channels poisoned");
+        if !channels.contains_key(channel) && channels.len() >= MAX_CHANNELS {
+            return Err(HttpError::TooManyPushChannels(MAX_CHANNELS));
+        }
+        let receiver = channels
+            .entry(channel.to_string())
+            .or_insert_with(|| broadcast::channel(CHANNEL_CAPACITY).0)
+            .subscribe();
+        Ok(Subscription {
+            receiver,
+            channel: channel.to_string(),
+            channels: self.channels.clone(),
+        })
+    }
+
+    /// How many channels have subscribers
+    pub fn channel_count(&self) -> usize {
+        self.channels.lock().expect("push channels poisoned").len()
+    }
+
+    /// Send `data` to the subscribers of `channel`, returning how many there were
+    pub fn publish(&self, channel: &str, data: String) -> usize {
+        let mut channels = self.channels.lock().expect("push channels poisoned");
+        let Some(sender) = channels.get(channel) else {
+            return 0;
+        };
+        match sender.send(data) {
+            Ok(subscribers) => subscribers,
+            Err(_) => {
+                // Everyone has gone, so forget the channel until it is subscribed to again
+                channels.remove(channel);
+                0
+            }
+        }
+    }
+
+    /// Poke a client message into the kernel
+    async fn poke(&self, channel: &str, data: &str) -> Result<PokeResult, HttpError> {
+        let mut slab = NounSlab::new();
+        let channel = Atom::from_value(&mut slab, channel)
+            .map_err(|e| HttpError::AtomCreationError(e.to_string()))?;
+        let data = Atom::from_value(&mut slab, data)
+            .map_err(|e| HttpError::AtomCreationError(e.to_string()))?;
+        let poke = T(
+            &mut slab,
+            &[D(tas!(b"push")), channel.as_noun(), data.as_noun()],
+        );
+        slab.set_root(poke);
+        Ok(self.handle.poke(HttpWire::Push.to_wire(), slab).await?)
+    }
+}
+
+/// One subscriber to a push channel. The channel is removed from the hub when its last
+/// subscription is dropped.
+pub struct Subscription {
+    receiver: broadcast::Receiver<String>,
+    channel: String,
+    channels: Channels,
+}
+
+impl Subscription {
+    pub async fn recv(&mut self) -> Result<String, broadcast::error::RecvError> {
+        self.receiver.recv().await
+    }
+
+    pub fn try_recv(&mut self) -> Result<String, broadcast::error::TryRecvError> {
+        self.receiver.try_recv()
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let mut channels = self.channels.lock().expect("push channels poisoned");
+        // Our receiver is still counted, so we are the last if there is only one
+        if channels
+            .get(&self.channel)
+            .is_some_and(|sender| sender.receiver_count() <= 1)
+        {
+            channels.remove(&self.channel);
+        }
+    }
+}
+
+/// Fixed one-second window on the messages one WebSocket pokes into the kernel
+struct PokeLimit {
+    per_sec: u32,
+    window: Instant,
+    count: u32,
+}
+
+impl PokeLimit {
+    fn new(per_sec: u32) -> Self {
+        PokeLimit {
+            per_sec,
+            window: Instant::now(),
+            count: 0,
+        }
+    }
+
+    fn allow(&mut self, now: Instant) -> bool {
+        if now.duration_since(self.window) >= Duration::from_secs(1) {
+            self.window = now;
+            self.count = 0;
+        }
+        if self.count >= self.per_sec {
+            return false;
+        }
+        self.count += 1;
+        true
+    }
+}
+
+/// Status to refuse a subscription with
+fn subscribe_status(e: HttpError) -> StatusCode {
+    warn!("Refusing push subscription: {}", e);
+    match e {
+        HttpError::TooManyPushChannels(_) => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::BAD_REQUEST,
+    }
+}
+
+/// Fan `%http-push` effects out to the subscribers of their channel
+pub async fn forward_push_effects(hub: Arc<PushHub>) {
+    let mut effects = hub.handle.subscribe_effects(&["http-push"]);
+    while let Some(effect) = effects.recv().await {
+        match push_effect(&effect) {
+            Ok((channel, data)) => {
+                let subscribers = hub.publish(&channel, data);
+                debug!("pushed to {} subscribers of {}", subscribers, channel);
+            }
+            Err(e) => warn!("Malformed %http-push effect: {}", e),
+        }
+    }
+}
+
+/// Decode `[%http-push channel=@t data=@t]`
+fn push_effect(effect: &NounSlab) -> Result<(String, String), HttpError> {
+    let push = unsafe { effect.root() }.as_cell()?.tail().as_cell()?;
+    let channel = push
+        .head()
+        .as_atom()?
+        .into_string()
+        .map_err(|e| HttpError::AtomCreationError(e.to_string()))?;
+    let data = push
+        .tail()
+        .as_atom()?
+        .into_string()
+        .map_err(|e| HttpError::AtomCreationError(e.to_string()))?;
+    Ok((channel, data))
+}
+
+/// `GET /push/sse/{channel}`
+pub async fn sse_handler(
+    Path(channel): Path<String>,
+    State(hub): State<Arc<PushHub>>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, StatusCode> {
+    let receiver = hub.subscribe(&channel).map_err(subscribe_status)?;
+    info!("SSE subscriber to {}", channel);
+    let events = stream::unfold(receiver, |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(data) => return Some((Ok(Event::default().data(data)), receiver)),
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    warn!("SSE subscriber missed {} messages", n);
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    });
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+/// `GET /push/ws/{channel}`
+pub async fn ws_handler(
+    ws: WebSocketUpgrade,
+    headers: HeaderMap,
+    Path(channel): Path<String>,
+    State(hub): State<Arc<PushHub>>,
+) -> Result<Response, StatusCode> {
+    if !hub.origin_allowed(&headers) {
+        warn!(
+            "Refusing push WebSocket from origin {:?}",
+            headers.get(ORIGIN)
+        );
+        return Err(StatusCode::FORBIDDEN);
+    }
+    let receiver = hub.subscribe(&channel).map_err(subscribe_status)?;
+    Ok(ws.on_upgrade(move |socket| websocket(socket, hub, channel, receiver)))
+}
+
+async fn websocket(
+    mut socket: WebSocket,
+    hub: Arc<PushHub>,
+    channel: String,
+    mut receiver: Subscription,
+) {
+    info!("WebSocket subscriber to {}", channel);
+    let mut limit = PokeLimit::new(hub.config.pokes_per_sec);
+    loop {
+        select! {
+            pushed = receiver.recv() => match pushed {
+                Ok(data) => {
+                    if socket.send(Message::Text(data.into())).await.is_err() {
+                        break;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    warn!("WebSocket subscriber missed {} messages", n);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            message = socket.recv() => match message {
+                Some(Ok(Message::Text(_))) if !limit.allow(Instant::now()) => {
+                    warn!("Dropping WebSocket message on {}: over {} a second", channel, limit.per_sec);
+                }
+                Some(Ok(Message::Text(text))) => {
+                    match hub.poke(&channel, text.as_str()).await {
+                        Ok(PokeResult::Ack) => {}
+                        Ok(PokeResult::Nack) => debug!("Kernel nacked WebSocket message on {}", channel),
+                        Err(e) => warn!("Failed to poke WebSocket message on {}: {}", channel, e),
+                    }
+                }
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                // Pings are answered by axum, and binary messages aren't part of the protocol
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+    debug!("WebSocket subscriber to {} disconnected", channel);
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::nockapp::driver::{test_handle, IOAction};
+
+    fn test_hub() -> (Arc<PushHub>, mpsc::Receiver<IOAction>) {
+        let (handle, rx_io) = test_handle(8);
+        (
+            Arc::new(PushHub::new(Arc::new(handle), PushConfig::default())),
+            rx_io,
+        )
+    }
+
+    fn upgrade_headers(origin: Option<&str>, host: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(HOST, host.parse().expect("host"));
+        if let Some(origin) = origin {
+            headers.insert(ORIGIN, origin.parse().expect("origin"));
+        }
+        headers
+    }
+
+    fn push(channel: &str, data: &str) -> NounSlab {
+        let mut slab = NounSlab::new();
+        let channel = Atom::from_value(&mut slab, channel).expect("channel");
+        let data = Atom::from_value(&mut slab, data).expect("data");
+        let effect = T(
+            &mut slab,
+            &[D(tas!(b"http-push")), channel.as_noun(), data.as_noun()],
+        );
+        slab.set_root(effect);
+        slab
+    }
+
+    #[tokio::test]
+    #[cfg_attr(miri, ignore)]
+    async fn test_push_effects_fan_out() {
+        let (hub, _rx_io) = test_hub();
+        let mut blocks_a = hub.subscribe("blocks").expect("subscribe");
+        let mut blocks_b = hub.subscribe("blocks").expect("subscribe");
+        let mut mining = hub.subscribe("mining").expect("subscribe");
+        let forwarder = tokio::spawn(forward_push_effects(hub.clone()));
+        // Let the forwarder subscribe before sending
+        while hub.handle.effect_sender.receiver_count() < 2 {
+            tokio::task::yield_now().await;
+        }
+
+        hub.handle
+            .effect_sender
+            .send(push("blocks", "height 10"))
+            .expect("send");
+        assert_eq!(blocks_a.recv().await.expect("recv"), "height 10");
+        assert_eq!(blocks_b.recv().await.expect("recv"), "height 10");
+        assert!(mining.try_recv().is_err());
+        assert_eq!(hub.publish("nobody", "lost".to_string()), 0);
+        forwarder.abort();
+    }
+
+    #[tokio::test]
+    #[cfg_attr(miri, ignore)]
+    async fn test_channels_are_bounded_and_forgotten() {
+        let (hub, _rx_io) = test_hub();
+        let first = hub.subscribe("blocks").expect("subscribe");
+        let second = hub.subscribe("blocks").expect("subscribe");
+        drop(first);
+        assert_eq!(hub.channel_count(), 1);
+        drop(second);
+        assert_eq!(hub.channel_count(), 0);
+
+        let long_name = "x".repeat(MAX_CHANNEL_NAME_LEN + 1);
+        assert!(matches!(
+            hub.subscribe(&long_name),
+            Err(HttpError::PushChannelNameTooLong(_))
+        ));
+
+        let subscriptions: Vec<Subscription> = (0..MAX_CHANNELS)
+            .map(|n| hub.subscribe(&n.to_string()).expect("subscribe"))
+            .collect();
+        assert!(matches!(
+            hub.subscribe("one-too-many"),
+            Err(HttpError::TooManyPushChannels(_))
+        ));
+        // Existing channels can still be joined
+        let _joined = hub.subscribe("0").expect("subscribe");
+        drop(subscriptions);
+        assert_eq!(hub.channel_count(), 1);
+    }
+
+    #[tokio::test]
+    #[cfg_attr(miri, ignore)]
+    async fn test_client_message_poke() {
+        let (hub, mut rx_io) = test_hub();
+        let kernel = tokio::spawn(async move {
+            let Some(IOAction::Poke {
+                wire,
+                poke,
+                ack_channel,
+                ..
+            }) = rx_io.recv().await
+            else {
+                panic!("expected a poke");
+            };
+            assert_eq!(wire, HttpWire::Push.to_wire());
+            let (channel, data) = push_effect(&poke).expect("push poke");
+            assert_eq!((channel.as_str(), data.as_str()), ("chat", "hello"));
+            let _ = ack_channel.send(PokeResult::Ack);
+        });
+        assert!(matches!(
+            hub.poke("chat", "hello").await.expect("poke"),
+            PokeResult::Ack
+        ));
+        kernel.await.expect("kernel task");
+    }
+
+    #[tokio::test]
+    #[cfg_attr(miri, ignore)]
+    async fn test_websocket_origins() {
+        let (same_origin, _rx_io) = test_hub();
+        assert!(same_origin.origin_allowed(&upgrade_headers(None, "pool.example")));
+        assert!(same_origin.origin_allowed(&upgrade_headers(
+            Some("https://pool.example"),
+            "pool.example"
+        )));
+        assert!(!same_origin.origin_allowed(&upgrade_headers(
+            Some("https://evil.example"),
+            "pool.example"
+        )));
+
+        let (handle, _rx_io) = test_handle(8);
+        let config = PushConfig {
+            allowed_origins: vec!["https://app.example".to_string()],
+            ..PushConfig::default()
+        };
+        let allowlisted = PushHub::new(Arc::new(handle), config);
+        assert!(allowlisted.origin_allowed(&upgrade_headers(
+            Some("https://app.example"),
+            "pool.example"
+        )));
+        assert!(!allowlisted.origin_allowed(&upgrade_headers(
+            Some("https://pool.example"),
+            "pool.example"
+        )));
+    }
+
+    #[test]
+    fn test_poke_limit() {
+        let start = Instant::now();
+        let mut limit = PokeLimit::new(2);
+        assert!(limit.allow(start));
+        assert!(limit.allow(start));
+        assert!(!limit.allow(start + Duration::from_millis(500)));
+        assert!(limit.allow(start + Duration::from_secs(1)));
+    }
+}
@@ -7,9 +7,10 @@ pub mod one_punch;
 pub mod timer;
 
 pub use exit::exit as exit_driver;
-pub use file::file as file_driver;
+pub use file::{file as file_driver, file_in as file_driver_in};
+pub use http::gateway::gateway as gateway_driver;
 pub use http::http::http as http_driver;
 pub use markdown::markdown as markdown_driver;
 pub use npc::{npc_client as npc_client_driver, npc_listener as npc_listener_driver};
 pub use one_punch::one_punch_man as one_punch_driver;
-pub use timer::make_timer_driver as timer_driver;
+pub use timer::{kernel_timers as kernel_timer_driver, make_timer_driver as timer_driver};
@@ -1,17 +1,49 @@
+//! NPC socket driver.
+//!
+//! Connections start with a version handshake: the connecting side sends
+//! `[NPC_PROTOCOL_MAGIC: u64 LE][version: u32 LE]` and the accepting side answers with the
+//! version both will speak. After that every message is framed as
+//! `[len: u64 LE][request id: u64 LE][jam of directive]`, where `len` covers the request id
+//! and the jam. A response carries the id of its request, so a connection can have many
+//! requests in flight and they are handled concurrently.
+//!
+//! Request ids are chosen by whoever sends the request and only mean something on that
+//! connection. Incoming pokes are given a fresh kernel pid, and `%npc` effects for that
+//! pid are sent back only to the connection which made the request, under its request id.
+//! Those pids start at [ROUTED_PID_BASE], so they never collide with the pids the kernel
+//! picks for its own requests, which must stay below it. Each request from our kernel is
+//! sent to a single connection, the first versioned one to take it (or the only one, for
+//! [npc_client]), and the response is routed back to the kernel from there.
+//!
+//! Clients which skip the handshake speak the legacy protocol, `[len: u64 LE][jam of [pid
+//! directive]]`, one request at a time, with pids used as request ids. A legacy acceptor
+//! never answers the hello, so if it goes unanswered the connector reconnects and speaks
+//! the legacy protocol.
+//!
+//! Besides `%poke` and `%peek`, a peer can send `[%upgrade jam=@]` to swap in the kernel
+//! jammed in `jam` while keeping the current state. It is answered with `%pack` once the new
+//! kernel is running and persisted, or `%nack` if the upgrade failed and the old kernel was
+//! kept. Upgrades are refused (and nacked) unless the app opted in with
+//! [crate::NockApp::set_allow_kernel_upgrade], e.g. by booting with `--allow-kernel-upgrade`.
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 
 use bytes::buf::BufMut;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use nockvm::mug::met3_usize;
 use nockvm::noun::{D, T};
 use nockvm_macros::tas;
-use tokio::io::{split, AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::io::{split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
 use tokio::net::{UnixListener, UnixStream};
 use tokio::select;
-use tokio::sync::Mutex;
-use tokio::task::JoinSet;
+use tokio::sync::{mpsc, Mutex};
 use tokio::time::{sleep, timeout, Duration};
-use tracing::{debug, error};
+use tracing::{debug, error, warn};
 
-use crate::nockapp::driver::{make_driver, IODriverFn, PokeResult, TaskJoinSet};
+use crate::nockapp::driver::{make_driver, IODriverFn, NockAppHandle, PokeResult, TaskJoinSet};
 use crate::nockapp::wire::{Wire, WireRepr};
 use crate::nockapp::NockAppError;
 use crate::noun::slab::NounSlab;
@@ -21,8 +53,21 @@ use crate::Bytes;
 const OVERALL_WRITE_TIMEOUT_SECS: u64 = 300; // 5 minutes
 const LENGTH_WRITE_TIMEOUT_SECS: u64 = 5; // 5 seconds
 const CONTENT_WRITE_TIMEOUT_SECS: u64 = 60; // 1 minute
+const HANDSHAKE_TIMEOUT_SECS: u64 = 5; // 5 seconds
 const LISTENER_SLEEP_MILLIS: u64 = 100; // 100ms to avoid tight-looping
 
+/// First eight bytes of a versioned connection. As a legacy length prefix it would be an
+/// exabyte-sized message, so it can't be mistaken for one.
+pub const NPC_PROTOCOL_MAGIC: u64 = tas!(b"npcproto");
+/// Newest protocol version this driver speaks
+pub const NPC_PROTOCOL_VERSION: u32 = 1;
+/// Frames buffered between a connection's socket tasks and its request handlers
+const FRAME_CHANNEL_SIZE: usize = 64;
+/// First pid given to incoming requests. Kernel pids in `%npc` effects are below this.
+pub const ROUTED_PID_BASE: u64 = 1 << 62;
+/// Most pokes whose pids are remembered for routing effects back to their connection
+const MAX_ROUTES: usize = 1 << 16;
+
 pub enum NpcWire {
     Poke(u64),
     Pack(u64),
@@ -45,9 +90,103 @@ impl Wire for NpcWire {
     }
 }
 
+/// Protocol spoken on a connection
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Protocol {
+    /// No handshake, `[pid directive]` nouns, one request at a time
+    Legacy,
+    /// Version handshake, request ids in the frame, concurrent requests
+    V1,
+}
+
+/// Which end of the socket a connection is
+#[derive(Clone, Copy, Debug)]
+enum Role {
+    Connector,
+    Acceptor,
+}
+
+/// A directive sent or received under a request id
+struct Frame {
+    id: u64,
+    directive: NounSlab,
+}
+
+/// Maps kernel pids to the connections whose requests they were made for. Shared by every
+/// connection from one listener.
+#[derive(Default)]
+struct NpcRouter {
+    next_connection: AtomicU64,
+    next_pid: AtomicU64,
+    /// Kernel pid -> (connection, request id)
+    routes: std::sync::Mutex<BTreeMap<u64, (u64, u64)>>,
+    /// Kernel pid -> connection, for requests our kernel sent until they are answered
+    requests: std::sync::Mutex<BTreeMap<u64, u64>>,
+}
+
+impl NpcRouter {
+    fn connect(&self) -> u64 {
+        self.next_connection.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// A fresh kernel pid for request `id` on `connection`
+    fn route(&self, connection: u64, id: u64) -> u64 {
+        let pid = ROUTED_PID_BASE + self.next_pid.fetch_add(1, Ordering::Relaxed);
+        let mut routes = self.routes.lock().expect("npc routes poisoned");
+        routes.insert(pid, (connection, id));
+        if routes.len() > MAX_ROUTES {
+            routes.pop_first();
+        }
+        pid
+    }
+
+    fn owner(&self, pid: u64) -> Option<(u64, u64)> {
+        self.routes
+            .lock()
+            .expect("npc routes poisoned")
+            .get(&pid)
+            .copied()
+    }
+
+    /// Take our kernel's request `pid` for `connection`, unless another connection has it
+    fn claim(&self, pid: u64, connection: u64) -> bool {
+        let mut requests = self.requests.lock().expect("npc requests poisoned");
+        match requests.entry(pid) {
+            Entry::Occupied(entry) => *entry.get() == connection,
+            Entry::Vacant(entry) => {
+                entry.insert(connection);
+                if requests.len() > MAX_ROUTES {
+                    requests.pop_first();
+                }
+                true
+            }
+        }
+    }
+
+    /// Forget our kernel's request `pid` once it is answered
+    fn release(&self, pid: u64) {
+        self.requests
+            .lock()
+            .expect("npc requests poisoned")
+            .remove(&pid);
+    }
+
+    fn disconnect(&self, connection: u64) {
+        self.routes
+            .lock()
+            .expect("npc routes poisoned")
+            .retain(|_, (owner, _)| *owner != connection);
+        self.requests
+            .lock()
+            .expect("npc requests poisoned")
+            .retain(|_, owner| *owner != connection);
+    }
+}
+
 /// NPC Listener IO driver
 pub fn npc_listener(listener: UnixListener) -> IODriverFn {
     make_driver(move |mut handle| async move {
+        let router = Arc::new(NpcRouter::default());
         let mut client_join_set = TaskJoinSet::new();
         loop {
             select! {
@@ -57,7 +196,8 @@ pub fn npc_listener(listener: UnixListener) -> IODriverFn {
                         Ok((stream, _)) => {
                             let (my_handle, their_handle) = handle.dup();
                             handle = my_handle;
-                            let _ = client_join_set.spawn(npc_client(stream)(their_handle));
+                            let connection = npc_connection(stream, Role::Acceptor, router.clone());
+                            let _ = client_join_set.spawn(connection(their_handle));
                         },
                         Err(e) => {
                             error!("Error accepting connection: {:?}", e);
@@ -82,173 +222,503 @@ pub fn npc_listener(listener: UnixListener) -> IODriverFn {
 
 /// NPC Client IO driver
 pub fn npc_client(stream: UnixStream) -> IODriverFn {
+    npc_connection(stream, Role::Connector, Arc::new(NpcRouter::default()))
+}
+
+fn npc_connection(stream: UnixStream, role: Role, router: Arc<NpcRouter>) -> IODriverFn {
     make_driver(move |handle| async move {
-        let (stream_read, mut stream_write) = split(stream);
-        let stream_read_arc = Arc::new(Mutex::new(stream_read));
-        let mut read_message_join_set = JoinSet::new();
-        read_message_join_set.spawn(read_message(stream_read_arc.clone()));
+        let mut stream = stream;
+        let (protocol, first_len) = match role {
+            Role::Connector => (connect_handshake(&mut stream).await?, None),
+            Role::Acceptor => accept_handshake(&mut stream).await?,
+        };
+        debug!("npc: {:?} connection speaking {:?}", role, protocol);
 
-        'driver: loop {
+        let (stream_read, stream_write) = split(stream);
+        let (incoming_tx, mut incoming) = mpsc::channel(FRAME_CHANNEL_SIZE);
+        let (outgoing, outgoing_rx) = mpsc::channel(FRAME_CHANNEL_SIZE);
+        let mut socket_tasks = TaskJoinSet::new();
+        socket_tasks.spawn(read_frames(stream_read, protocol, first_len, incoming_tx));
+        socket_tasks.spawn(write_frames(stream_write, protocol, outgoing_rx));
+
+        let mut effects = handle.subscribe_effects(&["npc"]);
+        let mut connection = Connection {
+            id: router.connect(),
+            protocol,
+            // A legacy peer on the listener may not expect requests, but a client's only
+            // connection has to carry them
+            takes_requests: protocol == Protocol::V1 || matches!(role, Role::Connector),
+            router,
+            handle: Arc::new(handle),
+            outgoing,
+            pending: HashMap::new(),
+            next_request: 0,
+        };
+        let mut requests = TaskJoinSet::new();
+        let result = loop {
             select! {
-                message = read_message_join_set.join_next() => {
-                    match message {
-                        Some(Ok(Ok(Some(mut slab)))) => {
-                            debug!("npc_client: read message");
-                            let Ok(message_cell) = unsafe { slab.root() }.as_cell() else {
-                                continue;
-                            };
-
-                            let (pid, directive_cell) = match (message_cell.head().as_direct(), message_cell.tail().as_cell()) {
-                                (Ok(direct), Ok(cell)) => (direct.data(), cell),
-                                _ => continue,
-                            };
-
-                            let Ok(directive_tag) = directive_cell.head().as_direct() else {
-                                continue;
-                            };
-                            let directive_tag = directive_tag.data();
-
-                            match directive_tag {
-                                tas!(b"poke") => {
-                                    debug!("npc_client: poke");
-                                    let mut poke_slab = NounSlab::new();
-                                    let poke = directive_cell.tail();
-                                    poke_slab.copy_into(poke);
-                                    let wire = NpcWire::Poke(pid).to_wire();
-                                    let result = handle.poke(wire, poke_slab).await?;
-                                    let (tag, noun) = match result {
-                                        PokeResult::Ack => (tas!(b"pack"), D(0)),
-                                        PokeResult::Nack => (tas!(b"nack"), D(0)),
-                                    };
-
-                                    let mut response_slab = NounSlab::new();
-                                    let response_noun = T(&mut response_slab, &[D(pid), D(tag), noun]);
-                                    response_slab.set_root(response_noun);
-                                    match write_message(&mut stream_write, response_slab).await {
-                                        Ok(false) => break 'driver,
-                                        Err(NockAppError::IoError(e)) if e.kind() == std::io::ErrorKind::TimedOut => {
-                                            error!("npc_client: write timeout, closing connection to allow reconnect");
-                                            break 'driver;
-                                        },
-                                        Err(e) => return Err(e),
-                                        Ok(true) => {}, // Success, continue
-                                    }
-                                },
-                                tas!(b"peek") => {
-                                    debug!("npc_client: peek");
-                                    let path = directive_cell.tail();
-                                    slab.set_root(path);
-                                    let peek_res = handle.peek(slab).await?;
-                                    match peek_res {
-                                        Some(mut bind_slab) => {
-                                            bind_slab.modify(|root| {
-                                                vec![D(pid), D(tas!(b"bind")), root]
-                                            });
-                                            match write_message(&mut stream_write, bind_slab).await {
-                                                Ok(false) => break 'driver,
-                                                Err(NockAppError::IoError(e)) if e.kind() == std::io::ErrorKind::TimedOut => {
-                                                    error!("npc_client: write timeout, closing connection to allow reconnect");
-                                                    break 'driver;
-                                                },
-                                                Err(e) => return Err(e),
-                                                Ok(true) => {}, // Success, continue
-                                            }
-                                        },
-                                        None => {
-                                            error!("npc: peek failed!");
-                                        }
-                                    }
-                                },
-                                tas!(b"pack") | tas!(b"nack") | tas!(b"bind") => {
-                                    debug!("npc_client: pack, nack, or bind");
-                                    let tag = match directive_tag {
-                                        tas!(b"pack") => tas!(b"npc-pack"),
-                                        tas!(b"nack") => tas!(b"npc-nack"),
-                                        tas!(b"bind") => tas!(b"npc-bind"),
-                                        _ => unreachable!(),
-                                    };
-                                    let wire = match directive_tag {
-                                        tas!(b"pack") => NpcWire::Pack(pid),
-                                        tas!(b"nack") => NpcWire::Nack(pid),
-                                        tas!(b"bind") => NpcWire::Bind(pid),
-                                        _ => unreachable!(),
-                                    };
-                                    let poke = if tag == tas!(b"npc-bind") {
-                                        T(&mut slab, &[D(tag), D(pid), directive_cell.tail()])
-                                    } else {
-                                        T(&mut slab, &[D(tag), D(pid)])
-                                    };
-                                    slab.set_root(poke);
-
-                                    handle.poke(wire.to_wire(), slab).await?;
-                                },
-                                _ => {
-                                    debug!("npc_client: unexpected message: {:?}", directive_tag);
-                                },
+                frame = incoming.recv() => {
+                    let Some(frame) = frame else {
+                        break Ok(());
+                    };
+                    if let Some(request) = connection.handle_frame(frame) {
+                        // Legacy clients can't tell responses apart, so keep them in order
+                        match protocol {
+                            Protocol::Legacy => {
+                                if let Err(e) = request.await {
+                                    break Err(e);
+                                }
+                            }
+                            Protocol::V1 => {
+                                requests.spawn(request);
                             }
-                        },
-                        Some(Ok(Ok(None))) => {
-                            break 'driver;
-                        },
-                        Some(Err(e)) => {
-                            error!("{e:?}");
-                        },
-                        Some(Ok(Err(e))) => {
-                            error!("{e:?}");
-                        },
-                        None => {
-                            read_message_join_set.spawn(read_message(stream_read_arc.clone()));
                         }
                     }
                 },
-                effect_res = handle.next_effect() => {
-                    let mut slab = effect_res?; // Closed error should error driver
-                    let Ok(effect_cell) = unsafe { slab.root() }.as_cell() else {
-                        continue;
+                Some(result) = socket_tasks.join_next() => {
+                    break match result {
+                        Ok(result) => result,
+                        Err(e) => Err(NockAppError::JoinError(e)),
                     };
-                    // TODO: distinguish connections
-                    if unsafe { effect_cell.head().raw_equals(&D(tas!(b"npc"))) } {
-                        slab.set_root(effect_cell.tail());
-                        match write_message(&mut stream_write, slab).await {
-                            Ok(false) => break 'driver,
-                            Err(NockAppError::IoError(e)) if e.kind() == std::io::ErrorKind::TimedOut => {
-                                error!("npc_client: write timeout, closing connection to allow reconnect");
-                                break 'driver;
-                            },
-                            Err(e) => return Err(e),
-                            Ok(true) => {}, // Success, continue
+                },
+                Some(result) = requests.join_next() => {
+                    match result {
+                        Ok(Ok(())) => {}
+                        Ok(Err(e)) => error!("npc: request error: {:?}", e),
+                        Err(e) => error!("npc: request join error: {:?}", e),
+                    }
+                },
+                effect = effects.recv() => {
+                    match effect {
+                        Some(slab) => connection.handle_effect(slab).await,
+                        None => break Err(NockAppError::ChannelClosedError), // Closed error should error driver
+                    }
+                }
+            }
+        };
+        connection.router.disconnect(connection.id);
+        result
+    })
+}
+
+/// State of one connection, owned by its driver loop
+struct Connection {
+    id: u64,
+    protocol: Protocol,
+    /// Whether requests from our kernel may be sent here
+    takes_requests: bool,
+    router: Arc<NpcRouter>,
+    handle: Arc<NockAppHandle>,
+    outgoing: mpsc::Sender<Frame>,
+    /// Request id -> kernel pid, for requests our kernel sent to the peer
+    pending: HashMap<u64, u64>,
+    next_request: u64,
+}
+
+impl Connection {
+    /// Turn an incoming frame into the work it asks for
+    fn handle_frame(
+        &mut self,
+        frame: Frame,
+    ) -> Option<BoxFuture<'static, Result<(), NockAppError>>> {
+        let Frame { id, mut directive } = frame;
+        let Ok(directive_cell) = unsafe { directive.root() }.as_cell() else {
+            return None;
+        };
+        let Ok(directive_tag) = directive_cell.head().as_direct() else {
+            return None;
+        };
+        let directive_tag = directive_tag.data();
+        let handle = self.handle.clone();
+        let outgoing = self.outgoing.clone();
+        let protocol = self.protocol;
+
+        match directive_tag {
+            tas!(b"poke") => {
+                debug!("npc_client: poke");
+                let pid = self.router.route(self.id, id);
+                let mut poke_slab = NounSlab::new();
+                poke_slab.copy_into(directive_cell.tail());
+                Some(
+                    async move {
+                        let wire = NpcWire::Poke(pid).to_wire();
+                        let tag = match handle.poke(wire, poke_slab).await? {
+                            PokeResult::Ack => tas!(b"pack"),
+                            PokeResult::Nack => tas!(b"nack"),
+                        };
+                        respond(&outgoing, id, tag_only(tag)).await
+                    }
+                    .boxed(),
+                )
+            }
+            tas!(b"peek") => {
+                debug!("npc_client: peek");
+                directive.set_root(directive_cell.tail());
+                Some(
+                    async move {
+                        match handle.peek(directive).await? {
+                            Some(mut bind_slab) => {
+                                bind_slab.modify(|root| vec![D(tas!(b"bind")), root]);
+                                respond(&outgoing, id, bind_slab).await
+                            }
+                            None => {
+                                error!("npc: peek failed!");
+                                // Versioned peers are owed a response to every request
+                                if protocol == Protocol::V1 {
+                                    respond(&outgoing, id, tag_only(tas!(b"nack"))).await?;
+                                }
+                                Ok(())
+                            }
                         }
                     }
+                    .boxed(),
+                )
+            }
+            tas!(b"upgrade") => {
+                debug!("npc_client: upgrade");
+                let Ok(kernel_atom) = directive_cell.tail().as_atom() else {
+                    warn!("npc: upgrade without a kernel jam");
+                    return None;
+                };
+                let kernel = kernel_atom.as_ne_bytes()[..met3_usize(kernel_atom)].to_vec();
+                Some(
+                    async move {
+                        let tag = match handle.upgrade_kernel(kernel).await {
+                            Ok(_) => tas!(b"pack"),
+                            Err(e) => {
+                                error!("npc: kernel upgrade failed: {}", e);
+                                tas!(b"nack")
+                            }
+                        };
+                        respond(&outgoing, id, tag_only(tag)).await
+                    }
+                    .boxed(),
+                )
+            }
+            tas!(b"pack") | tas!(b"nack") | tas!(b"bind") => {
+                debug!("npc_client: pack, nack, or bind");
+                let pid = match self.protocol {
+                    Protocol::Legacy => id,
+                    Protocol::V1 => {
+                        let Some(pid) = self.pending.remove(&id) else {
+                            warn!("npc: response to unknown request {}", id);
+                            return None;
+                        };
+                        pid
+                    }
+                };
+                self.router.release(pid);
+                let (tag, wire) = match directive_tag {
+                    tas!(b"pack") => (tas!(b"npc-pack"), NpcWire::Pack(pid)),
+                    tas!(b"nack") => (tas!(b"npc-nack"), NpcWire::Nack(pid)),
+                    _ => (tas!(b"npc-bind"), NpcWire::Bind(pid)),
+                };
+                let poke = if tag == tas!(b"npc-bind") {
+                    T(&mut directive, &[D(tag), D(pid), directive_cell.tail()])
+                } else {
+                    T(&mut directive, &[D(tag), D(pid)])
+                };
+                directive.set_root(poke);
+                Some(
+                    async move {
+                        handle.poke(wire.to_wire(), directive).await?;
+                        Ok(())
+                    }
+                    .boxed(),
+                )
+            }
+            _ => {
+                debug!("npc_client: unexpected message: {:?}", directive_tag);
+                None
+            }
+        }
+    }
+
+    /// Send an `%npc` effect to the peer if it belongs to this connection. A request from
+    /// our kernel belongs to the connection which claims it first.
+    async fn handle_effect(&mut self, mut slab: NounSlab) {
+        let Ok(effect_cell) = unsafe { slab.root() }.as_cell() else {
+            return;
+        };
+        let Ok(npc_cell) = effect_cell.tail().as_cell() else {
+            return;
+        };
+        let Ok(pid) = npc_cell.head().as_direct() else {
+            return;
+        };
+        let pid = pid.data();
+        let directive = npc_cell.tail();
+        let id = if pid >= ROUTED_PID_BASE {
+            match self.router.owner(pid) {
+                // About a request the peer on this connection made
+                Some((connection, id)) if connection == self.id => id,
+                // About another connection's request, or one no longer routed
+                _ => return,
+            }
+        } else {
+            // A request or response from our kernel
+            let is_request = directive.as_cell().is_ok_and(|cell| {
+                unsafe { cell.head().raw_equals(&D(tas!(b"poke"))) }
+                || unsafe { cell.head().raw_equals(&D(tas!(b"peek"))) }
+            });
+            if is_request && !(self.takes_requests && self.router.claim(pid, self.id)) {
+                return;
+            }
+            match self.protocol {
+                Protocol::V1 if is_request => {
+                    let id = self.next_request;
+                    self.next_request += 1;
+                    self.pending.insert(id, pid);
+                    id
                 }
+                _ => pid,
             }
+        };
+        slab.set_root(directive);
+        // If the writer has stopped the connection is closing, which the driver loop sees
+        let _ = self
+            .outgoing
+            .send(Frame {
+                id,
+                directive: slab,
+            })
+            .await;
+    }
+}
+
+/// `[tag 0]`
+fn tag_only(tag: u64) -> NounSlab {
+    let mut slab = NounSlab::new();
+    let noun = T(&mut slab, &[D(tag), D(0)]);
+    slab.set_root(noun);
+    slab
+}
+
+async fn respond(
+    outgoing: &mpsc::Sender<Frame>,
+    id: u64,
+    directive: NounSlab,
+) -> Result<(), NockAppError> {
+    outgoing
+        .send(Frame { id, directive })
+        .await
+        .map_err(|_| NockAppError::ChannelClosedError)
+}
+
+fn hello(version: u32) -> [u8; 12] {
+    let mut hello = [0u8; 12];
+    hello[..8].copy_from_slice(&NPC_PROTOCOL_MAGIC.to_le_bytes());
+    hello[8..].copy_from_slice(&version.to_le_bytes());
+    hello
+}
+
+fn handshake_timeout() -> NockAppError {
+    NockAppError::IoError(std::io::Error::new(
+        std::io::ErrorKind::TimedOut,
+        "Timeout during npc handshake",
+    ))
+}
+
+/// Offer our version and read back the one the acceptor chose. A legacy acceptor takes the
+/// hello for the length of a huge message and never answers, so if no reply comes we
+/// reconnect to the same socket and speak the legacy protocol on the fresh stream.
+async fn connect_handshake(stream: &mut UnixStream) -> Result<Protocol, NockAppError> {
+    let exchange = async {
+        stream.write_all(&hello(NPC_PROTOCOL_VERSION)).await?;
+        let mut reply = [0u8; 12];
+        stream.read_exact(&mut reply).await?;
+        Ok::<_, std::io::Error>(reply)
+    };
+    let reply = match timeout(Duration::from_secs(HANDSHAKE_TIMEOUT_SECS), exchange).await {
+        Ok(Ok(reply)) => reply,
+        Ok(Err(e))
+            if !matches!(
+                e.kind(),
+                std::io::ErrorKind::UnexpectedEof | std::io::ErrorKind::ConnectionReset
+            ) =>
+        {
+            return Err(NockAppError::IoError(e));
         }
-        Ok(())
-    })
+        // Timed out, or the acceptor hung up without answering
+        _ => {
+            let peer = stream.peer_addr().map_err(NockAppError::IoError)?;
+            let Some(path) = peer.as_pathname() else {
+                return Err(handshake_timeout());
+            };
+            warn!("npc: no handshake reply, falling back to the legacy protocol");
+            *stream = UnixStream::connect(path)
+                .await
+                .map_err(NockAppError::IoError)?;
+            return Ok(Protocol::Legacy);
+        }
+    };
+    let magic = u64::from_le_bytes(reply[..8].try_into().expect("8 byte magic"));
+    let version = u32::from_le_bytes(reply[8..].try_into().expect("4 byte version"));
+    match (magic, version) {
+        (NPC_PROTOCOL_MAGIC, 1) => Ok(Protocol::V1),
+        _ => Err(NockAppError::IoError(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Unsupported npc handshake reply: version {}", version),
+        ))),
+    }
 }
 
-async fn read_message(
-    stream_arc: Arc<Mutex<ReadHalf<UnixStream>>>,
-) -> Result<Option<NounSlab>, NockAppError> {
-    let mut stream = stream_arc.lock_owned().await;
-    let mut size_bytes = [0u8; 8];
-    debug!("Attempting to read message size...");
-    match stream.read_exact(&mut size_bytes).await {
-        Ok(0) => {
-            debug!("Connection closed");
-            return Ok(None);
+/// Read the connector's hello and answer it. A connection which doesn't start with one is
+/// legacy, and the eight bytes already read are the length of its first message.
+async fn accept_handshake(
+    stream: &mut UnixStream,
+) -> Result<(Protocol, Option<[u8; 8]>), NockAppError> {
+    let mut first = [0u8; 8];
+    stream
+        .read_exact(&mut first)
+        .await
+        .map_err(NockAppError::IoError)?;
+    if u64::from_le_bytes(first) != NPC_PROTOCOL_MAGIC {
+        return Ok((Protocol::Legacy, Some(first)));
+    }
+    let exchange = async {
+        let mut version = [0u8; 4];
+        stream.read_exact(&mut version).await?;
+        let version = u32::from_le_bytes(version).min(NPC_PROTOCOL_VERSION);
+        stream.write_all(&hello(version)).await?;
+        Ok::<_, std::io::Error>(version)
+    };
+    let version = timeout(Duration::from_secs(HANDSHAKE_TIMEOUT_SECS), exchange)
+        .await
+        .map_err(|_| handshake_timeout())?
+        .map_err(NockAppError::IoError)?;
+    match version {
+        1 => Ok((Protocol::V1, None)),
+        _ => Err(NockAppError::IoError(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Unsupported npc protocol version {}", version),
+        ))),
+    }
+}
+
+/// Read frames until the peer disconnects
+async fn read_frames(
+    mut stream: ReadHalf<UnixStream>,
+    protocol: Protocol,
+    mut first_len: Option<[u8; 8]>,
+    frames: mpsc::Sender<Frame>,
+) -> Result<(), NockAppError> {
+    loop {
+        let Some(payload) = read_payload(&mut stream, first_len.take()).await? else {
+            return Ok(());
+        };
+        match decode_frame(protocol, payload) {
+            Ok(Some(frame)) => {
+                if frames.send(frame).await.is_err() {
+                    return Ok(());
+                }
+            }
+            Ok(None) => debug!("npc: ignoring malformed message"),
+            Err(e) => error!("{e:?}"),
         }
-        Ok(size) => {
-            debug!("Read size: {:?}", size);
+    }
+}
+
+fn decode_frame(protocol: Protocol, payload: Bytes) -> Result<Option<Frame>, NockAppError> {
+    let mut directive = NounSlab::new();
+    match protocol {
+        Protocol::Legacy => {
+            let noun = directive.cue_into(payload)?;
+            let Ok(message_cell) = noun.as_cell() else {
+                return Ok(None);
+            };
+            let Ok(pid) = message_cell.head().as_direct() else {
+                return Ok(None);
+            };
+            directive.set_root(message_cell.tail());
+            Ok(Some(Frame {
+                id: pid.data(),
+                directive,
+            }))
         }
-        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
-            debug!("Connection closed unexpectedly");
-            return Ok(None);
+        Protocol::V1 => {
+            if payload.len() < 8 {
+                return Ok(None);
+            }
+            let id = u64::from_le_bytes(payload[..8].try_into().expect("8 byte request id"));
+            let noun = directive.cue_into(payload.slice(8..))?;
+            directive.set_root(noun);
+            Ok(Some(Frame { id, directive }))
         }
-        Err(e) => {
-            debug!("Error reading size: {:?}", e);
-            return Err(NockAppError::IoError(e));
+    }
+}
+
+/// Write frames until the channel closes or the peer goes away
+async fn write_frames(
+    mut stream: WriteHalf<UnixStream>,
+    protocol: Protocol,
+    mut frames: mpsc::Receiver<Frame>,
+) -> Result<(), NockAppError> {
+    while let Some(Frame { id, mut directive }) = frames.recv().await {
+        let written = match protocol {
+            Protocol::Legacy => {
+                directive.modify(|root| vec![D(id), root]);
+                write_message(&mut stream, directive).await
+            }
+            Protocol::V1 => write_payload(&mut stream, &id.to_le_bytes(), &directive.jam()).await,
+        };
+        match written {
+            Ok(false) => break,
+            Err(NockAppError::IoError(e)) if e.kind() == std::io::ErrorKind::TimedOut => {
+                error!("npc_client: write timeout, closing connection to allow reconnect");
+                break;
+            }
+            Err(e) => return Err(e),
+            Ok(true) => {} // Success, continue
         }
     }
+    Ok(())
+}
+
+async fn read_message(
+    stream_arc: Arc<Mutex<ReadHalf<UnixStream>>>,
+) -> Result<Option<NounSlab>, NockAppError> {
+    let mut stream = stream_arc.lock_owned().await;
+    let Some(payload) = read_payload(&mut *stream, None).await? else {
+        return Ok(None);
+    };
+    let mut slab = NounSlab::new();
+    let noun = slab.cue_into(payload)?;
+    slab.set_root(noun);
+    Ok(Some(slab))
+}
+
+/// Read one length-prefixed payload, or `None` if the connection closed. `size_bytes` is
+/// the length prefix if it has already been read.
+async fn read_payload<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    size_bytes: Option<[u8; 8]>,
+) -> Result<Option<Bytes>, NockAppError> {
+    let size_bytes = match size_bytes {
+        Some(size_bytes) => size_bytes,
+        None => {
+            let mut size_bytes = [0u8; 8];
+            debug!("Attempting to read message size...");
+            match stream.read_exact(&mut size_bytes).await {
+                Ok(0) => {
+                    debug!("Connection closed");
+                    return Ok(None);
+                }
+                Ok(size) => {
+                    debug!("Read size: {:?}", size);
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    debug!("Connection closed unexpectedly");
+                    return Ok(None);
+                }
+                Err(e) => {
+                    debug!("Error reading size: {:?}", e);
+                    return Err(NockAppError::IoError(e));
+                }
+            }
+            size_bytes
+        }
+    };
     let size = usize::from_le_bytes(size_bytes);
     debug!("Message size: {} bytes", size);
     let mut buf = Vec::with_capacity(size).limit(size);
@@ -267,18 +737,23 @@ async fn read_message(
         }
     }
     debug!("Successfully read entire message");
-    let mut slab = NounSlab::new();
-    let noun = slab.cue_into(Bytes::from(buf.into_inner()))?;
-    slab.set_root(noun);
-    Ok(Some(slab))
+    Ok(Some(Bytes::from(buf.into_inner())))
 }
 
 async fn write_message(
     stream: &mut WriteHalf<UnixStream>,
     msg_slab: NounSlab,
 ) -> Result<bool, NockAppError> {
-    let msg_bytes = msg_slab.jam();
-    let msg_len = msg_bytes.len();
+    write_payload(stream, &[], &msg_slab.jam()).await
+}
+
+/// Write `prefix` and `msg_bytes` as one length-prefixed payload
+async fn write_payload<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    prefix: &[u8],
+    msg_bytes: &[u8],
+) -> Result<bool, NockAppError> {
+    let msg_len = prefix.len() + msg_bytes.len();
     debug!("Attempting to write message of {} bytes", msg_len);
 
     // timeout wrapper for entire write operation
@@ -286,7 +761,6 @@ async fn write_message(
 
     match timeout(write_timeout, async {
         let mut msg_len_bytes = &msg_len.to_le_bytes()[..];
-        let mut msg_buf = &msg_bytes[..];
 
         // Write length header
         while !msg_len_bytes.is_empty() {
@@ -323,36 +797,38 @@ async fn write_message(
         }
 
         // Write message content
-        while !msg_buf.is_empty() {
-            debug!("Writing message content, {} bytes remaining", msg_buf.len());
+        for mut msg_buf in [prefix, msg_bytes] {
+            while !msg_buf.is_empty() {
+                debug!("Writing message content, {} bytes remaining", msg_buf.len());
 
-            match timeout(
-                Duration::from_secs(CONTENT_WRITE_TIMEOUT_SECS),
-                stream.write_buf(&mut msg_buf),
-            )
-            .await
-            {
-                Ok(Ok(bytes)) => {
-                    if bytes == 0 {
-                        debug!("Wrote 0 bytes for message content, connection likely closed");
-                        return Ok(false);
+                match timeout(
+                    Duration::from_secs(CONTENT_WRITE_TIMEOUT_SECS),
+                    stream.write_buf(&mut msg_buf),
+                )
+                .await
+                {
+                    Ok(Ok(bytes)) => {
+                        if bytes == 0 {
+                            debug!("Wrote 0 bytes for message content, connection likely closed");
+                            return Ok(false);
+                        }
+                        debug!("Wrote {} bytes of message content", bytes);
                     }
-                    debug!("Wrote {} bytes of message content", bytes);
-                }
-                Ok(Err(e)) => return Err(NockAppError::IoError(e)),
-                Err(_timeout) => {
-                    error!(
-                        "Timeout writing message content after {}s, {} bytes remaining",
-                        CONTENT_WRITE_TIMEOUT_SECS,
-                        msg_buf.len()
-                    );
-                    return Err(NockAppError::IoError(std::io::Error::new(
-                        std::io::ErrorKind::TimedOut,
-                        format!(
-                            "Timeout writing message content - {} bytes stuck",
+                    Ok(Err(e)) => return Err(NockAppError::IoError(e)),
+                    Err(_timeout) => {
+                        error!(
+                            "Timeout writing message content after {}s, {} bytes remaining",
+                            CONTENT_WRITE_TIMEOUT_SECS,
                             msg_buf.len()
-                        ),
-                    )));
+                        );
+                        return Err(NockAppError::IoError(std::io::Error::new(
+                            std::io::ErrorKind::TimedOut,
+                            format!(
+                                "Timeout writing message content - {} bytes stuck",
+                                msg_buf.len()
+                            ),
+                        )));
+                    }
                 }
             }
         }
@@ -384,14 +860,11 @@ mod tests {
 
     use tempfile::tempdir;
     use tokio::net::UnixStream;
-    use tokio::sync::{broadcast, mpsc};
     use tokio::time::timeout;
     use tracing_test::traced_test;
 
     use super::*;
-    use crate::metrics::NockAppMetrics;
-    use crate::nockapp::driver::{IOAction, NockAppHandle};
-    use crate::NockAppExit;
+    use crate::nockapp::driver::{test_handle, IOAction};
 
     async fn setup_socket_pair() -> (UnixStream, StdUnixStream) {
         let dir = tempdir().unwrap_or_else(|err| {
@@ -604,23 +1077,7 @@ mod tests {
         });
 
         // Create channels for driver communication
-        let (tx_io, mut rx_io) = mpsc::channel(32);
-        let (tx_effect_chan, rx_effect) = broadcast::channel(32);
-        let tx_effect = Arc::new(tx_effect_chan);
-        let metrics = Arc::new(
-            NockAppMetrics::register(gnort::global_metrics_registry())
-                .expect("Failed to register metrics!"),
-        );
-
-        let (tx_exit, _) = NockAppExit::new();
-
-        let handle = NockAppHandle {
-            io_sender: tx_io,
-            effect_sender: tx_effect.clone(),
-            effect_receiver: Mutex::new(rx_effect),
-            metrics: metrics,
-            exit: tx_exit,
-        };
+        let (handle, mut rx_io) = test_handle(32);
 
         // Spawn the listener driver
         let _driver_task = tokio::spawn(npc_listener(listener)(handle));
@@ -766,4 +1223,126 @@ mod tests {
         // Cleanup
         drop(client);
     }
+
+    #[tokio::test]
+    #[cfg_attr(miri, ignore)]
+    async fn test_npc_driver_request_ids() {
+        let dir = tempdir().expect("tempdir");
+        let socket_path = dir.path().join("test.sock");
+        let listener = UnixListener::bind(&socket_path).expect("bind");
+
+        let (handle, mut rx_io) = test_handle(32);
+        let _driver_task = tokio::spawn(npc_listener(listener)(handle));
+
+        let mut client = StdUnixStream::connect(&socket_path).expect("connect");
+        client
+            .write_all(&hello(NPC_PROTOCOL_VERSION))
+            .expect("write hello");
+        let mut reply = [0u8; 12];
+        client.read_exact(&mut reply).expect("read hello");
+        assert_eq!(reply, hello(NPC_PROTOCOL_VERSION));
+
+        // Poke under request id 7
+        let mut poke_slab = NounSlab::new();
+        let poke = T(&mut poke_slab, &[D(tas!(b"poke")), D(123), D(456)]);
+        poke_slab.set_root(poke);
+        let jam = poke_slab.jam();
+        client
+            .write_all(&((8 + jam.len()) as u64).to_le_bytes())
+            .expect("write length");
+        client.write_all(&7u64.to_le_bytes()).expect("write id");
+        client.write_all(&jam).expect("write jam");
+
+        let Some(IOAction::Poke { ack_channel, .. }) =
+            timeout(Duration::from_secs(1), rx_io.recv())
+                .await
+                .expect("poke timeout")
+        else {
+            panic!("Did not receive poke message");
+        };
+        ack_channel.send(PokeResult::Ack).expect("ack");
+
+        // The ack comes back under the same request id
+        client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .expect("read timeout");
+        let mut len = [0u8; 8];
+        client.read_exact(&mut len).expect("read length");
+        let mut payload = vec![0u8; u64::from_le_bytes(len) as usize];
+        client.read_exact(&mut payload).expect("read payload");
+        let frame = decode_frame(Protocol::V1, Bytes::from(payload))
+            .expect("decode")
+            .expect("frame");
+        assert_eq!(frame.id, 7);
+        let response = unsafe { frame.directive.root() }
+            .as_cell()
+            .expect("response cell");
+        assert!(unsafe { response.head().raw_equals(&D(tas!(b"pack"))) });
+        assert!(unsafe { response.tail().raw_equals(&D(0)) });
+    }
+
+    #[tokio::test]
+    #[cfg_attr(miri, ignore)]
+    async fn test_connect_handshake_legacy_fallback() {
+        let dir = tempdir().expect("tempdir");
+        let socket_path = dir.path().join("test.sock");
+        let listener = UnixListener::bind(&socket_path).expect("bind");
+        let mut stream = UnixStream::connect(&socket_path).await.expect("connect");
+
+        // A legacy acceptor reads the hello as a length prefix and hangs up on it
+        let acceptor = tokio::spawn(async move {
+            let (mut first, _) = listener.accept().await.expect("accept");
+            let mut len = [0u8; 8];
+            first.read_exact(&mut len).await.expect("read length");
+            assert_eq!(u64::from_le_bytes(len), NPC_PROTOCOL_MAGIC);
+            drop(first);
+            let (mut second, _) = listener.accept().await.expect("accept again");
+            let mut len = [0u8; 8];
+            second
+                .read_exact(&mut len)
+                .await
+                .expect("read legacy length");
+            u64::from_le_bytes(len)
+        });
+
+        let protocol = connect_handshake(&mut stream).await.expect("handshake");
+        assert_eq!(protocol, Protocol::Legacy);
+        let (_, mut stream_write) = split(stream);
+        let mut slab = NounSlab::new();
+        let message = T(&mut slab, &[D(1), D(tas!(b"peek")), D(0)]);
+        slab.set_root(message);
+        let len = slab.jam().len() as u64;
+        write_message(&mut stream_write, slab)
+            .await
+            .expect("write legacy message");
+        assert_eq!(acceptor.await.expect("acceptor"), len);
+    }
+
+    #[test]
+    fn test_routed_pids_above_kernel_pids() {
+        let router = NpcRouter::default();
+        let connection = router.connect();
+        let pid = router.route(connection, 7);
+        assert!(pid >= ROUTED_PID_BASE);
+        assert_eq!(router.owner(pid), Some((connection, 7)));
+        assert_eq!(router.owner(7), None);
+    }
+
+    #[test]
+    fn test_kernel_requests_claimed_once() {
+        let router = NpcRouter::default();
+        let first = router.connect();
+        let second = router.connect();
+        assert!(router.claim(3, first));
+        assert!(!router.claim(3, second));
+        assert!(router.claim(3, first));
+
+        // Once answered the pid can be used again
+        router.release(3);
+        assert!(router.claim(3, second));
+
+        // A closed connection's requests are up for grabs
+        router.disconnect(second);
+        assert!(router.claim(3, first));
+    }
 }
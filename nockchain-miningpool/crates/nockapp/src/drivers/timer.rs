@@ -1,18 +1,38 @@
+use std::collections::HashMap;
 use std::time::Duration;
 
+use nockvm::noun::{D, T};
+use nockvm_macros::tas;
 use tokio::time;
+use tracing::{debug, warn};
 
+use crate::kernel::timers::{da_to_atom, timer_request, ScheduledTimer, TimerRequest, TimerTable};
 use crate::nockapp::driver::*;
-use crate::nockapp::wire::Wire;
+use crate::nockapp::wire::{Wire, WireRepr};
 use crate::noun::slab::NounSlab;
+use crate::utils::{current_da, current_epoch_ms, da_to_unix_ms, unix_ms_to_da};
+
+/// Delay before a `%fire` poke which failed is retried, doubled on each further failure
+const RETRY_BACKOFF_MIN: Duration = Duration::from_millis(250);
+const RETRY_BACKOFF_MAX: Duration = Duration::from_secs(60);
 
 pub enum TimerWire {
     Tick,
+    Fire,
 }
 
 impl Wire for TimerWire {
     const VERSION: u64 = 1;
     const SOURCE: &'static str = "timer";
+
+    fn to_wire(&self) -> WireRepr {
+        match self {
+            TimerWire::Tick => WireRepr::no_tags(TimerWire::SOURCE, TimerWire::VERSION),
+            TimerWire::Fire => {
+                WireRepr::new(TimerWire::SOURCE, TimerWire::VERSION, vec!["fire".into()])
+            }
+        }
+    }
 }
 
 pub fn make_timer_driver(interval_secs: u64, timer_slab: NounSlab) -> IODriverFn {
@@ -29,3 +49,224 @@ pub fn make_timer_driver(interval_secs: u64, timer_slab: NounSlab) -> IODriverFn
         }
     })
 }
+
+/// Timer driver for timers the kernel schedules itself, see [crate::kernel::timers]
+///
+/// ## Effects
+/// `[%timer %set id=@ when=@da]` schedules or reschedules timer `id`
+///
+/// `[%timer %cancel id=@]` cancels it
+///
+/// When a timer is due it results in poke
+/// `[%timer %fire id=@ now=@da]`
+///
+/// Timers survive restarts: the driver starts from the timers in the checkpoint, and fires
+/// those which fell due while the app was down straight away. A `%fire` poke which is nacked
+/// or fails is retried with backoff until it is acked, or the kernel resets or cancels the timer.
+pub fn kernel_timers() -> IODriverFn {
+    make_driver(|handle| async move {
+        // Subscribe before fetching, so no effect falls between the two
+        let mut effects = handle.subscribe_effects(&["timer"]);
+        let mut table = TimerTable::default();
+        // Failed `%fire` pokes per timer, for backoff
+        let mut failures: HashMap<Vec<u8>, u32> = HashMap::new();
+        for timer in handle.scheduled_timers().await? {
+            table.set(timer.id, timer.when);
+        }
+        debug!("timer driver: {} timers scheduled at start", table.len());
+
+        loop {
+            let next = table.timers().into_iter().next();
+            let due = async {
+                match &next {
+                    Some(timer) => time::sleep(until(timer)).await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                effect = effects.recv() => {
+                    let Some(effect) = effect else {
+                        return Ok(());
+                    };
+                    let effect = unsafe { *effect.root() };
+                    match timer_request(effect) {
+                        Some(TimerRequest::Set { id, .. }) | Some(TimerRequest::Cancel { id }) => {
+                            failures.remove(&id);
+                        }
+                        Some(TimerRequest::Fire { .. }) | None => {}
+                    }
+                    table.apply_effect(effect);
+                }
+                _ = due => {
+                    let Some(timer) = &next else {
+                        continue;
+                    };
+                    // Effects of the poke are only applied once it returns, so the timer is
+                    // still the one which fell due here
+                    match handle.poke(TimerWire::Fire.to_wire(), fire_poke(timer)).await {
+                        Ok(PokeResult::Ack) => {
+                            table.cancel(&timer.id);
+                            failures.remove(&timer.id);
+                            continue;
+                        }
+                        Ok(PokeResult::Nack) => warn!("timer driver: kernel nacked a %fire poke"),
+                        Err(e) => warn!("timer driver: %fire poke failed: {}", e),
+                    }
+                    let failed = failures.entry(timer.id.clone()).or_default();
+                    let backoff = retry_backoff(*failed);
+                    *failed = failed.saturating_add(1);
+                    debug!("timer driver: retrying %fire in {:?}", backoff);
+                    let retry_ms = current_epoch_ms() + backoff.as_millis();
+                    table.set(timer.id.clone(), unix_ms_to_da(retry_ms));
+                }
+            }
+        }
+    })
+}
+
+/// How long until `timer` is due, zero if it already is
+fn until(timer: &ScheduledTimer) -> Duration {
+    if timer.when.0 <= current_da().0 {
+        return Duration::ZERO;
+    }
+    let due_ms = da_to_unix_ms(timer.when).saturating_sub(current_epoch_ms());
+    Duration::from_millis(due_ms.try_into().unwrap_or(u64::MAX))
+}
+
+/// Delay before retrying a `%fire` poke which has failed `failures` times before
+fn retry_backoff(failures: u32) -> Duration {
+    RETRY_BACKOFF_MIN
+        .saturating_mul(1 << failures.min(16))
+        .min(RETRY_BACKOFF_MAX)
+}
+
+/// `[%timer %fire id now]`
+fn fire_poke(timer: &ScheduledTimer) -> NounSlab {
+    let mut slab = NounSlab::new();
+    let id = timer.id_atom(&mut slab).as_noun();
+    let now = da_to_atom(&mut slab, current_da()).as_noun();
+    let poke = T(&mut slab, &[D(tas!(b"timer")), D(tas!(b"fire")), id, now]);
+    slab.set_root(poke);
+    slab
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::{mpsc, oneshot};
+
+    use super::*;
+    use crate::nockapp::driver::test_handle;
+    use crate::utils::DA;
+
+    /// The id of the next `%fire` poke, and the channel to answer it on
+    async fn next_fire(
+        rx_io: &mut mpsc::Receiver<IOAction>,
+    ) -> (Vec<u8>, oneshot::Sender<PokeResult>) {
+        let Some(IOAction::Poke {
+            wire,
+            poke,
+            ack_channel,
+            ..
+        }) = rx_io.recv().await
+        else {
+            panic!("expected a poke");
+        };
+        assert_eq!(wire, TimerWire::Fire.to_wire());
+        let Some(TimerRequest::Fire { id, .. }) = timer_request(unsafe { *poke.root() }) else {
+            panic!("expected a %fire poke");
+        };
+        (id, ack_channel)
+    }
+
+    #[tokio::test]
+    #[cfg_attr(miri, ignore)]
+    async fn test_kernel_timers_fire_when_due() {
+        let (handle, mut rx_io) = test_handle(8);
+        let effect_sender = handle.effect_sender.clone();
+        let driver = tokio::spawn(kernel_timers()(handle));
+
+        // A timer from the checkpoint which fell due while the app was down
+        let Some(IOAction::Timers { result_channel }) = rx_io.recv().await else {
+            panic!("expected a timers request");
+        };
+        let overdue = ScheduledTimer {
+            id: b"expiry".to_vec(),
+            when: DA(current_da().0 - 1),
+        };
+        let _ = result_channel.send(Ok(vec![overdue]));
+        let (id, ack_channel) = next_fire(&mut rx_io).await;
+        assert_eq!(id, b"expiry");
+        let _ = ack_channel.send(PokeResult::Ack);
+
+        // A timer set by an effect, then one set and cancelled before it is due
+        let mut set = NounSlab::new();
+        let when = da_to_atom(&mut set, current_da()).as_noun();
+        let effect = T(&mut set, &[D(tas!(b"timer")), D(tas!(b"set")), D(7), when]);
+        set.set_root(effect);
+        effect_sender.send(set).expect("send");
+        let mut cancelled = NounSlab::new();
+        let later = DA(current_da().0 + (3600 << 64));
+        let later = da_to_atom(&mut cancelled, later).as_noun();
+        let effect = T(
+            &mut cancelled,
+            &[D(tas!(b"timer")), D(tas!(b"set")), D(8), later],
+        );
+        cancelled.set_root(effect);
+        effect_sender.send(cancelled).expect("send");
+        let mut cancel = NounSlab::new();
+        let effect = T(&mut cancel, &[D(tas!(b"timer")), D(tas!(b"cancel")), D(8)]);
+        cancel.set_root(effect);
+        effect_sender.send(cancel).expect("send");
+
+        let (id, ack_channel) = next_fire(&mut rx_io).await;
+        assert_eq!(id, vec![7]);
+        let _ = ack_channel.send(PokeResult::Ack);
+        assert!(
+            time::timeout(Duration::from_millis(50), rx_io.recv())
+                .await
+                .is_err(),
+            "the cancelled timer shouldn't fire"
+        );
+        driver.abort();
+    }
+    #[tokio::test]
+    #[cfg_attr(miri, ignore)]
+    async fn test_kernel_timers_retry_nacked_fire() {
+        let (handle, mut rx_io) = test_handle(8);
+        let driver = tokio::spawn(kernel_timers()(handle));
+
+        let Some(IOAction::Timers { result_channel }) = rx_io.recv().await else {
+            panic!("expected a timers request");
+        };
+        let due = ScheduledTimer {
+            id: b"retry".to_vec(),
+            when: DA(current_da().0 - 1),
+        };
+        let _ = result_channel.send(Ok(vec![due]));
+        let (id, ack_channel) = next_fire(&mut rx_io).await;
+        assert_eq!(id, b"retry");
+        let _ = ack_channel.send(PokeResult::Nack);
+
+        // The timer is kept, and fired again after a backoff
+        assert!(
+            time::timeout(RETRY_BACKOFF_MIN / 2, rx_io.recv())
+                .await
+                .is_err(),
+            "a nacked %fire shouldn't be retried straight away"
+        );
+        let (id, ack_channel) = time::timeout(RETRY_BACKOFF_MIN * 4, next_fire(&mut rx_io))
+            .await
+            .expect("the nacked %fire should be retried");
+        assert_eq!(id, b"retry");
+        let _ = ack_channel.send(PokeResult::Ack);
+
+        // Once acked it is gone
+        assert!(
+            time::timeout(RETRY_BACKOFF_MIN * 2, rx_io.recv())
+                .await
+                .is_err(),
+            "an acked timer shouldn't fire again"
+        );
+        driver.abort();
+    }
+}
@@ -1,10 +1,9 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use chrono;
 use clap::{arg, command, ColorChoice, Parser, ValueEnum};
 use nockvm::jets::hot::HotEntry;
 use nockvm::noun::Atom;
-use tokio::fs;
 use tracing::{debug, info, Level};
 use tracing_subscriber::fmt::format::Writer;
 use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields};
@@ -14,13 +13,21 @@ use tracing_subscriber::util::SubscriberInitExt;
 use tracing_subscriber::{fmt, EnvFilter};
 
 use crate::export::ExportedState;
+use crate::history::{self, HistoryRetention};
 use crate::kernel::form::Kernel;
 use crate::noun::slab::{Jammer, NounSlab};
-use crate::save::SaveableCheckpoint;
+use crate::recorder::{
+    self, Recorder, RecorderConfig, ReplayReport, DEFAULT_RECORDING_FILES,
+    DEFAULT_RECORDING_FILE_BYTES,
+};
+use crate::save::{self, Compression, SaveableCheckpoint, Saver};
 use crate::utils::error::{CrownError, ExternalError};
+use crate::utils::NOCK_STACK_1KB;
 use crate::{default_data_dir, AtomExt, NockApp};
 
 const DEFAULT_SAVE_INTERVAL: u64 = 120000;
+const STACK_SAMPLES_FILE: &str = "nock-samples.folded";
+const JET_PROFILE_FILE: &str = "jet-profile.json";
 const DEFAULT_LOG_FILTER: &str = "info,slogger=trace";
 
 #[derive(Debug, Clone, ValueEnum)]
@@ -43,9 +50,22 @@ pub struct Cli {
     )]
     pub new: bool,
 
-    #[arg(long, help = "Make an Sword trace", default_value = "false")]
+    #[arg(long, help = "Make an Sword trace and a jet profile", default_value = "false")]
     pub trace: bool,
 
+    #[arg(
+        long,
+        help = "Sample the Nock stack every this many ms, writing folded stacks for flamegraphs to nock-samples.folded"
+    )]
+    pub sample_interval: Option<u64>,
+
+    #[arg(
+        long,
+        help = "Profile jet calls and unjetted hot arms, writing the report to jet-profile.json",
+        default_value = "false"
+    )]
+    pub jet_profile: bool,
+
     #[arg(
         long,
         default_value_t = DEFAULT_SAVE_INTERVAL,
@@ -75,6 +95,80 @@ pub struct Cli {
         default_value_t = NockStackSize::Normal
     )]
     pub stack_size: NockStackSize,
+
+    #[arg(
+        long,
+        help = "Cap the Nock stack at this many GiB. The stack only takes memory as it is used, so --stack-size can be generous; past the cap a computation fails with %meme instead."
+    )]
+    pub stack_limit: Option<usize>,
+
+    #[arg(
+        long,
+        help = "Compression for new checkpoints and exported state. Defaults to what the loaded checkpoint uses, or none.",
+        value_enum
+    )]
+    pub compression: Option<Compression>,
+
+    #[arg(
+        long,
+        help = "Also keep checkpoints for rollback: last:N keeps every save, hourly:N and daily:N keep the first save of each hour or day. Only the newest N are kept."
+    )]
+    pub checkpoint_history: Option<HistoryRetention>,
+
+    #[arg(
+        long,
+        help = "Wait for each event journal write to reach the disk before acking the poke, so acked events also survive power loss",
+        default_value = "false"
+    )]
+    pub journal_sync: bool,
+
+    #[arg(
+        long,
+        help = "Let drivers swap in a new kernel with an upgrade request, such as an npc %upgrade",
+        default_value = "false"
+    )]
+    pub allow_kernel_upgrade: bool,
+
+    #[arg(
+        long,
+        help = "List the checkpoints in the checkpoint history and exit",
+        default_value = "false"
+    )]
+    pub list_checkpoints: bool,
+
+    #[arg(
+        long,
+        help = "Boot from the checkpoint history entry at this event number, moving the current checkpoints and event journal into restore-backup. Only done once per entry, so restarts with the same flag boot normally",
+        conflicts_with = "new"
+    )]
+    pub restore_checkpoint: Option<u64>,
+
+    #[arg(
+        long,
+        help = "Record every poke and peek, with its effects or result, to rotating files in this directory"
+    )]
+    pub record: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Start a new recording file once the current one reaches this many MiB",
+        default_value_t = DEFAULT_RECORDING_FILE_BYTES / (1024 * 1024)
+    )]
+    pub record_file_mb: u64,
+
+    #[arg(
+        long,
+        help = "Keep at most this many recording files, deleting the oldest",
+        default_value_t = DEFAULT_RECORDING_FILES
+    )]
+    pub record_files: usize,
+
+    #[arg(
+        long,
+        help = "Re-apply the recording in this directory on top of the loaded checkpoint, report where the effects differ and exit",
+        conflicts_with_all = ["new", "record"]
+    )]
+    pub replay: Option<PathBuf>,
 }
 
 /// Result of setting up a NockApp
@@ -83,6 +177,10 @@ pub enum SetupResult<J> {
     App(NockApp<J>),
     /// State was exported successfully
     ExportedState,
+    /// The checkpoint history was listed
+    ListedCheckpoints,
+    /// A recording was replayed
+    Replayed(ReplayReport),
 }
 
 pub fn default_boot_cli(new: bool) -> Cli {
@@ -90,10 +188,23 @@ pub fn default_boot_cli(new: bool) -> Cli {
         save_interval: DEFAULT_SAVE_INTERVAL,
         new,
         trace: false,
+        sample_interval: None,
+        jet_profile: false,
         color: ColorChoice::Auto,
         state_jam: None,
         export_state_jam: None,
         stack_size: NockStackSize::Normal,
+        stack_limit: None,
+        compression: None,
+        checkpoint_history: None,
+        journal_sync: false,
+        allow_kernel_upgrade: false,
+        list_checkpoints: false,
+        restore_checkpoint: None,
+        record: None,
+        record_file_mb: DEFAULT_RECORDING_FILE_BYTES / (1024 * 1024),
+        record_files: DEFAULT_RECORDING_FILES,
+        replay: None,
     }
 }
 
@@ -246,6 +357,10 @@ pub async fn setup<J: Jammer + Send + 'static>(
             info!("Exiting after successful state export");
             std::process::exit(0);
         }
+        SetupResult::ListedCheckpoints => std::process::exit(0),
+        SetupResult::Replayed(report) => {
+            std::process::exit(if report.mismatches.is_empty() { 0 } else { 1 })
+        }
     }
 }
 
@@ -281,11 +396,29 @@ pub async fn setup_<J: Jammer + Send + 'static>(
         debug!("Deleted existing checkpoint directory: {:?}", jams_dir);
     }
 
+    if cli.list_checkpoints {
+        list_checkpoints(&jams_dir)?;
+        return Ok(SetupResult::ListedCheckpoints);
+    }
+
+    if let Some(event_num) = cli.restore_checkpoint {
+        history::restore(&jams_dir, event_num, &blake3::hash(jam))?;
+    }
+
     info!("kernel: starting");
     debug!("kernel: pma directory: {:?}", pma_dir);
     debug!("kernel: snapshots directory: {:?}", jams_dir);
 
-    let kernel_f = async |checkpoint| {
+    let kernel_f = async |checkpoint: Option<SaveableCheckpoint>| {
+        // A checkpoint saved after a kernel upgrade is booted into the upgraded kernel
+        let upgraded = match &checkpoint {
+            Some(checkpoint) => save::upgraded_kernel(&jams_dir, &checkpoint.ker_hash)?,
+            None => None,
+        };
+        if upgraded.is_some() {
+            info!("kernel: booting the kernel persisted by the last upgrade");
+        }
+        let jam = upgraded.as_deref().unwrap_or(jam);
         let kernel: Kernel<SaveableCheckpoint> = match cli.stack_size {
             NockStackSize::Tiny => {
                 Kernel::load_with_hot_state_tiny(jam, checkpoint, hot_state, test_jets, cli.trace)
@@ -316,12 +449,50 @@ pub async fn setup_<J: Jammer + Send + 'static>(
         res
     };
 
+    if let Some(replay_dir) = cli.replay.clone() {
+        // Boot from the checkpoint alone. NockApp::new would also replay the event journal,
+        // which holds the very events the recording is about to re-apply.
+        let (_saver, checkpoint) = Saver::<J>::try_load(&jams_dir, None).await?;
+        let kernel = kernel_f(checkpoint).await?;
+        let records = recorder::read_recording(&replay_dir)?;
+        let report = recorder::replay(&kernel, records).await?;
+        print_replay_report(&report);
+        return Ok(SetupResult::Replayed(report));
+    }
+
     let save_interval = std::time::Duration::from_millis(cli.save_interval);
 
-    let app: NockApp<J> = NockApp::new(kernel_f, &jams_dir, save_interval).await?;
+    let mut app: NockApp<J> = NockApp::new(kernel_f, &jams_dir, save_interval).await?;
+    if let Some(compression) = cli.compression {
+        app.set_checkpoint_compression(compression).await;
+    }
+    if let Some(retention) = cli.checkpoint_history {
+        app.set_checkpoint_history(retention).await;
+    }
+    if cli.journal_sync {
+        app.set_journal_sync(true);
+    }
+    if cli.allow_kernel_upgrade {
+        app.set_allow_kernel_upgrade(true);
+    }
+    if let Some(limit) = cli.stack_limit {
+        let words = limit
+            .checked_mul(NOCK_STACK_1KB << 20)
+            .ok_or_else(|| format!("Stack limit of {limit} GiB is too large"))?;
+        app.set_stack_limit(words).await?;
+    }
+    if let Some(interval) = cli.sample_interval {
+        let interval = std::time::Duration::from_millis(interval);
+        app.sample_stacks(PathBuf::from(STACK_SAMPLES_FILE), interval)
+            .await?;
+    }
+    if cli.trace || cli.jet_profile {
+        app.profile_jets(PathBuf::from(JET_PROFILE_FILE)).await?;
+    }
 
     if let Some(export_path) = cli.export_state_jam.clone() {
-        export_kernel_state(&app.kernel, &export_path).await?;
+        let compression = cli.compression.unwrap_or_default();
+        export_kernel_state(&app.kernel, &export_path, compression).await?;
         return Ok(SetupResult::ExportedState);
     }
 
@@ -329,18 +500,70 @@ pub async fn setup_<J: Jammer + Send + 'static>(
         import_kernel_state(&app.kernel, &import_path).await?;
     }
 
+    if let Some(record_dir) = cli.record.clone() {
+        let recorder = Recorder::open(RecorderConfig {
+            dir: record_dir,
+            max_file_bytes: cli.record_file_mb.saturating_mul(1024 * 1024),
+            max_files: cli.record_files,
+        })?;
+        app.set_recorder(recorder.spawn()?).await?;
+    }
+
     Ok(SetupResult::App(app))
 }
 
+/// Prints the checkpoints in the checkpoint history, oldest first
+fn list_checkpoints(jams_dir: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let entries = history::list(jams_dir)?;
+    if entries.is_empty() {
+        println!("No checkpoints in the checkpoint history");
+        return Ok(());
+    }
+    println!("{:>12}  {:<20}  {}", "EVENT", "SAVED (UTC)", "KERNEL HASH");
+    for entry in entries {
+        let saved = chrono::DateTime::from_timestamp(entry.timestamp as i64, 0)
+            .map(|time| time.format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_else(|| entry.timestamp.to_string());
+        println!("{:>12}  {:<20}  {}", entry.event_num, saved, entry.ker_hash);
+    }
+    Ok(())
+}
+
+/// Prints the result of replaying a recording
+fn print_replay_report(report: &ReplayReport) {
+    println!(
+        "Replayed {} pokes and {} peeks ({} earlier records skipped)",
+        report.pokes, report.peeks, report.skipped
+    );
+    println!(
+        "Kernel time: {:.3}s recorded, {:.3}s replayed",
+        report.recorded_time.as_secs_f64(),
+        report.replay_time.as_secs_f64()
+    );
+    if report.mismatches.is_empty() {
+        println!("All effects and peek results match the recording");
+        return;
+    }
+    println!(
+        "{} records differ from the recording:",
+        report.mismatches.len()
+    );
+    for mismatch in &report.mismatches {
+        println!("  record {}: {}", mismatch.index, mismatch.description);
+    }
+}
+
 /// Exports the kernel state to a jam file at the specified path
 async fn export_kernel_state<C>(
     kernel: &Kernel<C>,
     export_path: &str,
+    compression: Compression,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let kernel_state = kernel.export().await?;
     let exported_state = ExportedState::from_loadstate(kernel_state);
-    let state_bytes = exported_state.encode()?;
-    fs::write(export_path, state_bytes).await?;
+    // Writing (and compressing) the state blocks, so keep it off the async runtime
+    let path = PathBuf::from(export_path);
+    tokio::task::spawn_blocking(move || exported_state.save_to_file(&path, compression)).await??;
     info!("Successfully exported kernel state to: {:?}", export_path);
     Ok(())
 }
@@ -350,8 +573,9 @@ async fn import_kernel_state<C>(
     kernel: &Kernel<C>,
     import_path: &str,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let state_bytes = fs::read(import_path).await?;
-    let exported_state = ExportedState::decode(&state_bytes)?;
+    let path = PathBuf::from(import_path);
+    let exported_state =
+        tokio::task::spawn_blocking(move || ExportedState::load_from_file(&path)).await??;
     let kernel_state = exported_state.to_loadstate()?;
     kernel.import(kernel_state).await?;
     info!("Successfully imported kernel state from: {:?}", import_path);
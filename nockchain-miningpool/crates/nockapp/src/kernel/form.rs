@@ -6,6 +6,7 @@ use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::Instant;
 
+use bincode::{Decode, Encode};
 use blake3::{Hash, Hasher};
 use byteorder::{LittleEndian, WriteBytesExt};
 use nockvm::hamt::Hamt;
@@ -16,20 +17,25 @@ use nockvm::jets::nock::util::mook;
 use nockvm::mem::NockStack;
 use nockvm::mug::met3_usize;
 use nockvm::noun::{Atom, Cell, DirectAtom, IndirectAtom, Noun, Slots, D, T};
-use nockvm::trace::{path_to_cord, write_serf_trace_safe, TraceInfo};
+use nockvm::profile::{write_jet_profile_safe, JetProfile};
+use nockvm::trace::{
+    path_to_cord, write_samples_safe, write_serf_trace_safe, StackSampler, TraceInfo,
+};
 use nockvm_macros::tas;
 use tokio::sync::{mpsc, oneshot};
 use tokio::time::Duration;
-use tracing::{debug, warn};
+use tracing::{debug, info, warn};
 
+use crate::kernel::timers::{ScheduledTimer, TimerTable};
 use crate::metrics::NockAppMetrics;
+use crate::nockapp::recorder::{Record, RecorderHandle};
 use crate::nockapp::wire::{wire_to_noun, WireRepr};
 use crate::noun::slab::NounSlab;
 use crate::noun::slam;
 use crate::save::SaveableCheckpoint;
 use crate::utils::{
-    create_context, current_da, NOCK_STACK_SIZE, NOCK_STACK_SIZE_HUGE, NOCK_STACK_SIZE_LARGE,
-    NOCK_STACK_SIZE_MEDIUM, NOCK_STACK_SIZE_SMALL, NOCK_STACK_SIZE_TINY,
+    create_context, current_da, NOCK_STACK_1KB, NOCK_STACK_SIZE, NOCK_STACK_SIZE_HUGE,
+    NOCK_STACK_SIZE_LARGE, NOCK_STACK_SIZE_MEDIUM, NOCK_STACK_SIZE_SMALL, NOCK_STACK_SIZE_TINY,
 };
 use crate::{AtomExt, CrownError, NounExt, Result, ToBytesExt};
 
@@ -47,6 +53,70 @@ pub struct LoadState {
     pub kernel_state: NounSlab,
 }
 
+/// A kernel swapped in by [Kernel::upgrade]
+#[derive(Debug, Clone)]
+pub struct KernelUpgrade {
+    pub old_ker_hash: Hash,
+    pub new_ker_hash: Hash,
+    pub event_num: u64,
+}
+
+/// An upgrade the serf is running but hasn't committed to. The serf takes no other action
+/// until [PendingUpgrade::commit] or [PendingUpgrade::roll_back] is called, and dropping it
+/// rolls back, so nothing runs on a kernel which was never persisted.
+pub struct PendingUpgrade<C> {
+    pub upgrade: KernelUpgrade,
+    /// The upgraded kernel and its state, saved under the new kernel hash
+    pub checkpoint: C,
+    decision: oneshot::Sender<bool>,
+}
+
+impl<C> PendingUpgrade<C> {
+    /// Take the checkpoint, leaving what is needed to decide the upgrade
+    pub fn take_checkpoint(self) -> (C, PendingUpgrade<()>) {
+        let pending = PendingUpgrade {
+            upgrade: self.upgrade,
+            checkpoint: (),
+            decision: self.decision,
+        };
+        (self.checkpoint, pending)
+    }
+
+    /// Keep the new kernel
+    pub fn commit(self) {
+        let _ = self.decision.send(true);
+    }
+
+    /// Put the old kernel and its state back
+    pub fn roll_back(self) {
+        let _ = self.decision.send(false);
+    }
+}
+
+/// What [Serf::roll_back_upgrade] needs to put the old kernel back
+struct RollBack {
+    arvo: Noun,
+    ker_hash: Hash,
+}
+
+/// The entropy and time a poke was run with. The event journal and the recorder keep
+/// these so a replayed event sees exactly what the original did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode)]
+pub struct PokeInputs {
+    pub eny: u64,
+    pub now: u128,
+}
+
+impl PokeInputs {
+    /// Fresh entropy and the current time
+    pub fn fresh() -> Self {
+        PokeInputs {
+            eny: rand::random::<u64>(),
+            now: current_da().0,
+        }
+    }
+}
+
 // Actions to request of the serf thread
 pub enum SerfAction<C> {
     // Make a CheckPoint
@@ -60,6 +130,11 @@ pub enum SerfAction<C> {
     Export {
         result: oneshot::Sender<Result<LoadState>>,
     },
+    // Boot a new kernel and load the current state into it
+    Upgrade {
+        kernel: Vec<u8>,
+        result: oneshot::Sender<Result<PendingUpgrade<C>>>,
+    },
     // Get the state noun of the kernel as a slab
     GetKernelStateSlab {
         result: oneshot::Sender<Result<NounSlab>>,
@@ -68,6 +143,30 @@ pub enum SerfAction<C> {
     GetColdStateSlab {
         result: oneshot::Sender<NounSlab>,
     },
+    // Get the timers the kernel has scheduled
+    GetTimers {
+        result: oneshot::Sender<Vec<ScheduledTimer>>,
+    },
+    // Start, replace or stop sampling the Nock stack
+    SampleStacks {
+        sampler: Option<StackSampler>,
+        result: oneshot::Sender<()>,
+    },
+    // Start, replace or stop profiling jets
+    ProfileJets {
+        profile: Option<JetProfile>,
+        result: oneshot::Sender<()>,
+    },
+    // Start, replace or stop recording pokes and peeks
+    SetRecorder {
+        recorder: Option<RecorderHandle>,
+        result: oneshot::Sender<()>,
+    },
+    // Cap the words in use on the Nock stack
+    SetStackLimit {
+        limit: usize,
+        result: oneshot::Sender<Result<()>>,
+    },
     // Run a peek
     Peek {
         ovo: NounSlab,
@@ -79,7 +178,9 @@ pub enum SerfAction<C> {
     Poke {
         wire: WireRepr,
         cause: NounSlab,
-        result: oneshot::Sender<Result<NounSlab>>,
+        // Fresh inputs are drawn when the poke runs if this is None
+        inputs: Option<PokeInputs>,
+        result: oneshot::Sender<Result<(PokeInputs, NounSlab)>>,
         result_ack: oneshot::Receiver<()>,
     },
     // Provide metrics
@@ -212,6 +313,68 @@ impl<C> SerfThread<C> {
         }
     }
 
+    pub(crate) fn timers(&self) -> impl Future<Output = Result<Vec<ScheduledTimer>>> {
+        let (result, result_fut) = oneshot::channel();
+        let action_sender = self.action_sender.clone();
+        async move {
+            action_sender.send(SerfAction::GetTimers { result }).await?;
+            Ok(result_fut.await?)
+        }
+    }
+
+    pub(crate) fn sample_stacks(
+        &self,
+        sampler: Option<StackSampler>,
+    ) -> impl Future<Output = Result<()>> {
+        let (result, result_fut) = oneshot::channel();
+        let action_sender = self.action_sender.clone();
+        async move {
+            action_sender
+                .send(SerfAction::SampleStacks { sampler, result })
+                .await?;
+            Ok(result_fut.await?)
+        }
+    }
+
+    pub(crate) fn set_recorder(
+        &self,
+        recorder: Option<RecorderHandle>,
+    ) -> impl Future<Output = Result<()>> {
+        let (result, result_fut) = oneshot::channel();
+        let action_sender = self.action_sender.clone();
+        async move {
+            action_sender
+                .send(SerfAction::SetRecorder { recorder, result })
+                .await?;
+            Ok(result_fut.await?)
+        }
+    }
+
+    pub(crate) fn profile_jets(
+        &self,
+        profile: Option<JetProfile>,
+    ) -> impl Future<Output = Result<()>> {
+        let (result, result_fut) = oneshot::channel();
+        let action_sender = self.action_sender.clone();
+        async move {
+            action_sender
+                .send(SerfAction::ProfileJets { profile, result })
+                .await?;
+            Ok(result_fut.await?)
+        }
+    }
+
+    pub(crate) fn set_stack_limit(&self, limit: usize) -> impl Future<Output = Result<()>> {
+        let (result, result_fut) = oneshot::channel();
+        let action_sender = self.action_sender.clone();
+        async move {
+            action_sender
+                .send(SerfAction::SetStackLimit { limit, result })
+                .await?;
+            result_fut.await?
+        }
+    }
+
     // We are very carefully ensuring that the future does not contain the &self reference, to allow spawning a task without lifetime issues
     pub fn poke(&self, wire: WireRepr, cause: NounSlab) -> impl Future<Output = Result<NounSlab>> {
         let (result, result_fut) = oneshot::channel();
@@ -222,13 +385,58 @@ impl<C> SerfThread<C> {
                 .send(SerfAction::Poke {
                     wire,
                     cause,
+                    inputs: None,
+                    result,
+                    result_ack,
+                })
+                .await?;
+            let res = result_fut.await?;
+            let _ = result_ack_sender.send(());
+            res.map(|(_, effects)| effects)
+        }
+    }
+
+    /// Poke, also returning the event number the poke was accepted as and the inputs it
+    /// ran with. Fresh inputs are drawn unless `inputs` replays earlier ones.
+    ///
+    /// The serf waits for the result ack before taking the next action, so the event
+    /// number read before acking is this poke's.
+    pub fn poke_event(
+        &self,
+        wire: WireRepr,
+        cause: NounSlab,
+        inputs: Option<PokeInputs>,
+        timeout: Option<Duration>,
+    ) -> impl Future<Output = Result<(u64, PokeInputs, NounSlab)>> {
+        let (result, result_fut) = oneshot::channel();
+        let (result_ack_sender, result_ack) = oneshot::channel();
+        let action_sender = self.action_sender.clone();
+        let event_number = self.event_number.clone();
+        let cancel_task = timeout.map(|timeout| {
+            let cancel = self.cancel_token.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(timeout).await;
+                cancel.cancel();
+            })
+        });
+        async move {
+            action_sender
+                .send(SerfAction::Poke {
+                    wire,
+                    cause,
+                    inputs,
                     result,
                     result_ack,
                 })
                 .await?;
             let res = result_fut.await?;
+            if let Some(cancel_task) = cancel_task {
+                cancel_task.abort();
+                let _ = cancel_task.await;
+            }
+            let event_num = event_number.load(Ordering::SeqCst);
             let _ = result_ack_sender.send(());
-            res
+            res.map(|(inputs, effects)| (event_num, inputs, effects))
         }
     }
 
@@ -252,6 +460,7 @@ impl<C> SerfThread<C> {
                 .send(SerfAction::Poke {
                     wire,
                     cause,
+                    inputs: None,
                     result,
                     result_ack,
                 })
@@ -260,16 +469,21 @@ impl<C> SerfThread<C> {
             cancel_task.abort();
             let _ = cancel_task.await;
             let _ = result_ack_sender.send(());
-            res
+            res.map(|(_, effects)| effects)
         }
     }
 
-    pub(crate) fn poke_sync(&self, wire: WireRepr, cause: NounSlab) -> Result<NounSlab> {
+    pub(crate) fn poke_sync(
+        &self,
+        wire: WireRepr,
+        cause: NounSlab,
+    ) -> Result<(PokeInputs, NounSlab)> {
         let (result, result_fut) = oneshot::channel();
         let (result_ack_sender, result_ack) = oneshot::channel();
         self.action_sender.blocking_send(SerfAction::Poke {
             wire,
             cause,
+            inputs: None,
             result,
             result_ack,
         })?;
@@ -315,6 +529,17 @@ impl<C> SerfThread<C> {
             Ok(result_fut.await??)
         }
     }
+
+    pub fn upgrade(&self, kernel: Vec<u8>) -> impl Future<Output = Result<PendingUpgrade<C>>> {
+        let (result, result_fut) = oneshot::channel();
+        let action_sender = self.action_sender.clone();
+        async move {
+            action_sender
+                .send(SerfAction::Upgrade { kernel, result })
+                .await?;
+            Ok(result_fut.await??)
+        }
+    }
 }
 
 fn serf_loop<C: SerfCheckpoint>(
@@ -322,6 +547,9 @@ fn serf_loop<C: SerfCheckpoint>(
     mut action_receiver: mpsc::Receiver<SerfAction<C>>,
     inhibit: Arc<AtomicBool>,
 ) {
+    // Recorded here rather than by the tasks awaiting the results, so records are written
+    // in the order the serf ran them
+    let mut recorder: Option<RecorderHandle> = None;
     loop {
         let start = std::time::Instant::now();
         let Some(action) = action_receiver.blocking_recv() else {
@@ -336,6 +564,16 @@ fn serf_loop<C: SerfCheckpoint>(
         let action_start = std::time::Instant::now();
         match action {
             SerfAction::Stop => {
+                if let Some(sampler) = &mut serf.context.sampler {
+                    if let Err(e) = sampler.write() {
+                        warn!("Could not write stack samples: {e}");
+                    }
+                }
+                if let Some(profile) = &mut serf.context.jet_profile {
+                    if let Err(e) = profile.write() {
+                        warn!("Could not write jet profile: {e}");
+                    }
+                }
                 break;
             }
             SerfAction::Export { result } => {
@@ -384,6 +622,40 @@ fn serf_loop<C: SerfCheckpoint>(
                     }
                 }
             }
+            SerfAction::Upgrade { kernel, result } => match serf.upgrade(&kernel) {
+                Ok((upgrade, roll_back)) => {
+                    let metrics = serf.metrics.clone();
+                    let checkpoint = create_checkpoint(&mut serf, &metrics);
+                    let (decision, decision_fut) = oneshot::channel();
+                    let pending = PendingUpgrade {
+                        upgrade: upgrade.clone(),
+                        checkpoint,
+                        decision,
+                    };
+                    // Run nothing else on the new kernel until it has been persisted
+                    let committed = result.send(Ok(pending)).is_ok()
+                        && decision_fut.blocking_recv().unwrap_or(false);
+                    if committed {
+                        serf.commit_upgrade();
+                        info!(
+                            "Upgraded kernel {} to {} at event_num {}",
+                            upgrade.old_ker_hash, upgrade.new_ker_hash, upgrade.event_num
+                        );
+                    } else {
+                        serf.roll_back_upgrade(roll_back);
+                        warn!(
+                            "Kernel upgrade to {} was not persisted, keeping the current kernel",
+                            upgrade.new_ker_hash
+                        );
+                    }
+                }
+                Err(e) => {
+                    warn!("Kernel upgrade failed, keeping the current kernel: {}", e);
+                    if result.send(Err(e)).is_err() {
+                        debug!("Tried to send upgrade result to dropped channel");
+                    }
+                }
+            },
             SerfAction::GetKernelStateSlab { result } => {
                 let kernel_state_noun = serf.arvo.slot(STATE_AXIS);
                 let kernel_state_slab = kernel_state_noun.map_or_else(
@@ -423,6 +695,67 @@ fn serf_loop<C: SerfCheckpoint>(
                         .add_timing(&action_elapsed);
                 };
             }
+            SerfAction::GetTimers { result } => {
+                let _ = result.send(serf.timers.timers()).map_err(|e| {
+                    debug!("Could not send timers to dropped channel.");
+                    e
+                });
+            }
+            SerfAction::SampleStacks { sampler, result } => {
+                // Flush what the sampler being replaced has taken
+                if let Some(previous) = &mut serf.context.sampler {
+                    if let Err(e) = previous.write() {
+                        warn!("Could not write stack samples: {e}");
+                    }
+                }
+                serf.context.sampler = sampler;
+                let _ = result.send(()).map_err(|e| {
+                    debug!("Could not send sampler result to dropped channel.");
+                    e
+                });
+            }
+            SerfAction::ProfileJets { profile, result } => {
+                // Flush what the profile being replaced has counted
+                if let Some(previous) = &mut serf.context.jet_profile {
+                    if let Err(e) = previous.write() {
+                        warn!("Could not write jet profile: {e}");
+                    }
+                }
+                serf.context.jet_profile = profile;
+                let _ = result.send(()).map_err(|e| {
+                    debug!("Could not send jet profile result to dropped channel.");
+                    e
+                });
+            }
+            SerfAction::SetRecorder {
+                recorder: new_recorder,
+                result,
+            } => {
+                recorder = new_recorder;
+                let _ = result.send(()).map_err(|e| {
+                    debug!("Could not send recorder result to dropped channel.");
+                    e
+                });
+            }
+            SerfAction::SetStackLimit { limit, result } => {
+                let size = serf.context.stack.size();
+                let res = if limit > size {
+                    // Both are in words, but the stack is sized in GiB
+                    let gib = |words: usize| words as f64 / (NOCK_STACK_1KB << 20) as f64;
+                    Err(CrownError::Unknown(format!(
+                        "Stack limit of {:.2} GiB exceeds the stack size of {:.2} GiB",
+                        gib(limit),
+                        gib(size)
+                    )))
+                } else {
+                    serf.context.stack.set_limit(limit);
+                    Ok(())
+                };
+                let _ = result.send(res).map_err(|e| {
+                    debug!("Could not send stack limit result to dropped channel.");
+                    e
+                });
+            }
             SerfAction::Checkpoint { result } => {
                 let metrics_checkpoint = serf.metrics.clone();
                 let checkpoint = create_checkpoint(&mut serf, &metrics_checkpoint);
@@ -455,6 +788,11 @@ fn serf_loop<C: SerfCheckpoint>(
                         slab.copy_into(noun);
                         slab
                     });
+                    if let Some(recorder) = &recorder {
+                        let record =
+                            Record::peek(action_start, ovo.jam(), noun_slab_res.as_ref().ok());
+                        recorder.record_blocking(record);
+                    }
                     let _ = result.send(noun_slab_res).map_err(|e| {
                         debug!("Tried to send peek state to dropped channel");
                         e
@@ -468,6 +806,7 @@ fn serf_loop<C: SerfCheckpoint>(
             SerfAction::Poke {
                 wire,
                 cause,
+                inputs,
                 result,
                 result_ack,
             } => {
@@ -480,12 +819,23 @@ fn serf_loop<C: SerfCheckpoint>(
                         });
                 } else {
                     let cause_noun = cause.copy_to_stack(serf.stack());
-                    let noun_res = serf.poke(wire, cause_noun);
+                    let inputs = inputs.unwrap_or_else(PokeInputs::fresh);
+                    let recorded_wire = recorder.as_ref().map(|_| wire.clone());
+                    let noun_res = serf.poke_with(wire, cause_noun, inputs);
                     let noun_slab_res = noun_res.map(|noun| {
                         let mut slab = NounSlab::new();
                         slab.copy_into(noun);
-                        slab
+                        (inputs, slab)
                     });
+                    if let (Some(recorder), Some(wire)) = (&recorder, recorded_wire) {
+                        let event_num = serf.event_num.load(Ordering::SeqCst);
+                        let recorded = noun_slab_res
+                            .as_ref()
+                            .ok()
+                            .map(|(inputs, effects)| (event_num, *inputs, effects));
+                        let record = Record::poke(action_start, &wire, cause.jam(), recorded);
+                        recorder.record_blocking(record);
+                    }
                     let _ = result.send(noun_slab_res).map_err(|e| {
                         debug!("Failed to send poke result from serf thread");
                         e
@@ -536,6 +886,7 @@ fn create_checkpoint<C: SerfCheckpoint>(
         )
     });
     let cold_state = serf.context.cold;
+    let timers = serf.timers.clone();
 
     C::new(
         serf.stack(),
@@ -543,6 +894,7 @@ fn create_checkpoint<C: SerfCheckpoint>(
         event_num,
         ker_state,
         cold_state,
+        &timers,
         metrics,
     )
 }
@@ -704,9 +1056,29 @@ impl<C> Kernel<C> {
     }
 
     pub fn poke_sync(&self, wire: WireRepr, cause: NounSlab) -> Result<NounSlab> {
+        self.serf.poke_sync(wire, cause).map(|(_, effects)| effects)
+    }
+
+    /// [Kernel::poke_sync], also returning the inputs the poke ran with
+    pub(crate) fn poke_sync_inputs(
+        &self,
+        wire: WireRepr,
+        cause: NounSlab,
+    ) -> Result<(PokeInputs, NounSlab)> {
         self.serf.poke_sync(wire, cause)
     }
 
+    // We are very carefully ensuring the future does not contain the "self" reference to ensure no lifetime issues when spawning tasks
+    pub fn poke_event(
+        &self,
+        wire: WireRepr,
+        cause: NounSlab,
+        inputs: Option<PokeInputs>,
+        timeout: Option<Duration>,
+    ) -> impl Future<Output = Result<(u64, PokeInputs, NounSlab)>> {
+        self.serf.poke_event(wire, cause, inputs, timeout)
+    }
+
     pub fn peek_sync(&self, ovo: NounSlab) -> Result<NounSlab> {
         self.serf.peek_sync(ovo)
     }
@@ -717,6 +1089,39 @@ impl<C> Kernel<C> {
         self.serf.peek(ovo)
     }
 
+    /// The timers the kernel has scheduled, soonest first. See [crate::kernel::timers].
+    pub fn timers(&self) -> impl Future<Output = Result<Vec<ScheduledTimer>>> {
+        self.serf.timers()
+    }
+
+    /// Sample the Nock stack with `sampler`, replacing any sampler already running, or stop
+    /// sampling with `None`. See [StackSampler].
+    pub fn sample_stacks(&self, sampler: Option<StackSampler>) -> impl Future<Output = Result<()>> {
+        self.serf.sample_stacks(sampler)
+    }
+
+    /// Record every poke and peek the kernel runs with `recorder`, in the order they run,
+    /// replacing any recorder already set, or stop recording with `None`. See
+    /// [crate::recorder].
+    pub fn set_recorder(
+        &self,
+        recorder: Option<RecorderHandle>,
+    ) -> impl Future<Output = Result<()>> {
+        self.serf.set_recorder(recorder)
+    }
+
+    /// Profile jets with `profile`, replacing any profile already running, or stop profiling
+    /// with `None`. See [JetProfile].
+    pub fn profile_jets(&self, profile: Option<JetProfile>) -> impl Future<Output = Result<()>> {
+        self.serf.profile_jets(profile)
+    }
+
+    /// Cap the words in use on the Nock stack at `limit`. Past it, the computation fails with
+    /// `%meme` instead of taking more memory.
+    pub fn set_stack_limit(&self, limit: usize) -> impl Future<Output = Result<()>> {
+        self.serf.set_stack_limit(limit)
+    }
+
     pub fn import(&self, state: LoadState) -> impl Future<Output = Result<()>> {
         self.serf.import(state)
     }
@@ -725,6 +1130,14 @@ impl<C> Kernel<C> {
         self.serf.export()
     }
 
+    /// Swaps in the kernel jammed in `kernel`, carrying the current state over through its
+    /// `+load` arm. The event number is unchanged. If the new kernel fails to boot or to
+    /// load the state, the current kernel is kept. Otherwise the upgrade is pending until it
+    /// is committed or rolled back, see [PendingUpgrade].
+    pub fn upgrade(&self, kernel: Vec<u8>) -> impl Future<Output = Result<PendingUpgrade<C>>> {
+        self.serf.upgrade(kernel)
+    }
+
     pub(crate) fn provide_metrics(
         &mut self,
         metrics: Arc<NockAppMetrics>,
@@ -748,6 +1161,8 @@ pub struct Serf {
     pub event_num: Arc<AtomicU64>,
     /// A metrics
     pub metrics: Option<Arc<NockAppMetrics>>,
+    /// Timers scheduled by the kernel's `%timer` effects
+    pub timers: TimerTable,
 }
 
 impl Serf {
@@ -778,7 +1193,7 @@ impl Serf {
         hasher.update(kernel_bytes);
         let ker_hash = hasher.finalize();
 
-        let (maybe_state, cold, event_num_raw) = if let Some(c) = checkpoint {
+        let (maybe_state, cold, event_num_raw, timers) = if let Some(c) = checkpoint {
             let saveable = c.load();
 
             let checkpoint_noun = saveable.noun.copy_to_stack(&mut stack);
@@ -791,14 +1206,14 @@ impl Serf {
                 .expect("Could not load cold state from snapshot");
             let cold = Cold::from_vecs(&mut stack, cold_vecs.0, cold_vecs.1, cold_vecs.2);
             if saveable.ker_hash != ker_hash {
-                debug!(
+                info!(
                     "Loading snapshot from kernel {} into kernel {}",
                     saveable.ker_hash, ker_hash
                 );
             }
-            (Some(ker_state), cold, saveable.event_num)
+            (Some(ker_state), cold, saveable.event_num, saveable.timers)
         } else {
-            (None, Cold::new(&mut stack), 0)
+            (None, Cold::new(&mut stack), 0, TimerTable::default())
         };
 
         let event_num = Arc::new(AtomicU64::new(event_num_raw));
@@ -855,6 +1270,7 @@ impl Serf {
             event_num,
             cancel_token,
             metrics: None,
+            timers,
         };
 
         if let Some(kernel_state) = maybe_state {
@@ -879,7 +1295,7 @@ impl Serf {
     /// Result containing the peeked data or an error.
     #[tracing::instrument(skip_all)]
     pub fn peek(&mut self, ovo: Noun) -> Result<Noun> {
-        if self.context.trace_info.is_some() {
+        let res = if self.context.trace_info.is_some() {
             let trace_name = "peek";
             let start = Instant::now();
             let slam_res = self.slam(PEEK_AXIS, ovo);
@@ -888,7 +1304,11 @@ impl Serf {
             slam_res
         } else {
             self.slam(PEEK_AXIS, ovo)
-        }
+        };
+        write_samples_safe(&mut self.context);
+        write_jet_profile_safe(&mut self.context);
+
+        res
     }
 
     /// Generates a goof (error) noun.
@@ -928,6 +1348,60 @@ impl Serf {
         }
     }
 
+    /// Boots the kernel in `kernel_bytes` and loads the current kernel state into it.
+    ///
+    /// Nothing is changed until the load succeeds, so on failure the current kernel and its
+    /// state stay in place. On success the new kernel is running, but the old one can still be
+    /// put back with [Serf::roll_back_upgrade] until [Serf::commit_upgrade] is called, and
+    /// nothing else may run in between.
+    fn upgrade(&mut self, kernel_bytes: &[u8]) -> Result<(KernelUpgrade, RollBack)> {
+        let old_state = self.arvo.slot(STATE_AXIS)?;
+        let kernel_trap = Noun::cue_bytes_slice(&mut self.context.stack, kernel_bytes)?;
+        let fol = T(&mut self.context.stack, &[D(9), D(2), D(0), D(1)]);
+        let new_arvo = interpret(&mut self.context, kernel_trap, fol)?;
+
+        // +load is slammed on self.arvo, so point it at the new kernel for the call
+        let old_arvo = self.arvo;
+        self.arvo = new_arvo;
+        let arvo = match self.load(old_state) {
+            Ok(arvo) => arvo,
+            Err(e) => {
+                self.arvo = old_arvo;
+                return Err(e);
+            }
+        };
+
+        let old_ker_hash = self.ker_hash;
+        self.arvo = arvo;
+        self.ker_hash = blake3::hash(kernel_bytes);
+        let upgrade = KernelUpgrade {
+            old_ker_hash,
+            new_ker_hash: self.ker_hash,
+            event_num: self.event_num.load(Ordering::SeqCst),
+        };
+        let roll_back = RollBack {
+            arvo: old_arvo,
+            ker_hash: old_ker_hash,
+        };
+        Ok((upgrade, roll_back))
+    }
+
+    /// Keep the kernel [Serf::upgrade] swapped in
+    fn commit_upgrade(&mut self) {
+        let event_num = self.event_num.load(Ordering::SeqCst);
+        unsafe {
+            self.event_update(event_num, self.arvo);
+            self.preserve_event_update_leftovers();
+        }
+    }
+
+    /// Put back the kernel [Serf::upgrade] swapped out. The old kernel is still on the stack,
+    /// since nothing has been preserved since.
+    fn roll_back_upgrade(&mut self, roll_back: RollBack) {
+        self.arvo = roll_back.arvo;
+        self.ker_hash = roll_back.ker_hash;
+    }
+
     pub fn print_goof(&mut self, goof: Noun) {
         let tang = goof
             .as_cell()
@@ -956,6 +1430,12 @@ impl Serf {
                 let mut fec = cell.head();
                 let eve = self.event_num.load(Ordering::SeqCst);
 
+                // The cause is the last item of [eve wire eny our now cause]
+                if let Ok(cause) = job.slot(63) {
+                    self.timers.apply_cause(cause);
+                }
+                self.timers.apply_effects(fec);
+
                 unsafe {
                     self.event_update(eve + 1, cell.tail());
                     self.stack().preserve(&mut fec);
@@ -1014,6 +1494,8 @@ impl Serf {
         } else {
             self.slam(axis, ovo)
         };
+        write_samples_safe(&mut self.context);
+        write_jet_profile_safe(&mut self.context);
 
         match slam_res {
             Ok(res) => Ok(res),
@@ -1177,17 +1659,21 @@ impl Serf {
     /// # Returns
     ///
     /// Result containing the poke response or an error.
+    pub fn poke(&mut self, wire: WireRepr, cause: Noun) -> Result<Noun> {
+        self.poke_with(wire, cause, PokeInputs::fresh())
+    }
+
+    /// [Serf::poke] with the given entropy and time, e.g. to replay an event.
     #[tracing::instrument(level = "info", skip_all, fields(
         src = wire.source
     ))]
-    pub fn poke(&mut self, wire: WireRepr, cause: Noun) -> Result<Noun> {
-        let random_bytes = rand::random::<u64>();
-        let bytes = random_bytes.as_bytes()?;
+    pub fn poke_with(&mut self, wire: WireRepr, cause: Noun, inputs: PokeInputs) -> Result<Noun> {
+        let bytes = inputs.eny.as_bytes()?;
         let eny: Atom = Atom::from_bytes(&mut self.context.stack, &bytes);
         let our = <nockvm::noun::Atom as AtomExt>::from_value(&mut self.context.stack, 0)?; // Using 0 as default value
         let now: Atom = unsafe {
             let mut t_vec: Vec<u8> = vec![];
-            t_vec.write_u128::<LittleEndian>(current_da().0)?;
+            t_vec.write_u128::<LittleEndian>(inputs.now)?;
             IndirectAtom::new_raw_bytes(&mut self.context.stack, 16, t_vec.as_slice().as_ptr())
                 .normalize_as_atom()
         };
@@ -1329,6 +1815,7 @@ pub trait SerfCheckpoint: Send {
         event_num: u64,
         kernel_state: Noun,
         cold_state: Cold,
+        timers: &TimerTable,
         metrics: &Option<Arc<NockAppMetrics>>,
     ) -> Self;
 
@@ -1342,6 +1829,7 @@ impl SerfCheckpoint for SaveableCheckpoint {
         event_num: u64,
         kernel_state: Noun,
         cold_state: Cold,
+        timers: &TimerTable,
         metrics: &Option<Arc<NockAppMetrics>>,
     ) -> Self {
         let mut slab = NounSlab::new();
@@ -1372,6 +1860,7 @@ impl SerfCheckpoint for SaveableCheckpoint {
             ker_hash,
             event_num,
             noun: slab,
+            timers: timers.clone(),
         }
     }
 
@@ -0,0 +1,215 @@
+//! Timers scheduled by the kernel.
+//!
+//! The kernel sets a timer with an `[%timer %set id=@ when=@da]` effect and cancels it with
+//! `[%timer %cancel id=@]`. Setting an `id` which is already set reschedules it. When a
+//! timer is due, the timer driver pokes `[%timer %fire id=@ now=@da]`.
+//!
+//! The serf keeps the table of scheduled timers as it runs events, so the table is saved
+//! beside the kernel state in every checkpoint (see [crate::save::DeltaCheckpointV3]) and
+//! rebuilt by journal replay. A timer leaves the table once its `%fire` poke succeeds, so a
+//! timer whose poke was cut short by a restart fires again after it: delivery is at least once.
+use std::collections::BTreeMap;
+
+use bincode::{Decode, Encode};
+use nockvm::noun::{Atom, Noun, NounAllocator, D};
+
+use crate::utils::DA;
+use crate::{AtomExt, Bytes, NounExt};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScheduledTimer {
+    /// The timer's id atom, as little-endian bytes without trailing zeros
+    pub id: Vec<u8>,
+    pub when: DA,
+}
+
+impl ScheduledTimer {
+    pub fn id_atom<A: NounAllocator>(&self, allocator: &mut A) -> Atom {
+        bytes_to_atom(allocator, &self.id)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Encode, Decode)]
+pub struct TimerTable {
+    timers: BTreeMap<Vec<u8>, u128>,
+}
+
+impl TimerTable {
+    pub fn is_empty(&self) -> bool {
+        self.timers.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.timers.len()
+    }
+
+    /// The scheduled timers, soonest first
+    pub fn timers(&self) -> Vec<ScheduledTimer> {
+        let mut timers: Vec<ScheduledTimer> = self
+            .timers
+            .iter()
+            .map(|(id, when)| ScheduledTimer {
+                id: id.clone(),
+                when: DA(*when),
+            })
+            .collect();
+        timers.sort_by_key(|timer| timer.when.0);
+        timers
+    }
+
+    pub fn set(&mut self, id: Vec<u8>, when: DA) {
+        self.timers.insert(id, when.0);
+    }
+
+    pub fn cancel(&mut self, id: &[u8]) -> bool {
+        self.timers.remove(id).is_some()
+    }
+
+    /// Apply a `%timer` effect, ignoring any other effect
+    pub fn apply_effect(&mut self, effect: Noun) {
+        match timer_request(effect) {
+            Some(TimerRequest::Set { id, when }) => self.set(id, when),
+            Some(TimerRequest::Cancel { id }) => {
+                self.cancel(&id);
+            }
+            Some(TimerRequest::Fire { .. }) | None => {}
+        }
+    }
+
+    /// Apply every `%timer` effect in a list of effects
+    pub fn apply_effects(&mut self, effects: Noun) {
+        for effect in effects.list_iter() {
+            self.apply_effect(effect);
+        }
+    }
+
+    /// Drop the timer a successful `[%timer %fire id now]` poke delivered
+    pub fn apply_cause(&mut self, cause: Noun) {
+        if let Some(TimerRequest::Fire { id, .. }) = timer_request(cause) {
+            self.cancel(&id);
+        }
+    }
+}
+
+/// A `%timer` effect or poke
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TimerRequest {
+    Set { id: Vec<u8>, when: DA },
+    Cancel { id: Vec<u8> },
+    Fire { id: Vec<u8>, now: DA },
+}
+
+/// Decode `[%timer %set id when]`, `[%timer %cancel id]` or `[%timer %fire id now]`
+pub fn timer_request(noun: Noun) -> Option<TimerRequest> {
+    let cell = noun.as_cell().ok()?;
+    if !cell.head().as_atom().ok()?.eq_bytes(b"timer") {
+        return None;
+    }
+    let request = cell.tail().as_cell().ok()?;
+    let operation = request.head().as_atom().ok()?;
+    if operation.eq_bytes(b"cancel") {
+        let id = atom_to_bytes(request.tail().as_atom().ok()?);
+        return Some(TimerRequest::Cancel { id });
+    }
+    let args = request.tail().as_cell().ok()?;
+    let id = atom_to_bytes(args.head().as_atom().ok()?);
+    let time = atom_to_da(args.tail().as_atom().ok()?)?;
+    if operation.eq_bytes(b"set") {
+        Some(TimerRequest::Set { id, when: time })
+    } else if operation.eq_bytes(b"fire") {
+        Some(TimerRequest::Fire { id, now: time })
+    } else {
+        None
+    }
+}
+
+fn atom_to_bytes(atom: Atom) -> Vec<u8> {
+    atom.as_ne_bytes()[..atom.bit_size().div_ceil(8)].to_vec()
+}
+
+fn bytes_to_atom<A: NounAllocator>(allocator: &mut A, bytes: &[u8]) -> Atom {
+    if bytes.is_empty() {
+        return D(0).as_atom().expect("0 is an atom");
+    }
+    Atom::from_bytes(allocator, &Bytes::copy_from_slice(bytes))
+}
+
+pub(crate) fn atom_to_da(atom: Atom) -> Option<DA> {
+    let bytes = atom_to_bytes(atom);
+    if bytes.len() > 16 {
+        return None;
+    }
+    let mut le = [0u8; 16];
+    le[..bytes.len()].copy_from_slice(&bytes);
+    Some(DA(u128::from_le_bytes(le)))
+}
+
+pub(crate) fn da_to_atom<A: NounAllocator>(allocator: &mut A, da: DA) -> Atom {
+    let le = da.0.to_le_bytes();
+    let len = le
+        .iter()
+        .rposition(|byte| *byte != 0)
+        .map_or(0, |last| last + 1);
+    bytes_to_atom(allocator, &le[..len])
+}
+
+#[cfg(test)]
+mod tests {
+    use nockvm::noun::T;
+    use nockvm_macros::tas;
+
+    use super::*;
+    use crate::noun::slab::NounSlab;
+    use crate::utils::current_da;
+
+    fn request(slab: &mut NounSlab, operation: &str, id: &str, time: Option<DA>) -> Noun {
+        let operation = Atom::from_value(slab, operation)
+            .expect("operation")
+            .as_noun();
+        let id = Atom::from_value(slab, id).expect("id").as_noun();
+        match time {
+            Some(time) => {
+                let time = da_to_atom(slab, time).as_noun();
+                T(slab, &[D(tas!(b"timer")), operation, id, time])
+            }
+            None => T(slab, &[D(tas!(b"timer")), operation, id]),
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_timer_table_effects_and_fire() {
+        let mut slab = NounSlab::new();
+        let now = current_da();
+        let later = DA(now.0 + (1 << 64));
+        let set_retry = request(&mut slab, "set", "retry", Some(later));
+        let set_expiry = request(&mut slab, "set", "expiry", Some(now));
+        let cancel_retry = request(&mut slab, "cancel", "retry", None);
+        let fire_expiry = request(&mut slab, "fire", "expiry", Some(now));
+        let effects = T(&mut slab, &[set_retry, set_expiry, D(tas!(b"other")), D(0)]);
+
+        let mut table = TimerTable::default();
+        table.apply_effects(effects);
+        assert_eq!(table.len(), 2);
+        let timers = table.timers();
+        assert_eq!(timers[0].id, b"expiry");
+        assert_eq!(timers[0].when, now);
+        assert_eq!(timers[1].when, later);
+
+        table.apply_effect(cancel_retry);
+        table.apply_cause(fire_expiry);
+        assert!(table.is_empty());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_timer_table_encode_round_trip() {
+        let mut table = TimerTable::default();
+        table.set(b"retry".to_vec(), current_da());
+        table.set(Vec::new(), DA(0));
+        let encoded = bincode::encode_to_vec(&table, bincode::config::standard()).expect("encode");
+        let (decoded, _): (TimerTable, usize) =
+            bincode::decode_from_slice(&encoded, bincode::config::standard()).expect("decode");
+        assert_eq!(decoded, table);
+    }
+}
@@ -12,6 +12,9 @@
 //! - `noun`: Extensions and utilities for working with Urbit nouns.
 //! - `utils`: Errors, misc functions and extensions.
 //!
+// Lets the noun codec derives name `::nockapp` inside this crate too
+extern crate self as nockapp;
+
 pub mod drivers;
 pub mod kernel;
 pub mod nockapp;
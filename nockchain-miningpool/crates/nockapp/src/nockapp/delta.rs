@@ -0,0 +1,608 @@
+//! Delta checkpoints.
+//!
+//! Kernel state is split into chunks of hash-consed subtrees. A subtree is cut into its own
+//! chunk once it is big enough and its mug lands on a cut point, so chunk boundaries depend
+//! only on content and an unchanged subtree produces the same chunk on every save. Chunks
+//! are named by the blake3 hash of their encoding and appended to a pack file
+//! (`<generation>.chkpack`) only if the pack doesn't already have them, so a save writes
+//! just the subtrees which changed. Once a pack grows to several times the size of the live
+//! state, the next save compacts it into a new generation holding only live chunks.
+//!
+//! Pack records are framed as `[len: u64 LE][chunk id: 32 bytes][chunk]`, where the chunk
+//! is compressed with the pack's [Compression] and `len` is its stored length. Chunks are
+//! streamed to the pack as they are cut and read back one at a time while rebuilding, so
+//! neither a save nor a load holds the whole encoded state in memory.
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use bincode::{config, decode_from_slice, encode_to_vec, Decode, Encode};
+use blake3::Hash;
+use either::Either;
+use intmap::IntMap;
+use nockvm::noun::{Atom, Cell, IndirectAtom, Noun};
+use tracing::{debug, warn};
+
+use crate::nockapp::save::{CheckpointError, Compression};
+use crate::noun::slab::{slab_mug, NounSlab};
+
+/// Blake3 hash of an encoded chunk
+pub type ChunkId = [u8; 32];
+
+/// Subtrees lighter than this are never cut into their own chunk
+const MIN_CHUNK_WEIGHT: u64 = 1 << 10;
+/// Subtrees this heavy are always cut into their own chunk
+const MAX_CHUNK_WEIGHT: u64 = 1 << 16;
+/// Between the two weights, cut where the low bits of the mug are zero
+const CUT_MUG_MASK: u32 = 0xf;
+/// Compact once the pack is this many times the size of the live chunks
+const COMPACT_RATIO: u64 = 4;
+const PACK_EXTENSION: &str = "chkpack";
+const FRAME_HEADER_LEN: usize = 8 + 32;
+
+#[derive(Encode, Decode, Debug)]
+enum ChunkNode {
+    Direct(u64),
+    Indirect(Vec<u8>),
+    /// Indices of earlier nodes in the same chunk
+    Cell(u32, u32),
+    /// The root of another chunk
+    Ref(ChunkId),
+}
+
+/// A subtree in post-order. The last node is its root.
+#[derive(Encode, Decode, Debug)]
+struct Chunk {
+    nodes: Vec<ChunkNode>,
+}
+
+/// What a subtree turned into while chunking
+#[derive(Clone, Copy)]
+enum Piece {
+    /// Inlined into its parent's chunk, with this weight
+    Inline(u64),
+    Chunk(ChunkId),
+}
+
+/// Split a noun into chunks, handing each to `sink` as it is cut, children before parents.
+/// Returns the id of the root chunk. The noun must live in a [NounSlab], since its mugs are
+/// cached.
+pub fn chunk_noun<F>(root: Noun, mut sink: F) -> Result<ChunkId, CheckpointError>
+where
+    F: FnMut(ChunkId, &[u8]) -> Result<(), CheckpointError>,
+{
+    let mut pieces: IntMap<u64, Piece> = IntMap::new();
+    let inline_weight =
+        |pieces: &IntMap<u64, Piece>, noun: Noun| match pieces.get(unsafe { noun.as_raw() }) {
+            Some(Piece::Inline(weight)) => *weight,
+            _ => 1,
+        };
+
+    let mut stack = vec![(root, false)];
+    while let Some((noun, expanded)) = stack.pop() {
+        let raw = unsafe { noun.as_raw() };
+        if noun.is_direct() || pieces.contains_key(raw) {
+            continue;
+        }
+        let piece = match noun.as_either_atom_cell() {
+            Either::Left(atom) => Piece::Inline(1 + atom.as_ne_bytes().len() as u64 / 8),
+            Either::Right(cell) => {
+                if !expanded {
+                    stack.push((noun, true));
+                    stack.push((cell.tail(), false));
+                    stack.push((cell.head(), false));
+                    continue;
+                }
+                let weight = inline_weight(&pieces, cell.head())
+                    .saturating_add(inline_weight(&pieces, cell.tail()))
+                    .saturating_add(1);
+                let cut = weight >= MAX_CHUNK_WEIGHT
+                    || (weight >= MIN_CHUNK_WEIGHT && slab_mug(noun) & CUT_MUG_MASK == 0);
+                if cut {
+                    let (id, chunk) = encode_chunk(noun, &pieces)?;
+                    sink(id, &chunk)?;
+                    Piece::Chunk(id)
+                } else {
+                    Piece::Inline(weight)
+                }
+            }
+        };
+        pieces.insert(raw, piece);
+    }
+
+    match pieces.get(unsafe { root.as_raw() }) {
+        Some(Piece::Chunk(id)) if !root.is_direct() => Ok(*id),
+        _ => {
+            let (id, chunk) = encode_chunk(root, &pieces)?;
+            sink(id, &chunk)?;
+            Ok(id)
+        }
+    }
+}
+
+/// Encode the inline part of the subtree at `top`, referring to chunked subtrees by id.
+fn encode_chunk(
+    top: Noun,
+    pieces: &IntMap<u64, Piece>,
+) -> Result<(ChunkId, Vec<u8>), CheckpointError> {
+    let top_raw = unsafe { top.as_raw() };
+    let mut nodes = Vec::new();
+    let mut index: IntMap<u64, u32> = IntMap::new();
+    let mut stack = vec![(top, false)];
+    while let Some((noun, expanded)) = stack.pop() {
+        let raw = unsafe { noun.as_raw() };
+        if index.contains_key(raw) {
+            continue;
+        }
+        let node = match pieces.get(raw) {
+            Some(Piece::Chunk(id)) if raw != top_raw && !noun.is_direct() => ChunkNode::Ref(*id),
+            _ => match noun.as_either_atom_cell() {
+                Either::Left(atom) => match atom.as_either() {
+                    Either::Left(direct) => ChunkNode::Direct(direct.data()),
+                    Either::Right(indirect) => ChunkNode::Indirect(indirect.as_ne_bytes().to_vec()),
+                },
+                Either::Right(cell) => {
+                    if !expanded {
+                        stack.push((noun, true));
+                        stack.push((cell.tail(), false));
+                        stack.push((cell.head(), false));
+                        continue;
+                    }
+                    let head = unsafe { cell.head().as_raw() };
+                    let tail = unsafe { cell.tail().as_raw() };
+                    ChunkNode::Cell(
+                        *index.get(head).expect("head encoded before its cell"),
+                        *index.get(tail).expect("tail encoded before its cell"),
+                    )
+                }
+            },
+        };
+        index.insert(raw, nodes.len() as u32);
+        nodes.push(node);
+    }
+    let bytes = encode_to_vec(Chunk { nodes }, config::standard())?;
+    Ok((*blake3::hash(&bytes).as_bytes(), bytes))
+}
+
+/// The pack file for one generation of chunks.
+pub struct ChunkPack {
+    path: PathBuf,
+    generation: u64,
+    compression: Compression,
+    /// Offset and stored length of each chunk in the file
+    chunks: HashMap<ChunkId, (u64, u64)>,
+    len: u64,
+    /// Stored size of the chunks the last noun written uses
+    live: u64,
+}
+
+impl ChunkPack {
+    pub fn path(dir: &Path, generation: u64) -> PathBuf {
+        dir.join(format!("{}.{}", generation, PACK_EXTENSION))
+    }
+
+    /// Open an existing pack whose chunks are stored with `compression`. Only the frame
+    /// headers are read here; chunks are read and verified by [ChunkPack::rebuild].
+    pub fn open(
+        dir: &Path,
+        generation: u64,
+        compression: Compression,
+    ) -> Result<Self, CheckpointError> {
+        Self::open_(dir, generation, compression, true)
+    }
+
+    /// Open an existing pack only to read from it, as [ChunkPack::open] does but leaving any
+    /// torn chunks at the end of the file in place.
+    pub fn open_read_only(
+        dir: &Path,
+        generation: u64,
+        compression: Compression,
+    ) -> Result<Self, CheckpointError> {
+        Self::open_(dir, generation, compression, false)
+    }
+
+    fn open_(
+        dir: &Path,
+        generation: u64,
+        compression: Compression,
+        writable: bool,
+    ) -> Result<Self, CheckpointError> {
+        let path = Self::path(dir, generation);
+        let file = OpenOptions::new().read(true).write(writable).open(&path)?;
+        let file_len = file.metadata()?.len();
+        let mut reader = BufReader::new(&file);
+        let mut chunks = HashMap::new();
+        let mut header = [0u8; FRAME_HEADER_LEN];
+        let mut offset = 0;
+        while file_len - offset >= FRAME_HEADER_LEN as u64 {
+            reader.read_exact(&mut header)?;
+            let len = u64::from_le_bytes(header[..8].try_into().expect("8 byte length"));
+            let id: ChunkId = header[8..].try_into().expect("32 byte chunk id");
+            let start = offset + FRAME_HEADER_LEN as u64;
+            let Some(end) = start.checked_add(len).filter(|end| *end <= file_len) else {
+                break;
+            };
+            reader.seek_relative(len as i64)?;
+            chunks.insert(id, (start, len));
+            offset = end;
+        }
+        if offset < file_len && writable {
+            warn!(
+                "Dropping {} bytes of torn chunks from the end of {}",
+                file_len - offset,
+                path.display()
+            );
+            file.set_len(offset)?;
+        } else if offset < file_len {
+            debug!(
+                "Ignoring {} bytes of torn chunks at the end of {}",
+                file_len - offset,
+                path.display()
+            );
+        }
+        Ok(ChunkPack {
+            path,
+            generation,
+            compression,
+            chunks,
+            len: offset,
+            live: 0,
+        })
+    }
+
+    /// Start a new, empty pack, replacing any file already at its path.
+    pub fn create(
+        dir: &Path,
+        generation: u64,
+        compression: Compression,
+    ) -> Result<Self, CheckpointError> {
+        let path = Self::path(dir, generation);
+        File::create(&path)?;
+        Ok(ChunkPack {
+            path,
+            generation,
+            compression,
+            chunks: HashMap::new(),
+            len: 0,
+            live: 0,
+        })
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    /// Whether most of this pack is chunks which the last noun written no longer uses.
+    pub fn needs_compaction(&self) -> bool {
+        self.live > 0 && self.len > COMPACT_RATIO * self.live
+    }
+
+    /// Chunk `root`, append the chunks this pack doesn't have yet and sync them to disk.
+    /// Returns the id of the root chunk. If anything fails the pack is cut back to where
+    /// it was, so a failed save leaves no partial chunks behind.
+    pub fn write_noun(&mut self, root: Noun) -> Result<ChunkId, CheckpointError> {
+        let file = OpenOptions::new().append(true).open(&self.path)?;
+        let mut writer = BufWriter::new(&file);
+        let mut added = Vec::new();
+        let mut seen = HashSet::new();
+        let mut stored = Vec::new();
+        let mut total = 0;
+        let mut live = 0;
+        let mut len = self.len;
+        let result = chunk_noun(root, |id, chunk| {
+            total += 1;
+            if !seen.insert(id) {
+                return Ok(());
+            }
+            if let Some((_, stored_len)) = self.chunks.get(&id) {
+                live += FRAME_HEADER_LEN as u64 + stored_len;
+                return Ok(());
+            }
+            stored.clear();
+            self.compression.write_all(&mut stored, chunk)?;
+            writer.write_all(&(stored.len() as u64).to_le_bytes())?;
+            writer.write_all(&id)?;
+            writer.write_all(&stored)?;
+            let start = len + FRAME_HEADER_LEN as u64;
+            len = start + stored.len() as u64;
+            live += FRAME_HEADER_LEN as u64 + stored.len() as u64;
+            added.push((id, (start, stored.len() as u64)));
+            Ok(())
+        })
+        .and_then(|root| {
+            writer.flush()?;
+            writer.get_ref().sync_all()?;
+            Ok(root)
+        });
+        drop(writer);
+        let root = match result {
+            Ok(root) => root,
+            Err(e) => {
+                if let Err(truncate_err) = file.set_len(self.len) {
+                    warn!(
+                        "Failed to cut {} back after a failed write: {}",
+                        self.path.display(),
+                        truncate_err
+                    );
+                }
+                return Err(e);
+            }
+        };
+        debug!(
+            "Wrote {} of {} chunks ({} bytes) to {}",
+            added.len(),
+            total,
+            len - self.len,
+            self.path.display()
+        );
+        self.len = len;
+        self.live = live;
+        self.chunks.extend(added);
+        Ok(root)
+    }
+
+    /// Rebuild the noun whose root chunk is `root` into `slab`, reading chunks from the pack
+    /// as they are needed.
+    pub fn rebuild(&self, root: ChunkId, slab: &mut NounSlab) -> Result<Noun, CheckpointError> {
+        let mut file = File::open(&self.path)?;
+        let mut buffer = Vec::new();
+        let mut built: HashMap<ChunkId, Noun> = HashMap::new();
+        let mut decoded: HashMap<ChunkId, Chunk> = HashMap::new();
+        // Chunks whose children are being built. A chunk may be reached by several paths
+        // and sit on the stack more than once, but only a ref back to one of these is a cycle.
+        let mut in_progress: HashSet<ChunkId> = HashSet::new();
+        let mut stack = vec![root];
+        while let Some(&id) = stack.last() {
+            if built.contains_key(&id) {
+                stack.pop();
+                continue;
+            }
+            if !decoded.contains_key(&id) {
+                let chunk = self.read_chunk(&mut file, &id, &mut buffer)?;
+                decoded.insert(id, chunk);
+            }
+            let missing: Vec<ChunkId> = decoded[&id]
+                .nodes
+                .iter()
+                .filter_map(|node| match node {
+                    ChunkNode::Ref(child) if !built.contains_key(child) => Some(*child),
+                    _ => None,
+                })
+                .collect();
+            if !missing.is_empty() {
+                if missing.iter().any(|child| in_progress.contains(child)) {
+                    return Err(CheckpointError::CorruptChunk(Hash::from_bytes(id)));
+                }
+                in_progress.insert(id);
+                stack.extend(missing);
+                continue;
+            }
+
+            let chunk = decoded.remove(&id).expect("chunk decoded above");
+            let mut nouns: Vec<Noun> = Vec::with_capacity(chunk.nodes.len());
+            let corrupt = || CheckpointError::CorruptChunk(Hash::from_bytes(id));
+            for node in chunk.nodes {
+                let noun = match node {
+                    ChunkNode::Direct(d) => Atom::new(slab, d).as_noun(),
+                    ChunkNode::Indirect(bytes) if !bytes.is_empty() => unsafe {
+                        IndirectAtom::new_raw_bytes_ref(slab, &bytes)
+                            .normalize_as_atom()
+                            .as_noun()
+                    },
+                    ChunkNode::Indirect(_) => return Err(corrupt()),
+                    ChunkNode::Cell(head, tail) => {
+                        let head = *nouns.get(head as usize).ok_or_else(corrupt)?;
+                        let tail = *nouns.get(tail as usize).ok_or_else(corrupt)?;
+                        Cell::new(slab, head, tail).as_noun()
+                    }
+                    ChunkNode::Ref(child) => built[&child],
+                };
+                nouns.push(noun);
+            }
+            built.insert(id, *nouns.last().ok_or_else(corrupt)?);
+            in_progress.remove(&id);
+            stack.pop();
+        }
+        Ok(built[&root])
+    }
+
+    /// Read, decompress and verify one chunk, using `buffer` for its stored bytes.
+    fn read_chunk(
+        &self,
+        file: &mut File,
+        id: &ChunkId,
+        buffer: &mut Vec<u8>,
+    ) -> Result<Chunk, CheckpointError> {
+        let &(offset, len) = self
+            .chunks
+            .get(id)
+            .ok_or(CheckpointError::MissingChunk(Hash::from_bytes(*id)))?;
+        buffer.resize(len as usize, 0);
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(buffer)?;
+        let mut bytes = Vec::new();
+        self.compression
+            .reader(&buffer[..])
+            .and_then(|mut reader| reader.read_to_end(&mut bytes))
+            .map_err(|_| CheckpointError::CorruptChunk(Hash::from_bytes(*id)))?;
+        if blake3::hash(&bytes).as_bytes() != id {
+            return Err(CheckpointError::CorruptChunk(Hash::from_bytes(*id)));
+        }
+        let (chunk, _) = decode_from_slice::<Chunk, _>(&bytes, config::standard())?;
+        Ok(chunk)
+    }
+}
+
+/// Generations of the pack files in `dir`
+pub(crate) fn pack_generations(dir: &Path) -> Result<Vec<u64>, CheckpointError> {
+    let mut generations = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some(PACK_EXTENSION) {
+            continue;
+        }
+        if let Some(generation) = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.parse().ok())
+        {
+            generations.push(generation);
+        }
+    }
+    Ok(generations)
+}
+
+/// A generation no existing pack file uses
+pub fn next_generation(dir: &Path) -> Result<u64, CheckpointError> {
+    Ok(pack_generations(dir)?
+        .into_iter()
+        .max()
+        .map_or(0, |generation| generation + 1))
+}
+
+/// Delete every pack in `dir` whose generation isn't in `keep`.
+pub fn remove_unreferenced_packs(dir: &Path, keep: &[u64]) -> Result<(), CheckpointError> {
+    for generation in pack_generations(dir)? {
+        if !keep.contains(&generation) {
+            debug!("Removing unreferenced chunk pack {}", generation);
+            std::fs::remove_file(ChunkPack::path(dir, generation))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use nockvm::noun::{D, T};
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::noun::slab::slab_equality;
+
+    /// A long list of indirect atoms sharing one tail subtree
+    fn big_noun(first: u64) -> NounSlab {
+        let mut slab = NounSlab::new();
+        let shared = Atom::new(&mut slab, u64::MAX).as_noun();
+        let mut list = D(0);
+        for i in (0..20_000u64).rev() {
+            let value = if i == 0 { first } else { i | (1 << 63) };
+            let atom = Atom::new(&mut slab, value).as_noun();
+            list = T(&mut slab, &[atom, shared, list]);
+        }
+        slab.set_root(list);
+        slab
+    }
+
+    /// The root id and every chunk id `slab` is cut into
+    fn chunk_ids(slab: &NounSlab) -> (ChunkId, HashSet<ChunkId>) {
+        let mut ids = HashSet::new();
+        let root = chunk_noun(unsafe { *slab.root() }, |id, _| {
+            ids.insert(id);
+            Ok(())
+        })
+        .expect("chunk");
+        (root, ids)
+    }
+
+    /// Append `nodes` to the pack file in `dir` as one chunk, returning its id
+    fn append_chunk(dir: &Path, nodes: Vec<ChunkNode>) -> ChunkId {
+        let bytes = encode_to_vec(Chunk { nodes }, config::standard()).expect("encode");
+        let id = *blake3::hash(&bytes).as_bytes();
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(ChunkPack::path(dir, 0))
+            .expect("open pack");
+        file.write_all(&(bytes.len() as u64).to_le_bytes())
+            .expect("write");
+        file.write_all(&id).expect("write");
+        file.write_all(&bytes).expect("write");
+        id
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_rebuild_shared_chunk() {
+        let dir = TempDir::new().expect("tempdir");
+        ChunkPack::create(dir.path(), 0, Compression::None).expect("create");
+        // root -> {a, b} and b -> a: a is reached twice, which is not a cycle
+        let a = append_chunk(dir.path(), vec![ChunkNode::Direct(1)]);
+        let b = append_chunk(
+            dir.path(),
+            vec![ChunkNode::Ref(a), ChunkNode::Direct(2), ChunkNode::Cell(0, 1)],
+        );
+        let root = append_chunk(
+            dir.path(),
+            vec![ChunkNode::Ref(a), ChunkNode::Ref(b), ChunkNode::Cell(0, 1)],
+        );
+
+        let pack = ChunkPack::open(dir.path(), 0, Compression::None).expect("open");
+        let mut slab = NounSlab::new();
+        let noun = pack.rebuild(root, &mut slab).expect("rebuild");
+        slab.set_root(noun);
+        let mut expected = NounSlab::new();
+        let expected_root = T(&mut expected, &[D(1), D(1), D(2)]);
+        expected.set_root(expected_root);
+        assert!(slab_equality(&slab, &expected));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_delta_chunks_round_trip() {
+        for compression in [Compression::None, Compression::Zstd] {
+            let dir = TempDir::new().expect("tempdir");
+            let mut pack = ChunkPack::create(dir.path(), 0, compression).expect("create");
+
+            let first = big_noun(1);
+            let (first_root, first_ids) = chunk_ids(&first);
+            assert!(first_ids.len() > 1);
+            let written = pack.write_noun(unsafe { *first.root() }).expect("write");
+            assert_eq!(written, first_root);
+
+            // Changing the head of the list only rewrites the chunk holding it
+            let second = big_noun(2);
+            let (second_root, second_ids) = chunk_ids(&second);
+            assert_eq!(second_ids.difference(&first_ids).count(), 1);
+            pack.write_noun(unsafe { *second.root() }).expect("write");
+            assert!(!pack.needs_compaction());
+
+            let pack = ChunkPack::open(dir.path(), 0, compression).expect("open");
+            for (root, expected) in [(first_root, &first), (second_root, &second)] {
+                let mut slab = NounSlab::new();
+                let noun = pack.rebuild(root, &mut slab).expect("rebuild");
+                slab.set_root(noun);
+                assert!(slab_equality(&slab, expected));
+            }
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_open_torn_pack() {
+        let dir = TempDir::new().expect("tempdir");
+        let mut pack = ChunkPack::create(dir.path(), 0, Compression::None).expect("create");
+        let noun = big_noun(1);
+        let root = pack.write_noun(unsafe { *noun.root() }).expect("write");
+        let path = ChunkPack::path(dir.path(), 0);
+        let whole = std::fs::metadata(&path).expect("metadata").len();
+        let mut file = OpenOptions::new().append(true).open(&path).expect("open");
+        file.write_all(&[0xff; 12]).expect("write");
+
+        // Reading leaves the torn tail alone, and opening to write cuts it off
+        let pack = ChunkPack::open_read_only(dir.path(), 0, Compression::None).expect("open");
+        assert_eq!(
+            std::fs::metadata(&path).expect("metadata").len(),
+            whole + 12
+        );
+        let mut slab = NounSlab::new();
+        let rebuilt = pack.rebuild(root, &mut slab).expect("rebuild");
+        slab.set_root(rebuilt);
+        assert!(slab_equality(&slab, &noun));
+        ChunkPack::open(dir.path(), 0, Compression::None).expect("open");
+        assert_eq!(std::fs::metadata(&path).expect("metadata").len(), whole);
+    }
+}
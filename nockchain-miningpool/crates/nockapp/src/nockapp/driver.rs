@@ -5,13 +5,16 @@ use std::time::Duration;
 use futures::future::Future;
 use tokio::sync::{broadcast, mpsc, oneshot, Mutex};
 use tokio::task::JoinSet;
-use tracing::instrument;
+use tracing::{instrument, warn};
 
 use super::error::NockAppError;
 use super::metrics::NockAppMetrics;
 use super::wire::WireRepr;
 use super::NockAppExit;
+use crate::kernel::form::KernelUpgrade;
+use crate::kernel::timers::ScheduledTimer;
 use crate::noun::slab::NounSlab;
+use crate::AtomExt;
 
 pub type IODriverFuture = Pin<Box<dyn Future<Output = Result<(), NockAppError>> + Send>>;
 pub type IODriverFn = Box<dyn FnOnce(NockAppHandle) -> IODriverFuture>;
@@ -21,6 +24,10 @@ pub type ActionReceiver = mpsc::Receiver<IOAction>;
 pub type EffectSender = broadcast::Sender<NounSlab>;
 pub type EffectReceiver = broadcast::Receiver<NounSlab>;
 
+/// Effects the NockApp buffers for each subscriber before it lags, see
+/// [`NockAppHandle::subscribe_effects`]
+pub const EFFECT_SUBSCRIPTION_CAPACITY: usize = 1024;
+
 /// Result of a poke: either Ack if it succeeded or Nack if it failed
 #[derive(Debug)]
 pub enum PokeResult {
@@ -68,6 +75,15 @@ pub enum IOAction {
         path: NounSlab,
         result_channel: oneshot::Sender<Option<NounSlab>>,
     },
+    /// Kernel upgrade request to [`crate::NockApp`]
+    Upgrade {
+        kernel: Vec<u8>,
+        result_channel: oneshot::Sender<Result<KernelUpgrade, NockAppError>>,
+    },
+    /// Request for the timers the kernel has scheduled
+    Timers {
+        result_channel: oneshot::Sender<Result<Vec<ScheduledTimer>, NockAppError>>,
+    },
 }
 
 impl NockAppHandle {
@@ -196,6 +212,30 @@ impl NockAppHandle {
         Ok(result_future.await?)
     }
 
+    /// Swap in the kernel jammed in `kernel`, keeping the current state. See
+    /// [`crate::kernel::form::Kernel::upgrade`].
+    #[tracing::instrument(name = "nockapp::NockAppHandle::upgrade_kernel", skip_all)]
+    pub async fn upgrade_kernel(&self, kernel: Vec<u8>) -> Result<KernelUpgrade, NockAppError> {
+        let (result_channel, result_future) = oneshot::channel();
+        self.io_sender
+            .send(IOAction::Upgrade {
+                kernel,
+                result_channel,
+            })
+            .await?;
+        result_future.await?
+    }
+
+    /// The timers the kernel has scheduled, soonest first. See [`crate::kernel::timers`].
+    #[tracing::instrument(name = "nockapp::NockAppHandle::scheduled_timers", skip_all)]
+    pub async fn scheduled_timers(&self) -> Result<Vec<ScheduledTimer>, NockAppError> {
+        let (result_channel, result_future) = oneshot::channel();
+        self.io_sender
+            .send(IOAction::Timers { result_channel })
+            .await?;
+        result_future.await?
+    }
+
     #[instrument(skip(self))]
     pub async fn next_effect(&self) -> Result<NounSlab, NockAppError> {
         let mut effect_receiver = self.effect_receiver.lock().await;
@@ -217,6 +257,21 @@ impl NockAppHandle {
         }
     }
 
+    /// Receive only the effects whose head is one of `tags`, or every effect if `tags` is
+    /// empty.
+    ///
+    /// Each subscription reads the effect broadcast at its own pace, so a slow driver doesn't
+    /// hold up the others and nothing is dropped while it keeps within
+    /// [`EFFECT_SUBSCRIPTION_CAPACITY`] effects. Past that its oldest effects are skipped and
+    /// counted in `nockapp.effect_subscription.lag`.
+    pub fn subscribe_effects(&self, tags: &[&str]) -> EffectSubscription {
+        EffectSubscription {
+            effects: self.effect_sender.subscribe(),
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+            metrics: self.metrics.clone(),
+        }
+    }
+
     #[instrument(skip(self))]
     pub fn dup(self) -> (Self, Self) {
         let io_sender = self.io_sender.clone();
@@ -241,3 +296,114 @@ impl NockAppHandle {
         self.io_sender.clone()
     }
 }
+
+/// Effects selected by [`NockAppHandle::subscribe_effects`]
+pub struct EffectSubscription {
+    effects: EffectReceiver,
+    tags: Vec<String>,
+    metrics: Arc<NockAppMetrics>,
+}
+
+impl EffectSubscription {
+    /// The next matching effect, or `None` once the NockApp has shut down
+    pub async fn recv(&mut self) -> Option<NounSlab> {
+        loop {
+            match self.effects.recv().await {
+                Ok(effect) if effect_has_tag(&effect, &self.tags) => return Some(effect),
+                Ok(_) => {}
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    warn!(
+                        "Effect subscription {:?} lagged by {} effects",
+                        self.tags, n
+                    );
+                    let _ = self
+                        .metrics
+                        .effect_subscription_lagged
+                        .fetch_add(n as usize);
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+fn effect_has_tag(effect: &NounSlab, tags: &[String]) -> bool {
+    if tags.is_empty() {
+        return true;
+    }
+    let Ok(effect_cell) = unsafe { effect.root() }.as_cell() else {
+        return false;
+    };
+    let Ok(head) = effect_cell.head().as_atom() else {
+        return false;
+    };
+    tags.iter().any(|tag| head.eq_bytes(tag))
+}
+
+/// A handle on bare channels instead of a NockApp, for testing drivers. Effects are sent
+/// on its `effect_sender` and the IO actions it sends arrive on the returned receiver.
+#[cfg(test)]
+pub(crate) fn test_handle(capacity: usize) -> (NockAppHandle, ActionReceiver) {
+    let (io_sender, io_receiver) = mpsc::channel(capacity);
+    let (effect_sender, effect_receiver) = broadcast::channel(capacity);
+    let metrics = Arc::new(
+        NockAppMetrics::register(gnort::global_metrics_registry())
+            .expect("Failed to register metrics!"),
+    );
+    let (exit, _) = NockAppExit::new();
+    let handle = NockAppHandle {
+        io_sender,
+        effect_sender: Arc::new(effect_sender),
+        effect_receiver: Mutex::new(effect_receiver),
+        metrics,
+        exit,
+    };
+    (handle, io_receiver)
+}
+
+#[cfg(test)]
+mod tests {
+    use nockvm::noun::{D, T};
+    use nockvm_macros::tas;
+
+    use super::*;
+    use crate::NounExt;
+
+    fn effect(tag: u64, value: u64) -> NounSlab {
+        let mut slab = NounSlab::new();
+        let noun = T(&mut slab, &[D(tag), D(value)]);
+        slab.set_root(noun);
+        slab
+    }
+
+    #[tokio::test]
+    #[cfg_attr(miri, ignore)]
+    async fn test_subscribe_effects_filters_by_tag() {
+        let (handle, _rx_io) = test_handle(8);
+
+        let mut files = handle.subscribe_effects(&["file"]);
+        let mut everything = handle.subscribe_effects(&[]);
+        handle
+            .effect_sender
+            .send(effect(tas!(b"npc"), 1))
+            .expect("send npc effect");
+        handle
+            .effect_sender
+            .send(effect(tas!(b"file"), 2))
+            .expect("send file effect");
+
+        let received = files.recv().await.expect("file effect");
+        let cell = unsafe { received.root() }.as_cell().expect("cell");
+        assert!(cell.head().eq_bytes("file"));
+        assert_eq!(
+            cell.tail().as_atom().expect("atom").as_u64().expect("u64"),
+            2
+        );
+
+        for expected in ["npc", "file"] {
+            let received = everything.recv().await.expect("effect");
+            let cell = unsafe { received.root() }.as_cell().expect("cell");
+            assert!(cell.head().eq_bytes(expected));
+        }
+    }
+}
@@ -59,6 +59,8 @@ pub enum NockAppError {
     ConfigError(#[from] config::ConfigError),
     #[error("Checkpoing error: {0}")]
     CheckpointError(#[from] CheckpointError),
+    #[error("Kernel upgrades are not enabled")]
+    UpgradeDisabled,
 }
 
 impl From<TrySendError<IOAction>> for NockAppError {
@@ -1,15 +1,28 @@
-use bincode::{config, decode_from_slice, encode_to_vec, Decode, Encode};
-use blake3::Hash;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Cursor, Read, Seek, Write};
+use std::path::Path;
+
+use bincode::{config, decode_from_std_read, encode_into_std_write, Decode, Encode};
+use blake3::{Hash, Hasher};
+use bytes::Bytes;
 use nockvm_macros::tas;
+use tracing::debug;
 
 use crate::kernel::form::LoadState;
 use crate::noun::slab::NounSlab;
+use crate::save::{CheckpointError, Compression};
 use crate::{JammedNoun, NockAppError};
 
-const EXPORTED_STATE_MAGIC_BYTES: u64 = tas!(b"EXPJAM");
-const EXPORTED_STATE_VERSION: u32 = 0;
+pub(crate) const EXPORTED_STATE_MAGIC_BYTES: u64 = tas!(b"EXPJAM");
+const EXPORTED_STATE_VERSION_0: u32 = 0;
+const EXPORTED_STATE_VERSION_1: u32 = 1;
 
 /// A structure for exporting just the kernel state, without the cold state
+///
+/// Version 0 files are this structure encoded whole. Version 1 files are an
+/// [ExportedStateHeader] followed by the jam, compressed as the header says, up to the end
+/// of the file, so the jam is streamed through the encoder and decoder rather than copied
+/// into a second buffer.
 #[derive(Encode, Decode, PartialEq, Debug)]
 pub struct ExportedState {
     /// Magic bytes to identify exported state format
@@ -25,14 +38,44 @@ pub struct ExportedState {
     pub jam: JammedNoun,
 }
 
+/// Everything in a version 1 exported state file before the jam
+#[derive(Encode, Decode, PartialEq, Debug)]
+struct ExportedStateHeader {
+    magic_bytes: u64,
+    version: u32,
+    #[bincode(with_serde)]
+    ker_hash: Hash,
+    event_num: u64,
+    compression: Compression,
+    /// Uncompressed length of the jam, so it can be read into a buffer of the right size
+    jam_len: u64,
+    /// Checksum derived from ker_hash, event_num and the uncompressed jam
+    #[bincode(with_serde)]
+    checksum: Hash,
+}
+
+impl ExportedStateHeader {
+    fn checksum(ker_hash: &Hash, event_num: u64, jam: &[u8]) -> Hash {
+        let mut hasher = Hasher::new();
+        hasher.update(ker_hash.as_bytes());
+        hasher.update(&event_num.to_le_bytes());
+        hasher.update(&(jam.len() as u64).to_le_bytes());
+        hasher.update(jam);
+        hasher.finalize()
+    }
+}
+
 impl ExportedState {
-    pub fn encode(&self) -> Result<Vec<u8>, bincode::error::EncodeError> {
-        encode_to_vec(self, config::standard())
+    /// Encode this state in the version 1 format, uncompressed
+    pub fn encode(&self) -> Result<Vec<u8>, CheckpointError> {
+        let mut bytes = Vec::new();
+        self.write_to(&mut bytes, Compression::None)?;
+        Ok(bytes)
     }
 
-    pub fn decode(data: &[u8]) -> Result<Self, bincode::error::DecodeError> {
-        let (state, _) = decode_from_slice(data, config::standard())?;
-        Ok(state)
+    /// Decode an exported state of either version from `data`
+    pub fn decode(data: &[u8]) -> Result<Self, CheckpointError> {
+        Self::read_from(Cursor::new(data), data.len() as u64, Path::new("<bytes>"))
     }
 
     pub fn from_loadstate(state: LoadState) -> Self {
@@ -40,7 +83,7 @@ impl ExportedState {
 
         Self {
             magic_bytes: EXPORTED_STATE_MAGIC_BYTES,
-            version: EXPORTED_STATE_VERSION,
+            version: EXPORTED_STATE_VERSION_1,
             ker_hash: state.ker_hash,
             event_num: state.event_num,
             jam: jam,
@@ -58,4 +101,185 @@ impl ExportedState {
             event_num: self.event_num,
         })
     }
+
+    /// Write this state to `path` in the version 1 format, streaming the jam through
+    /// `compression` rather than encoding the whole file in memory.
+    pub fn save_to_file(
+        &self,
+        path: &Path,
+        compression: Compression,
+    ) -> Result<(), CheckpointError> {
+        let writer = self.write_to(BufWriter::new(File::create(path)?), compression)?;
+        writer.get_ref().sync_all()?;
+        debug!(
+            "Exported {} bytes of jammed state ({:?}) to {}",
+            self.jam.0.len(),
+            compression,
+            path.display()
+        );
+        Ok(())
+    }
+
+    /// Read an exported state of either version from `path`.
+    pub fn load_from_file(path: &Path) -> Result<Self, CheckpointError> {
+        let file = File::open(path)?;
+        let file_len = file.metadata()?.len();
+        Self::read_from(BufReader::new(file), file_len, path)
+    }
+
+    fn write_to<W: Write>(
+        &self,
+        mut writer: W,
+        compression: Compression,
+    ) -> Result<W, CheckpointError> {
+        let header = ExportedStateHeader {
+            magic_bytes: EXPORTED_STATE_MAGIC_BYTES,
+            version: EXPORTED_STATE_VERSION_1,
+            ker_hash: self.ker_hash,
+            event_num: self.event_num,
+            compression,
+            jam_len: self.jam.0.len() as u64,
+            checksum: ExportedStateHeader::checksum(&self.ker_hash, self.event_num, &self.jam.0),
+        };
+        encode_into_std_write(&header, &mut writer, config::standard())?;
+        let mut writer = compression.write_all(writer, &self.jam.0)?;
+        writer.flush()?;
+        Ok(writer)
+    }
+
+    /// Read an exported state from `reader`, which holds `len` bytes read from `path`
+    fn read_from<R: BufRead + Seek>(
+        mut reader: R,
+        len: u64,
+        path: &Path,
+    ) -> Result<Self, CheckpointError> {
+        let (magic_bytes, version) =
+            decode_from_std_read::<(u64, u32), _, _>(&mut reader, config::standard())?;
+        reader.rewind()?;
+        if magic_bytes != EXPORTED_STATE_MAGIC_BYTES {
+            return Err(CheckpointError::InvalidVersion(path.to_path_buf()));
+        }
+        match version {
+            EXPORTED_STATE_VERSION_0 => Ok(decode_from_std_read(&mut reader, config::standard())?),
+            EXPORTED_STATE_VERSION_1 => {
+                let header: ExportedStateHeader =
+                    decode_from_std_read(&mut reader, config::standard())?;
+                let remaining = len.saturating_sub(reader.stream_position()?);
+                // Don't allocate for a length the file can't hold. An uncompressed jam fills the
+                // rest of the file, and a compressed one is read as it decompresses.
+                let jam = match header.compression {
+                    Compression::None => {
+                        if header.jam_len != remaining {
+                            return Err(CheckpointError::InvalidJamLength(path.to_path_buf()));
+                        }
+                        let mut jam = vec![0; remaining as usize];
+                        reader.read_exact(&mut jam)?;
+                        jam
+                    }
+                    Compression::Zstd => {
+                        let mut jam = Vec::new();
+                        header
+                            .compression
+                            .reader(reader)?
+                            .take(header.jam_len.saturating_add(1))
+                            .read_to_end(&mut jam)?;
+                        if jam.len() as u64 != header.jam_len {
+                            return Err(CheckpointError::InvalidJamLength(path.to_path_buf()));
+                        }
+                        jam
+                    }
+                };
+                let checksum =
+                    ExportedStateHeader::checksum(&header.ker_hash, header.event_num, &jam);
+                if checksum != header.checksum {
+                    return Err(CheckpointError::InvalidChecksum(path.to_path_buf()));
+                }
+                Ok(Self {
+                    magic_bytes: header.magic_bytes,
+                    version: header.version,
+                    ker_hash: header.ker_hash,
+                    event_num: header.event_num,
+                    jam: JammedNoun::new(Bytes::from(jam)),
+                })
+            }
+            _ => Err(CheckpointError::InvalidVersion(path.to_path_buf())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nockvm::noun::{D, T};
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_exported_state_round_trip() {
+        let dir = TempDir::new().expect("tempdir");
+        let mut slab = NounSlab::new();
+        let noun = T(&mut slab, &[D(1), D(2), D(3)]);
+        slab.set_root(noun);
+        let state = ExportedState {
+            magic_bytes: EXPORTED_STATE_MAGIC_BYTES,
+            version: EXPORTED_STATE_VERSION_1,
+            ker_hash: blake3::hash(b"kernel"),
+            event_num: 7,
+            jam: JammedNoun::new(slab.jam()),
+        };
+        for compression in [Compression::None, Compression::Zstd] {
+            let path = dir.path().join(format!("{:?}.jam", compression));
+            state.save_to_file(&path, compression).expect("save");
+            let loaded = ExportedState::load_from_file(&path).expect("load");
+            assert_eq!(loaded, state);
+        }
+
+        // Version 0 files are still readable
+        let v0 = ExportedState {
+            version: EXPORTED_STATE_VERSION_0,
+            ..state
+        };
+        let path = dir.path().join("v0.jam");
+        std::fs::write(
+            &path,
+            bincode::encode_to_vec(&v0, config::standard()).expect("encode"),
+        )
+        .expect("write");
+        assert_eq!(ExportedState::load_from_file(&path).expect("load"), v0);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_exported_state_rejects_bad_files() {
+        let dir = TempDir::new().expect("tempdir");
+        let mut slab = NounSlab::new();
+        let noun = T(&mut slab, &[D(1), D(2), D(3)]);
+        slab.set_root(noun);
+        let state = ExportedState {
+            magic_bytes: EXPORTED_STATE_MAGIC_BYTES,
+            version: EXPORTED_STATE_VERSION_1,
+            ker_hash: blake3::hash(b"kernel"),
+            event_num: 7,
+            jam: JammedNoun::new(slab.jam()),
+        };
+        let bytes = state.encode().expect("encode");
+        assert_eq!(ExportedState::decode(&bytes).expect("decode"), state);
+
+        // A truncated jam doesn't match the length in the header
+        let path = dir.path().join("truncated.jam");
+        std::fs::write(&path, &bytes[..bytes.len() - 1]).expect("write");
+        assert!(matches!(
+            ExportedState::load_from_file(&path),
+            Err(CheckpointError::InvalidJamLength(_))
+        ));
+
+        // A flipped bit in the jam fails the checksum
+        let mut corrupt = bytes.clone();
+        *corrupt.last_mut().expect("jam") ^= 1;
+        assert!(matches!(
+            ExportedState::decode(&corrupt),
+            Err(CheckpointError::InvalidChecksum(_))
+        ));
+    }
 }
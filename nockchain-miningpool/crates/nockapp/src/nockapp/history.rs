@@ -0,0 +1,420 @@
+//! Checkpoint history.
+//!
+//! The two checkpoint slots are overwritten in turn, so on their own they can't roll back a
+//! bad kernel upgrade or corrupted state. When a [HistoryRetention] is configured, saves
+//! also copy the state into `history/` under the checkpoint directory as a self-contained
+//! [JammedCheckpointV1] named `<unix seconds>-<event_num>.chkjam`, keeping either the
+//! last N saves or one per hour or day. With compression on, the whole file is zstd
+//! compressed; readers tell the two apart by the frame magic.
+//!
+//! [restore] puts a history checkpoint back in the slots so the next boot loads it, moving
+//! the state it replaces into `restore-backup/`.
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bincode::{config, decode_from_std_read, encode_into_std_write, Decode};
+use blake3::Hash;
+use tracing::{debug, info, warn};
+
+use crate::nockapp::delta::{pack_generations, ChunkPack};
+use crate::nockapp::journal::JOURNAL_FILE;
+use crate::save::{
+    open_checkpoint, upgraded_kernel, CheckpointError, Compression, JammedCheckpointV1,
+    SaveableCheckpoint, CHECKPOINT_FILE_0, CHECKPOINT_FILE_1,
+};
+use crate::JammedNoun;
+
+const HISTORY_DIR: &str = "history";
+const HISTORY_EXTENSION: &str = "chkjam";
+/// Where [restore] moves the state it replaces, in a directory per restore
+const BACKUP_DIR: &str = "restore-backup";
+/// Names the history checkpoint last restored, so a restore is only done once
+const RESTORE_MARKER: &str = "restored";
+const HOUR_SECS: u64 = 60 * 60;
+const DAY_SECS: u64 = 24 * HOUR_SECS;
+
+/// Which saves to keep in the checkpoint history.
+///
+/// Parsed from `last:N`, `hourly:N` or `daily:N`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HistoryRetention {
+    /// Every save, keeping the last N
+    Last(usize),
+    /// The first save in each hour, keeping the last N
+    Hourly(usize),
+    /// The first save in each day, keeping the last N
+    Daily(usize),
+}
+
+impl HistoryRetention {
+    fn keep(&self) -> usize {
+        match self {
+            Self::Last(keep) | Self::Hourly(keep) | Self::Daily(keep) => *keep,
+        }
+    }
+
+    /// Length in seconds of the period each kept checkpoint stands for
+    fn period(&self) -> Option<u64> {
+        match self {
+            Self::Last(_) => None,
+            Self::Hourly(_) => Some(HOUR_SECS),
+            Self::Daily(_) => Some(DAY_SECS),
+        }
+    }
+}
+
+impl FromStr for HistoryRetention {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, keep) = s
+            .split_once(':')
+            .ok_or_else(|| format!("expected last:N, hourly:N or daily:N, got {}", s))?;
+        let keep: usize = keep
+            .parse()
+            .map_err(|e| format!("invalid checkpoint count {}: {}", keep, e))?;
+        if keep == 0 {
+            return Err("checkpoint count must be at least 1".to_string());
+        }
+        match kind {
+            "last" => Ok(Self::Last(keep)),
+            "hourly" => Ok(Self::Hourly(keep)),
+            "daily" => Ok(Self::Daily(keep)),
+            _ => Err(format!("expected last, hourly or daily, got {}", kind)),
+        }
+    }
+}
+
+/// A checkpoint in the history
+#[derive(Clone, Debug, PartialEq)]
+pub struct HistoryEntry {
+    pub path: PathBuf,
+    pub event_num: u64,
+    pub ker_hash: Hash,
+    /// When it was saved, in seconds since the unix epoch
+    pub timestamp: u64,
+}
+
+/// The leading fields of a [JammedCheckpointV1], so listing doesn't read whole checkpoints
+#[derive(Decode)]
+struct JammedCheckpointV1Header {
+    _magic_bytes: u64,
+    _version: u32,
+    #[bincode(with_serde)]
+    ker_hash: Hash,
+    #[bincode(with_serde)]
+    _checksum: Hash,
+    event_num: u64,
+}
+
+/// The history for one checkpoint directory
+pub struct CheckpointHistory {
+    dir: PathBuf,
+    retention: HistoryRetention,
+}
+
+impl CheckpointHistory {
+    pub fn new(checkpoint_dir: &Path, retention: HistoryRetention) -> Self {
+        CheckpointHistory {
+            dir: history_dir(checkpoint_dir),
+            retention,
+        }
+    }
+
+    /// Add `checkpoint` to the history if the retention calls for it, then drop the
+    /// checkpoints which fall out of the retention.
+    pub fn record(
+        &self,
+        checkpoint: &SaveableCheckpoint,
+        compression: Compression,
+    ) -> Result<(), CheckpointError> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        self.record_at(checkpoint, now, compression)
+    }
+
+    fn record_at(
+        &self,
+        checkpoint: &SaveableCheckpoint,
+        now: u64,
+        compression: Compression,
+    ) -> Result<(), CheckpointError> {
+        std::fs::create_dir_all(&self.dir)?;
+        let entries = read_entries(&self.dir)?;
+        let due = match (self.retention.period(), entries.last()) {
+            (Some(period), Some(last)) => last.timestamp / period != now / period,
+            _ => true,
+        };
+        if due {
+            let jam = JammedNoun::new(checkpoint.noun.jam());
+            let jammed = JammedCheckpointV1::new(checkpoint.ker_hash, checkpoint.event_num, jam);
+            let path = self.dir.join(format!(
+                "{}-{}.{}",
+                now, checkpoint.event_num, HISTORY_EXTENSION
+            ));
+            // Write beside the final path and rename, so the history never holds a torn file
+            let tmp_path = path.with_extension("tmp");
+            let mut writer = compression.writer(BufWriter::new(File::create(&tmp_path)?))?;
+            encode_into_std_write(&jammed, &mut writer, config::standard())?;
+            let mut writer = writer.finish()?;
+            writer.flush()?;
+            writer.get_ref().sync_all()?;
+            std::fs::rename(&tmp_path, &path)?;
+            debug!(
+                "Recorded checkpoint at event_num {} in history: {}",
+                checkpoint.event_num,
+                path.display()
+            );
+        }
+
+        let entries = read_entries(&self.dir)?;
+        let excess = entries.len().saturating_sub(self.retention.keep());
+        for entry in &entries[..excess] {
+            debug!("Dropping checkpoint from history: {}", entry.path.display());
+            std::fs::remove_file(&entry.path)?;
+        }
+        Ok(())
+    }
+}
+
+fn history_dir(checkpoint_dir: &Path) -> PathBuf {
+    checkpoint_dir.join(HISTORY_DIR)
+}
+
+/// Every checkpoint in the history for `checkpoint_dir`, oldest first.
+pub fn list(checkpoint_dir: &Path) -> Result<Vec<HistoryEntry>, CheckpointError> {
+    let dir = history_dir(checkpoint_dir);
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    read_entries(&dir)
+}
+
+fn read_entries(dir: &Path) -> Result<Vec<HistoryEntry>, CheckpointError> {
+    let mut entries = Vec::new();
+    for dir_entry in std::fs::read_dir(dir)? {
+        let path = dir_entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some(HISTORY_EXTENSION) {
+            continue;
+        }
+        let Some(timestamp) = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.split_once('-'))
+            .and_then(|(timestamp, _)| timestamp.parse().ok())
+        else {
+            continue;
+        };
+        let header = open_checkpoint(&path)
+            .map_err(CheckpointError::from)
+            .and_then(|mut reader| {
+                Ok(decode_from_std_read::<JammedCheckpointV1Header, _, _>(
+                    &mut reader,
+                    config::standard(),
+                )?)
+            });
+        match header {
+            Ok(header) => entries.push(HistoryEntry {
+                path,
+                event_num: header.event_num,
+                ker_hash: header.ker_hash,
+                timestamp,
+            }),
+            Err(e) => warn!(
+                "Skipping unreadable history checkpoint {}: {}",
+                path.display(),
+                e
+            ),
+        }
+    }
+    entries.sort_by_key(|entry| (entry.timestamp, entry.event_num));
+    Ok(entries)
+}
+
+/// Make the newest history checkpoint at `event_num` the one the next boot loads.
+///
+/// The checkpoint is validated first, and the kernel it was saved under must be at hand:
+/// either `boot_ker_hash`, the kernel the binary boots, or one persisted by an upgrade. The
+/// current checkpoints, their chunk packs and the event journal are then moved into a new
+/// directory under `restore-backup/`, since none of them may be applied on top of it.
+///
+/// History checkpoints hold the state but not the kernel's timers, so the restored state
+/// starts with none scheduled.
+///
+/// The restore is recorded, so asking for the same one again, as a restart with the same
+/// arguments does, leaves the state alone and returns `None`.
+pub fn restore(
+    checkpoint_dir: &Path,
+    event_num: u64,
+    boot_ker_hash: &Hash,
+) -> Result<Option<HistoryEntry>, CheckpointError> {
+    let entry = list(checkpoint_dir)?
+        .into_iter()
+        .rev()
+        .find(|entry| entry.event_num == event_num)
+        .ok_or(CheckpointError::MissingHistoryCheckpoint(event_num))?;
+    let marker = checkpoint_dir.join(RESTORE_MARKER);
+    let name = entry
+        .path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    if std::fs::read_to_string(&marker).is_ok_and(|restored| restored == name) {
+        info!(
+            "Checkpoint at event_num {} was already restored from {}, not restoring it again",
+            event_num,
+            entry.path.display()
+        );
+        return Ok(None);
+    }
+    JammedCheckpointV1::load_from_file(&entry.path)?;
+    if entry.ker_hash != *boot_ker_hash
+        && upgraded_kernel(checkpoint_dir, &entry.ker_hash)?.is_none()
+    {
+        return Err(CheckpointError::MissingKernel(entry.ker_hash));
+    }
+
+    let slot_0 = checkpoint_dir.join(CHECKPOINT_FILE_0);
+    let tmp_path = slot_0.with_extension("tmp");
+    std::fs::copy(&entry.path, &tmp_path)?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    let backup = checkpoint_dir.join(BACKUP_DIR).join(now.to_string());
+    std::fs::create_dir_all(&backup)?;
+    let mut current: Vec<PathBuf> = [CHECKPOINT_FILE_0, CHECKPOINT_FILE_1, JOURNAL_FILE]
+        .iter()
+        .map(|name| checkpoint_dir.join(name))
+        .collect();
+    current.extend(
+        pack_generations(checkpoint_dir)?
+            .into_iter()
+            .map(|generation| ChunkPack::path(checkpoint_dir, generation)),
+    );
+    for path in current.iter().filter(|path| path.exists()) {
+        if let Some(file_name) = path.file_name() {
+            std::fs::rename(path, backup.join(file_name))?;
+        }
+    }
+    std::fs::rename(&tmp_path, &slot_0)?;
+    std::fs::write(&marker, &name)?;
+    info!(
+        "Restored checkpoint at event_num {} from {}, moved the previous state to {}",
+        event_num,
+        entry.path.display(),
+        backup.display()
+    );
+    Ok(Some(entry))
+}
+
+#[cfg(test)]
+mod tests {
+    use nockvm::noun::{D, T};
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::kernel::timers::TimerTable;
+    use crate::noun::slab::NounSlab;
+
+    fn checkpoint(event_num: u64) -> SaveableCheckpoint {
+        let mut noun = NounSlab::new();
+        let root = T(&mut noun, &[D(event_num), D(0)]);
+        noun.set_root(root);
+        SaveableCheckpoint {
+            ker_hash: blake3::hash(b"kernel"),
+            event_num,
+            noun,
+            timers: TimerTable::default(),
+        }
+    }
+
+    fn event_nums(dir: &Path) -> Vec<u64> {
+        list(dir)
+            .expect("list")
+            .iter()
+            .map(|entry| entry.event_num)
+            .collect()
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_history_retention() {
+        let dir = TempDir::new().expect("tempdir");
+        let last = CheckpointHistory::new(dir.path(), HistoryRetention::Last(2));
+        for event_num in 1..=3 {
+            last.record_at(&checkpoint(event_num), 100 + event_num, Compression::None)
+                .expect("record");
+        }
+        assert_eq!(event_nums(dir.path()), vec![2, 3]);
+
+        let dir = TempDir::new().expect("tempdir");
+        let hourly = CheckpointHistory::new(dir.path(), HistoryRetention::Hourly(2));
+        // Only the first save in each hour is kept
+        for (event_num, now) in [(1, 10), (2, 20), (3, HOUR_SECS + 5), (4, 2 * HOUR_SECS)] {
+            hourly
+                .record_at(&checkpoint(event_num), now, Compression::None)
+                .expect("record");
+        }
+        assert_eq!(event_nums(dir.path()), vec![3, 4]);
+
+        assert_eq!("daily:7".parse(), Ok(HistoryRetention::Daily(7)));
+        assert!("weekly:7".parse::<HistoryRetention>().is_err());
+        assert!("last:0".parse::<HistoryRetention>().is_err());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_history_restore() {
+        for compression in [Compression::None, Compression::Zstd] {
+            let dir = TempDir::new().expect("tempdir");
+            let history = CheckpointHistory::new(dir.path(), HistoryRetention::Last(5));
+            history
+                .record_at(&checkpoint(4), 100, compression)
+                .expect("record");
+            std::fs::write(dir.path().join(CHECKPOINT_FILE_1), b"newer state").expect("write");
+            std::fs::write(dir.path().join(JOURNAL_FILE), b"newer events").expect("write");
+
+            let boot = blake3::hash(b"kernel");
+            assert!(matches!(
+                restore(dir.path(), 5, &boot),
+                Err(CheckpointError::MissingHistoryCheckpoint(5))
+            ));
+            // A checkpoint saved under a kernel we don't have is left alone
+            assert!(matches!(
+                restore(dir.path(), 4, &blake3::hash(b"other kernel")),
+                Err(CheckpointError::MissingKernel(_))
+            ));
+            assert!(dir.path().join(CHECKPOINT_FILE_1).exists());
+
+            let entry = restore(dir.path(), 4, &boot)
+                .expect("restore")
+                .expect("restored");
+            assert_eq!(entry.ker_hash, boot);
+            assert!(!dir.path().join(CHECKPOINT_FILE_1).exists());
+            assert!(!dir.path().join(JOURNAL_FILE).exists());
+            let restored = JammedCheckpointV1::load_from_file(&dir.path().join(CHECKPOINT_FILE_0))
+                .expect("load");
+            assert_eq!(restored.event_num, 4);
+
+            // The replaced state is kept
+            let backups: Vec<PathBuf> = std::fs::read_dir(dir.path().join(BACKUP_DIR))
+                .expect("backups")
+                .map(|entry| entry.expect("backup").path())
+                .collect();
+            assert_eq!(backups.len(), 1);
+            assert_eq!(
+                std::fs::read(backups[0].join(JOURNAL_FILE)).expect("read"),
+                b"newer events"
+            );
+
+            // Restarting with the same restore boots what is there now
+            std::fs::write(dir.path().join(JOURNAL_FILE), b"later events").expect("write");
+            assert!(restore(dir.path(), 4, &boot).expect("restore").is_none());
+            assert!(dir.path().join(JOURNAL_FILE).exists());
+        }
+    }
+}
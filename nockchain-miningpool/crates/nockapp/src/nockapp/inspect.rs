@@ -0,0 +1,622 @@
+//! Looking inside jams, checkpoints and exported state files without booting a kernel.
+//!
+//! [InspectedFile::read] works out what kind of file it was given, decodes and validates its
+//! header, and hands back the jam it carries. The rest of this module works on the cued noun:
+//! rendering it to a depth limit, navigating it by axis, attributing its size to subtrees and
+//! diffing it against another noun. Axes are [UBig]s, since a large state is deeper than 64 bits
+//! of axis. This is the library half of the `noun-inspect` binary.
+use std::collections::HashSet;
+use std::fmt::{self, Display};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use bincode::{config, decode_from_slice, decode_from_std_read};
+use bytes::Bytes;
+use ibig::UBig;
+use nockvm::mem::NockStack;
+use nockvm::mug::met3_usize;
+use nockvm::noun::{Atom, Noun};
+use nockvm::serialization::cue;
+use thiserror::Error;
+
+use crate::export::{ExportedState, EXPORTED_STATE_MAGIC_BYTES};
+use crate::nockapp::delta::ChunkPack;
+use crate::noun::slab::NounSlab;
+use crate::save::{
+    open_checkpoint, DeltaCheckpointV2, DeltaCheckpointV3, JammedCheckpointV0, JammedCheckpointV1,
+    JAM_MAGIC_BYTES, SNAPSHOT_VERSION_0, SNAPSHOT_VERSION_1, SNAPSHOT_VERSION_2,
+    SNAPSHOT_VERSION_3,
+};
+use crate::AtomExt;
+
+/// Atoms longer than this many bytes are abbreviated when rendered
+const MAX_RENDERED_ATOM_BYTES: usize = 32;
+
+#[derive(Debug, Error)]
+pub enum InspectError {
+    #[error("IO error: {0}")]
+    IOError(#[from] std::io::Error),
+    #[error("{0} does not carry a jam: {1}")]
+    NoJam(String, String),
+    #[error("Could not cue the jam: {0:?}")]
+    Cue(nockvm::interpreter::Error),
+    #[error("Invalid axis {0}")]
+    InvalidAxis(String),
+    #[error("No noun at axis {0}")]
+    NoSuchAxis(UBig),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    Checkpoint,
+    ExportedState,
+    /// Anything else is taken to be a bare jam
+    Jam,
+}
+
+impl Display for FileKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FileKind::Checkpoint => write!(f, "checkpoint"),
+            FileKind::ExportedState => write!(f, "exported state"),
+            FileKind::Jam => write!(f, "jam"),
+        }
+    }
+}
+
+/// A file as read from disk, before its jam is cued
+pub struct InspectedFile {
+    pub kind: FileKind,
+    /// Header fields in file order, as name and value
+    pub header: Vec<(&'static str, String)>,
+    /// Why the file failed to decode or validate, if it did
+    pub invalid: Option<String>,
+    /// Why there is no jam, if there isn't one
+    pub missing: Option<String>,
+    pub jam: Option<Bytes>,
+}
+
+impl InspectedFile {
+    /// Read the file at `path`. A corrupt file still reads, with as much of its header as could
+    /// be decoded and the failure in [InspectedFile::invalid]. A delta checkpoint's state is
+    /// rebuilt from the chunk pack beside it and jammed.
+    pub fn read(path: &Path) -> Result<Self, InspectError> {
+        // History checkpoints may be compressed whole
+        let magic = decode_from_std_read::<(u64, u32), _, _>(
+            &mut open_checkpoint(path)?,
+            config::standard(),
+        );
+        match magic {
+            Ok((JAM_MAGIC_BYTES, version)) => {
+                let mut bytes = Vec::new();
+                open_checkpoint(path)?.read_to_end(&mut bytes)?;
+                Ok(Self::checkpoint(path, version, &bytes))
+            }
+            Ok((EXPORTED_STATE_MAGIC_BYTES, version)) => Ok(Self::exported_state(path, version)),
+            _ => {
+                let mut bytes = Vec::new();
+                File::open(path)?.read_to_end(&mut bytes)?;
+                let jam = Bytes::from(bytes);
+                Ok(InspectedFile {
+                    kind: FileKind::Jam,
+                    header: vec![("jam bytes", jam.len().to_string())],
+                    invalid: None,
+                    missing: None,
+                    jam: Some(jam),
+                })
+            }
+        }
+    }
+
+    fn checkpoint(path: &Path, version: u32, bytes: &[u8]) -> Self {
+        let path = path.to_path_buf();
+        let mut file = InspectedFile {
+            kind: FileKind::Checkpoint,
+            header: vec![("magic", "CHKJAM".into()), ("version", version.to_string())],
+            invalid: None,
+            missing: None,
+            jam: None,
+        };
+        match version {
+            SNAPSHOT_VERSION_0 => {
+                match decode_from_slice::<JammedCheckpointV0, _>(bytes, config::standard()) {
+                    Ok((checkpoint, _)) => {
+                        file.header.extend([
+                            ("buffer", (checkpoint.buff_index as u8).to_string()),
+                            ("kernel hash", checkpoint.ker_hash.to_string()),
+                            ("checksum", checkpoint.checksum.to_string()),
+                            ("event", checkpoint.event_num.to_string()),
+                            ("jam bytes", checkpoint.jam.0.len().to_string()),
+                        ]);
+                        file.invalid = checkpoint.validate(&path).err().map(|e| e.to_string());
+                        file.jam = Some(checkpoint.jam.0);
+                    }
+                    Err(e) => file.invalid = Some(e.to_string()),
+                }
+            }
+            SNAPSHOT_VERSION_1 => {
+                match decode_from_slice::<JammedCheckpointV1, _>(bytes, config::standard()) {
+                    Ok((checkpoint, _)) => {
+                        file.header.extend([
+                            ("kernel hash", checkpoint.ker_hash.to_string()),
+                            ("checksum", checkpoint.checksum.to_string()),
+                            ("event", checkpoint.event_num.to_string()),
+                            ("jam bytes", checkpoint.jam.0.len().to_string()),
+                        ]);
+                        file.invalid = checkpoint.validate(&path).err().map(|e| e.to_string());
+                        file.jam = Some(checkpoint.jam.0);
+                    }
+                    Err(e) => file.invalid = Some(e.to_string()),
+                }
+            }
+            SNAPSHOT_VERSION_2 | SNAPSHOT_VERSION_3 => {
+                let decoded = if version == SNAPSHOT_VERSION_2 {
+                    decode_from_slice::<DeltaCheckpointV2, _>(bytes, config::standard()).map(
+                        |(checkpoint, _)| {
+                            let invalid = checkpoint.validate(&path).err();
+                            (DeltaCheckpointV3::from(checkpoint), invalid)
+                        },
+                    )
+                } else {
+                    decode_from_slice::<DeltaCheckpointV3, _>(bytes, config::standard()).map(
+                        |(checkpoint, _)| {
+                            let invalid = checkpoint.validate(&path).err();
+                            (checkpoint, invalid)
+                        },
+                    )
+                };
+                match decoded {
+                    Ok((checkpoint, invalid)) => {
+                        file.header.extend([
+                            ("kernel hash", checkpoint.ker_hash.to_string()),
+                            ("checksum", checkpoint.checksum.to_string()),
+                            ("event", checkpoint.event_num.to_string()),
+                            ("pack", checkpoint.pack.to_string()),
+                            ("compression", format!("{:?}", checkpoint.compression)),
+                            (
+                                "root chunk",
+                                blake3::Hash::from(checkpoint.root).to_string(),
+                            ),
+                            ("timers", checkpoint.timers.len().to_string()),
+                        ]);
+                        file.invalid = invalid.map(|e| e.to_string());
+                        // The state is in the chunk pack beside the checkpoint
+                        let dir = path.parent().unwrap_or(Path::new(""));
+                        let rebuilt =
+                            ChunkPack::open_read_only(dir, checkpoint.pack, checkpoint.compression)
+                                .and_then(|pack| {
+                                    let mut slab = NounSlab::new();
+                                    let root = pack.rebuild(checkpoint.root, &mut slab)?;
+                                    slab.set_root(root);
+                                    Ok(slab.jam())
+                                });
+                        match rebuilt {
+                            Ok(jam) => {
+                                file.header.push(("jam bytes", jam.len().to_string()));
+                                file.jam = Some(jam);
+                            }
+                            Err(e) => {
+                                file.missing = Some(format!(
+                                    "its state could not be rebuilt from {}: {}",
+                                    ChunkPack::path(dir, checkpoint.pack).display(),
+                                    e
+                                ))
+                            }
+                        }
+                    }
+                    Err(e) => file.invalid = Some(e.to_string()),
+                }
+            }
+            _ => file.invalid = Some(format!("unknown checkpoint version {version}")),
+        }
+        file
+    }
+
+    fn exported_state(path: &Path, version: u32) -> Self {
+        let mut file = InspectedFile {
+            kind: FileKind::ExportedState,
+            header: vec![("magic", "EXPJAM".into()), ("version", version.to_string())],
+            invalid: None,
+            missing: None,
+            jam: None,
+        };
+        match ExportedState::load_from_file(path) {
+            Ok(state) => {
+                file.header.extend([
+                    ("kernel hash", state.ker_hash.to_string()),
+                    ("event", state.event_num.to_string()),
+                    ("jam bytes", state.jam.0.len().to_string()),
+                ]);
+                file.jam = Some(state.jam.0);
+            }
+            Err(e) => file.invalid = Some(e.to_string()),
+        }
+        file
+    }
+
+    /// Cue the jam onto `stack`
+    pub fn cue(&self, stack: &mut NockStack, path: &Path) -> Result<Noun, InspectError> {
+        let Some(jam) = &self.jam else {
+            let why = self
+                .missing
+                .clone()
+                .or_else(|| self.invalid.clone())
+                .unwrap_or_default();
+            return Err(InspectError::NoJam(path.display().to_string(), why));
+        };
+        let atom = Atom::from_bytes(stack, jam);
+        cue(stack, atom).map_err(InspectError::Cue)
+    }
+}
+
+/// Parse an axis written in decimal
+pub fn parse_axis(axis: &str) -> Result<UBig, InspectError> {
+    match axis.parse::<UBig>() {
+        Ok(parsed) if parsed != UBig::from(0u8) => Ok(parsed),
+        _ => Err(InspectError::InvalidAxis(axis.to_string())),
+    }
+}
+
+/// The noun at `axis` of `noun`
+pub fn slot(noun: Noun, axis: &UBig) -> Result<Noun, InspectError> {
+    let missing = || InspectError::NoSuchAxis(axis.clone());
+    if *axis == UBig::from(0u8) {
+        return Err(missing());
+    }
+    let mut noun = noun;
+    // Below the leading 1, each bit of the axis picks the head (0) or the tail (1)
+    for bit in (0..axis.bit_len() - 1).rev() {
+        let cell = noun.as_cell().map_err(|_| missing())?;
+        noun = if axis.bit(bit) {
+            cell.tail()
+        } else {
+            cell.head()
+        };
+    }
+    Ok(noun)
+}
+
+/// Size of a noun in memory, counting sub-nouns it shares with itself once
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Mass {
+    pub cells: usize,
+    /// Atoms too large to store inline, which take memory of their own
+    pub indirect_atoms: usize,
+    pub words: usize,
+}
+
+impl Mass {
+    pub fn bytes(&self) -> usize {
+        self.words * 8
+    }
+}
+
+/// Measure `noun`, without recursing so that deep nouns can't overflow the stack
+pub fn mass(noun: Noun) -> Mass {
+    let mut mass = Mass::default();
+    let mut seen: HashSet<u64> = HashSet::new();
+    let mut todo = vec![noun];
+    while let Some(noun) = todo.pop() {
+        if noun.is_direct() || !seen.insert(unsafe { noun.as_raw() }) {
+            continue;
+        }
+        if let Ok(cell) = noun.as_cell() {
+            mass.cells += 1;
+            mass.words += 3;
+            todo.push(cell.tail());
+            todo.push(cell.head());
+        } else if let Ok(indirect) = noun.as_indirect() {
+            mass.indirect_atoms += 1;
+            mass.words += indirect.size() + 2;
+        }
+    }
+    mass
+}
+
+/// The mass of one subtree, at `axis` of the noun it was attributed from
+#[derive(Debug, Clone)]
+pub struct Subtree {
+    pub axis: UBig,
+    /// How far below the root the subtree is
+    pub depth: usize,
+    pub mass: Mass,
+}
+
+/// The mass of `noun` and of each of its subtrees down to `depth` levels below it, in
+/// depth-first order. Atoms which take no memory are left out.
+pub fn attribute(noun: Noun, depth: usize) -> Vec<Subtree> {
+    let mut subtrees = Vec::new();
+    let mut todo = vec![(UBig::from(1u8), 0, noun)];
+    while let Some((axis, level, noun)) = todo.pop() {
+        let noun_mass = mass(noun);
+        if noun_mass.words == 0 {
+            continue;
+        }
+        if level < depth {
+            if let Ok(cell) = noun.as_cell() {
+                let head = &axis << 1;
+                let tail = &head + UBig::from(1u8);
+                todo.push((tail, level + 1, cell.tail()));
+                todo.push((head, level + 1, cell.head()));
+            }
+        }
+        subtrees.push(Subtree {
+            axis,
+            depth: level,
+            mass: noun_mass,
+        });
+    }
+    subtrees
+}
+
+/// A place where two nouns differ
+#[derive(Debug, Clone)]
+pub struct Difference {
+    pub axis: UBig,
+    pub left: Noun,
+    pub right: Noun,
+}
+
+/// The axes where `left` and `right` differ, outermost first, up to `max` of them. Two cells
+/// differ where their heads or tails do, so only atoms, and a cell against an atom, are reported.
+pub fn diff(left: Noun, right: Noun, max: usize) -> Vec<Difference> {
+    let mut differences = Vec::new();
+    let mut todo = vec![(UBig::from(1u8), left, right)];
+    while let Some((axis, left, right)) = todo.pop() {
+        if differences.len() >= max {
+            break;
+        }
+        if unsafe { left.raw_equals(&right) } {
+            continue;
+        }
+        match (left.as_cell(), right.as_cell()) {
+            (Ok(left_cell), Ok(right_cell)) => {
+                let head = &axis << 1;
+                let tail = &head + UBig::from(1u8);
+                todo.push((tail, left_cell.tail(), right_cell.tail()));
+                todo.push((head, left_cell.head(), right_cell.head()));
+            }
+            (Err(_), Err(_)) => {
+                let (left_atom, right_atom) = (left.as_atom(), right.as_atom());
+                if let (Ok(l), Ok(r)) = (left_atom, right_atom) {
+                    if l.as_ne_bytes() != r.as_ne_bytes() {
+                        differences.push(Difference { axis, left, right });
+                    }
+                }
+            }
+            _ => differences.push(Difference { axis, left, right }),
+        }
+    }
+    differences
+}
+
+/// Render `noun` in Hoon-like syntax, showing cells nested at most `depth` deep and at most
+/// `width` items of each tuple or list. Whatever is left out is shown as `…`.
+pub fn render(noun: Noun, depth: usize, width: usize) -> String {
+    let mut out = String::new();
+    render_into(&mut out, noun, depth, width);
+    out
+}
+
+fn render_into(out: &mut String, noun: Noun, depth: usize, width: usize) {
+    let Ok(mut cell) = noun.as_cell() else {
+        if let Ok(atom) = noun.as_atom() {
+            out.push_str(&render_atom(atom));
+        }
+        return;
+    };
+    if depth == 0 {
+        out.push_str("[…]");
+        return;
+    }
+    out.push('[');
+    let mut items = 0;
+    loop {
+        if items == width {
+            out.push('…');
+            break;
+        }
+        render_into(out, cell.head(), depth - 1, width);
+        items += 1;
+        out.push(' ');
+        match cell.tail().as_cell() {
+            // Right-nested cells print as one tuple, so lists print flat
+            Ok(tail) => cell = tail,
+            Err(_) => {
+                render_into(out, cell.tail(), depth - 1, width);
+                break;
+            }
+        }
+    }
+    out.push(']');
+}
+
+/// Render an atom as a `%term`, a `'cord'` or a number, guessing from its bytes. Atoms
+/// longer than [MAX_RENDERED_ATOM_BYTES] are cut short with `…`.
+pub fn render_atom(atom: Atom) -> String {
+    let len = met3_usize(atom);
+    let bytes = &atom.as_ne_bytes()[..len];
+    let (text, more) = if len > MAX_RENDERED_ATOM_BYTES {
+        (&bytes[..MAX_RENDERED_ATOM_BYTES], "…")
+    } else {
+        (bytes, "")
+    };
+    let is_term = len >= 2
+        && bytes[0].is_ascii_lowercase()
+        && bytes
+            .iter()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || *b == b'-');
+    if is_term {
+        return format!("%{}{more}", String::from_utf8_lossy(text));
+    }
+    let is_cord = len >= 4
+        && bytes
+            .iter()
+            .all(|b| (0x20..0x7f).contains(b) && *b != b'\'');
+    if is_cord {
+        return format!("'{}{more}'", String::from_utf8_lossy(text));
+    }
+    if let Ok(direct) = atom.as_direct() {
+        return direct.data().to_string();
+    }
+    if len <= MAX_RENDERED_ATOM_BYTES {
+        return UBig::from_le_bytes(bytes).to_string();
+    }
+    let top: String = bytes
+        .iter()
+        .rev()
+        .take(8)
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    format!("<{len}-byte atom 0x{top}…>")
+}
+
+#[cfg(test)]
+mod tests {
+    use nockvm::noun::{D, T};
+    use nockvm_macros::tas;
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::noun::slab::NounSlab;
+    use crate::save::Compression;
+    use crate::JammedNoun;
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_render_and_slot() {
+        let mut stack = NockStack::new(1 << 20, 0);
+        let list = T(&mut stack, &[D(1), D(2), D(3), D(0)]);
+        let noun = T(&mut stack, &[D(tas!(b"foo")), list, D(4)]);
+        assert_eq!(render(noun, 4, 16), "[%foo [1 2 3 0] 4]");
+        assert_eq!(render(noun, 1, 16), "[%foo […] 4]");
+        assert_eq!(render(noun, 4, 2), "[%foo [1 2 …] …]");
+
+        // The list is at 6 and its second item at 6 of that, so 26 of the whole
+        let axis = parse_axis("26").expect("axis");
+        assert!(unsafe { slot(noun, &axis).expect("slot").raw_equals(&D(2)) });
+        assert!(matches!(
+            slot(noun, &parse_axis("14").expect("axis")),
+            Err(InspectError::NoSuchAxis(_))
+        ));
+        assert!(parse_axis("0").is_err());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_render_atom_is_bounded() {
+        let mut stack = NockStack::new(1 << 20, 0);
+        let cap = MAX_RENDERED_ATOM_BYTES;
+        let term = Atom::from_value(&mut stack, "a".repeat(cap + 10)).expect("term");
+        assert_eq!(render_atom(term), format!("%{}…", "a".repeat(cap)));
+        let cord = Atom::from_value(&mut stack, "A ".repeat(cap)).expect("cord");
+        assert_eq!(render_atom(cord), format!("'{}…'", "A ".repeat(cap / 2)));
+        let short = Atom::from_value(&mut stack, "hello world").expect("cord");
+        assert_eq!(render_atom(short), "'hello world'");
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_mass_diff_and_attribute() {
+        let mut stack = NockStack::new(1 << 20, 0);
+        let shared = T(&mut stack, &[D(1), D(2)]);
+        let left = T(&mut stack, &[shared, shared, D(3)]);
+        // Sharing counts once: three cells, 9 words
+        assert_eq!(
+            mass(left),
+            Mass {
+                cells: 3,
+                indirect_atoms: 0,
+                words: 9
+            }
+        );
+        let attribution = attribute(left, 1);
+        let axes: Vec<String> = attribution.iter().map(|s| s.axis.to_string()).collect();
+        assert_eq!(axes, ["1", "2", "3"]);
+        assert_eq!(attribution[2].mass.words, 6);
+
+        let changed = T(&mut stack, &[D(1), D(5)]);
+        let right = T(&mut stack, &[shared, changed, D(3), D(4)]);
+        let differences = diff(left, right, 10);
+        let axes: Vec<String> = differences.iter().map(|d| d.axis.to_string()).collect();
+        assert_eq!(axes, ["13", "7"]);
+        assert_eq!(diff(left, right, 1).len(), 1);
+        assert!(diff(left, left, 10).is_empty());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_inspect_files() {
+        let dir = TempDir::new().expect("tempdir");
+        let mut slab = NounSlab::new();
+        let noun = T(&mut slab, &[D(1), D(2), D(3)]);
+        slab.set_root(noun);
+        let jam = JammedNoun::new(slab.jam());
+        let mut stack = NockStack::new(1 << 20, 0);
+
+        let path = dir.path().join("state.jam");
+        std::fs::write(&path, &jam.0).expect("write jam");
+        let file = InspectedFile::read(&path).expect("read jam");
+        assert_eq!(file.kind, FileKind::Jam);
+        let cued = file.cue(&mut stack, &path).expect("cue");
+        assert_eq!(render(cued, 4, 16), "[1 2 3]");
+
+        let checkpoint = JammedCheckpointV1::new(blake3::hash(b"kernel"), 7, jam.clone());
+        let path = dir.path().join("0.chkjam");
+        std::fs::write(&path, checkpoint.encode().expect("encode")).expect("write");
+        let file = InspectedFile::read(&path).expect("read checkpoint");
+        assert_eq!(file.kind, FileKind::Checkpoint);
+        assert!(file.invalid.is_none());
+        assert!(file.header.contains(&("event", "7".into())));
+        assert!(file.cue(&mut stack, &path).is_ok());
+
+        // A bad checksum is reported, but the jam can still be looked at
+        let mut corrupt = checkpoint;
+        corrupt.event_num = 8;
+        std::fs::write(&path, corrupt.encode().expect("encode")).expect("write");
+        let file = InspectedFile::read(&path).expect("read corrupt checkpoint");
+        assert!(file.invalid.is_some());
+        assert!(file.cue(&mut stack, &path).is_ok());
+
+        let state = ExportedState {
+            magic_bytes: EXPORTED_STATE_MAGIC_BYTES,
+            version: 1,
+            ker_hash: blake3::hash(b"kernel"),
+            event_num: 7,
+            jam,
+        };
+        let path = dir.path().join("export.jam");
+        state
+            .save_to_file(&path, Compression::Zstd)
+            .expect("save export");
+        let file = InspectedFile::read(&path).expect("read export");
+        assert_eq!(file.kind, FileKind::ExportedState);
+        assert!(file.cue(&mut stack, &path).is_ok());
+
+        // A delta checkpoint is rebuilt from the pack beside it
+        let mut pack = ChunkPack::create(dir.path(), 3, Compression::Zstd).expect("create pack");
+        let root = pack
+            .write_noun(unsafe { *slab.root() })
+            .expect("write pack");
+        let checkpoint =
+            DeltaCheckpointV2::new(blake3::hash(b"kernel"), 9, 3, Compression::Zstd, root);
+        let path = dir.path().join("1.chkjam");
+        std::fs::write(&path, checkpoint.encode().expect("encode")).expect("write");
+        let file = InspectedFile::read(&path).expect("read delta checkpoint");
+        assert_eq!(file.kind, FileKind::Checkpoint);
+        assert!(file.invalid.is_none());
+        assert!(file.header.contains(&("pack", "3".into())));
+        let cued = file.cue(&mut stack, &path).expect("cue delta checkpoint");
+        assert_eq!(render(cued, 4, 16), "[1 2 3]");
+
+        // Without its pack there is nothing to cue
+        std::fs::remove_file(ChunkPack::path(dir.path(), 3)).expect("remove pack");
+        let file = InspectedFile::read(&path).expect("read delta checkpoint");
+        assert!(file.missing.is_some());
+        assert!(matches!(
+            file.cue(&mut stack, &path),
+            Err(InspectError::NoJam(_, _))
+        ));
+    }
+}
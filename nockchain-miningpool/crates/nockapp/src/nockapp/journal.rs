@@ -0,0 +1,379 @@
+//! Append-only event journal.
+//!
+//! Every poke the kernel accepts is appended here as `(event_num, wire, jammed cause)`,
+//! with the entropy and time it ran with, before it is acked, so the events processed
+//! since the last checkpoint survive a crash. A poke which can't be journaled is nacked,
+//! and since the kernel has already applied it, the journal refuses every later append
+//! and the NockApp shuts down rather than run on past the gap. On boot the entries after
+//! the newest checkpoint are replayed on top of it with their original inputs, up to the
+//! first gap, and anything past the gap is dropped from the file. After each successful
+//! save the entries the older checkpoint covers are dropped.
+//!
+//! Appends are only flushed to the OS by default; [EventJournal::set_sync] makes each
+//! append wait for the data to reach the disk, which also survives power loss.
+//!
+//! Each record is framed as `[len: u64 LE][blake3 of payload: 32 bytes][payload]`, where
+//! the payload is a bincode [JournalEntry]. A torn or corrupt record ends the journal;
+//! the file is cut back to the last good record when it is opened.
+use std::collections::BTreeSet;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+
+use bincode::{config, decode_from_slice, encode_to_vec, Decode, Encode};
+use bytes::Bytes;
+use tracing::{debug, warn};
+
+use crate::kernel::form::PokeInputs;
+use crate::nockapp::save::CheckpointError;
+use crate::nockapp::wire::{WireRepr, WireTag};
+use crate::JammedNoun;
+
+pub(crate) const JOURNAL_FILE: &str = "events.jnl";
+const FRAME_HEADER_LEN: usize = 8 + 32;
+
+#[derive(Encode, Decode, Clone, Debug, PartialEq, Eq)]
+enum JournalWireTag {
+    Direct(u64),
+    String(String),
+}
+
+/// An owned [WireRepr], which can be written to disk.
+#[derive(Encode, Decode, Clone, Debug, PartialEq, Eq)]
+pub struct JournalWire {
+    source: String,
+    version: u64,
+    tags: Vec<JournalWireTag>,
+}
+
+impl From<&WireRepr> for JournalWire {
+    fn from(wire: &WireRepr) -> Self {
+        let tags = wire
+            .tags
+            .iter()
+            .map(|tag| match tag {
+                WireTag::Direct(d) => JournalWireTag::Direct(*d),
+                WireTag::String(s) => JournalWireTag::String(s.clone()),
+            })
+            .collect();
+        JournalWire {
+            source: wire.source.to_string(),
+            version: wire.version,
+            tags,
+        }
+    }
+}
+
+impl JournalWire {
+    pub fn to_wire(&self) -> WireRepr {
+        let tags = self
+            .tags
+            .iter()
+            .map(|tag| match tag {
+                JournalWireTag::Direct(d) => WireTag::Direct(*d),
+                JournalWireTag::String(s) => WireTag::String(s.clone()),
+            })
+            .collect();
+        WireRepr::new(intern_source(&self.source), self.version, tags)
+    }
+}
+
+/// [WireRepr::source] is `&'static str`. Wire sources are a handful of driver names, so
+/// leaking each distinct one once is fine.
+fn intern_source(source: &str) -> &'static str {
+    static SOURCES: OnceLock<Mutex<BTreeSet<&'static str>>> = OnceLock::new();
+    let mut sources = SOURCES
+        .get_or_init(Default::default)
+        .lock()
+        .expect("wire source table poisoned");
+    if let Some(interned) = sources.get(source) {
+        return *interned;
+    }
+    let interned: &'static str = Box::leak(source.to_string().into_boxed_str());
+    sources.insert(interned);
+    interned
+}
+
+/// A poke the kernel accepted as event `event_num`.
+#[derive(Encode, Decode, Clone, Debug, PartialEq)]
+pub struct JournalEntry {
+    pub event_num: u64,
+    pub wire: JournalWire,
+    pub cause: JammedNoun,
+    pub inputs: PokeInputs,
+}
+
+impl JournalEntry {
+    pub fn new(event_num: u64, wire: &WireRepr, cause: Bytes, inputs: PokeInputs) -> Self {
+        JournalEntry {
+            event_num,
+            wire: wire.into(),
+            cause: JammedNoun::new(cause),
+            inputs,
+        }
+    }
+
+    fn frame(&self) -> Result<Vec<u8>, CheckpointError> {
+        let payload = encode_to_vec(self, config::standard())?;
+        let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+        frame.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+        frame.extend_from_slice(blake3::hash(&payload).as_bytes());
+        frame.extend_from_slice(&payload);
+        Ok(frame)
+    }
+}
+
+/// The event journal for a NockApp data directory.
+pub struct EventJournal {
+    path: PathBuf,
+    file: File,
+    sync: bool,
+    /// Set once an append fails, after which the journal has a gap and takes no more entries
+    failed: bool,
+}
+
+impl EventJournal {
+    /// Open (or create) the journal in `dir`, returning every intact entry in it.
+    pub fn open(dir: &Path) -> Result<(Self, Vec<JournalEntry>), CheckpointError> {
+        let path = dir.join(JOURNAL_FILE);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+        let (entries, good_len) = read_entries(&bytes);
+        if good_len < bytes.len() {
+            warn!(
+                "Dropping {} bytes of torn or corrupt records from the end of {}",
+                bytes.len() - good_len,
+                path.display()
+            );
+            file.set_len(good_len as u64)?;
+        }
+        debug!(
+            "Opened event journal at {} with {} entries",
+            path.display(),
+            entries.len()
+        );
+        let journal = EventJournal {
+            path,
+            file,
+            sync: false,
+            failed: false,
+        };
+        Ok((journal, entries))
+    }
+
+    /// Wait for each append to reach the disk with `sync_data` before returning
+    pub fn set_sync(&mut self, sync: bool) {
+        self.sync = sync;
+    }
+
+    /// Append an entry. This must complete before the poke is acked. Once an append has
+    /// failed, every later one fails too, so nothing is journaled past the missing event.
+    pub fn append(&mut self, entry: &JournalEntry) -> Result<(), CheckpointError> {
+        if self.failed {
+            return Err(CheckpointError::IOError(std::io::Error::other(
+                "event journal stopped after a failed append",
+            )));
+        }
+        let res = entry.frame().and_then(|frame| {
+            self.file.write_all(&frame)?;
+            if self.sync {
+                self.file.sync_data()?;
+            }
+            Ok(())
+        });
+        self.failed = res.is_err();
+        res
+    }
+
+    /// Drop every entry up to and including `event_num`, which a checkpoint now covers.
+    pub fn truncate_through(&mut self, event_num: u64) -> Result<(), CheckpointError> {
+        let mut bytes = Vec::new();
+        File::open(&self.path)?.read_to_end(&mut bytes)?;
+        let (mut entries, _) = read_entries(&bytes);
+        entries.retain(|e| e.event_num > event_num);
+        self.rewrite(&entries)?;
+        debug!(
+            "Truncated event journal through event {}, {} entries kept",
+            event_num,
+            entries.len()
+        );
+        Ok(())
+    }
+
+    /// Replace the journal with just `entries`.
+    pub fn rewrite(&mut self, entries: &[JournalEntry]) -> Result<(), CheckpointError> {
+        let mut kept = Vec::new();
+        for entry in entries {
+            kept.extend_from_slice(&entry.frame()?);
+        }
+
+        // Write the kept entries beside the journal and swap it in, so a crash part way
+        // through leaves either the old journal or the new one.
+        let tmp_path = self.path.with_extension("jnl.tmp");
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(&kept)?;
+        tmp.sync_all()?;
+        std::fs::rename(&tmp_path, &self.path)?;
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        Ok(())
+    }
+}
+
+/// Entries which should be replayed on top of a checkpoint at `event_num`: the run of
+/// consecutive events starting at `event_num + 1`. An event number journaled more than once
+/// was reused after a restart, so the entry appended last wins.
+pub fn entries_to_replay(mut entries: Vec<JournalEntry>, event_num: u64) -> Vec<JournalEntry> {
+    entries.retain(|e| e.event_num > event_num);
+    // The sort is stable, so reversing first puts the last appended entry of each event first
+    entries.reverse();
+    entries.sort_by_key(|e| e.event_num);
+    entries.dedup_by_key(|e| e.event_num);
+    let mut replay = Vec::with_capacity(entries.len());
+    for (expected, entry) in (event_num + 1..).zip(entries) {
+        if entry.event_num != expected {
+            warn!(
+                "Event journal is missing event {}, not replaying from event {}",
+                expected, entry.event_num
+            );
+            break;
+        }
+        replay.push(entry);
+    }
+    replay
+}
+
+/// Decode the intact records at the start of `bytes`, returning them and their length.
+fn read_entries(bytes: &[u8]) -> (Vec<JournalEntry>, usize) {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    while bytes.len() - offset >= FRAME_HEADER_LEN {
+        let header = &bytes[offset..offset + FRAME_HEADER_LEN];
+        let len = u64::from_le_bytes(header[..8].try_into().expect("8 byte length")) as usize;
+        let Some(payload) = bytes
+            .get(offset + FRAME_HEADER_LEN..)
+            .and_then(|rest| rest.get(..len))
+        else {
+            break;
+        };
+        if blake3::hash(payload).as_bytes() != &header[8..] {
+            break;
+        }
+        match decode_from_slice::<JournalEntry, _>(payload, config::standard()) {
+            Ok((entry, _)) => entries.push(entry),
+            Err(_) => break,
+        }
+        offset += FRAME_HEADER_LEN + len;
+    }
+    (entries, offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn entry(event_num: u64) -> JournalEntry {
+        let wire = WireRepr::new("test", 1, vec!["poke".into(), event_num.into()]);
+        let inputs = PokeInputs {
+            eny: event_num * 7,
+            now: event_num as u128,
+        };
+        JournalEntry::new(
+            event_num,
+            &wire,
+            Bytes::from(vec![event_num as u8; 4]),
+            inputs,
+        )
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_journal_append_truncate_reopen() {
+        let dir = TempDir::new().expect("tempdir");
+        let (mut journal, entries) = EventJournal::open(dir.path()).expect("open");
+        assert!(entries.is_empty());
+        journal.set_sync(true);
+        for event_num in 1..=5 {
+            journal.append(&entry(event_num)).expect("append");
+        }
+        journal.truncate_through(3).expect("truncate");
+        journal.append(&entry(6)).expect("append");
+        drop(journal);
+
+        let (_, entries) = EventJournal::open(dir.path()).expect("reopen");
+        assert_eq!(entries, vec![entry(4), entry(5), entry(6)]);
+        assert_eq!(
+            entries[0].wire.to_wire(),
+            WireRepr::new("test", 1, vec!["poke".into(), 4u64.into()])
+        );
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_journal_torn_tail() {
+        let dir = TempDir::new().expect("tempdir");
+        let (mut journal, _) = EventJournal::open(dir.path()).expect("open");
+        journal.append(&entry(1)).expect("append");
+        journal.append(&entry(2)).expect("append");
+        drop(journal);
+
+        // Simulate a crash part way through writing event 3
+        let path = dir.path().join(JOURNAL_FILE);
+        let frame = entry(3).frame().expect("frame");
+        let mut file = OpenOptions::new().append(true).open(&path).expect("open");
+        file.write_all(&frame[..frame.len() - 3]).expect("write");
+        drop(file);
+
+        let (mut journal, entries) = EventJournal::open(dir.path()).expect("reopen");
+        assert_eq!(entries, vec![entry(1), entry(2)]);
+        journal.append(&entry(3)).expect("append");
+        drop(journal);
+        let (_, entries) = EventJournal::open(dir.path()).expect("reopen");
+        assert_eq!(entries, vec![entry(1), entry(2), entry(3)]);
+    }
+
+    #[test]
+    fn test_entries_to_replay_stops_at_gap() {
+        let entries = vec![entry(5), entry(2), entry(3), entry(3), entry(1)];
+        let replay = entries_to_replay(entries, 1);
+        let nums: Vec<u64> = replay.iter().map(|e| e.event_num).collect();
+        assert_eq!(nums, vec![2, 3]);
+    }
+
+    #[test]
+    fn test_entries_to_replay_prefers_last_appended() {
+        // Event 3 was journaled past a gap, then reused after a restart
+        let mut reused = entry(3);
+        reused.inputs.eny = 0;
+        let entries = vec![entry(1), entry(3), entry(2), reused.clone()];
+        let replay = entries_to_replay(entries, 0);
+        assert_eq!(replay, vec![entry(1), entry(2), reused]);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_journal_stops_after_failed_append() {
+        let dir = TempDir::new().expect("tempdir");
+        let (mut journal, _) = EventJournal::open(dir.path()).expect("open");
+        journal.append(&entry(1)).expect("append");
+        // Appending to a file opened read-only fails like a full disk would
+        journal.file = File::open(dir.path().join(JOURNAL_FILE)).expect("open read-only");
+        assert!(journal.append(&entry(2)).is_err());
+        journal.file = OpenOptions::new()
+            .append(true)
+            .open(dir.path().join(JOURNAL_FILE))
+            .expect("open");
+        assert!(journal.append(&entry(3)).is_err());
+        drop(journal);
+
+        let (_, entries) = EventJournal::open(dir.path()).expect("reopen");
+        assert_eq!(entries, vec![entry(1)]);
+    }
+}
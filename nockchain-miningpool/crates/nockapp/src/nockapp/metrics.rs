@@ -9,6 +9,8 @@ metrics_struct![
     (poke_during_exit, "nockapp.poke_during_exit", Count),
     (peek_during_exit, "nockapp.peek_during_exit", Count),
     (least_free_space_seen_in_slam, "nockapp.least_free_space_seen_in_slam", Gauge),
+    (nock_stack_peak_usage, "nockapp.nock_stack.peak_usage", Gauge),
+    (nock_stack_out_of_memory, "nockapp.nock_stack.out_of_memory", Count),
     (save_jam_time, "nockapp.save_jam_time", TimingCount),
     (load_cue_time, "nockapp.load_cue_time", TimingCount),
     (serf_loop_blocking_recv, "nockapp.serf_loop.blocking_recv", TimingCount),
@@ -23,5 +25,6 @@ metrics_struct![
     (serf_loop_peek, "nockapp.serf_loop.peek", TimingCount),
     (serf_loop_poke, "nockapp.serf_loop.poke", TimingCount),
     (serf_loop_provide_metrics, "nockapp.serf_loop.provide_metrics", TimingCount),
-    (next_effect_lagged_error, "nockapp.next_effect.lag", Count)
+    (next_effect_lagged_error, "nockapp.next_effect.lag", Count),
+    (effect_subscription_lagged, "nockapp.effect_subscription.lag", Count)
 ];
@@ -1,8 +1,13 @@
 // pub(crate) mod actors;
+pub mod delta;
 pub mod driver;
 pub mod error;
 pub mod export;
+pub mod history;
+pub mod inspect;
+pub mod journal;
 pub(crate) mod metrics;
+pub mod recorder;
 pub mod save;
 pub mod test;
 pub mod wire;
@@ -12,12 +17,17 @@ use std::path::PathBuf;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
-use driver::{IOAction, IODriverFn, NockAppHandle, PokeResult};
+use driver::{IOAction, IODriverFn, NockAppHandle, PokeResult, EFFECT_SUBSCRIPTION_CAPACITY};
 pub use error::NockAppError;
 use futures::stream::StreamExt;
 use futures::FutureExt;
+use history::HistoryRetention;
+use journal::{EventJournal, JournalEntry};
 use metrics::*;
 use nockvm::noun::SIG;
+use nockvm::profile::JetProfile;
+use nockvm::trace::StackSampler;
+use recorder::RecorderHandle;
 use signal_hook::consts::signal::*;
 use signal_hook::consts::TERM_SIGNALS;
 use signal_hook_tokio::Signals;
@@ -28,9 +38,10 @@ use tokio_util::task::TaskTracker;
 use tracing::{debug, error, info, instrument, trace, warn};
 use wire::WireRepr;
 
-use crate::kernel::form::Kernel;
+use crate::kernel::form::{Kernel, KernelUpgrade, PokeInputs};
+use crate::kernel::timers::ScheduledTimer;
 use crate::noun::slab::{Jammer, NockJammer, NounSlab};
-use crate::save::{SaveableCheckpoint, Saver};
+use crate::save::{Compression, SaveableCheckpoint, Saver};
 
 type NockAppResult = Result<(), NockAppError>;
 
@@ -73,6 +84,10 @@ pub struct NockApp<J = NockJammer> {
     save_interval: Interval,
     /// Mutex to ensure only one save at a time
     pub(crate) save_mutex: Arc<Mutex<Saver<J>>>,
+    /// Event journal, appended to before each poke is acked
+    journal: Arc<std::sync::Mutex<EventJournal>>,
+    /// Whether drivers may swap in a new kernel with [IOAction::Upgrade]
+    allow_kernel_upgrade: bool,
     /// Shutdown oneshot sender
     pub npc_socket_path: Option<PathBuf>,
     metrics: Arc<NockAppMetrics>,
@@ -156,17 +171,20 @@ impl<J: Jammer + Send + 'static> NockApp<J> {
             NockAppMetrics::register(gnort::global_metrics_registry())
                 .expect("Failed to register metrics!"),
         );
-        let (saver, checkpoint) = Saver::<J>::try_load(snapshot_path, Some(metrics.clone()))
+        let (mut saver, checkpoint) = Saver::<J>::try_load(snapshot_path, Some(metrics.clone()))
             .await
             .expect("Failed to set up snapshotting");
+        let replay = saver.take_replay();
+        let journal = saver.journal();
         let save_mutex = Arc::new(Mutex::new(saver));
         let mut kernel = kernel_from_checkpoint(checkpoint).await?;
+        Self::replay_journal(&kernel, replay).await?;
         // important: we are tracking this separately here because
         // what matters is the last poke *we* received an ack for. Using
         // the Arc in the serf would result in a race condition!
 
         let (action_channel_sender, action_channel) = mpsc::channel(100);
-        let (effect_broadcast_sender, _) = broadcast::channel(100);
+        let (effect_broadcast_sender, _) = broadcast::channel(EFFECT_SUBSCRIPTION_CAPACITY);
         let effect_broadcast = Arc::new(effect_broadcast_sender);
         // let tasks = Arc::new(Mutex::new(TaskJoinSet::new()));
         // let tasks = TaskJoinSet::new();
@@ -198,6 +216,8 @@ impl<J: Jammer + Send + 'static> NockApp<J> {
             effect_broadcast,
             save_interval,
             save_mutex,
+            journal,
+            allow_kernel_upgrade: false,
             // cancel_token,
             npc_socket_path: None,
             metrics,
@@ -205,6 +225,40 @@ impl<J: Jammer + Send + 'static> NockApp<J> {
         })
     }
 
+    /// Poke journaled events back into a kernel freshly loaded from the checkpoint they follow.
+    /// Their effects were already emitted before the restart, so they are dropped.
+    async fn replay_journal(
+        kernel: &Kernel<SaveableCheckpoint>,
+        replay: Vec<JournalEntry>,
+    ) -> NockAppResult {
+        let Some(last) = replay.last().map(|entry| entry.event_num) else {
+            return Ok(());
+        };
+        info!("Replaying journaled events through event_num {}", last);
+        for entry in replay {
+            let mut cause = NounSlab::new();
+            let cause_noun = cause.cue_into(entry.cause.0.clone())?;
+            cause.set_root(cause_noun);
+            let (event_num, _inputs, _effects) = kernel
+                .poke_event(entry.wire.to_wire(), cause, Some(entry.inputs), None)
+                .await
+                .map_err(|e| {
+                    error!(
+                        "Replay of journaled event_num {} failed: {:?}",
+                        entry.event_num, e
+                    );
+                    e
+                })?;
+            if event_num != entry.event_num {
+                warn!(
+                    "Replayed journaled event_num {} as event_num {}",
+                    entry.event_num, event_num
+                );
+            }
+        }
+        Ok(())
+    }
+
     pub fn get_handle(&self) -> NockAppHandle {
         NockAppHandle {
             io_sender: self.action_channel_sender.clone(),
@@ -318,6 +372,104 @@ impl<J: Jammer + Send + 'static> NockApp<J> {
         Ok(())
     }
 
+    /// Compress the chunks of later checkpoints with `compression`
+    pub async fn set_checkpoint_compression(&self, compression: Compression) {
+        self.save_mutex.lock().await.set_compression(compression);
+    }
+
+    /// Wait for each event journal append to reach the disk before acking the poke
+    pub fn set_journal_sync(&self, sync: bool) {
+        self.journal
+            .lock()
+            .expect("event journal poisoned")
+            .set_sync(sync);
+    }
+
+    /// Keep later checkpoints in the checkpoint history, as `retention` says
+    pub async fn set_checkpoint_history(&self, retention: HistoryRetention) {
+        self.save_mutex
+            .lock()
+            .await
+            .set_history_retention(retention);
+    }
+
+    /// Let drivers upgrade the kernel. Off by default, since an upgrade runs whatever kernel
+    /// jam the driver hands over.
+    pub fn set_allow_kernel_upgrade(&mut self, allow: bool) {
+        self.allow_kernel_upgrade = allow;
+    }
+
+    /// Record the pokes and peeks the kernel runs with `recorder`. See [Kernel::set_recorder].
+    pub async fn set_recorder(&mut self, recorder: RecorderHandle) -> Result<(), NockAppError> {
+        Ok(self.kernel.set_recorder(Some(recorder)).await?)
+    }
+
+    /// Cap the words in use on the Nock stack at `limit`. See [Kernel::set_stack_limit].
+    pub async fn set_stack_limit(&self, limit: usize) -> Result<(), NockAppError> {
+        Ok(self.kernel.set_stack_limit(limit).await?)
+    }
+
+    /// Sample the Nock stack every `interval`, writing folded stacks to `path`. See
+    /// [nockvm::trace::StackSampler].
+    pub async fn sample_stacks(
+        &self,
+        path: PathBuf,
+        interval: Duration,
+    ) -> Result<(), NockAppError> {
+        let sampler = StackSampler::new(path, interval);
+        Ok(self.kernel.sample_stacks(Some(sampler)).await?)
+    }
+
+    /// Profile jet calls and unjetted hot arms, writing the report to `path`. See
+    /// [nockvm::profile::JetProfile].
+    pub async fn profile_jets(&self, path: PathBuf) -> Result<(), NockAppError> {
+        Ok(self
+            .kernel
+            .profile_jets(Some(JetProfile::new(path)))
+            .await?)
+    }
+
+    /// Swap in the kernel jammed in `kernel`, keeping the current state. See [Kernel::upgrade].
+    pub async fn upgrade_kernel(&mut self, kernel: Vec<u8>) -> Result<KernelUpgrade, NockAppError> {
+        self.upgrade_f(kernel).await
+    }
+
+    /// Upgrade to `kernel`, then persist it beside the checkpoints along with a checkpoint
+    /// saved under its hash, so a restart boots the upgraded kernel rather than the one built
+    /// into the binary. The serf runs nothing else until both are on disk, and goes back to
+    /// the old kernel if they can't be written. See [crate::save::upgraded_kernel].
+    fn upgrade_f(
+        &self,
+        kernel: Vec<u8>,
+    ) -> impl Future<Output = Result<KernelUpgrade, NockAppError>> + Send + 'static {
+        let upgrade_future = self.kernel.upgrade(kernel.clone());
+        let save_mutex = self.save_mutex.clone();
+        let metrics = self.metrics.clone();
+        async move {
+            // Hold the saver so no other save lands between the upgrade and persisting it
+            let mut saver = save_mutex.lock_owned().await;
+            let (checkpoint, pending) = upgrade_future.await?.take_checkpoint();
+            let persisted = async {
+                saver.save_upgraded_kernel(&kernel).await?;
+                saver.save(checkpoint, metrics).await?;
+                Ok::<(), NockAppError>(())
+            }
+            .await;
+            let upgrade = pending.upgrade.clone();
+            match persisted {
+                Ok(()) => {
+                    pending.commit();
+                    Ok(upgrade)
+                }
+                Err(e) => {
+                    error!("Kernel upgrade could not be persisted, rolling back: {}", e);
+                    pending.roll_back();
+                    Err(e)
+                }
+            }
+        }
+    }
+
     /// Peek at a noun in the kernel, blocking operation
     #[tracing::instrument(skip(self, path))]
     pub fn peek_sync(&mut self, path: NounSlab) -> Result<NounSlab, NockAppError> {
@@ -365,7 +517,12 @@ impl<J: Jammer + Send + 'static> NockApp<J> {
         cause: NounSlab,
     ) -> Result<Vec<NounSlab>, NockAppError> {
         // let wire_noun = wire.copy_to_stack(self.kernel.serf.stack());
-        let effects_slab = self.kernel.poke_sync(wire, cause)?;
+        let cause_jam = cause.jam();
+        let (inputs, effects_slab) = self.kernel.poke_sync_inputs(wire.clone(), cause)?;
+        // We hold &mut self, so nothing else can have poked the serf since
+        let event_num = self.kernel.serf.event_number.load(Ordering::SeqCst);
+        let entry = JournalEntry::new(event_num, &wire, cause_jam, inputs);
+        append_journal_entry(&self.journal, &entry)?;
         Ok(effects_slab.to_vec())
     }
 
@@ -375,7 +532,12 @@ impl<J: Jammer + Send + 'static> NockApp<J> {
         wire: WireRepr,
         cause: NounSlab,
     ) -> Result<Vec<NounSlab>, NockAppError> {
-        let effects_slab = self.kernel.poke(wire, cause).await?;
+        let cause_jam = cause.jam();
+        let (event_num, inputs, effects_slab) = self
+            .kernel
+            .poke_event(wire.clone(), cause, None, None)
+            .await?;
+        journal_event(self.journal.clone(), event_num, &wire, cause_jam, inputs).await?;
         Ok(effects_slab.to_vec())
     }
 
@@ -385,7 +547,12 @@ impl<J: Jammer + Send + 'static> NockApp<J> {
         cause: NounSlab,
         timeout: Duration,
     ) -> Result<Vec<NounSlab>, NockAppError> {
-        let effects_slab = self.kernel.poke_timeout(wire, cause, timeout).await?;
+        let cause_jam = cause.jam();
+        let (event_num, inputs, effects_slab) = self
+            .kernel
+            .poke_event(wire.clone(), cause, None, Some(timeout))
+            .await?;
+        journal_event(self.journal.clone(), event_num, &wire, cause_jam, inputs).await?;
         Ok(effects_slab.to_vec())
     }
 
@@ -563,12 +730,17 @@ impl<J: Jammer + Send + 'static> NockApp<J> {
     async fn handle_action(&self, action: IOAction) {
         // Stop processing events if we are exiting
         if self.exit_status.load(Ordering::SeqCst) {
-            if let IOAction::Poke { .. } = action {
-                self.metrics.poke_during_exit.increment();
-                debug!("Poked during exit. Ignoring.")
-            } else {
-                self.metrics.peek_during_exit.increment();
-                debug!("Peeked during exit. Ignoring.")
+            match action {
+                IOAction::Poke { .. } => {
+                    self.metrics.poke_during_exit.increment();
+                    debug!("Poked during exit. Ignoring.")
+                }
+                IOAction::Peek { .. } => {
+                    self.metrics.peek_during_exit.increment();
+                    debug!("Peeked during exit. Ignoring.")
+                }
+                IOAction::Upgrade { .. } => debug!("Kernel upgrade during exit. Ignoring."),
+                IOAction::Timers { .. } => debug!("Timers requested during exit. Ignoring."),
             }
             return;
         }
@@ -583,6 +755,11 @@ impl<J: Jammer + Send + 'static> NockApp<J> {
                 path,
                 result_channel,
             } => self.handle_peek(path, result_channel).await,
+            IOAction::Upgrade {
+                kernel,
+                result_channel,
+            } => self.handle_upgrade(kernel, result_channel).await,
+            IOAction::Timers { result_channel } => self.handle_timers(result_channel).await,
         }
     }
 
@@ -594,41 +771,37 @@ impl<J: Jammer + Send + 'static> NockApp<J> {
         ack_channel: tokio::sync::oneshot::Sender<PokeResult>,
         timeout: Option<Duration>,
     ) {
-        if let Some(timeout) = timeout {
-            let poke_future = self.kernel.poke_timeout(wire, cause, timeout);
-            let effect_broadcast = self.effect_broadcast.clone();
-            let _ = self.tasks.spawn(async move {
-                let poke_result = poke_future.await;
-                match poke_result {
-                    Ok(effects) => {
-                        let _ = ack_channel.send(PokeResult::Ack);
-                        for effect_slab in effects.to_vec() {
-                            let _ = effect_broadcast.send(effect_slab);
-                        }
-                    }
-                    Err(_) => {
+        let cause_jam = cause.jam();
+        let poke_future = self.kernel.poke_event(wire.clone(), cause, None, timeout);
+        let effect_broadcast = self.effect_broadcast.clone();
+        let journal = self.journal.clone();
+        let exit = self.exit.clone();
+        let _ = self.tasks.spawn(async move {
+            let poke_result = poke_future.await;
+            match poke_result {
+                Ok((event_num, inputs, effects)) => {
+                    // The serf has already applied the event, so one we can't journal would be
+                    // lost in a crash and leaves a gap in the journal. The poke fails, and so
+                    // does the NockApp, rather than running on past the gap.
+                    if let Err(e) =
+                        journal_event(journal, event_num, &wire, cause_jam, inputs).await
+                    {
                         let _ = ack_channel.send(PokeResult::Nack);
-                    }
-                }
-            });
-        } else {
-            let poke_future = self.kernel.poke(wire, cause);
-            let effect_broadcast = self.effect_broadcast.clone();
-            let _ = self.tasks.spawn(async move {
-                let poke_result = poke_future.await;
-                match poke_result {
-                    Ok(effects) => {
-                        let _ = ack_channel.send(PokeResult::Ack);
-                        for effect_slab in effects.to_vec() {
-                            let _ = effect_broadcast.send(effect_slab);
+                        if let Err(e) = exit.shutdown(Err(e)).await {
+                            error!("Error shutting down after a journal failure: {e}");
                         }
+                        return;
                     }
-                    Err(_) => {
-                        let _ = ack_channel.send(PokeResult::Nack);
+                    let _ = ack_channel.send(PokeResult::Ack);
+                    for effect_slab in effects.to_vec() {
+                        let _ = effect_broadcast.send(effect_slab);
                     }
                 }
-            });
-        }
+                Err(_) => {
+                    let _ = ack_channel.send(PokeResult::Nack);
+                }
+            }
+        });
     }
 
     #[instrument(skip_all)]
@@ -640,7 +813,6 @@ impl<J: Jammer + Send + 'static> NockApp<J> {
         let peek_future = self.kernel.peek(path);
         let _ = self.tasks.spawn(async move {
             let peek_res = peek_future.await;
-
             match peek_res {
                 Ok(res_slab) => {
                     let _ = result_channel.send(Some(res_slab));
@@ -653,6 +825,36 @@ impl<J: Jammer + Send + 'static> NockApp<J> {
         });
     }
 
+    #[instrument(skip_all)]
+    async fn handle_upgrade(
+        &self,
+        kernel: Vec<u8>,
+        result_channel: tokio::sync::oneshot::Sender<Result<KernelUpgrade, NockAppError>>,
+    ) {
+        if !self.allow_kernel_upgrade {
+            warn!("Refusing kernel upgrade: kernel upgrades are not enabled");
+            let _ = result_channel.send(Err(NockAppError::UpgradeDisabled));
+            return;
+        }
+        let upgrade_future = self.upgrade_f(kernel);
+        let _ = self.tasks.spawn(async move {
+            let upgrade_res = upgrade_future.await;
+            let _ = result_channel.send(upgrade_res);
+        });
+    }
+
+    #[instrument(skip_all)]
+    async fn handle_timers(
+        &self,
+        result_channel: tokio::sync::oneshot::Sender<Result<Vec<ScheduledTimer>, NockAppError>>,
+    ) {
+        let timers_future = self.kernel.timers();
+        let _ = self.tasks.spawn(async move {
+            let timers_res = timers_future.await.map_err(NockAppError::from);
+            let _ = result_channel.send(timers_res);
+        });
+    }
+
     // TODO: We should explicitly kick off a save somehow
     // TOOD: :>) spawn a task which awaits the signal stream and if there is a SIGINT, then call std::process::exit(1)
     #[instrument(skip_all)]
@@ -732,3 +934,31 @@ impl<J: Jammer + Send + 'static> NockApp<J> {
         Ok(NockAppRun::Pending)
     }
 }
+
+/// Record an accepted poke in the event journal. This has to happen before the poke is acked,
+/// and the poke fails if it doesn't. The append may sync to disk, so it runs on a blocking
+/// thread.
+async fn journal_event(
+    journal: Arc<std::sync::Mutex<EventJournal>>,
+    event_num: u64,
+    wire: &WireRepr,
+    cause: bytes::Bytes,
+    inputs: PokeInputs,
+) -> Result<(), NockAppError> {
+    let entry = JournalEntry::new(event_num, wire, cause, inputs);
+    tokio::task::spawn_blocking(move || append_journal_entry(&journal, &entry)).await?
+}
+
+fn append_journal_entry(
+    journal: &std::sync::Mutex<EventJournal>,
+    entry: &JournalEntry,
+) -> Result<(), NockAppError> {
+    journal
+        .lock()
+        .expect("event journal poisoned")
+        .append(entry)
+        .map_err(|e| {
+            error!("Failed to journal event_num {}: {}", entry.event_num, e);
+            e.into()
+        })
+}
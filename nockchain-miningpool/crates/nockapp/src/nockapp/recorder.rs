@@ -0,0 +1,504 @@
+//! Poke and peek recorder.
+//!
+//! When enabled, the serf records every poke it runs (wire, cause, whether it was acked,
+//! its event number, the entropy and time it ran with, and its effects) and every peek
+//! (path and result), with wall-clock timestamps and how long the kernel took. Since the
+//! serf runs one action at a time, records are written in the order the kernel saw them. The
+//! recording can then be replayed against a kernel booted from an earlier checkpoint with
+//! [replay], which re-runs each poke with its recorded entropy and time and diffs the
+//! effects and peek results against the recorded ones.
+//!
+//! The [Recorder] runs on its own thread behind a [RecorderHandle], so the serf only waits
+//! on writing records once the recorder has fallen well behind.
+//!
+//! Records are written as `[len: u64 LE][payload]`, where the payload is a bincode
+//! [Record], to files named `recording-<index>.rec` in the recording directory. A file is
+//! rotated out once it reaches [RecorderConfig::max_file_bytes], and only the newest
+//! [RecorderConfig::max_files] files are kept.
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use bincode::{config, decode_from_slice, encode_to_vec, Decode, Encode};
+use bytes::Bytes;
+use tokio::sync::mpsc;
+use tracing::{debug, error, info, warn};
+
+use crate::kernel::form::{Kernel, PokeInputs};
+use crate::nockapp::journal::JournalWire;
+use crate::nockapp::save::CheckpointError;
+use crate::nockapp::wire::WireRepr;
+use crate::noun::slab::NounSlab;
+use crate::JammedNoun;
+
+const RECORDING_PREFIX: &str = "recording-";
+const RECORDING_EXTENSION: &str = "rec";
+const FRAME_HEADER_LEN: usize = 8;
+pub const DEFAULT_RECORDING_FILE_BYTES: u64 = 64 * 1024 * 1024;
+pub const DEFAULT_RECORDING_FILES: usize = 8;
+/// Records queued for the recorder thread before handlers wait for it
+const RECORD_CHANNEL_SIZE: usize = 1024;
+
+/// Where to write a recording and how much of it to keep.
+#[derive(Clone, Debug)]
+pub struct RecorderConfig {
+    pub dir: PathBuf,
+    /// Start a new file once the current one is at least this large
+    pub max_file_bytes: u64,
+    /// Delete the oldest files beyond this many
+    pub max_files: usize,
+}
+
+impl RecorderConfig {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        RecorderConfig {
+            dir: dir.into(),
+            max_file_bytes: DEFAULT_RECORDING_FILE_BYTES,
+            max_files: DEFAULT_RECORDING_FILES,
+        }
+    }
+}
+
+/// A recorded poke. `event_num` and `inputs` are `None` if the poke was nacked.
+#[derive(Encode, Decode, Clone, Debug, PartialEq)]
+pub struct PokeRecord {
+    pub timestamp_ms: u64,
+    pub duration_us: u64,
+    pub event_num: Option<u64>,
+    pub inputs: Option<PokeInputs>,
+    pub wire: JournalWire,
+    pub cause: JammedNoun,
+    pub effects: Vec<JammedNoun>,
+}
+
+/// A recorded peek. `result` is `None` if the peek failed.
+#[derive(Encode, Decode, Clone, Debug, PartialEq)]
+pub struct PeekRecord {
+    pub timestamp_ms: u64,
+    pub duration_us: u64,
+    pub path: JammedNoun,
+    pub result: Option<JammedNoun>,
+}
+
+#[derive(Encode, Decode, Clone, Debug, PartialEq)]
+pub enum Record {
+    Poke(PokeRecord),
+    Peek(PeekRecord),
+}
+
+impl Record {
+    pub fn poke(
+        started: Instant,
+        wire: &WireRepr,
+        cause: Bytes,
+        result: Option<(u64, PokeInputs, &NounSlab)>,
+    ) -> Self {
+        let (event_num, inputs, effects) = match result {
+            Some((event_num, inputs, effects)) => (
+                Some(event_num),
+                Some(inputs),
+                effects
+                    .to_vec()
+                    .iter()
+                    .map(|effect| JammedNoun::new(effect.jam()))
+                    .collect(),
+            ),
+            None => (None, None, Vec::new()),
+        };
+        Record::Poke(PokeRecord {
+            timestamp_ms: now_ms(),
+            duration_us: started.elapsed().as_micros() as u64,
+            event_num,
+            inputs,
+            wire: wire.into(),
+            cause: JammedNoun::new(cause),
+            effects,
+        })
+    }
+
+    pub fn peek(started: Instant, path: Bytes, result: Option<&NounSlab>) -> Self {
+        Record::Peek(PeekRecord {
+            timestamp_ms: now_ms(),
+            duration_us: started.elapsed().as_micros() as u64,
+            path: JammedNoun::new(path),
+            result: result.map(|result| JammedNoun::new(result.jam())),
+        })
+    }
+
+    fn frame(&self) -> Result<Vec<u8>, CheckpointError> {
+        let payload = encode_to_vec(self, config::standard())?;
+        let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+        frame.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+        frame.extend_from_slice(&payload);
+        Ok(frame)
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Appends [Record]s to a rotating set of files.
+pub struct Recorder {
+    config: RecorderConfig,
+    index: u64,
+    written: u64,
+    file: BufWriter<File>,
+}
+
+impl Recorder {
+    /// Start a new recording file in `config.dir`, after any files already there.
+    pub fn open(config: RecorderConfig) -> Result<Self, CheckpointError> {
+        std::fs::create_dir_all(&config.dir)?;
+        let index = recording_files(&config.dir)?
+            .last()
+            .map(|(index, _)| index + 1)
+            .unwrap_or(0);
+        let file = create_recording_file(&config.dir, index)?;
+        info!("Recording pokes and peeks to {}", config.dir.display());
+        let mut recorder = Recorder {
+            config,
+            index,
+            written: 0,
+            file,
+        };
+        recorder.prune()?;
+        Ok(recorder)
+    }
+
+    pub fn record(&mut self, record: &Record) -> Result<(), CheckpointError> {
+        let frame = record.frame()?;
+        self.file.write_all(&frame)?;
+        // Flush each record so a crash loses at most the one being written
+        self.file.flush()?;
+        self.written += frame.len() as u64;
+        if self.written >= self.config.max_file_bytes {
+            self.rotate()?;
+        }
+        Ok(())
+    }
+
+    /// Move the recorder onto its own thread, which writes the records sent through the
+    /// returned handle until every handle is dropped.
+    pub fn spawn(mut self) -> std::io::Result<RecorderHandle> {
+        let (records, mut incoming) = mpsc::channel::<Record>(RECORD_CHANNEL_SIZE);
+        std::thread::Builder::new()
+            .name("recorder".to_string())
+            .spawn(move || {
+                while let Some(record) = incoming.blocking_recv() {
+                    if let Err(e) = self.record(&record) {
+                        error!("Failed to record poke or peek: {}", e);
+                    }
+                }
+            })?;
+        Ok(RecorderHandle { records })
+    }
+
+    fn rotate(&mut self) -> Result<(), CheckpointError> {
+        self.index += 1;
+        self.file = create_recording_file(&self.config.dir, self.index)?;
+        self.written = 0;
+        debug!("Rotated recording to file {}", self.index);
+        self.prune()
+    }
+
+    fn prune(&mut self) -> Result<(), CheckpointError> {
+        let files = recording_files(&self.config.dir)?;
+        let excess = files.len().saturating_sub(self.config.max_files.max(1));
+        for (_, path) in files.into_iter().take(excess) {
+            debug!("Removing old recording {}", path.display());
+            std::fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Sends records to a [Recorder] running on its own thread
+#[derive(Clone)]
+pub struct RecorderHandle {
+    records: mpsc::Sender<Record>,
+}
+
+impl RecorderHandle {
+    /// Queue `record` to be written, blocking if the recorder has fallen behind. Called from
+    /// the serf thread, never from async code.
+    pub fn record_blocking(&self, record: Record) {
+        if self.records.blocking_send(record).is_err() {
+            error!("Recorder thread has stopped, dropping record");
+        }
+    }
+}
+
+fn create_recording_file(dir: &Path, index: u64) -> Result<BufWriter<File>, CheckpointError> {
+    let path = dir.join(format!(
+        "{}{:08}.{}",
+        RECORDING_PREFIX, index, RECORDING_EXTENSION
+    ));
+    let file = OpenOptions::new().append(true).create(true).open(path)?;
+    Ok(BufWriter::new(file))
+}
+
+/// The recording files in `dir`, oldest first.
+fn recording_files(dir: &Path) -> Result<Vec<(u64, PathBuf)>, CheckpointError> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some(RECORDING_EXTENSION) {
+            continue;
+        }
+        let index = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.strip_prefix(RECORDING_PREFIX))
+            .and_then(|index| index.parse::<u64>().ok());
+        if let Some(index) = index {
+            files.push((index, path));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Read every intact record in the recording in `dir`, oldest first.
+pub fn read_recording(dir: &Path) -> Result<Vec<Record>, CheckpointError> {
+    let mut records = Vec::new();
+    for (_, path) in recording_files(dir)? {
+        let mut bytes = Vec::new();
+        File::open(&path)?.read_to_end(&mut bytes)?;
+        let mut offset = 0;
+        while bytes.len() - offset >= FRAME_HEADER_LEN {
+            let len = u64::from_le_bytes(
+                bytes[offset..offset + FRAME_HEADER_LEN]
+                    .try_into()
+                    .expect("8 byte length"),
+            ) as usize;
+            let Some(payload) = bytes
+                .get(offset + FRAME_HEADER_LEN..)
+                .and_then(|rest| rest.get(..len))
+            else {
+                break;
+            };
+            match decode_from_slice::<Record, _>(payload, config::standard()) {
+                Ok((record, _)) => records.push(record),
+                Err(_) => break,
+            }
+            offset += FRAME_HEADER_LEN + len;
+        }
+        if offset < bytes.len() {
+            warn!(
+                "Ignoring {} bytes of torn or corrupt records at the end of {}",
+                bytes.len() - offset,
+                path.display()
+            );
+        }
+    }
+    Ok(records)
+}
+
+/// A record whose replay didn't match the recording
+#[derive(Debug)]
+pub struct ReplayMismatch {
+    /// Index of the record in the recording
+    pub index: usize,
+    pub description: String,
+}
+
+/// Summary of a [replay]
+#[derive(Debug, Default)]
+pub struct ReplayReport {
+    /// Records before the kernel's event number, which were skipped
+    pub skipped: usize,
+    pub pokes: usize,
+    pub peeks: usize,
+    pub mismatches: Vec<ReplayMismatch>,
+    /// Kernel time for the replayed records, as recorded
+    pub recorded_time: Duration,
+    /// Kernel time for the replayed records, during the replay
+    pub replay_time: Duration,
+}
+
+/// Re-apply `records` to `kernel` and diff the effects and peek results against the
+/// recording.
+///
+/// Records from before the kernel's current event number are skipped, so the kernel can be
+/// booted from any checkpoint the recording covers.
+pub async fn replay<C>(
+    kernel: &Kernel<C>,
+    records: Vec<Record>,
+) -> Result<ReplayReport, CheckpointError> {
+    let start_event = kernel.serf.event_number.load(Ordering::SeqCst);
+    // Resume after the last poke the checkpoint already includes, keeping the nacks and
+    // peeks which followed it since they saw the same state.
+    let skipped = records
+        .iter()
+        .rposition(|record| {
+            matches!(record, Record::Poke(PokeRecord { event_num: Some(n), .. }) if *n <= start_event)
+        })
+        .map(|index| index + 1)
+        .unwrap_or(0);
+    info!(
+        "Replaying {} recorded pokes and peeks from event_num {}",
+        records.len() - skipped,
+        start_event
+    );
+
+    let mut report = ReplayReport {
+        skipped,
+        ..Default::default()
+    };
+    for (index, record) in records.into_iter().enumerate().skip(skipped) {
+        match record {
+            Record::Poke(poke) => {
+                report.pokes += 1;
+                report.recorded_time += Duration::from_micros(poke.duration_us);
+                let mut cause = NounSlab::new();
+                let cause_noun = cause.cue_into(poke.cause.0.clone())?;
+                cause.set_root(cause_noun);
+                let started = Instant::now();
+                // Run with the recorded entropy and time so the effects can match
+                let result = kernel
+                    .poke_event(poke.wire.to_wire(), cause, poke.inputs, None)
+                    .await;
+                report.replay_time += started.elapsed();
+                let result = result.map(|(event_num, _, effects)| (event_num, effects));
+                if let Some(description) = diff_poke(&poke, result.ok()) {
+                    report
+                        .mismatches
+                        .push(ReplayMismatch { index, description });
+                }
+            }
+            Record::Peek(peek) => {
+                report.peeks += 1;
+                report.recorded_time += Duration::from_micros(peek.duration_us);
+                let mut path = NounSlab::new();
+                let path_noun = path.cue_into(peek.path.0.clone())?;
+                path.set_root(path_noun);
+                let started = Instant::now();
+                let result = kernel.peek(path).await;
+                report.replay_time += started.elapsed();
+                let replayed = result.ok().map(|result| result.jam());
+                let recorded = peek.result.map(|result| result.0);
+                if replayed != recorded {
+                    report.mismatches.push(ReplayMismatch {
+                        index,
+                        description: "peek result differs".to_string(),
+                    });
+                }
+            }
+        }
+    }
+    Ok(report)
+}
+
+fn diff_poke(recorded: &PokeRecord, replayed: Option<(u64, NounSlab)>) -> Option<String> {
+    let wire = recorded.wire.to_wire();
+    let Some((event_num, effects)) = replayed else {
+        return recorded.event_num.map(|n| {
+            format!(
+                "poke on {:?} was acked as event_num {} but nacked on replay",
+                wire, n
+            )
+        });
+    };
+    let Some(recorded_event) = recorded.event_num else {
+        return Some(format!(
+            "poke on {:?} was nacked but acked on replay as event_num {}",
+            wire, event_num
+        ));
+    };
+    let effects: Vec<Bytes> = effects.to_vec().iter().map(|effect| effect.jam()).collect();
+    if effects.len() != recorded.effects.len() {
+        return Some(format!(
+            "event_num {} on {:?}: {} effects recorded, {} on replay",
+            recorded_event,
+            wire,
+            recorded.effects.len(),
+            effects.len()
+        ));
+    }
+    recorded
+        .effects
+        .iter()
+        .zip(&effects)
+        .position(|(recorded, replayed)| recorded.0 != *replayed)
+        .map(|i| {
+            format!(
+                "event_num {} on {:?}: effect {} differs",
+                recorded_event, wire, i
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use nockvm::noun::{D, T};
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn record(n: u64) -> Record {
+        let wire = WireRepr::new("test", 1, vec!["poke".into(), n.into()]);
+        let mut effects = NounSlab::new();
+        let list = T(&mut effects, &[D(n), D(0)]);
+        effects.set_root(list);
+        Record::poke(
+            Instant::now(),
+            &wire,
+            Bytes::from(vec![n as u8; 4]),
+            Some((n, PokeInputs { eny: n, now: 0 }, &effects)),
+        )
+    }
+
+    fn event_nums(records: &[Record]) -> Vec<Option<u64>> {
+        records
+            .iter()
+            .map(|record| match record {
+                Record::Poke(poke) => poke.event_num,
+                Record::Peek(_) => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_recording_rotates_and_reads_back() {
+        let dir = TempDir::new().expect("tempdir");
+        let config = RecorderConfig {
+            dir: dir.path().to_path_buf(),
+            max_file_bytes: 1,
+            max_files: 3,
+        };
+        let mut recorder = Recorder::open(config.clone()).expect("open");
+        for n in 1..=5 {
+            recorder.record(&record(n)).expect("record");
+        }
+        drop(recorder);
+
+        // Every record rotates, so only the last two records and the empty file after them
+        // are kept
+        assert_eq!(recording_files(dir.path()).expect("list").len(), 3);
+        let records = read_recording(dir.path()).expect("read");
+        assert_eq!(event_nums(&records), vec![Some(4), Some(5)]);
+        let Record::Poke(poke) = &records[0] else {
+            panic!("expected a poke record");
+        };
+        assert_eq!(poke.effects.len(), 1);
+        assert_eq!(poke.inputs, Some(PokeInputs { eny: 4, now: 0 }));
+        assert_eq!(
+            poke.wire.to_wire(),
+            WireRepr::new("test", 1, vec!["poke".into(), 4u64.into()])
+        );
+
+        // Reopening continues after the existing files
+        let mut recorder = Recorder::open(config).expect("reopen");
+        recorder.record(&record(6)).expect("record");
+        drop(recorder);
+        let records = read_recording(dir.path()).expect("read");
+        assert_eq!(event_nums(&records), vec![Some(4), Some(5), Some(6)]);
+    }
+}
@@ -1,5 +1,9 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fs::File;
 use std::future::Future;
-use std::path::PathBuf;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::Instant;
 
@@ -7,19 +11,140 @@ use bincode::config::Configuration;
 use bincode::{config, encode_to_vec, Decode, Encode};
 use blake3::{Hash, Hasher};
 use bytes::Bytes;
+use clap::ValueEnum;
 use nockvm_macros::tas;
 use thiserror::Error;
 use tokio::fs::create_dir_all;
+use tokio::io::AsyncWriteExt;
 use tokio::sync::oneshot;
 use tracing::{debug, error, trace, warn};
 
+use crate::kernel::timers::TimerTable;
 use crate::metrics::NockAppMetrics;
+use crate::nockapp::delta::{next_generation, remove_unreferenced_packs, ChunkId, ChunkPack};
+use crate::nockapp::history::{CheckpointHistory, HistoryRetention};
+use crate::nockapp::journal::{entries_to_replay, EventJournal, JournalEntry};
 use crate::noun::slab::{Jammer, NockJammer, NounSlab};
 use crate::JammedNoun;
 
-const JAM_MAGIC_BYTES: u64 = tas!(b"CHKJAM");
-const SNAPSHOT_VERSION_0: u32 = 0;
-const SNAPSHOT_VERSION_1: u32 = 1;
+pub(crate) const JAM_MAGIC_BYTES: u64 = tas!(b"CHKJAM");
+pub(crate) const SNAPSHOT_VERSION_0: u32 = 0;
+pub(crate) const SNAPSHOT_VERSION_1: u32 = 1;
+pub(crate) const SNAPSHOT_VERSION_2: u32 = 2;
+pub(crate) const SNAPSHOT_VERSION_3: u32 = 3;
+const ZSTD_LEVEL: i32 = 3;
+const ZSTD_FRAME_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+pub(crate) const CHECKPOINT_FILE_0: &str = "0.chkjam";
+pub(crate) const CHECKPOINT_FILE_1: &str = "1.chkjam";
+pub(crate) const UPGRADED_KERNEL_DIR: &str = "kernels";
+
+/// Compression applied to checkpoint chunks and exported state
+#[derive(Encode, Decode, Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd,
+}
+
+impl Compression {
+    /// Wrap `writer` so that what is written to it is compressed. The output is only
+    /// complete once [CompressedWriter::finish] is called.
+    pub fn writer<W: Write>(self, writer: W) -> std::io::Result<CompressedWriter<W>> {
+        match self {
+            Compression::None => Ok(CompressedWriter::None(writer)),
+            Compression::Zstd => Ok(CompressedWriter::Zstd(zstd::stream::Encoder::new(
+                writer, ZSTD_LEVEL,
+            )?)),
+        }
+    }
+
+    /// Write `bytes` to `writer` compressed, returning the writer once the output is complete.
+    pub fn write_all<W: Write>(self, writer: W, bytes: &[u8]) -> std::io::Result<W> {
+        let mut writer = self.writer(writer)?;
+        writer.write_all(bytes)?;
+        writer.finish()
+    }
+
+    /// Wrap `reader` so that reading from it yields the decompressed bytes.
+    pub fn reader<'a, R: BufRead + 'a>(self, reader: R) -> std::io::Result<Box<dyn Read + 'a>> {
+        match self {
+            Compression::None => Ok(Box::new(reader)),
+            Compression::Zstd => Ok(Box::new(zstd::stream::Decoder::with_buffer(reader)?)),
+        }
+    }
+
+    /// The compression of the stream in `reader`, telling zstd frames apart by their magic
+    fn sniff<R: BufRead>(reader: &mut R) -> std::io::Result<Self> {
+        if reader.fill_buf()?.starts_with(&ZSTD_FRAME_MAGIC) {
+            Ok(Compression::Zstd)
+        } else {
+            Ok(Compression::None)
+        }
+    }
+}
+
+/// A writer compressing everything written to it, from [Compression::writer]
+pub enum CompressedWriter<W: Write> {
+    None(W),
+    Zstd(zstd::stream::Encoder<'static, W>),
+}
+
+impl<W: Write> CompressedWriter<W> {
+    /// Complete the compressed output and return the inner writer
+    pub fn finish(self) -> std::io::Result<W> {
+        match self {
+            CompressedWriter::None(writer) => Ok(writer),
+            CompressedWriter::Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for CompressedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            CompressedWriter::None(writer) => writer.write(buf),
+            CompressedWriter::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            CompressedWriter::None(writer) => writer.flush(),
+            CompressedWriter::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// The kernel persisted by [Saver::save_upgraded_kernel] in checkpoint directory `dir` under
+/// `ker_hash`, the kernel hash a checkpoint was saved under. `None` if there is none, as when
+/// the checkpoint was saved by the kernel built into the binary.
+pub fn upgraded_kernel(dir: &Path, ker_hash: &Hash) -> std::io::Result<Option<Vec<u8>>> {
+    let kernel = match std::fs::read(upgraded_kernel_path(dir, ker_hash)) {
+        Ok(kernel) => kernel,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    if blake3::hash(&kernel) != *ker_hash {
+        return Ok(None);
+    }
+    Ok(Some(kernel))
+}
+
+/// Upgraded kernels are kept by hash, so a checkpoint in either slot can find its own kernel
+/// however many upgrades happened since.
+fn upgraded_kernel_path(dir: &Path, ker_hash: &Hash) -> PathBuf {
+    dir.join(UPGRADED_KERNEL_DIR)
+        .join(format!("{}.jam", ker_hash.to_hex()))
+}
+
+/// Open the checkpoint file at `path` for reading. Jammed checkpoints may be compressed
+/// whole (as history checkpoints are when compression is on), which is told apart by the
+/// zstd frame magic since an uncompressed checkpoint starts with [JAM_MAGIC_BYTES].
+pub(crate) fn open_checkpoint(path: &Path) -> std::io::Result<Box<dyn Read>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let compression = Compression::sniff(&mut reader)?;
+    compression.reader(reader)
+}
 
 pub enum WhichSnapshot {
     Snapshot0,
@@ -42,6 +167,21 @@ pub struct Saver<J = NockJammer> {
     save_to_next: WhichSnapshot,
     waiters: Vec<(u64, oneshot::Sender<()>)>,
     last_event_num: u64,
+    /// Pokes since the last checkpoint, shared with the poke path which appends to it
+    journal: Arc<std::sync::Mutex<EventJournal>>,
+    /// Journal entries to replay on top of the loaded checkpoint
+    replay: Vec<JournalEntry>,
+    dir: PathBuf,
+    /// Pack new delta checkpoints write their chunks to
+    pack: ChunkPack,
+    /// Pack generation used by the delta checkpoint at `path_0`, if it is one
+    pack_0: Option<u64>,
+    /// Pack generation used by the delta checkpoint at `path_1`, if it is one
+    pack_1: Option<u64>,
+    /// Compression for new chunks. Changing it starts a new pack on the next save.
+    compression: Compression,
+    /// Where saves are also kept for rollback, if anywhere
+    history: Option<CheckpointHistory>,
     _phantom: std::marker::PhantomData<J>,
 }
 
@@ -81,6 +221,41 @@ impl<J> Saver<J> {
     pub fn save_needed(&self, event_num: u64) -> bool {
         self.last_event_num < event_num
     }
+
+    pub fn journal(&self) -> Arc<std::sync::Mutex<EventJournal>> {
+        self.journal.clone()
+    }
+
+    /// Take the journal entries which follow the loaded checkpoint, in event order.
+    /// These must be poked into the kernel before any new events.
+    pub fn take_replay(&mut self) -> Vec<JournalEntry> {
+        std::mem::take(&mut self.replay)
+    }
+
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    /// Compress the chunks and history checkpoints of later saves with `compression`.
+    /// Defaults to whatever the loaded checkpoint used.
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.compression = compression;
+    }
+
+    /// Keep `kernel`, swapped in by an upgrade, beside the checkpoints so the next boot can
+    /// load the checkpoint into it. See [upgraded_kernel].
+    pub async fn save_upgraded_kernel(&self, kernel: &[u8]) -> Result<(), CheckpointError> {
+        let path = upgraded_kernel_path(&self.dir, &blake3::hash(kernel));
+        create_dir_all(self.dir.join(UPGRADED_KERNEL_DIR)).await?;
+        write_atomically(&path, kernel).await?;
+        debug!("Saved upgraded kernel to {}", path.display());
+        Ok(())
+    }
+
+    /// Also keep saves in the checkpoint history, as `retention` says.
+    pub fn set_history_retention(&mut self, retention: HistoryRetention) {
+        self.history = Some(CheckpointHistory::new(&self.dir, retention));
+    }
 }
 
 impl<J: Jammer> Saver<J> {
@@ -88,13 +263,15 @@ impl<J: Jammer> Saver<J> {
         path: &PathBuf,
         metrics: Option<Arc<NockAppMetrics>>,
     ) -> Result<(Self, Option<C>), CheckpointError> {
-        let path_0 = path.join("0.chkjam");
-        let path_1 = path.join("1.chkjam");
+        let path_0 = path.join(CHECKPOINT_FILE_0);
+        let path_1 = path.join(CHECKPOINT_FILE_1);
         let waiters = Vec::new();
 
         // No snapshot to load
         if !path_0.exists() && !path_1.exists() {
             create_dir_all(path).await?;
+            let (journal, entries) = EventJournal::open(path)?;
+            let pack = ChunkPack::create(path, next_generation(path)?, Compression::None)?;
             return Ok((
                 Self {
                     path_0,
@@ -102,85 +279,129 @@ impl<J: Jammer> Saver<J> {
                     save_to_next: WhichSnapshot::Snapshot0,
                     waiters,
                     last_event_num: 0,
+                    journal: Arc::new(std::sync::Mutex::new(journal)),
+                    replay: entries_to_replay(entries, 0),
+                    dir: path.clone(),
+                    pack,
+                    pack_0: None,
+                    pack_1: None,
+                    compression: Compression::None,
+                    history: None,
                     _phantom: std::marker::PhantomData,
                 },
                 None,
             ));
         }
 
-        let checkpoint_0 = match JammedCheckpointV1::load_from_file(&path_0).await {
-            Ok(c) => Ok(c),
-            Err(e_v1) => JammedCheckpointV0::load_from_file(&path_0)
-                .await
-                .map(|c0| JammedCheckpointV1::from(c0))
-                .and_then(|c| c.validate(&path_0).map(|_| c))
-                .map_err(|e_v0| CheckpointError::VersionsFailed {
-                    v1: Box::new(e_v1),
-                    v0: Box::new(e_v0),
-                }),
-        };
-
-        let checkpoint_1 = match JammedCheckpointV1::load_from_file(&path_1).await {
-            Ok(c) => Ok(c),
-            Err(e_v1) => JammedCheckpointV0::load_from_file(&path_1)
-                .await
-                .map(|c0| JammedCheckpointV1::from(c0))
-                .and_then(|c| c.validate(&path_1).map(|_| c))
-                .map_err(|e_v0| CheckpointError::VersionsFailed {
-                    v1: Box::new(e_v1),
-                    v0: Box::new(e_v0),
-                }),
-        };
+        let checkpoint_0 = LoadedCheckpoint::load_from_file(&path_0).await;
+        let checkpoint_1 = LoadedCheckpoint::load_from_file(&path_1).await;
+        let pack_0 = checkpoint_0.as_ref().ok().and_then(LoadedCheckpoint::pack);
+        let pack_1 = checkpoint_1.as_ref().ok().and_then(LoadedCheckpoint::pack);
 
-        let (jammed_checkpoint, save_to_next) = match (checkpoint_0, checkpoint_1) {
-            (Ok(c0), Ok(c1)) => {
-                if c0.event_num > c1.event_num {
-                    debug!(
-                        "Loading checkpoint at: {}, checksum: {}",
-                        path_0.display(),
-                        c0.checksum
-                    );
-                    (c0, WhichSnapshot::Snapshot1)
-                } else {
-                    debug!(
-                        "Loading checkpoint at: {}, checksum: {}",
-                        path_1.display(),
-                        c1.checksum
+        // Newest first, preferring 1.chkjam on a tie. If the newest can't be rebuilt we
+        // fall back to the other.
+        let mut errors = Vec::new();
+        let mut candidates = Vec::new();
+        for (checkpoint, checkpoint_path, save_to_next) in [
+            (checkpoint_1, &path_1, WhichSnapshot::Snapshot0),
+            (checkpoint_0, &path_0, WhichSnapshot::Snapshot1),
+        ] {
+            match checkpoint {
+                Ok(c) => candidates.push((c, checkpoint_path, save_to_next)),
+                Err(e) => {
+                    warn!(
+                        "checkpoint at {} failed to load: {}",
+                        checkpoint_path.display(),
+                        e
                     );
-                    (c1, WhichSnapshot::Snapshot0)
+                    errors.push(e);
                 }
             }
-            (Ok(c0), Err(e)) => {
-                warn!("checkpoint at {} failed to load: {}", path_1.display(), e);
-                debug!(
-                    "Loading checkpoint at: {}, checksum: {}",
-                    path_0.display(),
-                    c0.checksum
-                );
-                (c0, WhichSnapshot::Snapshot1)
-            }
-            (Err(e), Ok(c1)) => {
-                warn!("checkpoint at {} failed to load: {}", path_0.display(), e);
-                debug!(
-                    "Loading checkpoint at: {}, checksum: {}",
-                    path_1.display(),
-                    c1.checksum
-                );
-                (c1, WhichSnapshot::Snapshot0)
-            }
-            (Err(e0), Err(e1)) => {
-                error!("checkpoint at {} failed to load: {}", path_0.display(), e0);
-                error!("checkpoint at {} failed to load: {}", path_1.display(), e1);
-                return Err(CheckpointError::BothCheckpointsFailed(
-                    Box::new(e0),
-                    Box::new(e1),
-                ));
+        }
+        candidates.sort_by_key(|(c, _, _)| std::cmp::Reverse(c.event_num()));
+
+        let mut packs: HashMap<u64, ChunkPack> = HashMap::new();
+        let mut loaded = None;
+        for (checkpoint, checkpoint_path, save_to_next) in candidates {
+            debug!(
+                "Loading checkpoint at: {}, checksum: {}",
+                checkpoint_path.display(),
+                checkpoint.checksum()
+            );
+            let checkpoint_pack = checkpoint.pack();
+            let saveable = match checkpoint {
+                LoadedCheckpoint::Jammed(jammed) => {
+                    SaveableCheckpoint::from_jammed_checkpoint::<J>(jammed, metrics.clone())
+                }
+                LoadedCheckpoint::Delta(delta) => {
+                    let pack = match packs.entry(delta.pack) {
+                        Entry::Occupied(entry) => Ok(entry.into_mut()),
+                        Entry::Vacant(entry) => {
+                            ChunkPack::open(path, delta.pack, delta.compression)
+                                .map(|opened| entry.insert(opened))
+                        }
+                    };
+                    pack.and_then(|pack| {
+                        SaveableCheckpoint::from_delta_checkpoint(delta, pack, metrics.clone())
+                    })
+                }
+            };
+            match saveable {
+                Ok(saveable) => {
+                    loaded = Some((saveable, save_to_next, checkpoint_pack));
+                    break;
+                }
+                Err(e) => {
+                    warn!(
+                        "checkpoint at {} failed to load: {}",
+                        checkpoint_path.display(),
+                        e
+                    );
+                    errors.push(e);
+                }
             }
+        }
+        let Some((saveable, save_to_next, checkpoint_pack)) = loaded else {
+            let mut errors = errors.into_iter().map(Box::new);
+            let (Some(e_a), Some(e_b)) = (errors.next(), errors.next()) else {
+                unreachable!("each checkpoint either loads or fails with an error");
+            };
+            error!("Both checkpoints failed to load");
+            return Err(CheckpointError::BothCheckpointsFailed(e_a, e_b));
         };
-        let last_event_num = jammed_checkpoint.event_num;
-        let saveable = SaveableCheckpoint::from_jammed_checkpoint::<J>(jammed_checkpoint, metrics)?;
-        trace!("After from_jammed_checkpoint");
+        trace!("After loading checkpoint");
+
+        // Keep writing to the pack we loaded from, or start a new one after a jammed checkpoint
+        let pack = match checkpoint_pack.and_then(|generation| packs.remove(&generation)) {
+            Some(pack) => pack,
+            None => ChunkPack::create(path, next_generation(path)?, Compression::None)?,
+        };
+        let compression = pack.compression();
+        let last_event_num = saveable.event_num;
         let c = C::from_saveable(saveable)?;
+        let (mut journal, entries) = EventJournal::open(path)?;
+        let after = entries
+            .iter()
+            .filter(|e| e.event_num > last_event_num)
+            .count();
+        let replay = entries_to_replay(entries, last_event_num);
+        // Entries past a gap, or overwritten by a reused event number, would otherwise be
+        // replayed in place of the events which reuse their numbers after this boot
+        if after != replay.len() {
+            warn!(
+                "Dropping {} event journal entries past event_num {}",
+                after - replay.len(),
+                last_event_num + replay.len() as u64
+            );
+            journal.rewrite(&replay)?;
+        }
+        if !replay.is_empty() {
+            debug!(
+                "Replaying {} journaled events after checkpoint event_num {}",
+                replay.len(),
+                last_event_num
+            );
+        }
         Ok((
             Self {
                 path_0,
@@ -188,6 +409,14 @@ impl<J: Jammer> Saver<J> {
                 save_to_next,
                 waiters,
                 last_event_num,
+                journal: Arc::new(std::sync::Mutex::new(journal)),
+                replay,
+                dir: path.clone(),
+                pack,
+                pack_0,
+                pack_1,
+                compression,
+                history: None,
                 _phantom: std::marker::PhantomData,
             },
             Some(c),
@@ -204,10 +433,38 @@ impl<J: Jammer> Saver<J> {
         trace!("Saving checkpoint at event_num {}", event_num);
         let saveable = checkpoint.to_saveable();
         trace!("Converted checkpoint to saveable");
-        let jammed = saveable.to_jammed_checkpoint::<J>(metrics);
-        trace!("Converted saveable to jammed");
+        if self.pack.needs_compaction() || self.pack.compression() != self.compression {
+            let generation = next_generation(&self.dir)?;
+            debug!(
+                "Moving from chunk pack {} ({:?}) to {} ({:?})",
+                self.pack.generation(),
+                self.pack.compression(),
+                generation,
+                self.compression
+            );
+            self.pack = ChunkPack::create(&self.dir, generation, self.compression)?;
+        }
+        let chunk_start = Instant::now();
+        let root = self.pack.write_noun(unsafe { *saveable.noun.root() })?;
+        metrics.save_jam_time.add_timing(&chunk_start.elapsed());
+
+        let generation = self.pack.generation();
+        let delta = DeltaCheckpointV3::new(
+            saveable.ker_hash,
+            event_num,
+            generation,
+            self.compression,
+            root,
+            saveable.timers.clone(),
+        );
         let path = self.next_path();
-        jammed.save_to_file(&path).await?;
+        delta.save_to_file(&path).await?;
+        // The checkpoint in the other slot is what a boot falls back to if this one is lost
+        let older_event_num = self.last_event_num;
+        match self.save_to_next {
+            WhichSnapshot::Snapshot0 => self.pack_0 = Some(generation),
+            WhichSnapshot::Snapshot1 => self.pack_1 = Some(generation),
+        }
         self.save_to_next = self.save_to_next.next();
 
         debug!(
@@ -226,6 +483,37 @@ impl<J: Jammer> Saver<J> {
         self.last_event_num = event_num;
         self.waiters = still_waiting;
 
+        // Packs neither checkpoint uses are left over from compaction. A failure to remove
+        // one only leaves it on disk until the next save.
+        let keep: Vec<u64> = self.pack_0.into_iter().chain(self.pack_1).collect();
+        if let Err(e) = remove_unreferenced_packs(&self.dir, &keep) {
+            warn!("Failed to remove old chunk packs: {}", e);
+        }
+
+        // Both checkpoints are on disk, so the journal no longer needs the events the older
+        // one covers. Events since then stay in case this checkpoint fails to load. A failure
+        // here only leaves extra entries, which are skipped on replay.
+        let journal = self.journal.clone();
+        let truncated = tokio::task::spawn_blocking(move || {
+            journal
+                .lock()
+                .expect("event journal poisoned")
+                .truncate_through(older_event_num)
+        })
+        .await;
+        match truncated {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => warn!("Failed to truncate event journal: {}", e),
+            Err(e) => warn!("Failed to truncate event journal: {}", e),
+        }
+
+        // The history is only for rollback, so failing to record in it doesn't fail the save
+        if let Some(history) = &self.history {
+            if let Err(e) = history.record(&saveable, self.compression) {
+                warn!("Failed to record checkpoint history: {}", e);
+            }
+        }
+
         Ok(())
     }
 }
@@ -242,19 +530,13 @@ pub trait Checkpoint: Sized {
 pub struct SaveableCheckpoint {
     pub ker_hash: Hash,
     pub event_num: u64,
+    /// `[kernel_state cold_state]`
     pub noun: NounSlab,
+    /// Timers the kernel has scheduled, see [crate::kernel::timers]
+    pub timers: TimerTable,
 }
 
 impl SaveableCheckpoint {
-    #[tracing::instrument(skip(self, metrics))]
-    fn to_jammed_checkpoint<J: Jammer>(self, metrics: Arc<NockAppMetrics>) -> JammedCheckpointV1 {
-        let jam_start = Instant::now();
-        let jam = JammedNoun(self.noun.coerce_jammer::<J>().jam());
-        metrics.save_jam_time.add_timing(&jam_start.elapsed());
-
-        JammedCheckpointV1::new(self.ker_hash, self.event_num, jam)
-    }
-
     fn from_jammed_checkpoint<'a, J: Jammer>(
         jammed: JammedCheckpointV1,
         metrics: Option<Arc<NockAppMetrics>>,
@@ -268,6 +550,27 @@ impl SaveableCheckpoint {
             ker_hash: jammed.ker_hash,
             event_num: jammed.event_num,
             noun: slab,
+            timers: TimerTable::default(),
+        })
+    }
+}
+
+impl SaveableCheckpoint {
+    fn from_delta_checkpoint(
+        delta: DeltaCheckpointV3,
+        pack: &ChunkPack,
+        metrics: Option<Arc<NockAppMetrics>>,
+    ) -> Result<Self, CheckpointError> {
+        let mut slab = NounSlab::new();
+        let rebuild_start = Instant::now();
+        let root = pack.rebuild(delta.root, &mut slab)?;
+        metrics.map(|m| m.load_cue_time.add_timing(&rebuild_start.elapsed()));
+        slab.set_root(root);
+        Ok(Self {
+            ker_hash: delta.ker_hash,
+            event_num: delta.event_num,
+            noun: slab,
+            timers: delta.timers,
         })
     }
 }
@@ -298,6 +601,8 @@ pub enum CheckpointError {
     InvalidChecksum(PathBuf),
     #[error("Invalid version at {0}")]
     InvalidVersion(PathBuf),
+    #[error("Jam length in {0} does not match the file")]
+    InvalidJamLength(PathBuf),
     #[error("Sword noun error: {0}")]
     SwordNounError(#[from] nockvm::noun::Error),
     #[error("Sword cold error: {0}")]
@@ -308,6 +613,14 @@ pub enum CheckpointError {
     SwordInterpreterError,
     #[error("Cue error: {0}")]
     CueError(#[from] crate::noun::slab::CueError),
+    #[error("Missing checkpoint chunk {0}")]
+    MissingChunk(Hash),
+    #[error("Corrupt checkpoint chunk {0}")]
+    CorruptChunk(Hash),
+    #[error("No checkpoint at event {0} in the checkpoint history")]
+    MissingHistoryCheckpoint(u64),
+    #[error("No kernel with hash {0} to load the checkpoint into")]
+    MissingKernel(Hash),
     #[error("Loading at version 1 failed: {v1}\nLoading at version 0 failed: {v0}")]
     VersionsFailed {
         v1: Box<CheckpointError>,
@@ -375,20 +688,19 @@ impl JammedCheckpointV1 {
         hasher.finalize()
     }
 
-    #[tracing::instrument(skip_all)]
-    async fn load_from_file(path: &PathBuf) -> Result<Self, CheckpointError> {
-        debug!(
-            "Loading jammed checkpoint from file: {}",
-            path.as_os_str().to_str().unwrap()
-        );
-        let bytes = tokio::fs::read(path).await?;
-        let config = bincode::config::standard();
-        let (checkpoint, _) = bincode::decode_from_slice::<Self, Configuration>(&bytes, config)?;
+    fn decode<R: Read>(reader: &mut R, path: &PathBuf) -> Result<Self, CheckpointError> {
+        let checkpoint: Self = bincode::decode_from_std_read(reader, config::standard())?;
         checkpoint.validate(path)?;
         Ok(checkpoint)
     }
 
+    /// Read and validate the checkpoint at `path`
+    pub fn load_from_file(path: &PathBuf) -> Result<Self, CheckpointError> {
+        Self::decode(&mut open_checkpoint(path)?, path)
+    }
+
     #[tracing::instrument(skip(self))]
+    #[allow(dead_code)] // Preserving this for posterity
     async fn save_to_file(&self, path: &PathBuf) -> Result<(), CheckpointError> {
         let bytes = self.encode()?;
         trace!("Saving jammed checkpoint to file: {}", path.display());
@@ -397,6 +709,275 @@ impl JammedCheckpointV1 {
     }
 }
 
+/// A checkpoint whose kernel state is stored as chunks in a [ChunkPack], so that a save
+/// only writes the parts of the state which changed. See [crate::nockapp::delta].
+#[derive(Encode, Decode, PartialEq, Debug)]
+pub struct DeltaCheckpointV2 {
+    /// Magic bytes to identify checkpoint format
+    pub magic_bytes: u64,
+    /// Version of checkpoint
+    pub version: u32,
+    /// Hash of the boot kernel
+    #[bincode(with_serde)]
+    pub ker_hash: Hash,
+    /// Checksum derived from event_num, pack, compression and root (the entries below)
+    #[bincode(with_serde)]
+    pub checksum: Hash,
+    /// Event number
+    pub event_num: u64,
+    /// Generation of the chunk pack holding the state
+    pub pack: u64,
+    /// Compression of the chunks in the pack
+    pub compression: Compression,
+    /// Chunk holding the root of the state
+    pub root: ChunkId,
+}
+
+impl DeltaCheckpointV2 {
+    pub fn new(
+        ker_hash: Hash,
+        event_num: u64,
+        pack: u64,
+        compression: Compression,
+        root: ChunkId,
+    ) -> Self {
+        let checksum = Self::checksum(event_num, pack, compression, &root);
+        Self {
+            magic_bytes: JAM_MAGIC_BYTES,
+            version: SNAPSHOT_VERSION_2,
+            ker_hash,
+            checksum,
+            event_num,
+            pack,
+            compression,
+            root,
+        }
+    }
+
+    pub fn validate(&self, path: &PathBuf) -> Result<(), CheckpointError> {
+        if self.version != SNAPSHOT_VERSION_2 {
+            Err(CheckpointError::InvalidVersion(path.clone()))
+        } else if self.checksum
+            != Self::checksum(self.event_num, self.pack, self.compression, &self.root)
+        {
+            Err(CheckpointError::InvalidChecksum(path.clone()))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>, bincode::error::EncodeError> {
+        encode_to_vec(self, config::standard())
+    }
+
+    fn checksum(event_num: u64, pack: u64, compression: Compression, root: &ChunkId) -> Hash {
+        DeltaCheckpointV3::checksum(event_num, pack, compression, root, &TimerTable::default())
+    }
+
+    fn decode<R: Read>(reader: &mut R, path: &PathBuf) -> Result<Self, CheckpointError> {
+        let checkpoint: Self = bincode::decode_from_std_read(reader, config::standard())?;
+        checkpoint.validate(path)?;
+        Ok(checkpoint)
+    }
+}
+
+/// A [DeltaCheckpointV2] which also carries the kernel's timers. They are kept out of the
+/// state noun so nothing in the state can be mistaken for them.
+#[derive(Encode, Decode, PartialEq, Debug)]
+pub struct DeltaCheckpointV3 {
+    /// Magic bytes to identify checkpoint format
+    pub magic_bytes: u64,
+    /// Version of checkpoint
+    pub version: u32,
+    /// Hash of the boot kernel
+    #[bincode(with_serde)]
+    pub ker_hash: Hash,
+    /// Checksum derived from event_num, pack, compression, root and timers (the entries below)
+    #[bincode(with_serde)]
+    pub checksum: Hash,
+    /// Event number
+    pub event_num: u64,
+    /// Generation of the chunk pack holding the state
+    pub pack: u64,
+    /// Compression of the chunks in the pack
+    pub compression: Compression,
+    /// Chunk holding the root of the state
+    pub root: ChunkId,
+    /// Timers the kernel has scheduled
+    pub timers: TimerTable,
+}
+
+impl DeltaCheckpointV3 {
+    pub fn new(
+        ker_hash: Hash,
+        event_num: u64,
+        pack: u64,
+        compression: Compression,
+        root: ChunkId,
+        timers: TimerTable,
+    ) -> Self {
+        let checksum = Self::checksum(event_num, pack, compression, &root, &timers);
+        Self {
+            magic_bytes: JAM_MAGIC_BYTES,
+            version: SNAPSHOT_VERSION_3,
+            ker_hash,
+            checksum,
+            event_num,
+            pack,
+            compression,
+            root,
+            timers,
+        }
+    }
+
+    pub fn validate(&self, path: &PathBuf) -> Result<(), CheckpointError> {
+        if self.version != SNAPSHOT_VERSION_3 {
+            Err(CheckpointError::InvalidVersion(path.clone()))
+        } else if self.checksum
+            != Self::checksum(
+                self.event_num, self.pack, self.compression, &self.root, &self.timers,
+            )
+        {
+            Err(CheckpointError::InvalidChecksum(path.clone()))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>, bincode::error::EncodeError> {
+        encode_to_vec(self, config::standard())
+    }
+
+    /// The same as a [DeltaCheckpointV2] checksum when there are no timers
+    fn checksum(
+        event_num: u64,
+        pack: u64,
+        compression: Compression,
+        root: &ChunkId,
+        timers: &TimerTable,
+    ) -> Hash {
+        let mut hasher = Hasher::new();
+        hasher.update(&event_num.to_le_bytes());
+        hasher.update(&pack.to_le_bytes());
+        hasher.update(&[compression as u8]);
+        hasher.update(root);
+        for timer in timers.timers() {
+            hasher.update(&(timer.id.len() as u64).to_le_bytes());
+            hasher.update(&timer.id);
+            hasher.update(&timer.when.0.to_le_bytes());
+        }
+        hasher.finalize()
+    }
+
+    fn decode<R: Read>(reader: &mut R, path: &PathBuf) -> Result<Self, CheckpointError> {
+        let checkpoint: Self = bincode::decode_from_std_read(reader, config::standard())?;
+        checkpoint.validate(path)?;
+        Ok(checkpoint)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn save_to_file(&self, path: &PathBuf) -> Result<(), CheckpointError> {
+        let bytes = self.encode()?;
+        trace!("Saving delta checkpoint to file: {}", path.display());
+        write_atomically(path, &bytes).await?;
+        Ok(())
+    }
+}
+
+impl From<DeltaCheckpointV2> for DeltaCheckpointV3 {
+    fn from(v2: DeltaCheckpointV2) -> Self {
+        DeltaCheckpointV3 {
+            magic_bytes: v2.magic_bytes,
+            version: SNAPSHOT_VERSION_3,
+            ker_hash: v2.ker_hash,
+            checksum: v2.checksum,
+            event_num: v2.event_num,
+            pack: v2.pack,
+            compression: v2.compression,
+            root: v2.root,
+            timers: TimerTable::default(),
+        }
+    }
+}
+
+/// Write `bytes` beside `path`, sync them and rename them over it, then sync the directory,
+/// so `path` is never left torn and the new file survives power loss once this returns.
+async fn write_atomically(path: &Path, bytes: &[u8]) -> Result<(), CheckpointError> {
+    let tmp_path = path.with_extension("tmp");
+    let mut file = tokio::fs::File::create(&tmp_path).await?;
+    file.write_all(bytes).await?;
+    file.sync_all().await?;
+    tokio::fs::rename(&tmp_path, path).await?;
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        tokio::fs::File::open(dir).await?.sync_all().await?;
+    }
+    Ok(())
+}
+
+/// A checkpoint file as read from disk, before the kernel state is rebuilt from it
+enum LoadedCheckpoint {
+    Jammed(JammedCheckpointV1),
+    Delta(DeltaCheckpointV3),
+}
+
+impl LoadedCheckpoint {
+    #[tracing::instrument(skip_all)]
+    async fn load_from_file(path: &PathBuf) -> Result<Self, CheckpointError> {
+        debug!("Loading checkpoint from file: {}", path.display());
+        // Decode straight from the file rather than reading it into memory first, so a
+        // jammed checkpoint is only held once
+        let mut reader = open_checkpoint(path)?;
+        // Every version starts with the magic bytes and version number
+        let header =
+            bincode::decode_from_std_read::<(u64, u32), _, _>(&mut reader, config::standard());
+        // A compressed stream can't be rewound, so start over from a fresh one
+        let mut reader = open_checkpoint(path)?;
+        match header {
+            Ok((JAM_MAGIC_BYTES, SNAPSHOT_VERSION_3)) => {
+                return DeltaCheckpointV3::decode(&mut reader, path).map(Self::Delta);
+            }
+            Ok((JAM_MAGIC_BYTES, SNAPSHOT_VERSION_2)) => {
+                return DeltaCheckpointV2::decode(&mut reader, path)
+                    .map(|c2| Self::Delta(DeltaCheckpointV3::from(c2)));
+            }
+            _ => {}
+        }
+        match JammedCheckpointV1::decode(&mut reader, path) {
+            Ok(c) => Ok(Self::Jammed(c)),
+            Err(e_v1) => JammedCheckpointV0::load_from_file(path)
+                .await
+                .map(|c0| JammedCheckpointV1::from(c0))
+                .and_then(|c| c.validate(path).map(|_| c))
+                .map(Self::Jammed)
+                .map_err(|e_v0| CheckpointError::VersionsFailed {
+                    v1: Box::new(e_v1),
+                    v0: Box::new(e_v0),
+                }),
+        }
+    }
+
+    fn event_num(&self) -> u64 {
+        match self {
+            Self::Jammed(c) => c.event_num,
+            Self::Delta(c) => c.event_num,
+        }
+    }
+
+    fn checksum(&self) -> Hash {
+        match self {
+            Self::Jammed(c) => c.checksum,
+            Self::Delta(c) => c.checksum,
+        }
+    }
+
+    fn pack(&self) -> Option<u64> {
+        match self {
+            Self::Jammed(_) => None,
+            Self::Delta(c) => Some(c.pack),
+        }
+    }
+}
+
 impl From<JammedCheckpointV0> for JammedCheckpoint {
     fn from(v0: JammedCheckpointV0) -> Self {
         JammedCheckpointV1 {
@@ -0,0 +1,645 @@
+//! Typed conversion between Rust values and nouns.
+//!
+//! [NounEncode] and [NounDecode] are implemented for atoms, strings, options, vectors,
+//! tuples and maps, and can be derived for structs and enums:
+//!
+//! - A struct is the right-nested tuple of its fields, `[a b c]`. A struct with one field is
+//!   just that field, and one with no fields is `~`.
+//! - An enum is a tagged union. A variant with fields is `[%tag fields]`, its fields laid out
+//!   like a struct's, and a unit variant is the bare `%tag`. The tag is the variant name in
+//!   kebab case unless `#[noun(tag = "...")]` overrides it.
+//! - `#[noun(tag = "...")]` on the type itself wraps the whole thing as `[%tag ...]`.
+//!
+//! ```ignore
+//! #[derive(NounEncode, NounDecode)]
+//! #[noun(tag = "command")]
+//! enum Command {
+//!     // [%command %set-mining-key 'pubkey']
+//!     SetMiningKey(String),
+//!     // [%command %pause]
+//!     Pause,
+//! }
+//! ```
+//!
+//! Standard types map to their usual Hoon forms: `bool` is a loobean, [String] a cord,
+//! [Option] a unit, [Vec] a list, [BTreeSet] a set and [BTreeMap] a map. Opaque
+//! sub-nouns can be carried as [NounSlab] fields.
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
+
+pub use nockapp_macros::{NounDecode, NounEncode};
+use nockvm::mug::{calc_atom_mug_u32, calc_cell_mug_u32, get_mug, mug_u32_one};
+use nockvm::noun::{Atom, Noun, D, NO, T, YES};
+use thiserror::Error;
+
+use crate::noun::slab::{slab_noun_equality, NounSlab};
+use crate::AtomExt;
+
+/// Build a noun from a Rust value
+pub trait NounEncode {
+    fn to_noun(&self, slab: &mut NounSlab) -> Noun;
+
+    /// A new slab rooted at this value
+    fn to_slab(&self) -> NounSlab {
+        let mut slab = NounSlab::new();
+        let noun = self.to_noun(&mut slab);
+        slab.set_root(noun);
+        slab
+    }
+}
+
+/// Read a Rust value out of a noun
+pub trait NounDecode: Sized {
+    fn from_noun(noun: &Noun) -> Result<Self, NounDecodeError>;
+
+    /// Decode the root of `slab`
+    fn from_slab(slab: &NounSlab) -> Result<Self, NounDecodeError> {
+        Self::from_noun(unsafe { slab.root() })
+    }
+}
+
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum NounDecodeError {
+    #[error("expected an atom")]
+    ExpectedAtom,
+    #[error("expected a cell")]
+    ExpectedCell,
+    #[error("expected ~")]
+    ExpectedNull,
+    #[error("atom does not fit in {0}")]
+    AtomTooLarge(&'static str),
+    #[error("expected a loobean, got {0}")]
+    InvalidLoobean(u64),
+    #[error("expected a unit")]
+    InvalidUnit,
+    #[error("cord is not valid UTF-8")]
+    InvalidUtf8,
+    #[error("expected tag %{0}")]
+    ExpectedTag(&'static str),
+    #[error("unknown tag %{0}")]
+    UnknownTag(String),
+    #[error("{context}: {source}")]
+    Field {
+        context: &'static str,
+        #[source]
+        source: Box<NounDecodeError>,
+    },
+}
+
+// Helpers for the derive macros
+
+/// `~`
+pub fn null() -> Noun {
+    D(0)
+}
+
+pub fn cell(slab: &mut NounSlab, head: Noun, tail: Noun) -> Noun {
+    T(slab, &[head, tail])
+}
+
+/// The `@tas` for `tag`
+pub fn tag(slab: &mut NounSlab, tag: &str) -> Noun {
+    Atom::from_value(slab, tag)
+        .expect("str to atom is infallible")
+        .as_noun()
+}
+
+pub fn tag_eq(atom: Atom, tag: &str) -> bool {
+    atom.eq_bytes(tag)
+}
+
+pub fn unknown_tag(atom: Atom) -> NounDecodeError {
+    NounDecodeError::UnknownTag(
+        atom.into_string()
+            .unwrap_or_else(|_| format!("{:?}", atom.as_noun())),
+    )
+}
+
+pub fn split(noun: &Noun) -> Result<(Noun, Noun), NounDecodeError> {
+    let cell = noun.as_cell().map_err(|_| NounDecodeError::ExpectedCell)?;
+    Ok((cell.head(), cell.tail()))
+}
+
+pub fn expect_atom(noun: &Noun) -> Result<Atom, NounDecodeError> {
+    noun.as_atom().map_err(|_| NounDecodeError::ExpectedAtom)
+}
+
+pub fn expect_null(noun: &Noun) -> Result<(), NounDecodeError> {
+    match noun.as_atom().and_then(|atom| atom.as_u64()) {
+        Ok(0) => Ok(()),
+        _ => Err(NounDecodeError::ExpectedNull),
+    }
+}
+
+/// The tail of `[%tag tail]`
+pub fn expect_tag(noun: &Noun, tag: &'static str) -> Result<Noun, NounDecodeError> {
+    let (head, tail) = split(noun)?;
+    match head.as_atom() {
+        Ok(atom) if tag_eq(atom, tag) => Ok(tail),
+        _ => Err(NounDecodeError::ExpectedTag(tag)),
+    }
+}
+
+/// Decode a field, saying which one in the error
+pub fn field<T: NounDecode>(noun: &Noun, context: &'static str) -> Result<T, NounDecodeError> {
+    T::from_noun(noun).map_err(|source| NounDecodeError::Field {
+        context,
+        source: Box::new(source),
+    })
+}
+
+macro_rules! impl_unsigned {
+    ($($ty:ty),*) => {$(
+        impl NounEncode for $ty {
+            fn to_noun(&self, slab: &mut NounSlab) -> Noun {
+                Atom::new(slab, *self as u64).as_noun()
+            }
+        }
+
+        impl NounDecode for $ty {
+            fn from_noun(noun: &Noun) -> Result<Self, NounDecodeError> {
+                expect_atom(noun)?
+                    .as_u64()
+                    .ok()
+                    .and_then(|value| <$ty>::try_from(value).ok())
+                    .ok_or(NounDecodeError::AtomTooLarge(stringify!($ty)))
+            }
+        }
+    )*};
+}
+
+impl_unsigned!(u8, u16, u32, u64, usize);
+
+impl NounEncode for bool {
+    fn to_noun(&self, _slab: &mut NounSlab) -> Noun {
+        if *self {
+            YES
+        } else {
+            NO
+        }
+    }
+}
+
+impl NounDecode for bool {
+    fn from_noun(noun: &Noun) -> Result<Self, NounDecodeError> {
+        match expect_atom(noun)?.as_u64() {
+            Ok(0) => Ok(true),
+            Ok(1) => Ok(false),
+            Ok(other) => Err(NounDecodeError::InvalidLoobean(other)),
+            Err(_) => Err(NounDecodeError::AtomTooLarge("bool")),
+        }
+    }
+}
+
+impl NounEncode for () {
+    fn to_noun(&self, _slab: &mut NounSlab) -> Noun {
+        null()
+    }
+}
+
+impl NounDecode for () {
+    fn from_noun(noun: &Noun) -> Result<Self, NounDecodeError> {
+        expect_null(noun)
+    }
+}
+
+impl NounEncode for str {
+    fn to_noun(&self, slab: &mut NounSlab) -> Noun {
+        tag(slab, self)
+    }
+}
+
+impl NounEncode for String {
+    fn to_noun(&self, slab: &mut NounSlab) -> Noun {
+        self.as_str().to_noun(slab)
+    }
+}
+
+impl NounDecode for String {
+    fn from_noun(noun: &Noun) -> Result<Self, NounDecodeError> {
+        expect_atom(noun)?
+            .into_string()
+            .map_err(|_| NounDecodeError::InvalidUtf8)
+    }
+}
+
+impl<T: NounEncode + ?Sized> NounEncode for &T {
+    fn to_noun(&self, slab: &mut NounSlab) -> Noun {
+        (**self).to_noun(slab)
+    }
+}
+
+impl<T: NounEncode + ?Sized> NounEncode for Box<T> {
+    fn to_noun(&self, slab: &mut NounSlab) -> Noun {
+        (**self).to_noun(slab)
+    }
+}
+
+impl<T: NounDecode> NounDecode for Box<T> {
+    fn from_noun(noun: &Noun) -> Result<Self, NounDecodeError> {
+        T::from_noun(noun).map(Box::new)
+    }
+}
+
+/// An opaque noun, copied in and out
+impl NounEncode for NounSlab {
+    fn to_noun(&self, slab: &mut NounSlab) -> Noun {
+        slab.copy_into(unsafe { *self.root() })
+    }
+}
+
+impl NounDecode for NounSlab {
+    fn from_noun(noun: &Noun) -> Result<Self, NounDecodeError> {
+        let mut slab = NounSlab::new();
+        let copy = slab.copy_into(*noun);
+        slab.set_root(copy);
+        Ok(slab)
+    }
+}
+
+/// A unit: `~` or `[~ value]`
+impl<T: NounEncode> NounEncode for Option<T> {
+    fn to_noun(&self, slab: &mut NounSlab) -> Noun {
+        match self {
+            None => null(),
+            Some(value) => {
+                let value = value.to_noun(slab);
+                cell(slab, null(), value)
+            }
+        }
+    }
+}
+
+impl<T: NounDecode> NounDecode for Option<T> {
+    fn from_noun(noun: &Noun) -> Result<Self, NounDecodeError> {
+        if expect_null(noun).is_ok() {
+            return Ok(None);
+        }
+        let (head, value) = split(noun).map_err(|_| NounDecodeError::InvalidUnit)?;
+        expect_null(&head).map_err(|_| NounDecodeError::InvalidUnit)?;
+        T::from_noun(&value).map(Some)
+    }
+}
+
+/// A null-terminated list
+impl<T: NounEncode> NounEncode for Vec<T> {
+    fn to_noun(&self, slab: &mut NounSlab) -> Noun {
+        self.as_slice().to_noun(slab)
+    }
+}
+
+impl<T: NounEncode> NounEncode for [T] {
+    fn to_noun(&self, slab: &mut NounSlab) -> Noun {
+        let mut list = null();
+        for item in self.iter().rev() {
+            let item = item.to_noun(slab);
+            list = cell(slab, item, list);
+        }
+        list
+    }
+}
+
+impl<T: NounDecode> NounDecode for Vec<T> {
+    fn from_noun(noun: &Noun) -> Result<Self, NounDecodeError> {
+        let mut items = Vec::new();
+        let mut list = *noun;
+        while expect_null(&list).is_err() {
+            let (item, rest) = split(&list)?;
+            items.push(T::from_noun(&item)?);
+            list = rest;
+        }
+        Ok(items)
+    }
+}
+
+macro_rules! impl_tuple {
+    ($($name:ident),+; $last:ident) => {
+        impl<$($name: NounEncode,)+ $last: NounEncode> NounEncode for ($($name,)+ $last) {
+            #[allow(non_snake_case)]
+            fn to_noun(&self, slab: &mut NounSlab) -> Noun {
+                let ($($name,)+ $last) = self;
+                let mut nouns = vec![$($name.to_noun(slab),)+];
+                nouns.push($last.to_noun(slab));
+                T(slab, &nouns)
+            }
+        }
+
+        impl<$($name: NounDecode,)+ $last: NounDecode> NounDecode for ($($name,)+ $last) {
+            #[allow(non_snake_case)]
+            fn from_noun(noun: &Noun) -> Result<Self, NounDecodeError> {
+                let rest = *noun;
+                $(
+                    let (head, rest) = split(&rest)?;
+                    let $name = $name::from_noun(&head)?;
+                )+
+                Ok(($($name,)+ $last::from_noun(&rest)?))
+            }
+        }
+    };
+}
+
+impl_tuple!(A0; A1);
+impl_tuple!(A0, A1; A2);
+impl_tuple!(A0, A1, A2; A3);
+impl_tuple!(A0, A1, A2, A3; A4);
+
+/// A set, as the treap `put:in` would build
+impl<T: NounEncode> NounEncode for BTreeSet<T> {
+    fn to_noun(&self, slab: &mut NounSlab) -> Noun {
+        let mut treap = None;
+        for item in self {
+            let key = item.to_noun(slab);
+            treap = Some(treap_put(treap, TreapNode::leaf(key, None)));
+        }
+        treap_to_noun(treap, slab)
+    }
+}
+
+impl<T: NounDecode + Ord> NounDecode for BTreeSet<T> {
+    fn from_noun(noun: &Noun) -> Result<Self, NounDecodeError> {
+        let mut set = BTreeSet::new();
+        walk_treap(*noun, &mut |node| {
+            set.insert(T::from_noun(&node)?);
+            Ok(())
+        })?;
+        Ok(set)
+    }
+}
+
+/// A map, as the treap `put:by` would build
+impl<K: NounEncode, V: NounEncode> NounEncode for BTreeMap<K, V> {
+    fn to_noun(&self, slab: &mut NounSlab) -> Noun {
+        let mut treap = None;
+        for (key, value) in self {
+            let key = key.to_noun(slab);
+            let value = value.to_noun(slab);
+            treap = Some(treap_put(treap, TreapNode::leaf(key, Some(value))));
+        }
+        treap_to_noun(treap, slab)
+    }
+}
+
+impl<K: NounDecode + Ord, V: NounDecode> NounDecode for BTreeMap<K, V> {
+    fn from_noun(noun: &Noun) -> Result<Self, NounDecodeError> {
+        let mut map = BTreeMap::new();
+        walk_treap(*noun, &mut |node| {
+            let (key, value) = split(&node)?;
+            map.insert(K::from_noun(&key)?, V::from_noun(&value)?);
+            Ok(())
+        })?;
+        Ok(map)
+    }
+}
+
+/// A treap node under construction. `value` is `None` for sets.
+struct TreapNode {
+    key: Noun,
+    value: Option<Noun>,
+    left: Option<Box<TreapNode>>,
+    right: Option<Box<TreapNode>>,
+}
+
+impl TreapNode {
+    fn leaf(key: Noun, value: Option<Noun>) -> Box<TreapNode> {
+        Box::new(TreapNode {
+            key,
+            value,
+            left: None,
+            right: None,
+        })
+    }
+}
+
+/// `put:by` / `put:in`: insert `node`, ordered by [gor] and rotated up by [mor]
+fn treap_put(treap: Option<Box<TreapNode>>, node: Box<TreapNode>) -> Box<TreapNode> {
+    let Some(mut top) = treap else {
+        return node;
+    };
+    if slab_noun_equality(&node.key, &top.key) {
+        top.value = node.value;
+        return top;
+    }
+    if gor(node.key, top.key) {
+        let mut below = treap_put(top.left.take(), node);
+        if mor(top.key, below.key) {
+            top.left = Some(below);
+            top
+        } else {
+            top.left = below.right.take();
+            below.right = Some(top);
+            below
+        }
+    } else {
+        let mut below = treap_put(top.right.take(), node);
+        if mor(top.key, below.key) {
+            top.right = Some(below);
+            top
+        } else {
+            top.right = below.left.take();
+            below.left = Some(top);
+            below
+        }
+    }
+}
+
+fn treap_to_noun(treap: Option<Box<TreapNode>>, slab: &mut NounSlab) -> Noun {
+    let Some(node) = treap else {
+        return null();
+    };
+    let entry = match node.value {
+        Some(value) => cell(slab, node.key, value),
+        None => node.key,
+    };
+    let left = treap_to_noun(node.left, slab);
+    let right = treap_to_noun(node.right, slab);
+    T(slab, &[entry, left, right])
+}
+
+/// Call `visit` on the entry of every node in a treap
+fn walk_treap(
+    treap: Noun,
+    visit: &mut impl FnMut(Noun) -> Result<(), NounDecodeError>,
+) -> Result<(), NounDecodeError> {
+    if expect_null(&treap).is_ok() {
+        return Ok(());
+    }
+    let (entry, children) = split(&treap)?;
+    let (left, right) = split(&children)?;
+    visit(entry)?;
+    walk_treap(left, visit)?;
+    walk_treap(right, visit)
+}
+
+/// The mug of a noun, caching it on allocated nouns as nockvm does
+fn mug(noun: Noun) -> u32 {
+    if let Some(mug) = get_mug(noun) {
+        return mug;
+    }
+    match noun.as_cell() {
+        Ok(cell) => {
+            let head = mug(cell.head());
+            let tail = mug(cell.tail());
+            mug_u32_one(noun).unwrap_or_else(|| unsafe { calc_cell_mug_u32(head, tail) })
+        }
+        Err(_) => mug_u32_one(noun)
+            .unwrap_or_else(|| calc_atom_mug_u32(noun.as_atom().expect("not a cell"))),
+    }
+}
+
+/// Hoon `dor`: depth-first order
+fn dor(a: Noun, b: Noun) -> bool {
+    if slab_noun_equality(&a, &b) {
+        return true;
+    }
+    match (a.as_cell(), b.as_cell()) {
+        (Ok(a), Ok(b)) => {
+            if slab_noun_equality(&a.head(), &b.head()) {
+                dor(a.tail(), b.tail())
+            } else {
+                dor(a.head(), b.head())
+            }
+        }
+        (Ok(_), Err(_)) => false,
+        (Err(_), Ok(_)) => true,
+        (Err(_), Err(_)) => {
+            let (a, b) = (a.as_atom().expect("atom"), b.as_atom().expect("atom"));
+            atom_cmp(a, b) == Ordering::Less
+        }
+    }
+}
+
+fn atom_cmp(a: Atom, b: Atom) -> Ordering {
+    let (a, b) = (a.to_le_bytes(), b.to_le_bytes());
+    let significant = |bytes: &[u8]| {
+        bytes
+            .iter()
+            .rposition(|byte| *byte != 0)
+            .map_or(0, |i| i + 1)
+    };
+    let (a, b) = (&a[..significant(&a)], &b[..significant(&b)]);
+    a.len()
+        .cmp(&b.len())
+        .then_with(|| a.iter().rev().cmp(b.iter().rev()))
+}
+
+/// Hoon `gor`: mug order
+fn gor(a: Noun, b: Noun) -> bool {
+    let (c, d) = (mug(a), mug(b));
+    if c == d {
+        dor(a, b)
+    } else {
+        c < d
+    }
+}
+
+/// Hoon `mor`: double-mug order
+fn mor(a: Noun, b: Noun) -> bool {
+    let double_mug = |noun: Noun| calc_atom_mug_u32(unsafe { Atom::from_raw(mug(noun) as u64) });
+    let (c, d) = (double_mug(a), double_mug(b));
+    if c == d {
+        dor(a, b)
+    } else {
+        c < d
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nockvm_macros::tas;
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, NounEncode, NounDecode)]
+    struct Config {
+        share: u64,
+        m: u64,
+        keys: Vec<String>,
+    }
+
+    #[derive(Debug, PartialEq, NounEncode, NounDecode)]
+    #[noun(tag = "command")]
+    enum Command {
+        SetMiningKey(String),
+        SetMiningKeyAdvanced(Vec<Config>),
+        #[noun(tag = "enable")]
+        EnableMining {
+            enable: bool,
+        },
+        Pause,
+    }
+
+    fn round_trip<T: NounEncode + NounDecode + PartialEq + std::fmt::Debug>(value: T) {
+        let slab = value.to_slab();
+        assert_eq!(T::from_slab(&slab).expect("decode"), value);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_derived_shapes() {
+        let slab = Command::SetMiningKey("key".to_string()).to_slab();
+        let (command, rest) = split(unsafe { slab.root() }).expect("cell");
+        let (set, key) = split(&rest).expect("cell");
+        assert!(unsafe { command.raw_equals(&D(tas!(b"command"))) });
+        assert!(set.as_atom().expect("tag").eq_bytes("set-mining-key"));
+        assert!(key.as_atom().expect("cord").eq_bytes("key"));
+
+        let slab = Command::Pause.to_slab();
+        let pause = expect_tag(unsafe { slab.root() }, "command").expect("command");
+        assert!(unsafe { pause.raw_equals(&D(tas!(b"pause"))) });
+
+        round_trip(Command::SetMiningKeyAdvanced(vec![Config {
+            share: 1,
+            m: 2,
+            keys: vec!["a".to_string(), "b".to_string()],
+        }]));
+        round_trip(Command::EnableMining { enable: false });
+        round_trip((Some(3u64), None::<u32>, ()));
+
+        let mut wrong = NounSlab::new();
+        let noun = T(&mut wrong, &[D(tas!(b"command")), D(tas!(b"nope")), D(0)]);
+        wrong.set_root(noun);
+        assert_eq!(
+            Command::from_slab(&wrong),
+            Err(NounDecodeError::UnknownTag("nope".to_string()))
+        );
+        let mut wrong = NounSlab::new();
+        let noun = T(&mut wrong, &[D(tas!(b"command")), D(tas!(b"enable")), D(2)]);
+        wrong.set_root(noun);
+        assert!(matches!(
+            Command::from_slab(&wrong),
+            Err(NounDecodeError::Field {
+                context: "Command::EnableMining.enable",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_map_and_set_treaps() {
+        let map: BTreeMap<u64, String> = (0..50).map(|i| (i, format!("value {}", i))).collect();
+        round_trip(map.clone());
+        let set: BTreeSet<String> = map.values().cloned().collect();
+        round_trip(set);
+
+        // Every node sits above its children in mor order and between them in gor order
+        fn check(treap: Noun) {
+            let Ok(node) = treap.as_cell() else {
+                return;
+            };
+            let key = node.head().as_cell().expect("entry").head();
+            let (left, right) = split(&node.tail()).expect("children");
+            for (child, before) in [(left, true), (right, false)] {
+                if let Ok(child) = child.as_cell() {
+                    let child_key = child.head().as_cell().expect("entry").head();
+                    assert!(mor(key, child_key));
+                    assert_eq!(gor(child_key, key), before);
+                }
+            }
+            check(left);
+            check(right);
+        }
+        let slab = map.to_slab();
+        check(unsafe { *slab.root() });
+    }
+}
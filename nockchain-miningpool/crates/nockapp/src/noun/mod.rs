@@ -1,5 +1,7 @@
+pub mod codec;
 mod extensions;
 mod ops;
 pub mod slab;
+pub use codec::{NounDecode, NounDecodeError, NounEncode};
 pub use extensions::*;
 pub use ops::*;
@@ -1,6 +1,6 @@
 use std::sync::Arc;
 
-use nockvm::interpreter::{interpret, Context};
+use nockvm::interpreter::{interpret, Context, Error, Mote};
 use nockvm::noun::{Noun, D, T};
 use tracing::{span, Level};
 
@@ -34,11 +34,16 @@ pub fn slam(
     let sub = T(stack, &[arvo, ovo]);
 
     span!(Level::DEBUG, "interpret").in_scope(|| {
-        let res = interpret(context, sub, fol).map_err(CrownError::from);
+        let res = interpret(context, sub, fol);
         let _ = metrics.map(|m| {
             m.least_free_space_seen_in_slam
-                .swap((context.stack.least_space() * 8) as f64)
-        }); // least_space is in 8-byte words
-        res
+                .swap((context.stack.least_space() * 8) as f64);
+            m.nock_stack_peak_usage
+                .swap((context.stack.peak_usage() * 8) as f64);
+            if let Err(Error::NonDeterministic(Mote::Meme, _)) = res {
+                m.nock_stack_out_of_memory.increment();
+            }
+        }); // least_space and peak_usage are in 8-byte words
+        res.map_err(CrownError::from)
     })
 }
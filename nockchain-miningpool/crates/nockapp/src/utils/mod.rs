@@ -187,6 +187,8 @@ pub fn create_context(
         cache,
         scry_stack: D(0),
         trace_info,
+        jet_profile: None,
+        sampler: None,
         test_jets,
         running_status: cancel,
     }
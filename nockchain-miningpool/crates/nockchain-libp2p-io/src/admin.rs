@@ -0,0 +1,363 @@
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use libp2p::{Multiaddr, PeerId, Swarm};
+use nockapp::NockAppError;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{error, info, warn};
+
+use crate::behaviour::NockchainBehaviour;
+use crate::p2p_util::MessageTracker;
+
+/// A runtime change to (or query of) the swarm's peer state
+#[derive(Debug, Clone)]
+pub enum PeerAdminRequest {
+    /// List connected peers along with banned peers and IPs
+    ListPeers,
+    /// Dial a multiaddr
+    Dial(Multiaddr),
+    /// Block a peer ID and disconnect it
+    BanPeer(PeerId),
+    /// Remove a peer ID from the block list
+    UnbanPeer(PeerId),
+    /// Refuse connections from an IP and close existing ones
+    BanIp(IpAddr),
+    /// Accept connections from an IP again
+    UnbanIp(IpAddr),
+    /// Add a peer ID to the allow list (only if `--allowed-peers-path` was given)
+    AllowPeer(PeerId),
+    /// Remove a peer ID from the allow list
+    DisallowPeer(PeerId),
+}
+
+/// Per-peer view returned by [PeerAdminRequest::ListPeers]
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct PeerInfo {
+    pub peer_id: String,
+    pub addresses: Vec<String>,
+    pub connections: usize,
+    pub inbound: bool,
+    pub local: bool,
+    pub failed_pings: u64,
+    pub tracked_block_ids: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "status", rename_all = "kebab-case")]
+pub enum PeerAdminResponse {
+    Peers {
+        peers: Vec<PeerInfo>,
+        banned_peers: Vec<String>,
+        banned_ips: Vec<String>,
+    },
+    Ok,
+    Error {
+        message: String,
+    },
+}
+
+impl PeerAdminResponse {
+    fn error(message: impl Into<String>) -> Self {
+        PeerAdminResponse::Error {
+            message: message.into(),
+        }
+    }
+}
+
+/// A [PeerAdminRequest] on its way to the swarm loop, with a channel for the reply.
+///
+/// Like [crate::behaviour::SwarmAction], this exists because the swarm isn't `Send`
+/// and has to be driven from the driver's main loop.
+#[derive(Debug)]
+pub struct PeerAdminCommand {
+    pub request: PeerAdminRequest,
+    pub reply: oneshot::Sender<PeerAdminResponse>,
+}
+
+/// Applies an admin request to the swarm. Called from the driver's main loop.
+pub(crate) fn apply(
+    swarm: &mut Swarm<NockchainBehaviour>,
+    tracker: &mut MessageTracker,
+    request: PeerAdminRequest,
+) -> PeerAdminResponse {
+    match request {
+        PeerAdminRequest::ListPeers => {
+            let mut banned_peers: Vec<String> = swarm
+                .behaviour()
+                .allow_block_list
+                .blocked_peers()
+                .iter()
+                .map(|peer_id| peer_id.to_base58())
+                .collect();
+            banned_peers.sort();
+            PeerAdminResponse::Peers {
+                peers: tracker.peer_infos(),
+                banned_peers,
+                banned_ips: tracker.banned_ips().map(|ip| ip.to_string()).collect(),
+            }
+        }
+        PeerAdminRequest::Dial(addr) => {
+            info!("Admin: dialing {addr}");
+            match swarm.dial(addr.clone()) {
+                Ok(()) => PeerAdminResponse::Ok,
+                Err(e) => PeerAdminResponse::error(format!("failed to dial {addr}: {e}")),
+            }
+        }
+        PeerAdminRequest::BanPeer(peer_id) => {
+            warn!("Admin: banning peer {peer_id}");
+            swarm.behaviour_mut().allow_block_list.block_peer(peer_id);
+            let _ = swarm.disconnect_peer_id(peer_id);
+            tracker.remove_peer(&peer_id);
+            PeerAdminResponse::Ok
+        }
+        PeerAdminRequest::UnbanPeer(peer_id) => {
+            info!("Admin: unbanning peer {peer_id}");
+            swarm.behaviour_mut().allow_block_list.unblock_peer(peer_id);
+            PeerAdminResponse::Ok
+        }
+        PeerAdminRequest::BanIp(ip) => {
+            warn!("Admin: banning IP {ip}");
+            for connection_id in tracker.ban_ip(ip) {
+                swarm.close_connection(connection_id);
+            }
+            PeerAdminResponse::Ok
+        }
+        PeerAdminRequest::UnbanIp(ip) => {
+            info!("Admin: unbanning IP {ip}");
+            tracker.unban_ip(&ip);
+            PeerAdminResponse::Ok
+        }
+        PeerAdminRequest::AllowPeer(peer_id) => match swarm.behaviour_mut().allow_peers.as_mut() {
+            Some(allow_peers) => {
+                info!("Admin: allowing peer {peer_id}");
+                allow_peers.allow_peer(peer_id);
+                PeerAdminResponse::Ok
+            }
+            None => PeerAdminResponse::error("allow list is not enabled"),
+        },
+        PeerAdminRequest::DisallowPeer(peer_id) => {
+            match swarm.behaviour_mut().allow_peers.as_mut() {
+                Some(allow_peers) => {
+                    info!("Admin: disallowing peer {peer_id}");
+                    allow_peers.disallow_peer(peer_id);
+                    let _ = swarm.disconnect_peer_id(peer_id);
+                    PeerAdminResponse::Ok
+                }
+                None => PeerAdminResponse::error("allow list is not enabled"),
+            }
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct DialBody {
+    addr: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct PeerBody {
+    peer_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct BanBody {
+    peer_id: Option<String>,
+    ip: Option<IpAddr>,
+}
+
+fn parse_peer_id(peer_id: &str) -> Result<PeerId, PeerAdminResponse> {
+    PeerId::from_str(peer_id)
+        .map_err(|_| PeerAdminResponse::error(format!("invalid peer id: {peer_id}")))
+}
+
+fn ban_target(
+    body: BanBody,
+    by_peer: fn(PeerId) -> PeerAdminRequest,
+    by_ip: fn(IpAddr) -> PeerAdminRequest,
+) -> Result<PeerAdminRequest, PeerAdminResponse> {
+    match (body.peer_id, body.ip) {
+        (Some(peer_id), None) => Ok(by_peer(parse_peer_id(&peer_id)?)),
+        (None, Some(ip)) => Ok(by_ip(ip)),
+        _ => Err(PeerAdminResponse::error(
+            "expected exactly one of peer_id or ip",
+        )),
+    }
+}
+
+/// Sends a request to the swarm loop and waits for its reply
+async fn submit(
+    admin_tx: &mpsc::Sender<PeerAdminCommand>,
+    request: PeerAdminRequest,
+) -> (StatusCode, Json<PeerAdminResponse>) {
+    let (reply, reply_rx) = oneshot::channel();
+    if admin_tx
+        .send(PeerAdminCommand { request, reply })
+        .await
+        .is_err()
+    {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(PeerAdminResponse::error("libp2p driver is not running")),
+        );
+    }
+    match reply_rx.await {
+        Ok(response @ PeerAdminResponse::Error { .. }) => (StatusCode::BAD_REQUEST, Json(response)),
+        Ok(response) => (StatusCode::OK, Json(response)),
+        Err(_) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(PeerAdminResponse::error(
+                "libp2p driver dropped the request",
+            )),
+        ),
+    }
+}
+
+fn reject(response: PeerAdminResponse) -> (StatusCode, Json<PeerAdminResponse>) {
+    (StatusCode::BAD_REQUEST, Json(response))
+}
+
+async fn list_peers(
+    State(admin_tx): State<mpsc::Sender<PeerAdminCommand>>,
+) -> (StatusCode, Json<PeerAdminResponse>) {
+    submit(&admin_tx, PeerAdminRequest::ListPeers).await
+}
+
+async fn dial(
+    State(admin_tx): State<mpsc::Sender<PeerAdminCommand>>,
+    Json(body): Json<DialBody>,
+) -> (StatusCode, Json<PeerAdminResponse>) {
+    match body.addr.parse::<Multiaddr>() {
+        Ok(addr) => submit(&admin_tx, PeerAdminRequest::Dial(addr)).await,
+        Err(e) => reject(PeerAdminResponse::error(format!(
+            "invalid multiaddr {}: {e}",
+            body.addr
+        ))),
+    }
+}
+
+async fn ban(
+    State(admin_tx): State<mpsc::Sender<PeerAdminCommand>>,
+    Json(body): Json<BanBody>,
+) -> (StatusCode, Json<PeerAdminResponse>) {
+    match ban_target(body, PeerAdminRequest::BanPeer, PeerAdminRequest::BanIp) {
+        Ok(request) => submit(&admin_tx, request).await,
+        Err(response) => reject(response),
+    }
+}
+
+async fn unban(
+    State(admin_tx): State<mpsc::Sender<PeerAdminCommand>>,
+    Json(body): Json<BanBody>,
+) -> (StatusCode, Json<PeerAdminResponse>) {
+    match ban_target(body, PeerAdminRequest::UnbanPeer, PeerAdminRequest::UnbanIp) {
+        Ok(request) => submit(&admin_tx, request).await,
+        Err(response) => reject(response),
+    }
+}
+
+async fn allow(
+    State(admin_tx): State<mpsc::Sender<PeerAdminCommand>>,
+    Json(body): Json<PeerBody>,
+) -> (StatusCode, Json<PeerAdminResponse>) {
+    match parse_peer_id(&body.peer_id) {
+        Ok(peer_id) => submit(&admin_tx, PeerAdminRequest::AllowPeer(peer_id)).await,
+        Err(response) => reject(response),
+    }
+}
+
+async fn disallow(
+    State(admin_tx): State<mpsc::Sender<PeerAdminCommand>>,
+    Json(body): Json<PeerBody>,
+) -> (StatusCode, Json<PeerAdminResponse>) {
+    match parse_peer_id(&body.peer_id) {
+        Ok(peer_id) => submit(&admin_tx, PeerAdminRequest::DisallowPeer(peer_id)).await,
+        Err(response) => reject(response),
+    }
+}
+
+pub(crate) fn router(admin_tx: mpsc::Sender<PeerAdminCommand>) -> Router {
+    Router::new()
+        .route("/peers", get(list_peers))
+        .route("/peers/dial", post(dial))
+        .route("/peers/ban", post(ban))
+        .route("/peers/unban", post(unban))
+        .route("/peers/allow", post(allow))
+        .route("/peers/disallow", post(disallow))
+        .with_state(admin_tx)
+}
+
+/// Serves the peer admin HTTP API on `addr`.
+///
+/// This endpoint is unauthenticated, so it should only ever be bound to loopback
+/// or a trusted management network.
+pub(crate) async fn serve(
+    addr: SocketAddr,
+    admin_tx: mpsc::Sender<PeerAdminCommand>,
+) -> Result<(), NockAppError> {
+    if !addr.ip().is_loopback() {
+        warn!("Peer admin API bound to non-loopback address {addr}, it has no authentication");
+    }
+    let listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .map_err(NockAppError::IoError)?;
+    info!("Peer admin API listening on {addr}");
+    axum::serve(listener, router(admin_tx)).await.map_err(|e| {
+        error!("Peer admin API failed: {e}");
+        NockAppError::IoError(e)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ban_target() {
+        let peer_id = PeerId::random();
+        let by_peer = ban_target(
+            BanBody {
+                peer_id: Some(peer_id.to_base58()),
+                ip: None,
+            },
+            PeerAdminRequest::BanPeer,
+            PeerAdminRequest::BanIp,
+        );
+        assert!(matches!(by_peer, Ok(PeerAdminRequest::BanPeer(p)) if p == peer_id));
+
+        let ip: IpAddr = "192.168.1.20".parse().expect("Failed to parse IP");
+        let by_ip = ban_target(
+            BanBody {
+                peer_id: None,
+                ip: Some(ip),
+            },
+            PeerAdminRequest::UnbanPeer,
+            PeerAdminRequest::UnbanIp,
+        );
+        assert!(matches!(by_ip, Ok(PeerAdminRequest::UnbanIp(i)) if i == ip));
+
+        let both = ban_target(
+            BanBody {
+                peer_id: Some(peer_id.to_base58()),
+                ip: Some(ip),
+            },
+            PeerAdminRequest::BanPeer,
+            PeerAdminRequest::BanIp,
+        );
+        assert!(matches!(both, Err(PeerAdminResponse::Error { .. })));
+
+        let bad_peer = ban_target(
+            BanBody {
+                peer_id: Some("not-a-peer-id".to_string()),
+                ip: None,
+            },
+            PeerAdminRequest::BanPeer,
+            PeerAdminRequest::BanIp,
+        );
+        assert!(matches!(bad_peer, Err(PeerAdminResponse::Error { .. })));
+    }
+}
@@ -4,12 +4,14 @@ use libp2p::request_response::cbor;
 use libp2p::swarm::behaviour::toggle::Toggle;
 use libp2p::swarm::NetworkBehaviour;
 use libp2p::{
-    allow_block_list, connection_limits, identify, kad, memory_connection_limits, ping,
+    allow_block_list, connection_limits, identify, kad, mdns, memory_connection_limits, ping,
     request_response,
 };
+use tracing::error;
 
 use crate::config::LibP2PConfig;
 use crate::messages::{NockchainRequest, NockchainResponse};
+use crate::quic_first::QuicFirst;
 
 #[derive(Debug)]
 pub enum SwarmAction {
@@ -21,6 +23,13 @@ pub enum SwarmAction {
         peer_id: libp2p::PeerId,
         request: NockchainRequest,
     },
+    /// A block fetch request to a local peer, tracked so the fetch can fall back to
+    /// remote peers if it fails
+    SendLocalFetchRequest {
+        peer_id: libp2p::PeerId,
+        request: NockchainRequest,
+        fetch: u64,
+    },
     BlockPeer {
         peer_id: libp2p::PeerId,
     },
@@ -30,12 +39,16 @@ pub enum SwarmAction {
 #[behaviour(to_swarm = "NockchainEvent")]
 /** Composed [NetworkBehaviour] implementation for Nockchain */
 pub(crate) struct NockchainBehaviour {
+    /// Dials QUIC addresses first. Must stay the first field, see [QuicFirst]
+    quic_first: QuicFirst,
     /// Allows nodes to connect via just IP and port and exchange pubkeys
     identify: identify::Behaviour,
     /// Connectivity testing
     ping: ping::Behaviour,
     /// Peer discovery via a DHT
     pub kad: kad::Behaviour<kad::store::MemoryStore>,
+    /// LAN peer discovery
+    pub mdns: Toggle<mdns::tokio::Behaviour>,
     /// Peer banning
     pub allow_block_list: allow_block_list::Behaviour<allow_block_list::BlockedPeers>,
     /// Peer whitelisting
@@ -75,6 +88,23 @@ impl NockchainBehaviour {
             ));
             let kad_behaviour = kad::Behaviour::with_config(peer_id, memory_store, kad_config);
 
+            let mdns_behaviour = if libp2p_config.enable_mdns {
+                let mdns_config = mdns::Config {
+                    query_interval: libp2p_config.mdns_query_interval(),
+                    ..Default::default()
+                };
+                match mdns::tokio::Behaviour::new(mdns_config, peer_id) {
+                    Ok(behaviour) => Some(behaviour),
+                    Err(e) => {
+                        error!("Could not start mDNS, LAN discovery disabled: {e}");
+                        None
+                    }
+                }
+            } else {
+                None
+            };
+            let mdns = Toggle::<mdns::tokio::Behaviour>::from(mdns_behaviour);
+
             let request_response_config = request_response::Config::default()
                 .with_max_concurrent_streams(
                     libp2p_config.request_response_max_concurrent_streams(),
@@ -104,9 +134,11 @@ impl NockchainBehaviour {
 
             let peer_store_behaviour = libp2p::peer_store::Behaviour::new(peer_store_memory);
             NockchainBehaviour {
+                quic_first: QuicFirst::new(record_capacity.get()),
                 ping: ping::Behaviour::default(),
                 identify: identify_behaviour,
                 kad: kad_behaviour,
+                mdns,
                 allow_block_list: allow_block_list::Behaviour::default(),
                 allow_peers,
                 request_response: request_response_behaviour,
@@ -132,6 +164,8 @@ pub enum NockchainEvent {
     Ping(ping::Event),
     /// DHT state changes
     Kad(kad::Event),
+    /// Peers discovered or expired on the local network
+    Mdns(mdns::Event),
     /// Request or response received from peer
     RequestResponse(request_response::Event<NockchainRequest, NockchainResponse>),
     /// Peer store events
@@ -156,6 +190,12 @@ impl From<kad::Event> for NockchainEvent {
     }
 }
 
+impl From<mdns::Event> for NockchainEvent {
+    fn from(event: mdns::Event) -> Self {
+        Self::Mdns(event)
+    }
+}
+
 impl From<Infallible> for NockchainEvent {
     fn from(i: Infallible) -> Self {
         match i {}
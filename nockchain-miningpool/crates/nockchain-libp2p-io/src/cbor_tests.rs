@@ -29,11 +29,14 @@ impl Arbitrary for TestByteBuf {
 
 impl Arbitrary for NockchainRequest {
     fn arbitrary(g: &mut Gen) -> Self {
-        match bool::arbitrary(g) {
-            true => NockchainRequest::Gossip {
+        match u8::arbitrary(g) % 3 {
+            0 => NockchainRequest::Gossip {
+                message: TestByteBuf::arbitrary(g).into(),
+            },
+            1 => NockchainRequest::CompactGossip {
                 message: TestByteBuf::arbitrary(g).into(),
             },
-            false => NockchainRequest::Request {
+            _ => NockchainRequest::Request {
                 pow: {
                     let mut arr = [0u8; 16];
                     for i in 0..16 {
@@ -0,0 +1,247 @@
+//! Compact block relay.
+//!
+//! A gossiped `[%heard-block page]` carries the page's `tx-ids` as a z-set of full tip5
+//! hashes. The compact form is the same noun with every tx id in that z-set replaced by a
+//! short id (its first limb), keeping the shape of the tree. A receiver which already knows
+//! the ids swaps them back in and gets the identical page, so the digest still checks out.
+//! If any short id is unknown or ambiguous the receiver fetches that page by its digest
+//! with a `[%by-id block-id]` request (never by height, which could return a different
+//! block at the same height), and the kernel then requests only the raw transactions it
+//! doesn't have.
+use std::collections::BTreeMap;
+
+use nockapp::noun::slab::NounSlab;
+use nockapp::NockAppError;
+use nockvm::noun::{Atom, Noun, T};
+
+use crate::tip5_util::extract_5_tuple;
+
+/** How many tx ids we remember for rebuilding compact blocks */
+const TX_ID_INDEX_CAPACITY: usize = 1 << 16;
+
+/// The five limbs of a tip5 tx id
+pub type TxIdLimbs = [u64; 5];
+
+pub fn tx_id_limbs(tx_id: Noun) -> Result<TxIdLimbs, NockAppError> {
+    let mut limbs = [0u64; 5];
+    for (limb, noun) in limbs.iter_mut().zip(extract_5_tuple(tx_id)?) {
+        *limb = noun.as_atom()?.as_u64()?;
+    }
+    Ok(limbs)
+}
+
+pub fn short_tx_id(limbs: &TxIdLimbs) -> u64 {
+    limbs[0]
+}
+
+/// Full tx ids we know about, keyed by short id.
+///
+/// Short ids shared by two different tx ids are kept as `None` so we never rebuild a
+/// block with the wrong transaction.
+#[derive(Debug, Default)]
+pub struct TxIdIndex {
+    ids: BTreeMap<u64, Option<TxIdLimbs>>,
+}
+
+impl TxIdIndex {
+    pub fn insert(&mut self, limbs: TxIdLimbs) {
+        let short = short_tx_id(&limbs);
+        match self.ids.get(&short) {
+            Some(Some(known)) if *known != limbs => {
+                self.ids.insert(short, None);
+            }
+            Some(_) => {}
+            None => {
+                if self.ids.len() >= TX_ID_INDEX_CAPACITY {
+                    // Short ids are hash limbs, so this evicts an arbitrary entry
+                    self.ids.pop_first();
+                }
+                self.ids.insert(short, Some(limbs));
+            }
+        }
+    }
+
+    pub fn get(&self, short: u64) -> Option<&TxIdLimbs> {
+        self.ids.get(&short)?.as_ref()
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+}
+
+/// Turn a `[%heard-block page]` into its compact form.
+pub fn compact_heard_block(heard_block: &NounSlab) -> Result<NounSlab, NockAppError> {
+    let mut slab = heard_block.clone();
+    let root = unsafe { *slab.root() };
+    let new_root = map_heard_block_tx_ids(&mut slab, root, &mut |slab, tx_id| {
+        let short = short_tx_id(&tx_id_limbs(tx_id)?);
+        Ok(Atom::new(slab, short).as_noun())
+    })?;
+    slab.set_root(new_root);
+    Ok(slab)
+}
+
+/// Rebuild a full `[%heard-block page]` from its compact form.
+///
+/// Returns the short ids we couldn't resolve if the block can't be rebuilt from `index`.
+pub fn expand_heard_block(
+    compact: &NounSlab,
+    index: &TxIdIndex,
+) -> Result<Result<NounSlab, Vec<u64>>, NockAppError> {
+    let mut slab = compact.clone();
+    let mut missing = Vec::new();
+    let root = unsafe { *slab.root() };
+    let new_root = map_heard_block_tx_ids(&mut slab, root, &mut |slab, short_id| {
+        let short = short_id.as_atom()?.as_u64()?;
+        let Some(limbs) = index.get(short) else {
+            missing.push(short);
+            return Ok(short_id);
+        };
+        let atoms: Vec<Noun> = limbs
+            .iter()
+            .map(|limb| Atom::new(slab, *limb).as_noun())
+            .collect();
+        Ok(T(slab, &atoms))
+    })?;
+    if !missing.is_empty() {
+        return Ok(Err(missing));
+    }
+    slab.set_root(new_root);
+    Ok(Ok(slab))
+}
+
+/// Digest (block id) of the page in a (full or compact) `[%heard-block page]`
+pub fn heard_block_digest(heard_block: &NounSlab) -> Result<Noun, NockAppError> {
+    let page = unsafe { *heard_block.root() }.as_cell()?.tail();
+    Ok(page.as_cell()?.head())
+}
+
+/// Height of the page in a (full or compact) `[%heard-block page]`
+pub fn heard_block_height(heard_block: &NounSlab) -> Result<u64, NockAppError> {
+    // page: [digest pow parent tx-ids coinbase timestamp epoch-counter target accumulated-work height msg]
+    let mut noun = unsafe { *heard_block.root() }.as_cell()?.tail();
+    for _ in 0..9 {
+        noun = noun.as_cell()?.tail();
+    }
+    Ok(noun.as_cell()?.head().as_atom()?.as_u64()?)
+}
+
+type TxIdMapper<'a> = dyn FnMut(&mut NounSlab, Noun) -> Result<Noun, NockAppError> + 'a;
+
+/// Rebuild `[%heard-block page]` with each key of `tx-ids.page` passed through `f`
+fn map_heard_block_tx_ids(
+    slab: &mut NounSlab,
+    heard_block: Noun,
+    f: &mut TxIdMapper,
+) -> Result<Noun, NockAppError> {
+    let heard_block = heard_block.as_cell()?;
+    if !heard_block.head().eq_bytes(b"heard-block") {
+        return Err(NockAppError::OtherError);
+    }
+    let page = heard_block.tail().as_cell()?;
+    let digest = page.head();
+    let page = page.tail().as_cell()?;
+    let pow = page.head();
+    let page = page.tail().as_cell()?;
+    let parent = page.head();
+    let page = page.tail().as_cell()?;
+    let tx_ids = map_tree(slab, page.head(), f)?;
+    Ok(T(
+        slab,
+        &[heard_block.head(), digest, pow, parent, tx_ids, page.tail()],
+    ))
+}
+
+/// Map over the keys of a z-set, which is either `~` or `[n=key l=tree r=tree]`
+fn map_tree(slab: &mut NounSlab, tree: Noun, f: &mut TxIdMapper) -> Result<Noun, NockAppError> {
+    if let Ok(atom) = tree.as_atom() {
+        return if atom.as_u64()? == 0 {
+            Ok(tree)
+        } else {
+            Err(NockAppError::OtherError)
+        };
+    }
+    let node = tree.as_cell()?;
+    let branches = node.tail().as_cell()?;
+    let key = f(slab, node.head())?;
+    let left = map_tree(slab, branches.head(), f)?;
+    let right = map_tree(slab, branches.tail(), f)?;
+    Ok(T(slab, &[key, left, right]))
+}
+
+#[cfg(test)]
+mod tests {
+    use nockapp::noun::slab::slab_equality;
+    use nockvm::noun::D;
+    use nockvm_macros::tas;
+
+    use super::*;
+
+    fn tx_id(slab: &mut NounSlab, seed: u64) -> Noun {
+        T(
+            slab,
+            &[D(seed), D(seed + 1), D(seed + 2), D(seed + 3), D(seed + 4)],
+        )
+    }
+
+    fn heard_block() -> NounSlab {
+        let mut slab = NounSlab::new();
+        let (a, b, c) = (
+            tx_id(&mut slab, 10),
+            tx_id(&mut slab, 20),
+            tx_id(&mut slab, 30),
+        );
+        let leaf_a = T(&mut slab, &[a, D(0), D(0)]);
+        let leaf_c = T(&mut slab, &[c, D(0), D(0)]);
+        let tx_ids = T(&mut slab, &[b, leaf_a, leaf_c]);
+        let digest = tx_id(&mut slab, 1);
+        let parent = tx_id(&mut slab, 2);
+        // coinbase timestamp epoch-counter target accumulated-work height msg
+        let rest = T(&mut slab, &[D(0), D(1), D(2), D(3), D(4), D(42), D(0)]);
+        let page = T(&mut slab, &[digest, D(0), parent, tx_ids, rest]);
+        let root = T(&mut slab, &[D(tas!(b"heard-block")), page]);
+        slab.set_root(root);
+        slab
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_compact_block_round_trip() {
+        let block = heard_block();
+        let compact = compact_heard_block(&block).expect("compact");
+        assert!(compact.jam().len() < block.jam().len());
+        assert_eq!(heard_block_height(&compact).expect("height"), 42);
+        let digest = heard_block_digest(&compact).expect("digest");
+        assert_eq!(tx_id_limbs(digest).expect("digest limbs"), [1, 2, 3, 4, 5]);
+
+        let mut index = TxIdIndex::default();
+        index.insert([10, 11, 12, 13, 14]);
+        index.insert([20, 21, 22, 23, 24]);
+        let missing = expand_heard_block(&compact, &index)
+            .expect("expand")
+            .expect_err("tx 30 is unknown");
+        assert_eq!(missing, vec![30]);
+
+        index.insert([30, 31, 32, 33, 34]);
+        let expanded = expand_heard_block(&compact, &index)
+            .expect("expand")
+            .expect("all txs known");
+        assert!(slab_equality(&expanded, &block));
+    }
+
+    #[test]
+    fn test_ambiguous_short_id() {
+        let mut index = TxIdIndex::default();
+        index.insert([7, 1, 1, 1, 1]);
+        assert_eq!(index.get(7), Some(&[7, 1, 1, 1, 1]));
+        index.insert([7, 2, 2, 2, 2]);
+        assert_eq!(index.get(7), None);
+        index.insert([7, 1, 1, 1, 1]);
+        assert_eq!(index.get(7), None);
+    }
+}
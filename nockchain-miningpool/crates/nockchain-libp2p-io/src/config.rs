@@ -1,3 +1,4 @@
+use std::net::SocketAddr;
 use std::num::NonZero;
 use std::time::Duration;
 
@@ -69,6 +70,22 @@ const POKE_TIMEOUT_SECS: u64 = 60;
 
 const FAILED_PINGS_BEFORE_CLOSE: u64 = 4;
 
+// mDNS constants
+/** Whether LAN peer discovery via mDNS is enabled */
+const ENABLE_MDNS: bool = false;
+/** How often we should send mDNS queries on the local network */
+const MDNS_QUERY_INTERVAL: Duration = Duration::from_secs(60);
+/** How long local peers get to answer a block fetch before it is sent to remote peers */
+const LOCAL_FETCH_TIMEOUT: Duration = Duration::from_millis(2000);
+
+// TCP fallback constants
+/** Whether to run a TCP (Noise + Yamux) transport alongside QUIC */
+const ENABLE_TCP: bool = false;
+
+// Compact block relay constants
+/** Whether to gossip blocks in compact form. Peers must understand it, so this is opt-in */
+const COMPACT_BLOCK_RELAY: bool = false;
+
 /// Configuration struct that allows overriding default constants from environment variables
 #[derive(Debug, Deserialize, Clone)]
 pub struct LibP2PConfig {
@@ -178,6 +195,44 @@ pub struct LibP2PConfig {
     /// Number of failed pings before closing connection
     #[serde(default = "default_failed_pings_before_close")]
     pub failed_pings_before_close: u64,
+
+    /// Discover peers on the local network via mDNS.
+    /// Block fetches go to peers found this way first, and to the rest only if they fail.
+    #[serde(default = "default_enable_mdns")]
+    pub enable_mdns: bool,
+
+    /// How often we should send mDNS queries on the local network (seconds)
+    #[serde(default = "default_mdns_query_interval_secs")]
+    pub mdns_query_interval_secs: u64,
+
+    /// How long local peers get to answer a block fetch before it is also sent to remote
+    /// peers (milliseconds)
+    #[serde(default = "default_local_fetch_timeout_millisecs")]
+    pub local_fetch_timeout_millisecs: u64,
+
+    /// Address to serve the peer admin HTTP API on. Disabled if unset.
+    /// The API is unauthenticated, so keep this on loopback.
+    #[serde(default)]
+    pub peer_admin_bind: Option<SocketAddr>,
+
+    /// Run a TCP transport secured with Noise and multiplexed with Yamux alongside QUIC,
+    /// for networks which block or rate-limit UDP. Every QUIC bind address also gets a
+    /// TCP listener on the same IP and port, and QUIC addresses are dialed first.
+    #[serde(default = "default_enable_tcp")]
+    pub enable_tcp: bool,
+
+    /// How many of a peer's addresses to dial at once. Addresses are sorted QUIC first, so
+    /// setting this to 1 gives QUIC the first shot before falling back to TCP, at the cost
+    /// of serialising dials. Defaults to 1 when TCP is enabled so QUIC is preferred, and to
+    /// the libp2p default otherwise.
+    #[serde(default)]
+    pub dial_concurrency_factor: Option<NonZero<u8>>,
+
+    /// Gossip blocks with short tx ids instead of full ones; receivers rebuild them from
+    /// the tx ids they know. Compact gossip is always accepted, but only nodes running a
+    /// version that understands it can decode it, so only enable this once peers have upgraded.
+    #[serde(default = "default_compact_block_relay")]
+    pub compact_block_relay: bool,
 }
 
 // Default value functions
@@ -259,6 +314,26 @@ fn default_failed_pings_before_close() -> u64 {
     FAILED_PINGS_BEFORE_CLOSE // Number of failed pings before closing connection
 }
 
+fn default_enable_mdns() -> bool {
+    ENABLE_MDNS
+}
+
+fn default_mdns_query_interval_secs() -> u64 {
+    MDNS_QUERY_INTERVAL.as_secs()
+}
+
+fn default_local_fetch_timeout_millisecs() -> u64 {
+    LOCAL_FETCH_TIMEOUT.as_millis() as u64
+}
+
+fn default_enable_tcp() -> bool {
+    ENABLE_TCP
+}
+
+fn default_compact_block_relay() -> bool {
+    COMPACT_BLOCK_RELAY
+}
+
 // Do _not_ use this default implementation in production code. It's just a fallback.
 // Use from_env() to load from environment variables with sensible defaults.
 impl Default for LibP2PConfig {
@@ -287,6 +362,13 @@ impl Default for LibP2PConfig {
             seen_tx_clear_interval: default_seen_tx_clear_interval(),
             poke_timeout_secs: default_poke_timeout_secs(),
             failed_pings_before_close: default_failed_pings_before_close(),
+            enable_mdns: default_enable_mdns(),
+            mdns_query_interval_secs: default_mdns_query_interval_secs(),
+            local_fetch_timeout_millisecs: default_local_fetch_timeout_millisecs(),
+            peer_admin_bind: None,
+            enable_tcp: default_enable_tcp(),
+            dial_concurrency_factor: None,
+            compact_block_relay: default_compact_block_relay(),
         }
     }
 }
@@ -399,4 +481,25 @@ impl LibP2PConfig {
     pub fn failed_pings_before_close(&self) -> u64 {
         self.failed_pings_before_close
     }
+
+    /// Get mDNS query interval as Duration
+    pub fn mdns_query_interval(&self) -> Duration {
+        Duration::from_secs(self.mdns_query_interval_secs)
+    }
+
+    /// Get the local block fetch timeout as Duration
+    pub fn local_fetch_timeout(&self) -> Duration {
+        Duration::from_millis(self.local_fetch_timeout_millisecs)
+    }
+
+    /// Get the dial concurrency factor, 1 if unset and TCP is enabled so QUIC is dialed first
+    pub fn dial_concurrency_factor(&self) -> Option<NonZero<u8>> {
+        self.dial_concurrency_factor.or_else(|| {
+            if self.enable_tcp {
+                NonZero::new(1)
+            } else {
+                None
+            }
+        })
+    }
 }
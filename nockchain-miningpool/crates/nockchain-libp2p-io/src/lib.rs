@@ -1,9 +1,14 @@
+pub mod admin;
 pub mod config;
 pub mod metrics;
 pub mod messages;
 pub mod nc;
 pub mod behaviour;
+pub mod compact;
 pub mod p2p_util;
+mod quic_first;
+#[cfg(feature = "sim")]
+pub mod sim;
 pub mod tip5_util;
 
 #[cfg(test)]
@@ -11,6 +11,8 @@ pub enum NockchainRequest {
     },
     /// Gossip a block or TX to another node
     Gossip { message: ByteBuf },
+    /// Gossip a block with its tx ids shortened, see [crate::compact]
+    CompactGossip { message: ByteBuf },
 }
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
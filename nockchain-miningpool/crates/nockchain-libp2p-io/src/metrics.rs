@@ -12,9 +12,13 @@ metrics_struct![
     (gossip_erred_heard_tx, "nockchain-libp2p-io.gossip_erred_heard_tx", Count),
     (gossip_erred_heard_elders, "nockchain-libp2p-io.gossip_erred_heard_elders", Count),
     (gossip_dropped, "nockchain-libp2p-io.gossip_dropped", Count),
+    (compact_blocks_sent, "nockchain-libp2p-io.compact_blocks_sent", Count),
+    (compact_blocks_rebuilt, "nockchain-libp2p-io.compact_blocks_rebuilt", Count),
+    (compact_blocks_missing_txs, "nockchain-libp2p-io.compact_blocks_missing_txs", Count),
     (requests_peeked_some, "nockchain-libp2p-io.requests_peeked_some", Count),
     (requests_peeked_none, "nockchain-libp2p-io.requests_peeked_none", Count),
     (requests_erred_block_by_height, "nockchain-libp2p-io.requests_erred_block_by_height", Count),
+    (requests_erred_block_by_id, "nockchain-libp2p-io.requests_erred_block_by_id", Count),
     (requests_erred_elders_by_id, "nockchain-libp2p-io.requests_erred_elders_by_id", Count),
     (requests_erred_raw_tx_by_id, "nockchain-libp2p-io.requests_erred_raw_tx_by_id", Count),
     (requests_dropped, "nockchain-libp2p-io.requests_dropped", Count),
@@ -6,6 +6,8 @@ use std::sync::Arc;
 use bytes::Bytes;
 use either::{Either, Left, Right};
 use futures::{Future, StreamExt};
+use libp2p::core::transport::OptionalTransport;
+use libp2p::core::upgrade;
 use libp2p::identify::Event::Received;
 use libp2p::identity::Keypair;
 use libp2p::kad::NoKnownPeers;
@@ -16,7 +18,8 @@ use libp2p::request_response::Message::*;
 use libp2p::request_response::{self};
 use libp2p::swarm::{ConnectionId, DialError, ListenError, SwarmEvent};
 use libp2p::{
-    allow_block_list, connection_limits, memory_connection_limits, Multiaddr, PeerId, Swarm,
+    allow_block_list, connection_limits, kad, mdns, memory_connection_limits, noise, tcp, yamux,
+    Multiaddr, PeerId, Swarm, Transport,
 };
 use nockapp::driver::{IODriverFn, NockAppHandle, PokeResult};
 use nockapp::noun::slab::NounSlab;
@@ -34,13 +37,17 @@ use tokio::task::{AbortHandle, JoinError, JoinSet};
 use tokio::time::{Duration, MissedTickBehavior};
 use tracing::{debug, error, info, instrument, trace, warn};
 
+use crate::admin::{self, PeerAdminCommand};
 use crate::config::LibP2PConfig;
 use crate::metrics::NockchainP2PMetrics;
 use crate::behaviour::*;
+use crate::compact::{
+    compact_heard_block, expand_heard_block, heard_block_digest, heard_block_height, tx_id_limbs,
+};
 use crate::messages::{NockchainRequest, NockchainResponse};
 use crate::p2p_util::{
-    log_fail2ban_ipv4, log_fail2ban_ipv6, CacheResponse, MessageTracker, NockchainDataRequest,
-    NockchainFact, PeerIdExt,
+    log_fail2ban_ipv4, log_fail2ban_ipv6, quic_first_dial_opts, tcp_listen_addr, CacheResponse,
+    MessageTracker, NockchainDataRequest, NockchainFact, PeerIdExt,
 };
 use crate::tip5_util::tip5_hash_to_base58;
 
@@ -186,289 +193,461 @@ pub fn make_libp2p_driver(
         Box::pin(async move {
             let libp2p_config = LibP2PConfig::from_env()?;
             debug!("Libp2p config: {:?}", libp2p_config);
-            let kademlia_bootstrap_interval = libp2p_config.kademlia_bootstrap_interval();
-            let force_peer_dial_interval = libp2p_config.force_peer_dial_interval();
-            let request_high_reset = libp2p_config.request_high_reset();
-            let initial_peer_retries = libp2p_config.initial_peer_retries;
-            let request_high_threshold = libp2p_config.request_high_threshold;
-            let peer_status_interval = libp2p_config.peer_status_interval_secs();
-            let elders_debounce_reset = libp2p_config.elders_debounce_reset();
-            let seen_tx_clear_interval = libp2p_config.seen_tx_clear_interval();
-            let min_peers = libp2p_config.min_peers();
-            let poke_timeout = libp2p_config.poke_timeout();
-            let (resolver_config, resolver_opts) =
-                if let Ok(sys) = hickory_resolver::system_conf::read_system_conf() {
-                    debug!("resolver configs and opts: {:?}", sys);
-                    sys
-                } else {
-                    (hickory_resolver::config::ResolverConfig::cloudflare(), hickory_resolver::config::ResolverOpts::default())
-                };
+            let swarm = build_swarm(
+                keypair,
+                libp2p_config.clone(),
+                allowed,
+                limits,
+                memory_limits,
+            )?;
+            let params = SwarmParams {
+                bind,
+                initial_peers,
+                force_peers,
+                prune_inbound_size,
+                equix_builder,
+                chain_interval,
+                init_complete_tx,
+                admin: mpsc::channel::<PeerAdminCommand>(16),
+            };
+            run_swarm(handle, swarm, libp2p_config, params, metrics).await
+        })
+    })
+}
 
-            let max_idle_timeout_millisecs = libp2p_config.max_idle_timeout_millisecs();
-            let keep_alive_interval = libp2p_config.keep_alive_interval();
-            let handshake_timeout = libp2p_config.handshake_timeout();
-            let connection_timeout = libp2p_config.connection_timeout();
-            let swarm_idle_timeout = libp2p_config.swarm_idle_timeout();
-            let mut swarm = libp2p::SwarmBuilder::with_existing_identity(keypair)
-                .with_tokio()
-                .with_quic_config(|mut cfg| {
-                    cfg.max_idle_timeout = max_idle_timeout_millisecs;
-                    cfg.keep_alive_interval = keep_alive_interval;
-                    cfg.handshake_timeout = handshake_timeout;
-                    cfg
-                })
-                .with_dns_config(resolver_config, resolver_opts)
-                .with_behaviour(crate::behaviour::NockchainBehaviour::pre_new(
-                    libp2p_config, allowed, limits, memory_limits,
-                ))
-                .map_err(|e| {
-                    error!("Could not create swarm: {}", e);
-                    NockAppError::OtherError
-                })?
-                .with_swarm_config(|cfg| cfg.with_idle_connection_timeout(swarm_idle_timeout))
-                .with_connection_timeout(connection_timeout)
-                .build();
-
-            for bind_addr in bind {
-                if let Err(e) = swarm.listen_on(bind_addr.clone()) {
-                    error!("Failed to listen on {bind_addr:?}: {e}");
-                }
+/// Build a swarm speaking QUIC, plus TCP + Noise + Yamux if `enable_tcp` is set, with DNS
+/// resolution on top, carrying the nockchain behaviour.
+fn build_swarm(
+    keypair: Keypair,
+    libp2p_config: LibP2PConfig,
+    allowed: Option<allow_block_list::Behaviour<allow_block_list::AllowedPeers>>,
+    limits: connection_limits::ConnectionLimits,
+    memory_limits: Option<memory_connection_limits::Behaviour>,
+) -> Result<Swarm<NockchainBehaviour>, NockAppError> {
+    let (resolver_config, resolver_opts) =
+        if let Ok(sys) = hickory_resolver::system_conf::read_system_conf() {
+            debug!("resolver configs and opts: {:?}", sys);
+            sys
+        } else {
+            (
+                hickory_resolver::config::ResolverConfig::cloudflare(),
+                hickory_resolver::config::ResolverOpts::default(),
+            )
+        };
+
+    let max_idle_timeout_millisecs = libp2p_config.max_idle_timeout_millisecs();
+    let keep_alive_interval = libp2p_config.keep_alive_interval();
+    let handshake_timeout = libp2p_config.handshake_timeout();
+    let connection_timeout = libp2p_config.connection_timeout();
+    let swarm_idle_timeout = libp2p_config.swarm_idle_timeout();
+    let enable_tcp = libp2p_config.enable_tcp;
+    let dial_concurrency_factor = libp2p_config.dial_concurrency_factor();
+    let swarm = libp2p::SwarmBuilder::with_existing_identity(keypair)
+        .with_tokio()
+        .with_quic_config(|mut cfg| {
+            cfg.max_idle_timeout = max_idle_timeout_millisecs;
+            cfg.keep_alive_interval = keep_alive_interval;
+            cfg.handshake_timeout = handshake_timeout;
+            cfg
+        })
+        .with_other_transport(|key| -> Result<_, noise::Error> {
+            if !enable_tcp {
+                return Ok(OptionalTransport::none());
             }
-            let (swarm_tx, mut swarm_rx) = mpsc::channel::<SwarmAction>(1000); // number needs to be high enough to send gossips to peers
-            let mut join_set = TrackedJoinSet::<Result<(), NockAppError>>::new();
-            let message_tracker = Arc::new(Mutex::new(MessageTracker::new(
-                metrics.clone(),
-                seen_tx_clear_interval,
-            )));
-            let mut kad_bootstrap = tokio::time::interval(kademlia_bootstrap_interval);
-            kad_bootstrap.set_missed_tick_behavior(MissedTickBehavior::Skip);
-            let mut force_peer_dial = tokio::time::interval(force_peer_dial_interval);
-            force_peer_dial.set_missed_tick_behavior(MissedTickBehavior::Skip);
-            let mut reset_request_counts = tokio::time::interval(request_high_reset);
-            reset_request_counts.set_missed_tick_behavior(MissedTickBehavior::Skip);
-            let mut reset_elders_debounce = tokio::time::interval(elders_debounce_reset);
-            reset_elders_debounce.set_missed_tick_behavior(MissedTickBehavior::Skip);
-            let mut nockchain_timer = tokio::time::interval(chain_interval);
-            nockchain_timer.set_missed_tick_behavior(MissedTickBehavior::Skip);
-            let nockchain_timer_mutex = Arc::new(Mutex::new(()));
-            let (traffic_handle, effect_handle) = handle.dup();
-            let traffic_cop =
-                traffic_cop::TrafficCop::new(traffic_handle, &mut join_set, poke_timeout);
-
-            let mut initial_peer_retries_remaining = initial_peer_retries;
-            dial_peers(&mut swarm, &initial_peers)?;
-            if let Some(tx) = init_complete_tx {
-                let _ = tx.send(());
-                debug!("libp2p driver initialization complete signal sent");
+            Ok(OptionalTransport::some(
+                tcp::tokio::Transport::new(tcp::Config::default())
+                    .upgrade(upgrade::Version::V1)
+                    .authenticate(noise::Config::new(key)?)
+                    .multiplex(yamux::Config::default()),
+            ))
+        })
+        .map_err(|e| {
+            error!("Could not create TCP transport: {}", e);
+            NockAppError::OtherError
+        })?
+        .with_dns_config(resolver_config, resolver_opts)
+        .with_behaviour(crate::behaviour::NockchainBehaviour::pre_new(
+            libp2p_config, allowed, limits, memory_limits,
+        ))
+        .map_err(|e| {
+            error!("Could not create swarm: {}", e);
+            NockAppError::OtherError
+        })?
+        .with_swarm_config(|cfg| {
+            let cfg = cfg.with_idle_connection_timeout(swarm_idle_timeout);
+            match dial_concurrency_factor {
+                Some(factor) => cfg.with_dial_concurrency_factor(factor),
+                None => cfg,
             }
-            let mut connectivity_interval = tokio::time::interval(peer_status_interval);
-            loop {
-                let timer_fut = async {
-                    let _ = nockchain_timer.tick().await;
-                    nockchain_timer_mutex.clone().lock_owned().await
-                };
-                tokio::select! {
-                    guard = timer_fut => {
-                        join_set.spawn("timer".to_string(), send_timer_poke(guard, traffic_cop.clone(), metrics.clone()))
-                    }
-                    _ = connectivity_interval.tick() => {
-                        let peer_count = log_peer_status(&mut swarm, &metrics).await;
-                        if peer_count < min_peers {
-                            let tracker = message_tracker.lock().await;
-                            dial_more_peers(&mut swarm, tracker);
+        })
+        .with_connection_timeout(connection_timeout)
+        .build();
+    Ok(swarm)
+}
+
+/// Everything the swarm event loop needs besides the swarm, the kernel handle and the config.
+pub(crate) struct SwarmParams {
+    pub(crate) bind: Vec<Multiaddr>,
+    pub(crate) initial_peers: Vec<Multiaddr>,
+    pub(crate) force_peers: Vec<Multiaddr>,
+    pub(crate) prune_inbound_size: Option<usize>,
+    pub(crate) equix_builder: equix::EquiXBuilder,
+    pub(crate) chain_interval: Duration,
+    pub(crate) init_complete_tx: Option<tokio::sync::oneshot::Sender<()>>,
+    /// Peer admin channel. The sender is handed to the HTTP admin API if one is configured.
+    pub(crate) admin: (
+        mpsc::Sender<PeerAdminCommand>,
+        mpsc::Receiver<PeerAdminCommand>,
+    ),
+}
+
+/// Drive a built swarm: listen, dial, and shuttle messages between peers and the kernel.
+pub(crate) async fn run_swarm(
+    handle: NockAppHandle,
+    mut swarm: Swarm<NockchainBehaviour>,
+    libp2p_config: LibP2PConfig,
+    params: SwarmParams,
+    metrics: Arc<NockchainP2PMetrics>,
+) -> Result<(), NockAppError> {
+    let SwarmParams {
+        bind,
+        initial_peers,
+        force_peers,
+        prune_inbound_size,
+        equix_builder,
+        chain_interval,
+        init_complete_tx,
+        admin: (admin_tx, mut admin_rx),
+    } = params;
+    let kademlia_bootstrap_interval = libp2p_config.kademlia_bootstrap_interval();
+    let force_peer_dial_interval = libp2p_config.force_peer_dial_interval();
+    let request_high_reset = libp2p_config.request_high_reset();
+    let initial_peer_retries = libp2p_config.initial_peer_retries;
+    let request_high_threshold = libp2p_config.request_high_threshold;
+    let peer_status_interval = libp2p_config.peer_status_interval_secs();
+    let elders_debounce_reset = libp2p_config.elders_debounce_reset();
+    let seen_tx_clear_interval = libp2p_config.seen_tx_clear_interval();
+    let min_peers = libp2p_config.min_peers();
+    let poke_timeout = libp2p_config.poke_timeout();
+    let peer_admin_bind = libp2p_config.peer_admin_bind;
+    let enable_tcp = libp2p_config.enable_tcp;
+    let compact_block_relay = libp2p_config.compact_block_relay;
+    let local_fetch_timeout = libp2p_config.local_fetch_timeout();
+
+    let mut listen_addrs = bind.clone();
+    if enable_tcp {
+        // Dual-stack: listen on TCP wherever we listen on QUIC, unless TCP was bound explicitly
+        for tcp_addr in bind.iter().filter_map(tcp_listen_addr) {
+            if !listen_addrs.contains(&tcp_addr) {
+                listen_addrs.push(tcp_addr);
+            }
+        }
+    }
+    for bind_addr in listen_addrs {
+        if !enable_tcp && bind_addr.iter().any(|p| matches!(p, Protocol::Tcp(_))) {
+            warn!("Not listening on {bind_addr}: TCP transport is disabled, set NOCKCHAIN_LIBP2P_ENABLE_TCP=true");
+            continue;
+        }
+        if let Err(e) = swarm.listen_on(bind_addr.clone()) {
+            error!("Failed to listen on {bind_addr:?}: {e}");
+        }
+    }
+    let (swarm_tx, mut swarm_rx) = mpsc::channel::<SwarmAction>(1000); // number needs to be high enough to send gossips to peers
+    let mut join_set = TrackedJoinSet::<Result<(), NockAppError>>::new();
+    if let Some(addr) = peer_admin_bind {
+        join_set.spawn(
+            "peer_admin".to_string(),
+            admin::serve(addr, admin_tx.clone()),
+        );
+    }
+    let message_tracker = {
+        let mut tracker = MessageTracker::new(metrics.clone(), seen_tx_clear_interval);
+        tracker.compact_block_relay = compact_block_relay;
+        tracker.local_fetch_timeout = local_fetch_timeout;
+        Arc::new(Mutex::new(tracker))
+    };
+    let mut kad_bootstrap = tokio::time::interval(kademlia_bootstrap_interval);
+    kad_bootstrap.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    let mut force_peer_dial = tokio::time::interval(force_peer_dial_interval);
+    force_peer_dial.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    let mut reset_request_counts = tokio::time::interval(request_high_reset);
+    reset_request_counts.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    let mut reset_elders_debounce = tokio::time::interval(elders_debounce_reset);
+    reset_elders_debounce.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    let mut nockchain_timer = tokio::time::interval(chain_interval);
+    nockchain_timer.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    let nockchain_timer_mutex = Arc::new(Mutex::new(()));
+    let (traffic_handle, effect_handle) = handle.dup();
+    let traffic_cop = traffic_cop::TrafficCop::new(traffic_handle, &mut join_set, poke_timeout);
+
+    let mut initial_peer_retries_remaining = initial_peer_retries;
+    dial_peers(&mut swarm, &initial_peers)?;
+    if let Some(tx) = init_complete_tx {
+        let _ = tx.send(());
+        debug!("libp2p driver initialization complete signal sent");
+    }
+    let mut connectivity_interval = tokio::time::interval(peer_status_interval);
+    loop {
+        let timer_fut = async {
+            let _ = nockchain_timer.tick().await;
+            nockchain_timer_mutex.clone().lock_owned().await
+        };
+        tokio::select! {
+            guard = timer_fut => {
+                join_set.spawn("timer".to_string(), send_timer_poke(guard, traffic_cop.clone(), metrics.clone()))
+            }
+            _ = connectivity_interval.tick() => {
+                let peer_count = log_peer_status(&mut swarm, &metrics).await;
+                if peer_count < min_peers {
+                    let tracker = message_tracker.lock().await;
+                    dial_more_peers(&mut swarm, tracker);
+                }
+            },
+            Ok(noun_slab) = effect_handle.next_effect() => {
+                let _span = tracing::trace_span!("broadcast").entered();
+                let swarm_tx_clone = swarm_tx.clone();
+                let equix_builder_clone = equix_builder.clone();
+                let local_peer_id = *swarm.local_peer_id();
+                let connected_peers: Vec<PeerId> = swarm.connected_peers().cloned().collect();
+                let message_tracker_clone = Arc::clone(&message_tracker); // Clone the Arc, not the MessageTracker
+                let metrics_clone = metrics.clone();
+                join_set.spawn("handle_effect".to_string(), async move {
+                    handle_effect(noun_slab, swarm_tx_clone, equix_builder_clone, local_peer_id, connected_peers, message_tracker_clone, metrics_clone).await
+                });
+            },
+            Some(event) = swarm.next() => {
+                match event {
+                    SwarmEvent::NewListenAddr { address, .. } => {
+                        info!("SEvent: Listening on {address:?}");
+                    },
+                    SwarmEvent::ListenerError { error, .. } => {
+                        error!("SEvent: Listener error: {error:?}");
+                    },
+                    SwarmEvent::ListenerClosed { addresses, reason, .. } => {
+                        if let Err(e) = reason {
+                            error!("SEvent: Listener closed on {addresses:?} because of {e:?}");
+                        } else {
+                            info!("SEvent: Listener closed on {addresses:?}");
                         }
                     },
-                    Ok(noun_slab) = effect_handle.next_effect() => {
-                        let _span = tracing::trace_span!("broadcast").entered();
-                        let swarm_tx_clone = swarm_tx.clone();
-                        let equix_builder_clone = equix_builder.clone();
-                        let local_peer_id = *swarm.local_peer_id();
-                        let connected_peers: Vec<PeerId> = swarm.connected_peers().cloned().collect();
-                        let message_tracker_clone = Arc::clone(&message_tracker); // Clone the Arc, not the MessageTracker
-                        let metrics_clone = metrics.clone();
-                        join_set.spawn("handle_effect".to_string(), async move {
-                            handle_effect(noun_slab, swarm_tx_clone, equix_builder_clone, local_peer_id, connected_peers, message_tracker_clone, metrics_clone).await
-                        });
+                    SwarmEvent::Behaviour(NockchainEvent::Identify(Received { connection_id: _, peer_id, info })) => {
+                        trace!("SEvent: identify_received");
+                        swarm.add_external_address(info.observed_addr.clone());
+                        let us = *swarm.local_peer_id();
+                        trace!("Adding address {} for us: {}", info.observed_addr, us);
+                        swarm.behaviour_mut().kad.add_address(&us, info.observed_addr);
+                        for addr in info.listen_addrs {
+                            trace!("Adding address {} for peer {}", addr, peer_id);
+                            swarm.add_peer_address(peer_id, addr.clone());
+                            swarm.behaviour_mut().kad.add_address(&peer_id, addr);
+                        }
                     },
-                    Some(event) = swarm.next() => {
-                        match event {
-                            SwarmEvent::NewListenAddr { address, .. } => {
-                                info!("SEvent: Listening on {address:?}");
-                            },
-                            SwarmEvent::ListenerError { error, .. } => {
-                                error!("SEvent: Listener error: {error:?}");
-                            },
-                            SwarmEvent::ListenerClosed { addresses, reason, .. } => {
-                                if let Err(e) = reason {
-                                    error!("SEvent: Listener closed on {addresses:?} because of {e:?}");
-                                } else {
-                                    info!("SEvent: Listener closed on {addresses:?}");
-                                }
-                            },
-                            SwarmEvent::Behaviour(NockchainEvent::Identify(Received { connection_id: _, peer_id, info })) => {
-                                trace!("SEvent: identify_received");
-                                swarm.add_external_address(info.observed_addr.clone());
-                                let us = *swarm.local_peer_id();
-                                let kad = &mut swarm.behaviour_mut().kad;
-                                trace!("Adding address {} for us: {}", info.observed_addr, us);
-                                kad.add_address(&us, info.observed_addr);
-                                for addr in info.listen_addrs {
-                                    trace!("Adding address {} for peer {}", addr, peer_id);
-                                    kad.add_address(&peer_id, addr);
-                                }
-                            },
-                            SwarmEvent::ConnectionEstablished { connection_id, peer_id, endpoint, .. } => {
-                                message_tracker.lock().await.track_connection(connection_id, peer_id, endpoint.get_remote_address(), endpoint.clone());
-                                debug!("SEvent: {peer_id} is new friend via: {endpoint:?}");
-                            },
-                            SwarmEvent::ConnectionClosed { connection_id, peer_id, endpoint, cause, .. } => {
-                                let mut message_tracker_lock = message_tracker.lock().await;
-                                let _ = message_tracker_lock.lost_connection(connection_id);
-                                if let Some(cause) = cause {
-                                    debug!("SEvent: friendship ended with {peer_id} via: {endpoint:?}. cause: {cause:?}");
-                                } else {
-                                    debug!("SEvent: friendship ended by us with {peer_id} via: {endpoint:?}.");
+                    SwarmEvent::Behaviour(NockchainEvent::Mdns(mdns::Event::Discovered(discovered))) => {
+                        let mut tracker = message_tracker.lock().await;
+                        for (peer_id, addr) in discovered {
+                            debug!("SEvent: mDNS discovered {peer_id} at {addr}");
+                            swarm.add_peer_address(peer_id, addr.clone());
+                            swarm.behaviour_mut().kad.add_address(&peer_id, addr.clone());
+                            tracker.add_local_peer(peer_id, addr.clone());
+                            if !swarm.is_connected(&peer_id) {
+                                if let Err(err) = swarm.dial(addr) {
+                                    log_dial_error(err);
                                 }
-                            },
-                            SwarmEvent::IncomingConnectionError { local_addr, send_back_addr, error, .. } => {
-                               trace!("SEvent: Failed incoming connection from {} to {}: {}",
-                               send_back_addr, local_addr, error);
-
-                               // When connection limits are reached, randomly prune inbound connections
-                               match error {
-                                   ListenError::Denied { cause } => {
-                                       metrics.incoming_connections_blocked_by_limits.increment();
-                                       if let Some(prune_factor) = prune_inbound_size {
-                                           if let Ok(_exceeded) = cause.downcast::<libp2p::connection_limits::Exceeded>() {
-                                               message_tracker.lock().await.prune_inbound_connections(metrics.clone(), &mut swarm, prune_factor);
-                                           }
-                                       }
-                                   }
-                                   _ => {}
-                               }
-                            },
-                            SwarmEvent::Behaviour(NockchainEvent::RequestResponse(Message { connection_id , peer, message })) => {
-                                trace!("SEvent: received RequestResponse");
-                                let _span = tracing::debug_span!("SwarmEvent::Behavior(NockchainEvent::RequestResponse(…))").entered();
-                                let swarm_tx_clone = swarm_tx.clone();
-                                let mut equix_builder_clone = equix_builder.clone();
-                                let local_peer_id = *swarm.local_peer_id();
-                                // We have to dup and move a handle back into `handle` to propitiate the borrow checker
-                                let traffic_clone = traffic_cop.clone();
-                                let metrics = metrics.clone();
-                                let message_tracker_clone = Arc::clone(&message_tracker); // Clone the Arc, not the MessageTracker
-                                join_set.spawn("handle_request_response".to_string(), async move {
-                                    handle_request_response(peer, connection_id, message, swarm_tx_clone, &mut equix_builder_clone, local_peer_id, traffic_clone, metrics.clone(), message_tracker_clone, request_high_threshold).await
-                                });
-                            },
-                            SwarmEvent::Behaviour(NockchainEvent::RequestResponse(OutboundFailure { peer, error, ..})) => {
-                                log_outbound_failure(peer, error, metrics.clone());
-                            }
-                            SwarmEvent::Behaviour(NockchainEvent::RequestResponse(InboundFailure { peer, error, .. })) => {
-                                log_inbound_failure(peer, error, metrics.clone());
-                            }
-                            SwarmEvent::Behaviour(NockchainEvent::Ping(e)) => {
-                                // TODO: 暂时跳过ping事件的细分处理，等待确认正确的Event变体类型
-                                trace!("SEvent: Ping event received: {:?}", e);
-                            },
-                            SwarmEvent::OutgoingConnectionError { error, .. } => {
-                                log_dial_error(error);
-                            },
-                            SwarmEvent::IncomingConnection {
-                                local_addr,
-                                send_back_addr,
-                                connection_id,
-                                ..
-                            } => {
-                                debug!("SEvent: Incoming connection from {local_addr:?} to {send_back_addr:?} with {connection_id:?}");
-                            },
-                            SwarmEvent::Dialing { peer_id, connection_id } => {
-                                debug!("SEvent: Dialing {peer_id:?} {connection_id}");
-                            },
-                            _ => {
-                                // Handle other swarm events
-                                trace!("SEvent: other swarm event {:?}", event);
                             }
                         }
                     },
-                    Some(swarm_action) = swarm_rx.recv() => {
-                        // We do this because Swarm doesn't implement Send, and so we can't pass it into the tasks
-                        // being spawned in the match cases above.
-                        match swarm_action {
-                            SwarmAction::SendRequest { peer_id, request } => {
-                                trace!("SAction: SendRequest: {peer_id}");
-                                let _ = swarm.behaviour_mut().request_response.send_request(&peer_id, request);
-                            },
-                            SwarmAction::SendResponse { channel, response } => {
-                                trace!("SAction: SendResponse");
-                                let _ = swarm.behaviour_mut().request_response.send_response(channel, response);
-                            },
-                            SwarmAction::BlockPeer { peer_id } => {
-                                warn!("SAction: Blocking peer {peer_id}");
-                                // Block the peer in the allow_block_list
-                                swarm.behaviour_mut().allow_block_list.block_peer(peer_id);
-                                {
-                                    // get peer IP address from the swarm
-                                    let peer_addresses = swarm.behaviour_mut().peer_store.store().addresses_of_peer(&peer_id);
-                                    if let Some(peer_multi_addrs) = peer_addresses {
-                                        for multi_addr in peer_multi_addrs {
-                                            for protocol in multi_addr.iter() {
-
-                                                match protocol {
-                                                    libp2p::core::multiaddr::Protocol::Ip4(ip) => {
-                                                        log_fail2ban_ipv4(&peer_id, &ip);
-                                                    },
-                                                    libp2p::core::multiaddr::Protocol::Ip6(ip) => {
-                                                        log_fail2ban_ipv6(&peer_id, &ip);
-                                                    },
-                                                    // TODO: Dns?
-                                                    _ => {}
-                                                }
-                                            }
-                                        }
-                                    } else {
-                                        error!("Failed to get peer IP address for peer id: {peer_id}");
-                                    };
-                                }
-                                // Disconnect the peer if they're currently connected
-                                let _ = swarm.disconnect_peer_id(peer_id);
-                            },
+                    SwarmEvent::Behaviour(NockchainEvent::Kad(kad::Event::RoutingUpdated { peer, addresses, .. })) => {
+                        // Kad only reports a peer's first address to the swarm, pass the rest on for QUIC-first dials
+                        for addr in addresses.iter() {
+                            swarm.add_peer_address(peer, addr.clone());
                         }
                     },
-                    _ = kad_bootstrap.tick() => {
-                        // If we don't have any peers, we should retry dialing our initial peers
-                        if let Err(NoKnownPeers())= swarm.behaviour_mut().kad.bootstrap() {
-                            if initial_peer_retries_remaining > 0 {
-                                info!("Failed to bootstrap: {}", NoKnownPeers());
-                                initial_peer_retries_remaining -= 1;
-                                dial_peers(&mut swarm, &initial_peers)?;
-                            } else {
-                                warn!("Failed to bootstrap after {} retries, will not attempt to redial initial peers.", initial_peer_retries);
-                            }
+                    SwarmEvent::Behaviour(NockchainEvent::Mdns(mdns::Event::Expired(expired))) => {
+                        let mut tracker = message_tracker.lock().await;
+                        for (peer_id, addr) in expired {
+                            debug!("SEvent: mDNS record expired for {peer_id} at {addr}");
+                            tracker.remove_local_peer(&peer_id, &addr);
                         }
                     },
-                    _ = force_peer_dial.tick() => {
-                        debug!("Force dialing peers");
-                        dial_peers(&mut swarm, &force_peers)?;
+                    SwarmEvent::ConnectionEstablished { connection_id, peer_id, endpoint, .. } => {
+                        let mut tracker = message_tracker.lock().await;
+                        if tracker.is_banned_addr(endpoint.get_remote_address()) {
+                            debug!("SEvent: closing connection from banned address {}", endpoint.get_remote_address());
+                            swarm.close_connection(connection_id);
+                        }
+                        tracker.track_connection(connection_id, peer_id, endpoint.get_remote_address(), endpoint.clone());
+                        debug!("SEvent: {peer_id} is new friend via: {endpoint:?}");
                     },
-                    _ = reset_request_counts.tick() => {
-                        trace!("Resetting request counts");
-                        message_tracker.lock().await.reset_requests();
+                    SwarmEvent::ConnectionClosed { connection_id, peer_id, endpoint, cause, .. } => {
+                        let mut message_tracker_lock = message_tracker.lock().await;
+                        let _ = message_tracker_lock.lost_connection(connection_id);
+                        if let Some(cause) = cause {
+                            debug!("SEvent: friendship ended with {peer_id} via: {endpoint:?}. cause: {cause:?}");
+                        } else {
+                            debug!("SEvent: friendship ended by us with {peer_id} via: {endpoint:?}.");
+                        }
                     },
-                    _ = reset_elders_debounce.tick() => {
-                        trace!("Resetting elders debounce");
-                        let mut tracker = message_tracker.lock().await;
-                        tracker.seen_elders.clear();
+                    SwarmEvent::IncomingConnectionError { local_addr, send_back_addr, error, .. } => {
+                       trace!("SEvent: Failed incoming connection from {} to {}: {}",
+                       send_back_addr, local_addr, error);
+
+                       // When connection limits are reached, randomly prune inbound connections
+                       match error {
+                           ListenError::Denied { cause } => {
+                               metrics.incoming_connections_blocked_by_limits.increment();
+                               if let Some(prune_factor) = prune_inbound_size {
+                                   if let Ok(_exceeded) = cause.downcast::<libp2p::connection_limits::Exceeded>() {
+                                       message_tracker.lock().await.prune_inbound_connections(metrics.clone(), &mut swarm, prune_factor);
+                                   }
+                               }
+                           }
+                           _ => {}
+                       }
+                    },
+                    SwarmEvent::Behaviour(NockchainEvent::RequestResponse(Message { connection_id , peer, message })) => {
+                        trace!("SEvent: received RequestResponse");
+                        if let Response { request_id, .. } = &message {
+                            message_tracker.lock().await.local_fetch_answered(request_id);
+                        }
+                        let _span = tracing::debug_span!("SwarmEvent::Behavior(NockchainEvent::RequestResponse(…))").entered();
+                        let swarm_tx_clone = swarm_tx.clone();
+                        let mut equix_builder_clone = equix_builder.clone();
+                        let local_peer_id = *swarm.local_peer_id();
+                        // We have to dup and move a handle back into `handle` to propitiate the borrow checker
+                        let traffic_clone = traffic_cop.clone();
+                        let metrics = metrics.clone();
+                        let message_tracker_clone = Arc::clone(&message_tracker); // Clone the Arc, not the MessageTracker
+                        join_set.spawn("handle_request_response".to_string(), async move {
+                            handle_request_response(peer, connection_id, message, swarm_tx_clone, &mut equix_builder_clone, local_peer_id, traffic_clone, metrics.clone(), message_tracker_clone, request_high_threshold).await
+                        });
                     },
-                    Some(result) = join_set.join_next() => {
-                        if let Err(e) = result {
-                            error!("Task error: {:?}", e);
+                    SwarmEvent::Behaviour(NockchainEvent::RequestResponse(OutboundFailure { peer, request_id, error, ..})) => {
+                        log_outbound_failure(peer, error, metrics.clone());
+                        let local_fetch = message_tracker.lock().await.local_fetch_failed(&request_id);
+                        if let Some(local_fetch) = local_fetch {
+                            debug!("SEvent: every local peer failed a block fetch, asking remote peers");
+                            let swarm_tx_clone = swarm_tx.clone();
+                            let equix_builder_clone = equix_builder.clone();
+                            let local_peer_id = *swarm.local_peer_id();
+                            join_set.spawn("local_fetch_fallback".to_string(), async move {
+                                send_requests(local_fetch.remote_peers, &local_fetch.request, swarm_tx_clone, equix_builder_clone, local_peer_id).await
+                            });
                         }
+                    }
+                    SwarmEvent::Behaviour(NockchainEvent::RequestResponse(InboundFailure { peer, error, .. })) => {
+                        log_inbound_failure(peer, error, metrics.clone());
+                    }
+                    SwarmEvent::Behaviour(NockchainEvent::Ping(e)) => {
+                        // TODO: 暂时跳过ping事件的细分处理，等待确认正确的Event变体类型
+                        trace!("SEvent: Ping event received: {:?}", e);
+                    },
+                    SwarmEvent::OutgoingConnectionError { error, .. } => {
+                        log_dial_error(error);
+                    },
+                    SwarmEvent::IncomingConnection {
+                        local_addr,
+                        send_back_addr,
+                        connection_id,
+                        ..
+                    } => {
+                        debug!("SEvent: Incoming connection from {local_addr:?} to {send_back_addr:?} with {connection_id:?}");
+                    },
+                    SwarmEvent::Dialing { peer_id, connection_id } => {
+                        debug!("SEvent: Dialing {peer_id:?} {connection_id}");
                     },
+                    _ => {
+                        // Handle other swarm events
+                        trace!("SEvent: other swarm event {:?}", event);
+                    }
                 }
-            }
-        })
-    })
+            },
+            Some(swarm_action) = swarm_rx.recv() => {
+                // We do this because Swarm doesn't implement Send, and so we can't pass it into the tasks
+                // being spawned in the match cases above.
+                match swarm_action {
+                    SwarmAction::SendRequest { peer_id, request } => {
+                        trace!("SAction: SendRequest: {peer_id}");
+                        let _ = swarm.behaviour_mut().request_response.send_request(&peer_id, request);
+                    },
+                    SwarmAction::SendLocalFetchRequest { peer_id, request, fetch } => {
+                        trace!("SAction: SendLocalFetchRequest: {peer_id}");
+                        let request_id = swarm.behaviour_mut().request_response.send_request(&peer_id, request);
+                        message_tracker.lock().await.track_local_fetch_request(request_id, fetch);
+                    },
+                    SwarmAction::SendResponse { channel, response } => {
+                        trace!("SAction: SendResponse");
+                        let _ = swarm.behaviour_mut().request_response.send_response(channel, response);
+                    },
+                    SwarmAction::BlockPeer { peer_id } => {
+                        warn!("SAction: Blocking peer {peer_id}");
+                        // Block the peer in the allow_block_list
+                        swarm.behaviour_mut().allow_block_list.block_peer(peer_id);
+                        {
+                            // get peer IP address from the swarm
+                            let peer_addresses = swarm.behaviour_mut().peer_store.store().addresses_of_peer(&peer_id);
+                            if let Some(peer_multi_addrs) = peer_addresses {
+                                for multi_addr in peer_multi_addrs {
+                                    for protocol in multi_addr.iter() {
+
+                                        match protocol {
+                                            libp2p::core::multiaddr::Protocol::Ip4(ip) => {
+                                                log_fail2ban_ipv4(&peer_id, &ip);
+                                            },
+                                            libp2p::core::multiaddr::Protocol::Ip6(ip) => {
+                                                log_fail2ban_ipv6(&peer_id, &ip);
+                                            },
+                                            // TODO: Dns?
+                                            _ => {}
+                                        }
+                                    }
+                                }
+                            } else {
+                                error!("Failed to get peer IP address for peer id: {peer_id}");
+                            };
+                        }
+                        // Disconnect the peer if they're currently connected
+                        let _ = swarm.disconnect_peer_id(peer_id);
+                    },
+                }
+            },
+            Some(PeerAdminCommand { request, reply }) = admin_rx.recv() => {
+                debug!("Peer admin request: {request:?}");
+                let mut tracker = message_tracker.lock().await;
+                let response = admin::apply(&mut swarm, &mut tracker, request);
+                let _ = reply.send(response);
+            },
+            _ = kad_bootstrap.tick() => {
+                // If we don't have any peers, we should retry dialing our initial peers
+                if let Err(NoKnownPeers())= swarm.behaviour_mut().kad.bootstrap() {
+                    if initial_peer_retries_remaining > 0 {
+                        info!("Failed to bootstrap: {}", NoKnownPeers());
+                        initial_peer_retries_remaining -= 1;
+                        dial_peers(&mut swarm, &initial_peers)?;
+                    } else {
+                        warn!("Failed to bootstrap after {} retries, will not attempt to redial initial peers.", initial_peer_retries);
+                    }
+                }
+            },
+            _ = force_peer_dial.tick() => {
+                debug!("Force dialing peers");
+                dial_peers(&mut swarm, &force_peers)?;
+            },
+            _ = reset_request_counts.tick() => {
+                trace!("Resetting request counts");
+                message_tracker.lock().await.reset_requests();
+            },
+            _ = reset_elders_debounce.tick() => {
+                trace!("Resetting elders debounce");
+                let mut tracker = message_tracker.lock().await;
+                tracker.seen_elders.clear();
+            },
+            Some(result) = join_set.join_next() => {
+                if let Err(e) = result {
+                    error!("Task error: {:?}", e);
+                }
+            },
+        }
+    }
 }
 
 impl NockchainRequest {
@@ -480,6 +659,14 @@ impl NockchainRequest {
         }
     }
 
+    /// Make a new "request" which gossips a block in compact form
+    fn new_compact_gossip(compact_block: &NounSlab) -> NockchainRequest {
+        let message_bytes = ByteBuf::from(compact_block.jam().as_ref());
+        NockchainRequest::CompactGossip {
+            message: message_bytes,
+        }
+    }
+
     /// Make a new request for a block or a TX
     fn new_request(
         builder: &mut equix::EquiXBuilder,
@@ -552,7 +739,8 @@ impl NockchainRequest {
                 pow_buf.extend_from_slice(&message[..]);
                 builder.verify_bytes(&pow_buf[..], pow)
             }
-            NockchainRequest::Gossip { message: _ } => Ok(()),
+            NockchainRequest::Gossip { message: _ }
+            | NockchainRequest::CompactGossip { message: _ } => Ok(()),
         }
     }
 }
@@ -590,6 +778,74 @@ async fn send_timer_poke(
     Ok(())
 }
 
+/// Send the request effect in `noun_slab` to each of `peers`
+async fn send_requests(
+    peers: Vec<PeerId>,
+    noun_slab: &NounSlab,
+    swarm_tx: mpsc::Sender<SwarmAction>,
+    mut equix_builder: equix::EquiXBuilder,
+    local_peer_id: PeerId,
+) -> Result<(), NockAppError> {
+    debug!("Sending request to {} peers", peers.len());
+    for peer_id in peers {
+        let request =
+            NockchainRequest::new_request(&mut equix_builder, &local_peer_id, &peer_id, noun_slab);
+        swarm_tx
+            .send(SwarmAction::SendRequest { peer_id, request })
+            .await
+            .map_err(|_e| NockAppError::OtherError)?;
+    }
+    Ok(())
+}
+
+/// Send the block fetch in `noun_slab` to the `local` peers only. It goes on to the
+/// `remote` peers if no local peer has answered within the local fetch timeout, or
+/// sooner once every local request has failed.
+async fn send_local_fetch(
+    local: Vec<PeerId>,
+    remote: Vec<PeerId>,
+    noun_slab: NounSlab,
+    swarm_tx: mpsc::Sender<SwarmAction>,
+    mut equix_builder: equix::EquiXBuilder,
+    local_peer_id: PeerId,
+    message_tracker: Arc<Mutex<MessageTracker>>,
+) -> Result<(), NockAppError> {
+    let (fetch, timeout) = {
+        let mut tracker = message_tracker.lock().await;
+        let fetch = tracker.start_local_fetch(local.len(), remote, noun_slab.clone());
+        (fetch, tracker.local_fetch_timeout)
+    };
+    debug!("Sending block fetch to {} local peers", local.len());
+    for peer_id in local {
+        let request =
+            NockchainRequest::new_request(&mut equix_builder, &local_peer_id, &peer_id, &noun_slab);
+        swarm_tx
+            .send(SwarmAction::SendLocalFetchRequest {
+                peer_id,
+                request,
+                fetch,
+            })
+            .await
+            .map_err(|_e| NockAppError::OtherError)?;
+    }
+    tokio::spawn(async move {
+        tokio::time::sleep(timeout).await;
+        let local_fetch = message_tracker.lock().await.take_local_fetch(fetch);
+        if let Some(local_fetch) = local_fetch {
+            debug!("Local peers did not answer a block fetch in time, asking remote peers");
+            if let Err(e) = send_requests(
+                local_fetch.remote_peers, &local_fetch.request, swarm_tx, equix_builder,
+                local_peer_id,
+            )
+            .await
+            {
+                warn!("Could not send block fetch to remote peers: {e:?}");
+            }
+        }
+    });
+    Ok(())
+}
+
 async fn handle_effect(
     noun_slab: NounSlab,
     swarm_tx: mpsc::Sender<SwarmAction>,
@@ -612,6 +868,7 @@ async fn handle_effect(
 
             // Check if this is a heard-block gossip
             let gossip_noun = unsafe { tail_slab.root() };
+            let mut compact = false;
             if let Ok(data_cell) = gossip_noun.as_cell() {
                 if data_cell.head().eq_bytes(b"heard-block") {
                     trace!("Gossip effect for heard-block, clearing block and elders cache");
@@ -619,10 +876,24 @@ async fn handle_effect(
                     tracker.block_cache.clear();
                     tracker.elders_cache.clear();
                     tracker.elders_negative_cache.clear();
+                    compact = tracker.compact_block_relay;
                 }
             }
 
-            let gossip_request = NockchainRequest::new_gossip(&tail_slab);
+            let gossip_request = if compact {
+                match compact_heard_block(&tail_slab) {
+                    Ok(compact_slab) => {
+                        metrics.compact_blocks_sent.increment();
+                        NockchainRequest::new_compact_gossip(&compact_slab)
+                    }
+                    Err(e) => {
+                        warn!("Could not compact heard-block, gossiping it in full: {e:?}");
+                        NockchainRequest::new_gossip(&tail_slab)
+                    }
+                }
+            } else {
+                NockchainRequest::new_gossip(&tail_slab)
+            };
             debug!("Gossiping to {} peers", connected_peers.len());
             for peer_id in connected_peers.clone() {
                 let gossip_request_clone = gossip_request.clone();
@@ -657,7 +928,19 @@ async fn handle_effect(
                         connected_peers.clone()
                     }
                 } else {
-                    connected_peers.clone()
+                    // Block fetches go to LAN peers first if we have any
+                    let (local, remote) = message_tracker
+                        .lock()
+                        .await
+                        .split_local_peers(connected_peers.clone());
+                    if !local.is_empty() {
+                        return send_local_fetch(
+                            local, remote, noun_slab, swarm_tx, equix_builder, local_peer_id,
+                            message_tracker,
+                        )
+                        .await;
+                    }
+                    remote
                 }
             } else {
                 connected_peers.clone()
@@ -674,19 +957,10 @@ async fn handle_effect(
                 }
             }
 
-            debug!("Sending request to {} peers", target_peers.len());
-
-            for peer_id in target_peers {
-                let local_peer_id_clone = local_peer_id;
-                let mut equix_builder_clone = equix_builder.clone();
-                let request = NockchainRequest::new_request(
-                    &mut equix_builder_clone, &local_peer_id_clone, &peer_id, &noun_slab,
-                );
-                swarm_tx
-                    .send(SwarmAction::SendRequest { peer_id, request })
-                    .await
-                    .map_err(|_e| NockAppError::OtherError)?;
-            }
+            send_requests(
+                target_peers, &noun_slab, swarm_tx, equix_builder, local_peer_id,
+            )
+            .await?;
         }
         EffectType::LiarPeer => {
             let effect_cell = unsafe { noun_slab.root().as_cell()? };
@@ -807,6 +1081,9 @@ async fn handle_effect(
                     .expect("failed to convert tx ID to base58");
                 trace!("seen tx id: {:?}", &tx_id_str);
                 tracker.seen_txs.insert(tx_id_str);
+                if let Ok(limbs) = tx_id_limbs(tx_id.as_noun()) {
+                    tracker.tx_ids.insert(limbs);
+                }
             }
         }
         EffectType::Unknown => {
@@ -873,6 +1150,60 @@ async fn handle_request_response(
             } else {
                 debug!("Request received but connection not tracked. Bug?");
             }
+            // Compact blocks become ordinary gossip once rebuilt. If we can't rebuild one,
+            // ack it and ask the sender for that block by id. The kernel then fetches only
+            // the raw transactions it is missing.
+            let request = match request {
+                NockchainRequest::CompactGossip { message } => {
+                    let mut compact_slab = NounSlab::new();
+                    let compact_noun = compact_slab.cue_into(Bytes::from(message.to_vec()))?;
+                    compact_slab.set_root(compact_noun);
+                    let expanded = {
+                        let tracker = message_tracker.lock().await;
+                        expand_heard_block(&compact_slab, &tracker.tx_ids)?
+                    };
+                    match expanded {
+                        Ok(block_slab) => {
+                            metrics.compact_blocks_rebuilt.increment();
+                            NockchainRequest::new_gossip(&block_slab)
+                        }
+                        Err(missing) => {
+                            metrics.compact_blocks_missing_txs.increment();
+                            let height = heard_block_height(&compact_slab)?;
+                            debug!(
+                                "{} unknown tx ids in compact block at height {height} from {peer}, requesting it by id",
+                                missing.len()
+                            );
+                            let response = NockchainResponse::Ack { acked: true };
+                            swarm_tx
+                                .send(SwarmAction::SendResponse { channel, response })
+                                .await
+                                .map_err(|_| NockAppError::OtherError)?;
+                            let mut block_request = NounSlab::new();
+                            let block_id =
+                                block_request.copy_into(heard_block_digest(&compact_slab)?);
+                            let by_id = make_tas(&mut block_request, "by-id").as_noun();
+                            let request_noun = T(
+                                &mut block_request,
+                                &[D(tas!(b"request")), D(tas!(b"block")), by_id, block_id],
+                            );
+                            block_request.set_root(request_noun);
+                            let request = NockchainRequest::new_request(
+                                equix_builder, &local_peer_id, &peer, &block_request,
+                            );
+                            swarm_tx
+                                .send(SwarmAction::SendRequest {
+                                    peer_id: peer,
+                                    request,
+                                })
+                                .await
+                                .map_err(|_| NockAppError::OtherError)?;
+                            return Ok(());
+                        }
+                    }
+                }
+                request => request,
+            };
             let mut request_slab = NounSlab::new();
             match request {
                 NockchainRequest::Request {
@@ -940,6 +1271,10 @@ async fn handle_request_response(
                                         debug!("Peek error getting block at height: {:?}", height);
                                         metrics.requests_erred_block_by_height.increment();
                                     }
+                                    NockchainDataRequest::BlockById(ref id, _) => {
+                                        debug!("Peek error getting block with id: {:?}", id);
+                                        metrics.requests_erred_block_by_id.increment();
+                                    }
                                     NockchainDataRequest::EldersById(ref id, _, _) => {
                                         debug!("Peek error getting elders of id: {:?}", id);
                                         metrics.requests_erred_elders_by_id.increment();
@@ -976,6 +1311,16 @@ async fn handle_request_response(
                                 }
                             }
                         }
+                        NockchainDataRequest::BlockById(..) => {
+                            let scry_res = unsafe { scry_res_slab.root() };
+                            match create_scry_response(scry_res, "heard-block", &mut res_slab) {
+                                Left(()) => {
+                                    trace!("No data found for incoming block by-id request");
+                                    return Ok(());
+                                }
+                                Right(result) => result?,
+                            }
+                        }
                         NockchainDataRequest::EldersById(id, _, _) => {
                             let scry_res = unsafe { scry_res_slab.root() };
                             match create_scry_response(scry_res, "heard-elders", &mut res_slab) {
@@ -994,7 +1339,7 @@ async fn handle_request_response(
                                 }
                             }
                         }
-                        NockchainDataRequest::RawTransactionById(ref id, _) => {
+                        NockchainDataRequest::RawTransactionById(ref id, ref id_slab) => {
                             let scry_res = unsafe { scry_res_slab.root() };
                             match create_scry_response(scry_res, "heard-tx", &mut res_slab) {
                                 Left(()) => {
@@ -1006,6 +1351,9 @@ async fn handle_request_response(
                                         let mut tracker = message_tracker.lock().await;
                                         trace!("cacheing tx request by id={:?}", id);
                                         tracker.tx_cache.insert(id.clone(), scry_res_slab.clone());
+                                        if let Ok(limbs) = tx_id_limbs(unsafe { *id_slab.root() }) {
+                                            tracker.tx_ids.insert(limbs);
+                                        }
                                     }
                                     result?
                                 }
@@ -1163,6 +1511,9 @@ async fn handle_request_response(
                     send_response.await??;
                     poke_kernel.await??;
                 }
+                NockchainRequest::CompactGossip { .. } => {
+                    unreachable!("compact gossip is rebuilt into gossip above")
+                }
             }
         }
         Response { response, .. } => match response {
@@ -1357,7 +1708,7 @@ async fn log_peer_status(
 /// `[%request [type data]]` where:
 /// - `type` can be either "block" or "raw-tx"
 /// - For "block" type:
-///   - `data` can be either `[%by-height height]` or `[%elders block-id peer-id]`
+///   - `data` can be `[%by-height height]`, `[%by-id block-id]` or `[%elders block-id peer-id]`
 /// - For "raw-tx" type:
 ///   - `data` must be `[%by-id id]`
 ///
@@ -1372,6 +1723,8 @@ async fn log_peer_status(
 /// For a block by height request:
 /// [%request [%block [%by-height 123]]] -> [%heavy-n 123 0]
 /// For a block by id request:
+/// [%request [%block [%by-id [1 2 3 4 5]]]] -> [%block base58-block-id 0]
+/// For an elders request:
 /// [%request [%block [%elders [1 2 3 4 5] abcDEF]]] -> [%elders base58-block-id peer-id 0]
 /// For a raw transaction request:
 /// [%request [%raw-tx [%by-id [1 2 3 4 5]]]] -> [%raw-transaction base58-tx-id 0]
@@ -1385,6 +1738,14 @@ fn request_to_scry_slab(request: NockchainDataRequest) -> Result<NounSlab, NockA
             slab.set_root(noun);
             Ok(slab)
         }
+        NockchainDataRequest::BlockById(str, _) => {
+            debug!("Requesting block by ID: {}", str);
+            let mut slab = NounSlab::new();
+            let id_atom = Atom::from_value(&mut slab, str)?;
+            let noun = T(&mut slab, &[D(tas!(b"block")), id_atom.as_noun(), D(0)]);
+            slab.set_root(noun);
+            Ok(slab)
+        }
         NockchainDataRequest::EldersById(str, _, _) => {
             debug!("Requesting elders by ID: {}", str);
             let mut slab = NounSlab::new();
@@ -1684,6 +2045,41 @@ mod tests {
             );
         }
 
+        // Test block by-id request
+        {
+            let mut slab: NounSlab = NounSlab::new();
+            let block_id = T(&mut slab, &[D(1), D(2), D(3), D(4), D(5)]);
+            let by_id_tas = make_tas(&mut slab, "by-id");
+            let by_id = T(&mut slab, &[by_id_tas.as_noun(), block_id]);
+            let block_cell = T(&mut slab, &[D(tas!(b"block")), by_id]);
+            let request = T(&mut slab, &[D(tas!(b"request")), block_cell]);
+            slab.set_root(request);
+
+            let data_request = NockchainDataRequest::from_noun(request)
+                .expect("Could not create request from noun");
+            let result_slab = request_to_scry_slab(data_request).expect("scry path");
+            let result = unsafe { result_slab.root() }
+                .as_cell()
+                .expect("scry path is a cell");
+
+            // [%block block_id_b58 0]
+            assert!(result.head().eq_bytes(b"block"));
+            let tail = result.tail().as_cell().expect("tail is a cell");
+            let block_id_str = String::from_utf8(
+                tail.head()
+                    .as_atom()
+                    .expect("block id atom")
+                    .to_bytes_until_nul()
+                    .expect("block id bytes"),
+            )
+            .expect("block id utf8");
+            assert_eq!(
+                block_id_str,
+                tip5_hash_to_base58(block_id).expect("base58 block id")
+            );
+            assert_eq!(tail.tail().as_direct().expect("trailing ~").data(), 0);
+        }
+
         // Test invalid elders request (not a cell)
         {
             let mut slab: NounSlab = NounSlab::new();
@@ -1831,6 +2227,63 @@ mod tests {
         assert!(swarm_rx.try_recv().is_err(), "Should only send one action");
     }
 
+    #[tokio::test]
+    #[cfg_attr(miri, ignore)]
+    async fn test_block_request_falls_back_to_remote_peers() {
+        use equix::EquiXBuilder;
+        use tokio::sync::mpsc;
+
+        let mut effect_slab = NounSlab::new();
+        let by_height = Atom::from_value(&mut effect_slab, "by-height")
+            .expect("Failed to create by-height atom");
+        let effect = T(
+            &mut effect_slab,
+            &[D(tas!(b"request")), D(tas!(b"block")), by_height.as_noun(), D(1)],
+        );
+        effect_slab.set_root(effect);
+        let metrics = Arc::new(
+            NockchainP2PMetrics::register(gnort::global_metrics_registry())
+                .expect("Could not register metrics"),
+        );
+        let local_peer = PeerId::random();
+        let remote_peer = PeerId::random();
+        let mut tracker =
+            MessageTracker::new(metrics.clone(), LIBP2P_CONFIG.seen_tx_clear_interval);
+        tracker.add_local_peer(
+            local_peer,
+            "/ip4/192.168.1.2/udp/3006/quic-v1".parse().unwrap(),
+        );
+        tracker.local_fetch_timeout = Duration::from_millis(50);
+        let (swarm_tx, mut swarm_rx) = mpsc::channel(4);
+
+        handle_effect(
+            effect_slab,
+            swarm_tx,
+            EquiXBuilder::new(),
+            PeerId::random(),
+            vec![remote_peer, local_peer],
+            Arc::new(Mutex::new(tracker)),
+            metrics,
+        )
+        .await
+        .expect("handle_effect should succeed");
+
+        // Only the local peer is asked at first
+        match swarm_rx.recv().await {
+            Some(SwarmAction::SendLocalFetchRequest { peer_id, .. }) => {
+                assert_eq!(peer_id, local_peer)
+            }
+            other => panic!("Expected SendLocalFetchRequest, got {:?}", other),
+        }
+        assert!(swarm_rx.try_recv().is_err());
+
+        // Nobody answered, so the remote peer is asked once the timeout passes
+        match swarm_rx.recv().await {
+            Some(SwarmAction::SendRequest { peer_id, .. }) => assert_eq!(peer_id, remote_peer),
+            other => panic!("Expected SendRequest, got {:?}", other),
+        }
+    }
+
     #[tokio::test]
     #[cfg_attr(miri, ignore)] // ibig has a memory leak so miri fails this test
     async fn test_track_add_effect() {
@@ -2319,13 +2772,12 @@ fn dial_peers(
 ) -> Result<(), NockAppError> {
     let mut rng = rand::thread_rng();
 
-    let cloned_peers: &mut [libp2p::Multiaddr] = &mut peers.to_vec();
-    cloned_peers.shuffle(&mut rng);
+    let mut dials = quic_first_dial_opts(peers.to_vec());
+    dials.shuffle(&mut rng);
 
-    for peer in cloned_peers {
-        let peer = peer.clone();
-        debug!("Dialing peer: {}", peer);
-        let _ = swarm.dial(peer.clone()).map_err(log_dial_error);
+    for dial in dials {
+        debug!("Dialing peer: {:?}", dial.get_peer_id());
+        let _ = swarm.dial(dial).map_err(log_dial_error);
     }
     Ok(())
 }
@@ -2605,10 +3057,11 @@ fn dial_more_peers(swarm: &mut Swarm<NockchainBehaviour>, tracker: MutexGuard<Me
             }
         }
     }
-    addresses_to_dial.shuffle(&mut rand::thread_rng());
-    for address in addresses_to_dial {
-        info!("Redialing {}", address);
-        if let Err(err) = swarm.dial(address) {
+    let mut dials = quic_first_dial_opts(addresses_to_dial);
+    dials.shuffle(&mut rand::thread_rng());
+    for dial in dials {
+        info!("Redialing {:?}", dial.get_peer_id());
+        if let Err(err) = swarm.dial(dial) {
             log_dial_error(err);
         };
     }
@@ -1,9 +1,13 @@
 use std::collections::{BTreeMap, BTreeSet};
-use std::net::{Ipv4Addr, Ipv6Addr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 
 use libp2p::core::ConnectedPoint;
+use libp2p::multiaddr::Protocol;
+use libp2p::request_response::OutboundRequestId;
+use libp2p::swarm::dial_opts::{DialOpts, PeerCondition};
 use libp2p::swarm::ConnectionId;
 use libp2p::{Multiaddr, PeerId, Swarm};
 use nockapp::noun::slab::NounSlab;
@@ -13,6 +17,8 @@ use nockvm_macros::tas;
 use rand::prelude::SliceRandom;
 use tracing::{info, trace, warn};
 
+use crate::admin::PeerInfo;
+use crate::compact::TxIdIndex;
 use crate::metrics::NockchainP2PMetrics;
 use crate::tip5_util::tip5_hash_to_base58;
 
@@ -25,6 +31,62 @@ pub fn log_fail2ban_ipv6(peer_id: &PeerId, ip: &Ipv6Addr) {
     warn!("fail2ban: Blocked peer {peer_id} with IPv6 address: {ip}");
 }
 
+/// Returns the first IP address in a multiaddr, if any
+pub fn multiaddr_ip(addr: &Multiaddr) -> Option<IpAddr> {
+    addr.iter().find_map(|protocol| match protocol {
+        Protocol::Ip4(ip) => Some(IpAddr::V4(ip)),
+        Protocol::Ip6(ip) => Some(IpAddr::V6(ip)),
+        _ => None,
+    })
+}
+
+/// Whether a multiaddr goes over QUIC
+pub fn is_quic_addr(addr: &Multiaddr) -> bool {
+    addr.iter()
+        .any(|protocol| matches!(protocol, Protocol::QuicV1 | Protocol::Quic))
+}
+
+/// The TCP counterpart of a QUIC listen address: the same IP and port, with
+/// `/udp/<port>/quic-v1` replaced by `/tcp/<port>`. Returns `None` for non-QUIC addresses.
+pub fn tcp_listen_addr(addr: &Multiaddr) -> Option<Multiaddr> {
+    if !is_quic_addr(addr) {
+        return None;
+    }
+    Some(
+        addr.iter()
+            .filter_map(|protocol| match protocol {
+                Protocol::Udp(port) => Some(Protocol::Tcp(port)),
+                Protocol::QuicV1 | Protocol::Quic => None,
+                other => Some(other),
+            })
+            .collect(),
+    )
+}
+
+/// Turn a list of addresses into dials, one per peer where the address names the peer
+/// (`/p2p/<peer id>`), with that peer's QUIC addresses ahead of any others. Addresses
+/// without a peer ID are dialed on their own.
+pub fn quic_first_dial_opts(addrs: Vec<Multiaddr>) -> Vec<DialOpts> {
+    let mut by_peer: BTreeMap<PeerId, Vec<Multiaddr>> = BTreeMap::new();
+    let mut opts = Vec::new();
+    for addr in addrs {
+        match addr.iter().last() {
+            Some(Protocol::P2p(peer_id)) => by_peer.entry(peer_id).or_default().push(addr),
+            _ => opts.push(DialOpts::unknown_peer_id().address(addr).build()),
+        }
+    }
+    for (peer_id, mut addrs) in by_peer {
+        addrs.sort_by_key(|addr| !is_quic_addr(addr));
+        opts.push(
+            DialOpts::peer_id(peer_id)
+                .addresses(addrs)
+                .condition(PeerCondition::Always)
+                .build(),
+        );
+    }
+    opts
+}
+
 pub trait PeerIdExt {
     fn from_noun(noun: Noun) -> Result<PeerId, NockAppError>;
 }
@@ -43,6 +105,15 @@ pub enum CacheResponse {
     NegativeCached,
 }
 
+/// A block fetch sent to local peers first, kept until one of them answers or it falls
+/// back to the remote peers
+pub struct LocalFetch {
+    pub remote_peers: Vec<PeerId>,
+    /// The request effect, for sending on to the remote peers
+    pub request: NounSlab,
+    outstanding: usize,
+}
+
 /// This struct is used to track which peers sent us which block IDs.
 /// `block_id_to_peers` is the one we really care about, since it's what we use
 /// to figure out which peers to ban when we get a %liar-block-id effect.
@@ -70,8 +141,23 @@ pub struct MessageTracker {
     pub first_negative: u64,
     pub seen_tx_clear_interval: u64,
     pub last_tx_cache_clear_height: u64,
+    // Tx ids we know, for rebuilding compact blocks
+    pub tx_ids: TxIdIndex,
+    // Whether we gossip blocks in compact form
+    pub compact_block_relay: bool,
     // Track failed pings per peer
     failed_pings_by_peer: BTreeMap<PeerId, u64>,
+    // Peers discovered on the local network via mDNS, with their unexpired addresses
+    local_peers: BTreeMap<PeerId, BTreeSet<Multiaddr>>,
+    // Block fetches sent to local peers which haven't been answered or fallen back yet
+    local_fetches: BTreeMap<u64, LocalFetch>,
+    // The local fetch each outbound request to a local peer belongs to
+    local_fetch_requests: BTreeMap<OutboundRequestId, u64>,
+    next_local_fetch: u64,
+    // How long local peers get to answer a block fetch before remote peers are asked
+    pub local_fetch_timeout: Duration,
+    // IPs banned through the peer admin API
+    banned_ips: BTreeSet<IpAddr>,
 }
 
 impl MessageTracker {
@@ -94,7 +180,15 @@ impl MessageTracker {
             first_negative: 0,
             seen_tx_clear_interval,
             last_tx_cache_clear_height: 0,
+            tx_ids: TxIdIndex::default(),
+            compact_block_relay: false,
             failed_pings_by_peer: BTreeMap::new(),
+            local_peers: BTreeMap::new(),
+            local_fetches: BTreeMap::new(),
+            local_fetch_requests: BTreeMap::new(),
+            next_local_fetch: 0,
+            local_fetch_timeout: Duration::ZERO,
+            banned_ips: BTreeSet::new(),
         }
     }
 
@@ -347,6 +441,7 @@ impl MessageTracker {
                     Ok(CacheResponse::NotCached)
                 }
             }
+            NockchainDataRequest::BlockById(..) => Ok(CacheResponse::NotCached),
             NockchainDataRequest::RawTransactionById(id, _) => {
                 if let Some(cached_transaction) = self.tx_cache.get(&id) {
                     trace!("found cached transaction request by id={:?}", id);
@@ -393,6 +488,141 @@ impl MessageTracker {
     pub fn remove_failed_ping_tracking(&mut self, peer_id: &PeerId) {
         self.failed_pings_by_peer.remove(peer_id);
     }
+
+    /// Record an address mDNS discovered a peer at on the local network
+    pub fn add_local_peer(&mut self, peer_id: PeerId, addr: Multiaddr) {
+        let addrs = self.local_peers.entry(peer_id).or_default();
+        if addrs.is_empty() {
+            info!("Discovered local peer: {}", peer_id);
+        }
+        addrs.insert(addr);
+    }
+
+    /// Forget an address whose mDNS record expired. The peer stays local while it has
+    /// other unexpired addresses.
+    pub fn remove_local_peer(&mut self, peer_id: &PeerId, addr: &Multiaddr) {
+        let Some(addrs) = self.local_peers.get_mut(peer_id) else {
+            return;
+        };
+        addrs.remove(addr);
+        if addrs.is_empty() {
+            self.local_peers.remove(peer_id);
+            info!("Local peer expired: {}", peer_id);
+        }
+    }
+
+    pub fn is_local_peer(&self, peer_id: &PeerId) -> bool {
+        self.local_peers.contains_key(peer_id)
+    }
+
+    /// Splits `peers` into local and remote ones, for sending block fetches to the LAN
+    /// before the wider network.
+    pub fn split_local_peers(&self, peers: Vec<PeerId>) -> (Vec<PeerId>, Vec<PeerId>) {
+        peers
+            .into_iter()
+            .partition(|peer_id| self.local_peers.contains_key(peer_id))
+    }
+
+    /// Start a block fetch sent to `local` local peers, which falls back to `remote_peers`
+    /// unless one of them answers. Returns the fetch to tag the local requests with.
+    pub fn start_local_fetch(
+        &mut self,
+        local: usize,
+        remote_peers: Vec<PeerId>,
+        request: NounSlab,
+    ) -> u64 {
+        let fetch = self.next_local_fetch;
+        self.next_local_fetch += 1;
+        self.local_fetches.insert(
+            fetch,
+            LocalFetch {
+                remote_peers,
+                request,
+                outstanding: local,
+            },
+        );
+        fetch
+    }
+
+    /// Record the outbound request sent to a local peer for `fetch`
+    pub fn track_local_fetch_request(&mut self, request_id: OutboundRequestId, fetch: u64) {
+        if self.local_fetches.contains_key(&fetch) {
+            self.local_fetch_requests.insert(request_id, fetch);
+        }
+    }
+
+    /// A local peer answered `request_id`, so its fetch needs no fallback
+    pub fn local_fetch_answered(&mut self, request_id: &OutboundRequestId) {
+        if let Some(fetch) = self.local_fetch_requests.remove(request_id) {
+            if self.local_fetches.remove(&fetch).is_some() {
+                trace!("Local fetch {fetch} answered by a local peer");
+            }
+        }
+    }
+
+    /// `request_id` to a local peer failed. Returns its fetch for the remote peers once
+    /// every local request for it has failed.
+    pub fn local_fetch_failed(&mut self, request_id: &OutboundRequestId) -> Option<LocalFetch> {
+        let fetch = self.local_fetch_requests.remove(request_id)?;
+        let local_fetch = self.local_fetches.get_mut(&fetch)?;
+        local_fetch.outstanding = local_fetch.outstanding.saturating_sub(1);
+        if local_fetch.outstanding > 0 {
+            return None;
+        }
+        self.take_local_fetch(fetch)
+    }
+
+    /// Take `fetch` for the remote peers if no local peer answered it in time
+    pub fn take_local_fetch(&mut self, fetch: u64) -> Option<LocalFetch> {
+        let local_fetch = self.local_fetches.remove(&fetch)?;
+        self.local_fetch_requests.retain(|_, f| *f != fetch);
+        Some(local_fetch)
+    }
+
+    /// Bans an IP and returns the connections currently open from it, which the caller should close.
+    pub fn ban_ip(&mut self, ip: IpAddr) -> Vec<ConnectionId> {
+        self.banned_ips.insert(ip);
+        self.peer_connections
+            .values()
+            .flat_map(|connections| connections.iter())
+            .filter(|(_, addr)| multiaddr_ip(addr) == Some(ip))
+            .map(|(connection_id, _)| *connection_id)
+            .collect()
+    }
+
+    pub fn unban_ip(&mut self, ip: &IpAddr) {
+        self.banned_ips.remove(ip);
+    }
+
+    pub fn banned_ips(&self) -> impl Iterator<Item = &IpAddr> {
+        self.banned_ips.iter()
+    }
+
+    /// Returns true if the address belongs to a banned IP
+    pub fn is_banned_addr(&self, addr: &Multiaddr) -> bool {
+        multiaddr_ip(addr).is_some_and(|ip| self.banned_ips.contains(&ip))
+    }
+
+    /// Summarizes every connected peer for the peer admin API
+    pub fn peer_infos(&self) -> Vec<PeerInfo> {
+        self.peer_connections
+            .iter()
+            .map(|(peer_id, connections)| PeerInfo {
+                peer_id: peer_id.to_base58(),
+                addresses: connections.values().map(|addr| addr.to_string()).collect(),
+                connections: connections.len(),
+                inbound: connections
+                    .keys()
+                    .any(|connection_id| self.inbound_connections.contains_key(connection_id)),
+                local: self.local_peers.contains_key(peer_id),
+                failed_pings: self.get_failed_pings(peer_id),
+                tracked_block_ids: self
+                    .peer_to_block_ids
+                    .get(peer_id)
+                    .map_or(0, |block_ids| block_ids.len()),
+            })
+            .collect()
+    }
 }
 
 const POKE_VERSION: u64 = 0;
@@ -454,6 +684,7 @@ impl NockchainFact {
 #[derive(Debug, Clone)]
 pub enum NockchainDataRequest {
     BlockByHeight(u64),                   // Height requested
+    BlockById(String, NounSlab),          // Block ID as string, block id as noun,
     EldersById(String, PeerId, NounSlab), // Block ID as string, peer id, block id as noun,
     RawTransactionById(String, NounSlab), // transaction id as string, transaction id as noun,
 }
@@ -471,12 +702,22 @@ impl NockchainDataRequest {
             if kind_cell.head().eq_bytes(b"block") {
                 // block_cell type
                 // $%  [%by-height p=page-number:dt]
+                //     [%by-id p=block-id:dt]
                 //     [%elders p=block-id:dt q=peer-id]
                 // ==
+                // %by-id is only sent by the driver, to fetch a compact block it can't rebuild
                 let block_cell = kind_cell.tail().as_cell()?;
                 if block_cell.head().eq_bytes(b"by-height") {
                     let height = block_cell.tail().as_atom()?.as_u64()?;
                     Ok(Self::BlockByHeight(height))
+                } else if block_cell.head().eq_bytes(b"by-id") {
+                    let block_id = tip5_hash_to_base58(block_cell.tail())?;
+                    let slab = {
+                        let mut slab = NounSlab::new();
+                        slab.copy_into(block_cell.tail());
+                        slab
+                    };
+                    Ok(Self::BlockById(block_id, slab))
                 } else if block_cell.head().eq_bytes(b"elders") {
                     let elders_cell = block_cell.tail().as_cell()?;
                     let block_id = tip5_hash_to_base58(elders_cell.head())?;
@@ -882,6 +1123,101 @@ mod tests {
         assert!(!tracker.is_tracking_block_id(other_block_id));
     }
 
+    #[test]
+    fn test_split_local_peers() {
+        let metrics = Arc::new(
+            NockchainP2PMetrics::register(gnort::global_metrics_registry())
+                .expect("Could not register metrics"),
+        );
+        let mut tracker = MessageTracker::new(metrics, LIBP2P_CONFIG.seen_tx_clear_interval);
+        let local_peer = PeerId::random();
+        let remote_peer = PeerId::random();
+        let peers = vec![remote_peer, local_peer];
+        let addr_1: Multiaddr = "/ip4/192.168.1.2/udp/3006/quic-v1".parse().unwrap();
+        let addr_2: Multiaddr = "/ip4/192.168.1.3/udp/3006/quic-v1".parse().unwrap();
+
+        // No local peers known, so everyone is remote
+        assert_eq!(
+            tracker.split_local_peers(peers.clone()),
+            (vec![], peers.clone())
+        );
+
+        tracker.add_local_peer(local_peer, addr_1.clone());
+        tracker.add_local_peer(local_peer, addr_2.clone());
+        assert!(tracker.is_local_peer(&local_peer));
+        assert_eq!(
+            tracker.split_local_peers(peers.clone()),
+            (vec![local_peer], vec![remote_peer])
+        );
+
+        // One expired address leaves the peer local through the other
+        tracker.remove_local_peer(&local_peer, &addr_1);
+        assert!(tracker.is_local_peer(&local_peer));
+
+        tracker.remove_local_peer(&local_peer, &addr_2);
+        assert!(!tracker.is_local_peer(&local_peer));
+        assert_eq!(tracker.split_local_peers(peers.clone()), (vec![], peers));
+    }
+
+    #[test]
+    fn test_local_fetch_falls_back_once() {
+        let metrics = Arc::new(
+            NockchainP2PMetrics::register(gnort::global_metrics_registry())
+                .expect("Could not register metrics"),
+        );
+        let mut tracker = MessageTracker::new(metrics, LIBP2P_CONFIG.seen_tx_clear_interval);
+        let remote_peer = PeerId::random();
+
+        let fetch = tracker.start_local_fetch(1, vec![remote_peer], NounSlab::new());
+        let local_fetch = tracker
+            .take_local_fetch(fetch)
+            .expect("fetch should fall back");
+        assert_eq!(local_fetch.remote_peers, vec![remote_peer]);
+        // Already fallen back, so a later timeout or failure does nothing
+        assert!(tracker.take_local_fetch(fetch).is_none());
+
+        // Fetches are numbered apart
+        let other = tracker.start_local_fetch(1, vec![remote_peer], NounSlab::new());
+        assert_ne!(fetch, other);
+    }
+
+    #[test]
+    fn test_ban_ip() {
+        let metrics = Arc::new(
+            NockchainP2PMetrics::register(gnort::global_metrics_registry())
+                .expect("Could not register metrics"),
+        );
+        let mut tracker = MessageTracker::new(metrics, LIBP2P_CONFIG.seen_tx_clear_interval);
+        let peer_id = PeerId::random();
+        let banned_addr: Multiaddr = "/ip4/10.0.0.7/udp/3006/quic-v1"
+            .parse()
+            .expect("Failed to parse multiaddr");
+        let other_addr: Multiaddr = "/ip4/10.0.0.8/udp/3006/quic-v1"
+            .parse()
+            .expect("Failed to parse multiaddr");
+        let connection_id = ConnectionId::new_unchecked(1);
+        let endpoint = ConnectedPoint::Dialer {
+            address: banned_addr.clone(),
+            role_override: libp2p::core::Endpoint::Dialer,
+            port_use: libp2p::core::transport::PortUse::Reuse,
+        };
+        tracker.track_connection(connection_id, peer_id, &banned_addr, endpoint);
+
+        let ip: IpAddr = "10.0.0.7".parse().expect("Failed to parse IP");
+        assert_eq!(tracker.ban_ip(ip), vec![connection_id]);
+        assert!(tracker.is_banned_addr(&banned_addr));
+        assert!(!tracker.is_banned_addr(&other_addr));
+
+        let infos = tracker.peer_infos();
+        assert_eq!(infos.len(), 1);
+        assert_eq!(infos[0].peer_id, peer_id.to_base58());
+        assert_eq!(infos[0].connections, 1);
+        assert!(!infos[0].inbound);
+
+        tracker.unban_ip(&ip);
+        assert!(!tracker.is_banned_addr(&banned_addr));
+    }
+
     #[test]
     fn test_fail2ban_logging() {
         let peer_id: PeerId = libp2p::PeerId::from_bytes(&[0; 2]).unwrap();
@@ -894,4 +1230,31 @@ mod tests {
         assert_eq!(ipv4_display, "192.168.1.1");
         assert_eq!(ipv6_display, "2001:db8:db8:db8:db8:db8:db8:1");
     }
+
+    #[test]
+    fn test_tcp_listen_addr() {
+        let quic: Multiaddr = "/ip4/0.0.0.0/udp/3006/quic-v1".parse().unwrap();
+        let tcp: Multiaddr = "/ip4/0.0.0.0/tcp/3006".parse().unwrap();
+        assert!(is_quic_addr(&quic));
+        assert!(!is_quic_addr(&tcp));
+        assert_eq!(tcp_listen_addr(&quic), Some(tcp.clone()));
+        assert_eq!(tcp_listen_addr(&tcp), None);
+    }
+
+    #[test]
+    fn test_quic_first_dial_opts() {
+        let peer_id = PeerId::random();
+        let tcp: Multiaddr = format!("/ip4/10.0.0.1/tcp/3006/p2p/{peer_id}")
+            .parse()
+            .unwrap();
+        let quic: Multiaddr = format!("/ip4/10.0.0.1/udp/3006/quic-v1/p2p/{peer_id}")
+            .parse()
+            .unwrap();
+        let anonymous: Multiaddr = "/ip4/10.0.0.2/udp/3006/quic-v1".parse().unwrap();
+        let opts = quic_first_dial_opts(vec![tcp, anonymous, quic]);
+        // Both addresses of the named peer collapse into a single dial
+        assert_eq!(opts.len(), 2);
+        assert_eq!(opts[0].get_peer_id(), None);
+        assert_eq!(opts[1].get_peer_id(), Some(peer_id));
+    }
 }
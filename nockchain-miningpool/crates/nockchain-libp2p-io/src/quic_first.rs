@@ -0,0 +1,157 @@
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::convert::Infallible;
+use std::task::{Context, Poll};
+
+use libp2p::core::transport::PortUse;
+use libp2p::core::Endpoint;
+use libp2p::swarm::{
+    dummy, ConnectionDenied, ConnectionId, FromSwarm, NetworkBehaviour, NewExternalAddrOfPeer,
+    THandler, THandlerInEvent, THandlerOutEvent, ToSwarm,
+};
+use libp2p::{Multiaddr, PeerId};
+
+use crate::p2p_util::is_quic_addr;
+
+/// QUIC addresses kept per peer
+const MAX_ADDRS_PER_PEER: usize = 8;
+
+/// Puts each peer's QUIC addresses ahead of its others on every dial, including the dials
+/// kad and request_response make on their own.
+///
+/// The swarm asks each behaviour for a peer's addresses in field order and keeps the first
+/// of any duplicates, so this has to be the first field of
+/// [crate::behaviour::NockchainBehaviour]. It learns addresses from the swarm's
+/// `NewExternalAddrOfPeer` events (see [libp2p::Swarm::add_peer_address]) and forgets the
+/// oldest peers past its capacity.
+pub(crate) struct QuicFirst {
+    addrs: HashMap<PeerId, BTreeSet<Multiaddr>>,
+    /// Peers in the order they were first seen, for eviction
+    order: VecDeque<PeerId>,
+    capacity: usize,
+}
+
+impl QuicFirst {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            addrs: HashMap::new(),
+            order: VecDeque::new(),
+            capacity: capacity.max(1),
+        }
+    }
+
+    /// Remember `addr` for `peer_id` if it goes over QUIC
+    fn add_address(&mut self, peer_id: &PeerId, addr: &Multiaddr) {
+        if !is_quic_addr(addr) {
+            return;
+        }
+        if !self.addrs.contains_key(peer_id) {
+            if self.order.len() >= self.capacity {
+                if let Some(oldest) = self.order.pop_front() {
+                    self.addrs.remove(&oldest);
+                }
+            }
+            self.order.push_back(*peer_id);
+        }
+        let addrs = self.addrs.entry(*peer_id).or_default();
+        if addrs.len() < MAX_ADDRS_PER_PEER {
+            addrs.insert(addr.clone());
+        }
+    }
+
+    fn quic_addresses(&self, peer_id: &PeerId) -> Vec<Multiaddr> {
+        self.addrs
+            .get(peer_id)
+            .map(|addrs| addrs.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+impl NetworkBehaviour for QuicFirst {
+    type ConnectionHandler = dummy::ConnectionHandler;
+    type ToSwarm = Infallible;
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        _connection_id: ConnectionId,
+        _peer: PeerId,
+        _local_addr: &Multiaddr,
+        _remote_addr: &Multiaddr,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        Ok(dummy::ConnectionHandler)
+    }
+
+    fn handle_pending_outbound_connection(
+        &mut self,
+        _connection_id: ConnectionId,
+        maybe_peer: Option<PeerId>,
+        _addresses: &[Multiaddr],
+        _effective_role: Endpoint,
+    ) -> Result<Vec<Multiaddr>, ConnectionDenied> {
+        Ok(maybe_peer
+            .map(|peer_id| self.quic_addresses(&peer_id))
+            .unwrap_or_default())
+    }
+
+    fn handle_established_outbound_connection(
+        &mut self,
+        _connection_id: ConnectionId,
+        _peer: PeerId,
+        _addr: &Multiaddr,
+        _role_override: Endpoint,
+        _port_use: PortUse,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        Ok(dummy::ConnectionHandler)
+    }
+
+    fn on_swarm_event(&mut self, event: FromSwarm) {
+        if let FromSwarm::NewExternalAddrOfPeer(NewExternalAddrOfPeer { peer_id, addr }) = event {
+            self.add_address(&peer_id, addr);
+        }
+    }
+
+    fn on_connection_handler_event(
+        &mut self,
+        _peer_id: PeerId,
+        _connection_id: ConnectionId,
+        event: THandlerOutEvent<Self>,
+    ) {
+        match event {}
+    }
+
+    fn poll(
+        &mut self,
+        _cx: &mut Context<'_>,
+    ) -> Poll<ToSwarm<Self::ToSwarm, THandlerInEvent<Self>>> {
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quic_first_keeps_quic_addresses() {
+        let mut quic_first = QuicFirst::new(1);
+        let peer = PeerId::random();
+        let quic: Multiaddr = "/ip4/10.0.0.1/udp/3006/quic-v1".parse().unwrap();
+        let tcp: Multiaddr = "/ip4/10.0.0.1/tcp/3006".parse().unwrap();
+        quic_first.add_address(&peer, &tcp);
+        quic_first.add_address(&peer, &quic);
+        let addrs = quic_first
+            .handle_pending_outbound_connection(
+                ConnectionId::new_unchecked(0),
+                Some(peer),
+                &[],
+                Endpoint::Dialer,
+            )
+            .expect("not denied");
+        assert_eq!(addrs, vec![quic.clone()]);
+
+        // Past capacity the oldest peer is forgotten
+        let other = PeerId::random();
+        quic_first.add_address(&other, &quic);
+        assert!(quic_first.quic_addresses(&peer).is_empty());
+        assert_eq!(quic_first.quic_addresses(&other), vec![quic]);
+    }
+}
@@ -0,0 +1,389 @@
+//! Support for running several nockchain nodes inside one process.
+//!
+//! Nodes built with [make_sim_libp2p_driver] talk over libp2p's in-memory transport
+//! (`/memory/<port>` multiaddrs) with the same behaviour and event loop as the real
+//! driver. Each node gets a [SimNodeHandle] which can inject latency, partition the
+//! node from some of its peers, and reach the peer admin interface directly.
+//!
+//! Link conditions are applied by the node's transport, underneath every protocol, so the
+//! driver itself runs unchanged. Only built with the `sim` feature.
+use std::collections::BTreeSet;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use futures::{AsyncRead, AsyncWrite};
+use libp2p::core::muxing::{StreamMuxer, StreamMuxerBox, StreamMuxerEvent, SubstreamBox};
+use libp2p::core::transport::MemoryTransport;
+use libp2p::core::upgrade;
+use libp2p::identity::Keypair;
+use libp2p::multiaddr::Protocol;
+use libp2p::swarm::SwarmEvent;
+use libp2p::{connection_limits, noise, yamux, Multiaddr, PeerId, Swarm, Transport};
+use nockapp::driver::IODriverFn;
+use nockapp::noun::slab::NounSlab;
+use nockapp::NockAppError;
+use nockvm::noun::{D, T};
+use nockvm_macros::tas;
+use serde_bytes::ByteBuf;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, error};
+
+use crate::admin::{PeerAdminCommand, PeerAdminRequest, PeerAdminResponse};
+use crate::behaviour::NockchainBehaviour;
+use crate::config::LibP2PConfig;
+use crate::messages::NockchainRequest;
+use crate::metrics::NockchainP2PMetrics;
+use crate::nc::{run_swarm, SwarmParams};
+
+/// Network conditions applied by a simulated node's transport to its own connections.
+#[derive(Debug, Default)]
+pub struct LinkConditions {
+    latency_ms: AtomicU64,
+    partitioned: std::sync::Mutex<BTreeSet<PeerId>>,
+}
+
+impl LinkConditions {
+    /// Hold back the first read on every substream by `latency`. Each request and response
+    /// travels on a fresh substream, so every one of them arrives late.
+    pub fn set_latency(&self, latency: Duration) {
+        self.latency_ms
+            .store(latency.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    pub fn latency(&self) -> Option<Duration> {
+        match self.latency_ms.load(Ordering::Relaxed) {
+            0 => None,
+            ms => Some(Duration::from_millis(ms)),
+        }
+    }
+
+    /// Cut the node off from `peers`. New connections to and from them are refused, and
+    /// existing ones fail the next time any protocol uses them.
+    pub fn partition_from(&self, peers: impl IntoIterator<Item = PeerId>) {
+        self.partitioned
+            .lock()
+            .expect("partition set poisoned")
+            .extend(peers);
+    }
+
+    /// Undo every partition. The driver's usual redialing reconnects the peers.
+    pub fn heal(&self) {
+        self.partitioned
+            .lock()
+            .expect("partition set poisoned")
+            .clear();
+    }
+
+    pub fn is_partitioned(&self, peer: &PeerId) -> bool {
+        self.partitioned
+            .lock()
+            .expect("partition set poisoned")
+            .contains(peer)
+    }
+}
+
+fn partitioned_error() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::ConnectionReset,
+        "partitioned by the simulator",
+    )
+}
+
+/// A connection muxer which fails once its peer is partitioned off and hands out
+/// [SimSubstream]s.
+struct SimMuxer {
+    inner: StreamMuxerBox,
+    peer: PeerId,
+    conditions: Arc<LinkConditions>,
+}
+
+impl SimMuxer {
+    fn substream(&self, inner: SubstreamBox) -> SimSubstream {
+        SimSubstream {
+            inner,
+            peer: self.peer,
+            conditions: self.conditions.clone(),
+            delay: None,
+            delayed: false,
+        }
+    }
+}
+
+impl StreamMuxer for SimMuxer {
+    type Substream = SimSubstream;
+    type Error = io::Error;
+
+    fn poll_inbound(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Self::Substream, Self::Error>> {
+        if self.conditions.is_partitioned(&self.peer) {
+            return Poll::Ready(Err(partitioned_error()));
+        }
+        let inner = ready!(Pin::new(&mut self.inner).poll_inbound(cx))?;
+        Poll::Ready(Ok(self.substream(inner)))
+    }
+
+    fn poll_outbound(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Self::Substream, Self::Error>> {
+        if self.conditions.is_partitioned(&self.peer) {
+            return Poll::Ready(Err(partitioned_error()));
+        }
+        let inner = ready!(Pin::new(&mut self.inner).poll_outbound(cx))?;
+        Poll::Ready(Ok(self.substream(inner)))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+
+    fn poll(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<StreamMuxerEvent, Self::Error>> {
+        if self.conditions.is_partitioned(&self.peer) {
+            return Poll::Ready(Err(partitioned_error()));
+        }
+        Pin::new(&mut self.inner).poll(cx)
+    }
+}
+
+/// A substream which fails once its peer is partitioned off, and whose first read waits
+/// out the simulated latency.
+struct SimSubstream {
+    inner: SubstreamBox,
+    peer: PeerId,
+    conditions: Arc<LinkConditions>,
+    delay: Option<Pin<Box<tokio::time::Sleep>>>,
+    delayed: bool,
+}
+
+impl AsyncRead for SimSubstream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if this.conditions.is_partitioned(&this.peer) {
+            return Poll::Ready(Err(partitioned_error()));
+        }
+        if !this.delayed {
+            if let Some(latency) = this.conditions.latency() {
+                let delay = this
+                    .delay
+                    .get_or_insert_with(|| Box::pin(tokio::time::sleep(latency)));
+                ready!(delay.as_mut().poll(cx));
+            }
+            this.delayed = true;
+            this.delay = None;
+        }
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for SimSubstream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.conditions.is_partitioned(&self.peer) {
+            return Poll::Ready(Err(partitioned_error()));
+        }
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+/// Controls for a node driven by [make_sim_libp2p_driver].
+#[derive(Debug, Clone)]
+pub struct SimNodeHandle {
+    pub peer_id: PeerId,
+    pub addr: Multiaddr,
+    pub conditions: Arc<LinkConditions>,
+    admin_tx: mpsc::Sender<PeerAdminCommand>,
+}
+
+impl SimNodeHandle {
+    /// Send a request to the node's peer admin interface and wait for the reply.
+    pub async fn admin(
+        &self,
+        request: PeerAdminRequest,
+    ) -> Result<PeerAdminResponse, NockAppError> {
+        let (reply, reply_rx) = oneshot::channel();
+        self.admin_tx
+            .send(PeerAdminCommand { request, reply })
+            .await
+            .map_err(|_| NockAppError::ChannelClosedError)?;
+        reply_rx.await.map_err(|_| NockAppError::ChannelClosedError)
+    }
+
+    /// Peer IDs this node has banned, as strings.
+    pub async fn banned_peers(&self) -> Result<Vec<String>, NockAppError> {
+        match self.admin(PeerAdminRequest::ListPeers).await? {
+            PeerAdminResponse::Peers { banned_peers, .. } => Ok(banned_peers),
+            _ => Err(NockAppError::OtherError),
+        }
+    }
+}
+
+/// The in-memory multiaddr for simulated node `port`. Port 0 is reserved by the transport.
+pub fn memory_addr(port: u64) -> Multiaddr {
+    Multiaddr::empty().with(Protocol::Memory(port))
+}
+
+/// Build a swarm over the in-memory transport, secured with noise and multiplexed with yamux.
+/// Its connections are subject to `conditions`: those with partitioned peers are refused.
+pub fn build_memory_swarm(
+    keypair: Keypair,
+    libp2p_config: LibP2PConfig,
+    conditions: Arc<LinkConditions>,
+) -> Result<Swarm<NockchainBehaviour>, NockAppError> {
+    let swarm_idle_timeout = libp2p_config.swarm_idle_timeout();
+    let swarm = libp2p::SwarmBuilder::with_existing_identity(keypair)
+        .with_tokio()
+        .with_other_transport(move |key| -> Result<_, noise::Error> {
+            Ok(MemoryTransport::default()
+                .upgrade(upgrade::Version::V1)
+                .authenticate(noise::Config::new(key)?)
+                .multiplex(yamux::Config::default())
+                .and_then(move |(peer, muxer), _| async move {
+                    if conditions.is_partitioned(&peer) {
+                        return Err(partitioned_error());
+                    }
+                    let muxer = SimMuxer {
+                        inner: StreamMuxerBox::new(muxer),
+                        peer,
+                        conditions,
+                    };
+                    Ok((peer, muxer))
+                }))
+        })
+        .map_err(|e| {
+            error!("Could not create memory transport: {}", e);
+            NockAppError::OtherError
+        })?
+        .with_behaviour(NockchainBehaviour::pre_new(
+            libp2p_config,
+            None,
+            connection_limits::ConnectionLimits::default(),
+            None,
+        ))
+        .map_err(|e| {
+            error!("Could not create swarm: {}", e);
+            NockAppError::OtherError
+        })?
+        .with_swarm_config(|cfg| cfg.with_idle_connection_timeout(swarm_idle_timeout))
+        .build();
+    Ok(swarm)
+}
+
+/// Make a libp2p driver for a simulated node listening on [memory_addr]`(port)`.
+///
+/// The node runs the real driver loop with the default [LibP2PConfig]; only the transport
+/// differs. Use the returned [SimNodeHandle] to shape its links and inspect its peers.
+pub fn make_sim_libp2p_driver(
+    keypair: Keypair,
+    port: u64,
+    initial_peers: &[Multiaddr],
+    equix_builder: equix::EquiXBuilder,
+    chain_interval: Duration,
+    init_complete_tx: Option<oneshot::Sender<()>>,
+) -> (IODriverFn, SimNodeHandle) {
+    let addr = memory_addr(port);
+    let conditions = Arc::new(LinkConditions::default());
+    let (admin_tx, admin_rx) = mpsc::channel::<PeerAdminCommand>(16);
+    let sim_handle = SimNodeHandle {
+        peer_id: keypair.public().to_peer_id(),
+        addr: addr.clone(),
+        conditions: conditions.clone(),
+        admin_tx: admin_tx.clone(),
+    };
+    let initial_peers = Vec::from(initial_peers);
+    let driver: IODriverFn = Box::new(move |handle| {
+        let metrics = Arc::new(
+            NockchainP2PMetrics::register(gnort::global_metrics_registry())
+                .expect("Failed to register metrics!"),
+        );
+        Box::pin(async move {
+            let libp2p_config = LibP2PConfig::default();
+            let swarm = build_memory_swarm(keypair, libp2p_config.clone(), conditions)?;
+            let params = SwarmParams {
+                bind: vec![addr],
+                initial_peers: initial_peers.clone(),
+                force_peers: initial_peers,
+                prune_inbound_size: None,
+                equix_builder,
+                chain_interval,
+                init_complete_tx,
+                admin: (admin_tx, admin_rx),
+            };
+            run_swarm(handle, swarm, libp2p_config, params, metrics).await
+        })
+    });
+    (driver, sim_handle)
+}
+
+/// Run a peer which connects to `target` and keeps sending it requests with invalid
+/// proof-of-work. Honest nodes should ban it. Runs until the task is aborted.
+pub async fn run_liar_peer(
+    keypair: Keypair,
+    target: Multiaddr,
+    period: Duration,
+) -> Result<(), NockAppError> {
+    use futures::StreamExt;
+
+    let mut swarm = build_memory_swarm(
+        keypair,
+        LibP2PConfig::default(),
+        Arc::new(LinkConditions::default()),
+    )?;
+    let mut request_slab = NounSlab::new();
+    let request = T(
+        &mut request_slab,
+        &[D(tas!(b"request")), D(tas!(b"block")), D(tas!(b"by-height")), D(1)],
+    );
+    request_slab.set_root(request);
+    let bogus = NockchainRequest::Request {
+        pow: Default::default(),
+        nonce: 0,
+        message: ByteBuf::from(request_slab.jam().as_ref()),
+    };
+    let mut ticker = tokio::time::interval(period);
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {
+                let peers: Vec<PeerId> = swarm.connected_peers().cloned().collect();
+                if peers.is_empty() {
+                    if let Err(e) = swarm.dial(target.clone()) {
+                        debug!("liar: dial failed: {e}");
+                    }
+                }
+                for peer in peers {
+                    let _ = swarm.behaviour_mut().request_response.send_request(&peer, bogus.clone());
+                }
+            },
+            Some(event) = swarm.next() => {
+                if let SwarmEvent::ConnectionEstablished { peer_id, .. } = event {
+                    debug!("liar: connected to {peer_id}");
+                    let _ = swarm.behaviour_mut().request_response.send_request(&peer_id, bogus.clone());
+                }
+            },
+        }
+    }
+}
@@ -1578,6 +1578,10 @@ async fn main() -> Result<()> {
     let nockchain_cli = nockchain::config::NockchainCli {
         nockapp_cli: boot::default_boot_cli(true),
         npc_socket: ".socket/nockchain_npc.sock".to_string(),
+        gateway_bind: None,
+        gateway_token: None,
+        gateway_peek: vec![],
+        gateway_poke: vec![],
         mine: false,
         mining_pubkey: Some(mining_pubkey.clone()),
         mining_key_adv: None,
@@ -1,3 +1,4 @@
+use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::time::Duration;
 
@@ -46,6 +47,30 @@ pub struct NockchainCli {
         default_value = ".socket/nockchain_npc.sock"
     )]
     pub npc_socket: String,
+    #[arg(
+        long,
+        help = "Serve the JSON-RPC gateway on this address, e.g. 127.0.0.1:8545"
+    )]
+    pub gateway_bind: Option<SocketAddr>,
+    #[arg(
+        long,
+        env = "NOCKCHAIN_GATEWAY_TOKEN",
+        hide_env_values = true,
+        help = "Bearer token required by the JSON-RPC gateway"
+    )]
+    pub gateway_token: Option<String>,
+    #[arg(
+        long,
+        help = "Peek path tag the gateway allows, or * for all",
+        action = ArgAction::Append
+    )]
+    pub gateway_peek: Vec<String>,
+    #[arg(
+        long,
+        help = "Poke cause tag the gateway allows, or * for all",
+        action = ArgAction::Append
+    )]
+    pub gateway_poke: Vec<String>,
     #[arg(long, help = "Mine in-kernel", default_value = "false")]
     pub mine: bool,
     #[arg(
@@ -70,7 +95,11 @@ pub struct NockchainCli {
     pub allowed_peers_path: Option<String>,
     #[arg(long, help = "Don't dial default peers")]
     pub no_default_peers: bool,
-    #[arg(long, help = "Bind address", action = ArgAction::Append)]
+    #[arg(
+        long,
+        help = "Bind address, e.g. /ip4/0.0.0.0/udp/3006/quic-v1. With NOCKCHAIN_LIBP2P_ENABLE_TCP=true, QUIC binds also listen on the same TCP port, and /tcp/<port> binds are accepted",
+        action = ArgAction::Append
+    )]
     pub bind: Vec<String>,
     #[arg(
         long,
@@ -131,6 +160,13 @@ impl NockchainCli {
             );
         }
 
+        if self.gateway_bind.is_some() && self.gateway_token.is_none() {
+            return Err(
+                "Cannot specify gateway_bind without gateway_token (or NOCKCHAIN_GATEWAY_TOKEN)"
+                    .to_string(),
+            );
+        }
+
         Ok(())
     }
 }
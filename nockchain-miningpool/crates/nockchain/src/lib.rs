@@ -527,6 +527,19 @@ pub async fn init_with_kernel<J: Jammer + Send + 'static>(
         .add_io_driver(nockapp::npc_listener_driver(listener))
         .await;
 
+    let gateway = cli.as_ref().and_then(|c| {
+        let bind = c.gateway_bind?;
+        let token = c.gateway_token.clone()?;
+        Some(nockapp::drivers::http::GatewayConfig {
+            peeks: c.gateway_peek.clone(),
+            pokes: c.gateway_poke.clone(),
+            ..nockapp::drivers::http::GatewayConfig::new(bind, token)
+        })
+    });
+    if let Some(config) = gateway {
+        nockapp.add_io_driver(nockapp::gateway_driver(config)).await;
+    }
+
     nockapp.add_io_driver(nockapp::exit_driver()).await;
 
     Ok(nockapp)
@@ -5,6 +5,7 @@ use nockapp::kernel::form::SerfThread;
 use nockapp::nockapp::driver::{IODriverFn, NockAppHandle, PokeResult};
 use nockapp::nockapp::wire::Wire;
 use nockapp::nockapp::NockAppError;
+use nockapp::noun::codec::{NounDecode, NounEncode};
 use nockapp::noun::slab::NounSlab;
 use nockapp::noun::{AtomExt, NounExt};
 use nockapp::save::SaveableCheckpoint;
@@ -12,8 +13,7 @@ use nockapp::utils::NOCK_STACK_SIZE_TINY;
 use nockapp::CrownError;
 use nockchain_libp2p_io::tip5_util::tip5_hash_to_base58;
 use nockvm::interpreter::NockCancelToken;
-use nockvm::noun::{Atom, D, NO, T, YES};
-use nockvm_macros::tas;
+use nockvm::noun::{Atom, D, T};
 use rand::Rng;
 use tokio::sync::Mutex;
 use tracing::{debug, info, instrument, warn};
@@ -48,7 +48,7 @@ impl Wire for MiningWire {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, NounEncode)]
 pub struct MiningKeyConfig {
     pub share: u64,
     pub m: u64,
@@ -78,6 +78,35 @@ impl FromStr for MiningKeyConfig {
     }
 }
 
+/// Mining pokes, `[%command ...]`
+#[derive(NounEncode)]
+#[noun(tag = "command")]
+enum MiningCommand {
+    SetMiningKey(String),
+    SetMiningKeyAdvanced(Vec<MiningKeyConfig>),
+    EnableMining(bool),
+}
+
+/// `[%mine version header target pow-len]`
+#[derive(NounDecode)]
+#[noun(tag = "mine")]
+struct MineEffect {
+    version: NounSlab,
+    header: NounSlab,
+    target: NounSlab,
+    pow_len: u64,
+}
+
+/// `[version header nonce target pow-len]`, poked into the mining kernel
+#[derive(NounEncode)]
+struct CandidatePoke<'a> {
+    version: &'a NounSlab,
+    header: &'a NounSlab,
+    nonce: &'a NounSlab,
+    target: &'a NounSlab,
+    pow_len: u64,
+}
+
 struct MiningData {
     pub block_header: NounSlab,
     pub version: NounSlab,
@@ -141,6 +170,7 @@ pub fn create_mining_driver(
             let mining_data: Mutex<Option<MiningData>> = Mutex::new(None);
             let mut cancel_tokens: Vec<NockCancelToken> = Vec::<NockCancelToken>::new();
 
+            let mut mine_effects = handle.subscribe_effects(&["mine"]);
             loop {
                 tokio::select! {
                         mining_result = mining_attempts.join_next(), if !mining_attempts.is_empty() => {
@@ -187,74 +217,56 @@ pub fn create_mining_driver(
                             }
                         }
 
-                    effect_res = handle.next_effect() => {
-                        let Ok(effect) = effect_res else {
-                            warn!("Error receiving effect in mining driver: {effect_res:?}");
-                            continue;
+                    effect = mine_effects.recv() => {
+                        let Some(effect) = effect else {
+                            warn!("Effect subscription closed, stopping mining driver");
+                            return Ok(());
                         };
-                        let Ok(effect_cell) = (unsafe { effect.root().as_cell() }) else {
-                            drop(effect);
-                            continue;
+                        let mine = match MineEffect::from_slab(&effect) {
+                            Ok(mine) => mine,
+                            Err(e) => {
+                                warn!("Malformed %mine effect: {e}");
+                                continue;
+                            }
                         };
-
-                        if effect_cell.head().eq_bytes("mine") {
-                            let (version_slab, header_slab, target_slab, pow_len) = {
-                                let [version, commit, target, pow_len_noun] = effect_cell.tail().uncell().expect(
-                                    "Expected three elements in %mine effect",
-                                );
-                                let mut version_slab = NounSlab::new();
-                                version_slab.copy_into(version);
-                                let mut header_slab = NounSlab::new();
-                                header_slab.copy_into(commit);
-                                let mut target_slab = NounSlab::new();
-                                target_slab.copy_into(target);
-                                let pow_len =
-                                    pow_len_noun
-                                        .as_atom()
-                                        .expect("Expected pow-len to be an atom")
-                                        .as_u64()
-                                        .expect("Expected pow-len to be a u64");
-                                (version_slab, header_slab, target_slab, pow_len)
-                            };
-                            debug!("received new candidate block header: {:?}",
-                                tip5_hash_to_base58(*unsafe { header_slab.root() })
-                                .expect("Failed to convert header to Base58")
-                            );
-                            *(mining_data.lock().await) = Some(MiningData {
-                                block_header: header_slab,
-                                version: version_slab,
-                                target: target_slab,
-                                pow_len: pow_len
-                            });
-
-                            // Mining hasn't started yet, so start it
-                            if mining_attempts.is_empty() {
-                                info!("starting mining threads");
-                                for i in 0..num_threads {
-                                    let kernel = Vec::from(KERNEL);
-                                    let serf = SerfThread::<SaveableCheckpoint>::new(
-                                        kernel,
-                                        None,
-                                        hot_state.clone(),
-                                        NOCK_STACK_SIZE_TINY,
-                                        test_jets.clone(),
-                                        false,
-                                    )
-                                    .await
-                                    .expect("Could not load mining kernel");
-
-                                    cancel_tokens.push(serf.cancel_token.clone());
-
-                                    start_mining_attempt(serf, mining_data.lock().await, &mut mining_attempts, None, i).await;
-                                }
-                                info!("mining threads started with {} threads", num_threads);
-                            } else {
-                                // Mining is already running so cancel all the running attemps
-                                // which are mining on the old block.
-                                debug!("restarting mining attempts with new block header.");
-                                for token in &cancel_tokens {
-                                    token.cancel();
-                                }
+                        debug!("received new candidate block header: {:?}",
+                            tip5_hash_to_base58(*unsafe { mine.header.root() })
+                            .expect("Failed to convert header to Base58")
+                        );
+                        *(mining_data.lock().await) = Some(MiningData {
+                            block_header: mine.header,
+                            version: mine.version,
+                            target: mine.target,
+                            pow_len: mine.pow_len
+                        });
+
+                        // Mining hasn't started yet, so start it
+                        if mining_attempts.is_empty() {
+                            info!("starting mining threads");
+                            for i in 0..num_threads {
+                                let kernel = Vec::from(KERNEL);
+                                let serf = SerfThread::<SaveableCheckpoint>::new(
+                                    kernel,
+                                    None,
+                                    hot_state.clone(),
+                                    NOCK_STACK_SIZE_TINY,
+                                    test_jets.clone(),
+                                    false,
+                                )
+                                .await
+                                .expect("Could not load mining kernel");
+
+                                cancel_tokens.push(serf.cancel_token.clone());
+
+                                start_mining_attempt(serf, mining_data.lock().await, &mut mining_attempts, None, i).await;
+                            }
+                            info!("mining threads started with {} threads", num_threads);
+                        } else {
+                            // Mining is already running so cancel all the running attemps
+                            // which are mining on the old block.
+                            debug!("restarting mining attempts with new block header.");
+                            for token in &cancel_tokens {
+                                token.cancel();
                             }
                         }
                     }
@@ -265,17 +277,14 @@ pub fn create_mining_driver(
 }
 
 fn create_poke(mining_data: &MiningData, nonce: &NounSlab) -> NounSlab {
-    let mut slab = NounSlab::new();
-    let header = slab.copy_into(unsafe { *(mining_data.block_header.root()) });
-    let version = slab.copy_into(unsafe { *(mining_data.version.root()) });
-    let target = slab.copy_into(unsafe { *(mining_data.target.root()) });
-    let nonce = slab.copy_into(unsafe { *(nonce.root()) });
-    let poke_noun = T(
-        &mut slab,
-        &[version, header, nonce, target, D(mining_data.pow_len)],
-    );
-    slab.set_root(poke_noun);
-    slab
+    CandidatePoke {
+        version: &mining_data.version,
+        header: &mining_data.block_header,
+        nonce,
+        target: &mining_data.target,
+        pow_len: mining_data.pow_len,
+    }
+    .to_slab()
 }
 
 #[instrument(skip(handle, pubkey))]
@@ -283,19 +292,11 @@ async fn set_mining_key(
     handle: &NockAppHandle,
     pubkey: String,
 ) -> Result<PokeResult, NockAppError> {
-    let mut set_mining_key_slab = NounSlab::new();
-    let set_mining_key = Atom::from_value(&mut set_mining_key_slab, "set-mining-key")
-        .expect("Failed to create set-mining-key atom");
-    let pubkey_cord =
-        Atom::from_value(&mut set_mining_key_slab, pubkey).expect("Failed to create pubkey atom");
-    let set_mining_key_poke = T(
-        &mut set_mining_key_slab,
-        &[D(tas!(b"command")), set_mining_key.as_noun(), pubkey_cord.as_noun()],
-    );
-    set_mining_key_slab.set_root(set_mining_key_poke);
-
     handle
-        .poke(MiningWire::SetPubKey.to_wire(), set_mining_key_slab)
+        .poke(
+            MiningWire::SetPubKey.to_wire(),
+            MiningCommand::SetMiningKey(pubkey).to_slab(),
+        )
         .await
 }
 
@@ -303,54 +304,36 @@ async fn set_mining_key_advanced(
     handle: &NockAppHandle,
     configs: Vec<MiningKeyConfig>,
 ) -> Result<PokeResult, NockAppError> {
-    let mut set_mining_key_slab = NounSlab::new();
-    let set_mining_key_adv = Atom::from_value(&mut set_mining_key_slab, "set-mining-key-advanced")
-        .expect("Failed to create set-mining-key-advanced atom");
-
-    // Create the list of configs
-    let mut configs_list = D(0);
-    for config in configs {
-        // Create the list of keys
-        let mut keys_noun = D(0);
-        for key in config.keys {
-            let key_atom =
-                Atom::from_value(&mut set_mining_key_slab, key).expect("Failed to create key atom");
-            keys_noun = T(&mut set_mining_key_slab, &[key_atom.as_noun(), keys_noun]);
-        }
-
-        // Create the config tuple [share m keys]
-        let config_tuple = T(
-            &mut set_mining_key_slab,
-            &[D(config.share), D(config.m), keys_noun],
-        );
-
-        configs_list = T(&mut set_mining_key_slab, &[config_tuple, configs_list]);
-    }
-
-    let set_mining_key_poke = T(
-        &mut set_mining_key_slab,
-        &[D(tas!(b"command")), set_mining_key_adv.as_noun(), configs_list],
-    );
-    set_mining_key_slab.set_root(set_mining_key_poke);
-
     handle
-        .poke(MiningWire::SetPubKey.to_wire(), set_mining_key_slab)
+        .poke(
+            MiningWire::SetPubKey.to_wire(),
+            set_mining_key_advanced_poke(configs),
+        )
         .await
 }
 
+/// `[%command %set-mining-key-advanced configs]`. The configs and the keys in each were
+/// always consed up front to back, so the kernel gets both lists in reverse order.
+fn set_mining_key_advanced_poke(configs: Vec<MiningKeyConfig>) -> NounSlab {
+    let configs = configs
+        .into_iter()
+        .rev()
+        .map(|mut config| {
+            config.keys.reverse();
+            config
+        })
+        .collect();
+    MiningCommand::SetMiningKeyAdvanced(configs).to_slab()
+}
+
 //TODO add %set-mining-key-multisig poke
 #[instrument(skip(handle))]
 async fn enable_mining(handle: &NockAppHandle, enable: bool) -> Result<PokeResult, NockAppError> {
-    let mut enable_mining_slab = NounSlab::new();
-    let enable_mining = Atom::from_value(&mut enable_mining_slab, "enable-mining")
-        .expect("Failed to create enable-mining atom");
-    let enable_mining_poke = T(
-        &mut enable_mining_slab,
-        &[D(tas!(b"command")), enable_mining.as_noun(), if enable { YES } else { NO }],
-    );
-    enable_mining_slab.set_root(enable_mining_poke);
     handle
-        .poke(MiningWire::Enable.to_wire(), enable_mining_slab)
+        .poke(
+            MiningWire::Enable.to_wire(),
+            MiningCommand::EnableMining(enable).to_slab(),
+        )
         .await
 }
 
@@ -396,3 +379,41 @@ async fn start_mining_attempt(
         (serf, id, result)
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use nockapp::noun::slab::slab_equality;
+    use nockvm_macros::tas;
+
+    use super::*;
+
+    #[test]
+    fn test_set_mining_key_advanced_poke() {
+        let configs = vec![
+            "1,1:a,b".parse::<MiningKeyConfig>().expect("config"),
+            "2,1:c".parse::<MiningKeyConfig>().expect("config"),
+        ];
+
+        let mut expected = NounSlab::new();
+        let cord =
+            |slab: &mut NounSlab, s: &str| Atom::from_value(slab, s).expect("cord").as_noun();
+        let (a, b, c) = (
+            cord(&mut expected, "a"),
+            cord(&mut expected, "b"),
+            cord(&mut expected, "c"),
+        );
+        let tag = cord(&mut expected, "set-mining-key-advanced");
+        let first = T(&mut expected, &[D(1), D(1), b, a, D(0)]);
+        let second = T(&mut expected, &[D(2), D(1), c, D(0)]);
+        let poke = T(
+            &mut expected,
+            &[D(tas!(b"command")), tag, second, first, D(0)],
+        );
+        expected.set_root(poke);
+
+        assert!(slab_equality(
+            &set_mining_key_advanced_poke(configs),
+            &expected
+        ));
+    }
+}
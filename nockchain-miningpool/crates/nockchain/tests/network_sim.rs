@@ -0,0 +1,245 @@
+//! In-process network simulation: several fakenet nodes gossiping over libp2p's memory
+//! transport, with injected latency, a partition and a peer sending bad proof-of-work.
+
+use std::time::Duration;
+
+use kernels::dumb::KERNEL;
+use libp2p::identity::Keypair;
+use libp2p::Multiaddr;
+use nockapp::driver::{make_driver, NockAppHandle};
+use nockapp::kernel::boot;
+use nockapp::noun::slab::NounSlab;
+use nockapp::NockApp;
+use nockchain::driver_init::DriverInitSignals;
+use nockchain::mining::{create_mining_driver, MiningKeyConfig};
+use nockchain::setup;
+use nockchain_libp2p_io::sim::{make_sim_libp2p_driver, run_liar_peer, SimNodeHandle};
+use nockvm::noun::{D, T};
+use nockvm_macros::tas;
+use tempfile::TempDir;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use zkvm_jetpack::hot::produce_prover_hot_state;
+
+const MINING_PUBKEY: &str = "3geooCU86ng5CEdXKqmmb37nScanFH7K782VRJHwnNegFmkEf7mdM2pftFE8t8tT1kBiJVHNzmNCfzAdD8ABJUo52GGfTLDbK9wj7Dh8Y6KEuqgL4MngbfEVW456vTanW2ij";
+const CHAIN_INTERVAL: Duration = Duration::from_secs(1);
+const CONVERGENCE_TIMEOUT: Duration = Duration::from_secs(600);
+const BAN_TIMEOUT: Duration = Duration::from_secs(60);
+
+struct SimNode {
+    net: SimNodeHandle,
+    kernel: NockAppHandle,
+    _run: JoinHandle<()>,
+    _data_dir: TempDir,
+}
+
+impl SimNode {
+    /// Jam of the node's `[%heavy ~]` peek, or `None` if it has no heaviest block yet.
+    async fn heaviest(&self) -> Option<Vec<u8>> {
+        let mut path = NounSlab::new();
+        let heavy = T(&mut path, &[D(tas!(b"heavy")), D(0)]);
+        path.set_root(heavy);
+        let result = self.kernel.peek(path).await.ok()??;
+        // (unit (unit (unit block-id))): anything shorter than `[~ ~ ~ id]` means no block
+        let mut noun = unsafe { *result.root() };
+        for _ in 0..3 {
+            noun = noun.as_cell().ok()?.tail();
+        }
+        Some(result.jam().to_vec())
+    }
+}
+
+async fn boot_node(port: u64, peers: &[Multiaddr], mine: bool) -> SimNode {
+    let data_dir = tempfile::tempdir().expect("tempdir");
+    let hot_state = produce_prover_hot_state();
+    let mut nockapp: NockApp = boot::setup(
+        KERNEL,
+        Some(boot::default_boot_cli(true)),
+        hot_state.as_slice(),
+        "nockchain-sim",
+        Some(data_dir.path().to_path_buf()),
+    )
+    .await
+    .expect("boot kernel");
+
+    setup::poke(
+        &mut nockapp,
+        setup::SetupCommand::PokeFakenetConstants(setup::fakenet_blockchain_constants(2, 1)),
+    )
+    .await
+    .expect("set constants");
+    setup::poke(
+        &mut nockapp,
+        setup::SetupCommand::PokeSetGenesisSeal(setup::FAKENET_GENESIS_MESSAGE.to_string()),
+    )
+    .await
+    .expect("set genesis seal");
+    setup::poke(&mut nockapp, setup::SetupCommand::PokeSetBtcData)
+        .await
+        .expect("set btc data");
+
+    // Same ordering as `init_with_kernel`: born once mining and libp2p are up, then genesis.
+    let mut born_signals = DriverInitSignals::new();
+    let mining_init_tx = born_signals.register_driver("mining");
+    let libp2p_init_tx = born_signals.register_driver("libp2p");
+    let _ = born_signals.create_task();
+
+    let mut genesis_signals = DriverInitSignals::new();
+    let born_init_tx = genesis_signals.register_driver("born");
+    let _ = genesis_signals.create_task();
+    let genesis = setup::heard_fake_genesis_block(None).expect("fake genesis");
+    nockapp
+        .add_io_driver(genesis_signals.create_driver(genesis, None))
+        .await;
+
+    let mining_config = vec![MiningKeyConfig {
+        share: 1,
+        m: 1,
+        keys: vec![MINING_PUBKEY.to_string()],
+    }];
+    nockapp
+        .add_io_driver(create_mining_driver(
+            Some(mining_config),
+            mine,
+            1,
+            Some(mining_init_tx),
+        ))
+        .await;
+
+    let (libp2p_driver, net) = make_sim_libp2p_driver(
+        Keypair::generate_ed25519(),
+        port,
+        peers,
+        equix::EquiXBuilder::new(),
+        CHAIN_INTERVAL,
+        Some(libp2p_init_tx),
+    );
+    nockapp.add_io_driver(libp2p_driver).await;
+
+    let mut born_slab = NounSlab::new();
+    let born = T(
+        &mut born_slab,
+        &[D(tas!(b"command")), D(tas!(b"born")), D(0)],
+    );
+    born_slab.set_root(born);
+    nockapp
+        .add_io_driver(born_signals.create_driver(born_slab, Some(born_init_tx)))
+        .await;
+
+    // Hand a kernel handle out to the test so it can peek while the node runs.
+    let (handle_tx, handle_rx) = oneshot::channel();
+    nockapp
+        .add_io_driver(make_driver(move |handle| {
+            Box::pin(async move {
+                let _ = handle_tx.send(handle);
+                std::future::pending::<()>().await;
+                Ok(())
+            })
+        }))
+        .await;
+
+    let run = tokio::spawn(async move {
+        let _ = nockapp.run().await;
+    });
+    let kernel = handle_rx.await.expect("kernel handle");
+    SimNode {
+        net,
+        kernel,
+        _run: run,
+        _data_dir: data_dir,
+    }
+}
+
+/// Wait until every node reports the same heaviest block, other than `not`.
+async fn converge(nodes: &[SimNode], not: Option<&Vec<u8>>) -> Vec<u8> {
+    tokio::time::timeout(CONVERGENCE_TIMEOUT, async {
+        loop {
+            tokio::time::sleep(Duration::from_secs(2)).await;
+            let mut heads = Vec::with_capacity(nodes.len());
+            for node in nodes {
+                heads.push(node.heaviest().await);
+            }
+            let Some(Some(first)) = heads.first().cloned() else {
+                continue;
+            };
+            if Some(&first) != not && heads.iter().all(|h| h.as_ref() == Some(&first)) {
+                return first;
+            }
+        }
+    })
+    .await
+    .expect("nodes did not converge on a heaviest block")
+}
+
+/// One honest node, which never mines, and a peer sending it bad proof-of-work, which
+/// should be banned.
+#[tokio::test(flavor = "multi_thread")]
+async fn test_liar_is_banned() {
+    let node = boot_node(1, &[], false).await;
+    let liar_id = Keypair::generate_ed25519();
+    let liar_peer = liar_id.public().to_peer_id().to_base58();
+    let liar = tokio::spawn(run_liar_peer(
+        liar_id,
+        node.net.addr.clone(),
+        Duration::from_millis(200),
+    ));
+
+    tokio::time::timeout(BAN_TIMEOUT, async {
+        loop {
+            let banned = node.net.banned_peers().await.expect("list peers");
+            if banned.contains(&liar_peer) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+    })
+    .await
+    .expect("peer sending bad powork was not banned");
+    liar.abort();
+}
+
+/// Three nodes, one mining and one behind a slow link, agree on a heaviest block, keep
+/// agreeing after one is partitioned off and healed, and ban a peer sending bad
+/// proof-of-work.
+#[tokio::test(flavor = "multi_thread")]
+async fn test_nodes_converge_through_partition_and_liar() {
+    let miner = boot_node(1, &[], true).await;
+    let a = boot_node(2, &[miner.net.addr.clone()], false).await;
+    let b = boot_node(3, &[miner.net.addr.clone(), a.net.addr.clone()], false).await;
+    b.net.conditions.set_latency(Duration::from_millis(200));
+
+    let liar_id = Keypair::generate_ed25519();
+    let liar_peer = liar_id.public().to_peer_id();
+    let liar = tokio::spawn(run_liar_peer(
+        liar_id,
+        a.net.addr.clone(),
+        Duration::from_secs(1),
+    ));
+
+    let nodes = [miner, a, b];
+    let before = converge(&nodes, None).await;
+
+    // Cut `b` off from everyone, let the miner move on, then heal and catch up.
+    let [miner, a, b] = &nodes;
+    b.net
+        .conditions
+        .partition_from([miner.net.peer_id, a.net.peer_id]);
+    miner.net.conditions.partition_from([b.net.peer_id]);
+    a.net.conditions.partition_from([b.net.peer_id]);
+    tokio::time::sleep(Duration::from_secs(30)).await;
+    for node in &nodes {
+        node.net.conditions.heal();
+    }
+    let after = converge(&nodes, Some(&before)).await;
+    assert_ne!(
+        before, after,
+        "chain should have advanced across the partition"
+    );
+
+    let banned = a.net.banned_peers().await.expect("list peers");
+    assert!(
+        banned.contains(&liar_peer.to_base58()),
+        "peer sending bad powork should be banned, banned: {banned:?}"
+    );
+    liar.abort();
+}
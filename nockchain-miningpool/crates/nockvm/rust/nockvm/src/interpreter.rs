@@ -1,4 +1,5 @@
 use std::ops::{DerefMut, Neg};
+use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
 use std::pin::Pin;
 use std::result;
 use std::sync::atomic::{AtomicIsize, Ordering};
@@ -16,9 +17,10 @@ use crate::jets::hot::Hot;
 use crate::jets::list::util::weld;
 use crate::jets::warm::Warm;
 use crate::jets::{cold, JetErr};
-use crate::mem::{NockStack, Preserve};
+use crate::mem::{AllocationError, NockStack, Preserve};
 use crate::noun::{Atom, Cell, IndirectAtom, Noun, Slots, D, T};
-use crate::trace::{write_nock_trace, TraceInfo, TraceStack};
+use crate::profile::JetProfile;
+use crate::trace::{folded_stack, write_nock_trace, StackSampler, TraceInfo, TraceStack};
 use crate::unifying_equality::unifying_equality;
 use crate::{assert_acyclic, assert_no_forwarding_pointers, assert_no_junior_pointers, flog, noun};
 
@@ -297,6 +299,8 @@ pub struct Context {
     pub cache: Hamt<Noun>,
     pub scry_stack: Noun,
     pub trace_info: Option<TraceInfo>,
+    pub jet_profile: Option<JetProfile>,
+    pub sampler: Option<StackSampler>,
     pub running_status: Arc<AtomicIsize>,
     pub test_jets: Hamt<()>,
 }
@@ -442,8 +446,40 @@ fn debug_assertions(stack: &mut NockStack, noun: Noun) {
     assert_no_junior_pointers!(stack, noun);
 }
 
-/** Interpret nock */
-pub fn interpret(context: &mut Context, mut subject: Noun, formula: Noun) -> Result {
+/** Interpret nock
+ *
+ * Running out of space on the NockStack fails the computation with %meme, leaving the stack as it
+ * was before the call.
+ */
+pub fn interpret(context: &mut Context, subject: Noun, formula: Noun) -> Result {
+    let snapshot = context.save();
+    let virtual_frame: *const u64 = context.stack.get_frame_pointer();
+    match catch_unwind(AssertUnwindSafe(|| {
+        interpret_unguarded(context, subject, formula)
+    })) {
+        Ok(res) => res,
+        Err(payload) => match payload.downcast::<AllocationError>() {
+            Ok(oom) if matches!(*oom, AllocationError::OutOfMemory(_)) => {
+                leave_running(context);
+                // Unwinding builds the trace, so let it use the headroom past the limit
+                let limit = context.stack.limit();
+                context.stack.set_limit(context.stack.size());
+                let err = exit(
+                    context,
+                    &snapshot,
+                    virtual_frame,
+                    Error::NonDeterministic(Mote::Meme, D(0)),
+                );
+                context.stack.set_limit(limit);
+                Err(err)
+            }
+            Ok(oom) => resume_unwind(oom),
+            Err(payload) => resume_unwind(payload),
+        },
+    }
+}
+
+fn interpret_unguarded(context: &mut Context, mut subject: Noun, formula: Noun) -> Result {
     let orig_subject = subject; // for debugging
     let snapshot = context.save();
     let virtual_frame: *const u64 = context.stack.get_frame_pointer();
@@ -475,12 +511,14 @@ pub fn interpret(context: &mut Context, mut subject: Noun, formula: Noun) -> Res
 
     // Setup stack for Nock computation
     unsafe {
-        context.stack.frame_push(2);
+        context.stack.frame_push(3);
 
         // Bottom of mean stack
         *(context.stack.local_noun_pointer(0)) = D(0);
         // Bottom of trace stack
         *(context.stack.local_noun_pointer(1) as *mut *const TraceStack) = std::ptr::null();
+        // Bottom of this frame's part of the trace stack
+        *(context.stack.local_noun_pointer(2) as *mut *const TraceStack) = std::ptr::null();
 
         *(context.stack.push()) = NockWork::Done;
     };
@@ -500,6 +538,12 @@ pub fn interpret(context: &mut Context, mut subject: Noun, formula: Noun) -> Res
         push_formula(&mut context.stack, formula, true)?;
 
         loop {
+            if let Some(sampler) = &context.sampler {
+                if sampler.is_due() {
+                    take_sample(context);
+                }
+            }
+
             let work: NockWork = *context.stack.top();
             match work {
                 NockWork::Done => {
@@ -745,13 +789,25 @@ pub fn interpret(context: &mut Context, mut subject: Noun, formula: Noun) -> Res
                         }
                         Todo9::ComputeResult => {
                             if let Ok(mut formula) = res.slot_atom(kale.axis) {
+                                let mut jetted = false;
                                 if !cfg!(feature = "sham_hints") {
-                                    if let Some((jet, _path, test)) = context
+                                    if let Some((jet, path, test)) = context
                                         .warm
                                         .find_jet(&mut context.stack, &mut res, &mut formula)
                                         .next()
                                     {
-                                        match jet(context, res) {
+                                        jetted = true;
+                                        let jet_res = if context.jet_profile.is_some() {
+                                            let start = Instant::now();
+                                            let jet_res = jet(context, res);
+                                            if let Some(profile) = &mut context.jet_profile {
+                                                profile.record_jet(jet, path, &jet_res, start);
+                                            }
+                                            jet_res
+                                        } else {
+                                            jet(context, res)
+                                        };
+                                        match jet_res {
                                             Ok(mut jet_res) => {
                                                 if test {
                                                     let mut test_res =
@@ -775,6 +831,15 @@ pub fn interpret(context: &mut Context, mut subject: Noun, formula: Noun) -> Res
                                     }
                                 };
 
+                                // A core the cold state knows, but with no jet: an unjetted hot arm
+                                if !jetted {
+                                    if let Some(profile) = &mut context.jet_profile {
+                                        profile.record_unjetted(
+                                            &mut context.stack, &mut context.cold, &mut res,
+                                        );
+                                    }
+                                }
+
                                 let stack = &mut context.stack;
                                 if kale.tail {
                                     stack.pop::<NockWork>();
@@ -786,6 +851,10 @@ pub fn interpret(context: &mut Context, mut subject: Noun, formula: Noun) -> Res
                                         if let Some(path) = context.cold.matches(stack, &mut res) {
                                             append_trace(stack, path);
                                         };
+                                    } else if context.sampler.is_some() {
+                                        if let Some(path) = context.cold.matches(stack, &mut res) {
+                                            replace_trace(stack, path);
+                                        };
                                     };
 
                                     subject = res;
@@ -807,7 +876,7 @@ pub fn interpret(context: &mut Context, mut subject: Noun, formula: Noun) -> Res
                                     // We could trace on 2 as well, but 2 only comes from Hoon via
                                     // '.*', so we can assume it's never directly used to invoke
                                     // jetted code.
-                                    if context.trace_info.is_some() {
+                                    if context.trace_info.is_some() || context.sampler.is_some() {
                                         if let Some(path) = context.cold.matches(stack, &mut res) {
                                             append_trace(stack, path);
                                         };
@@ -1021,6 +1090,16 @@ pub fn interpret(context: &mut Context, mut subject: Noun, formula: Noun) -> Res
         }
     };
 
+    leave_running(context);
+
+    match nock {
+        Ok(res) => Ok(res),
+        Err(err) => Err(exit(context, &snapshot, virtual_frame, err)),
+    }
+}
+
+/** Undo the running count taken on entry to [interpret] */
+fn leave_running(context: &mut Context) {
     loop {
         let running_status = context.running_status.load(Ordering::SeqCst);
         if running_status < NockCancelToken::RUNNING_IDLE {
@@ -1053,11 +1132,6 @@ pub fn interpret(context: &mut Context, mut subject: Noun, formula: Noun) -> Res
             }
         }
     }
-
-    match nock {
-        Ok(res) => Ok(res),
-        Err(err) => Err(exit(context, &snapshot, virtual_frame, err)),
-    }
 }
 
 fn push_formula(stack: &mut NockStack, formula: Noun, tail: bool) -> Result {
@@ -1316,14 +1390,19 @@ fn exit(
     }
 }
 
-/** Push frame onto NockStack while preserving the mean stack.
+/** Push frame onto NockStack while preserving the mean stack and the trace stack.
+ *
+ * The new frame's trace entries go on top of its parent's, so that the whole trace stack can be
+ * sampled from the top frame. Local 2 marks where the parent's entries start.
  */
 fn mean_frame_push(stack: &mut NockStack, slots: usize) {
     unsafe {
         let trace = *(stack.local_noun_pointer(0));
-        stack.frame_push(slots + 2);
+        let trace_stack = *(stack.local_noun_pointer(1) as *const *const TraceStack);
+        stack.frame_push(slots + 3);
         *(stack.local_noun_pointer(0)) = trace;
-        *(stack.local_noun_pointer(1) as *mut *const TraceStack) = std::ptr::null();
+        *(stack.local_noun_pointer(1) as *mut *const TraceStack) = trace_stack;
+        *(stack.local_noun_pointer(2) as *mut *const TraceStack) = trace_stack;
     }
 }
 
@@ -1432,13 +1511,39 @@ fn append_trace(stack: &mut NockStack, path: Noun) {
     }
 }
 
+/// Replace the newest trace entry of this frame, or push one if it has none. For tail calls when
+/// only sampling, which doesn't need every entry's timing.
+fn replace_trace(stack: &mut NockStack, path: Noun) {
+    unsafe {
+        let trace_stack = *(stack.local_noun_pointer(1) as *const *mut TraceStack);
+        let frame_base = *(stack.local_noun_pointer(2) as *const *const TraceStack);
+        if trace_stack.is_null() || trace_stack as *const TraceStack == frame_base {
+            append_trace(stack, path);
+        } else {
+            (*trace_stack).path = path;
+            (*trace_stack).start = Instant::now();
+        }
+    }
+}
+
+/// Record a sample of the trace stack and mean stack for the stack sampler
+unsafe fn take_sample(context: &mut Context) {
+    let trace_stack = *(context.stack.local_noun_pointer(1) as *const *const TraceStack);
+    let mean = *(context.stack.local_noun_pointer(0));
+    let folded = folded_stack(trace_stack, mean);
+    if let Some(sampler) = &mut context.sampler {
+        sampler.record(folded);
+    }
+}
+
 /// Write fast-hinted traces to trace file
 unsafe fn write_trace(context: &mut Context) {
     if let Some(ref mut info) = &mut context.trace_info {
         let trace_stack = *(context.stack.local_noun_pointer(1) as *mut *const TraceStack);
+        let frame_base = *(context.stack.local_noun_pointer(2) as *mut *const TraceStack);
         // Abort writing to trace file if we encountered an error. This should
         // result in a well-formed partial trace file.
-        if let Err(_e) = write_nock_trace(&mut context.stack, info, trace_stack) {
+        if let Err(_e) = write_nock_trace(&mut context.stack, info, trace_stack, frame_base) {
             flog!(context, "\rserf: error writing nock trace to file: {:?}", _e);
             context.trace_info = None;
         }
@@ -1485,7 +1590,17 @@ mod hint {
                     let jet_name = jet_formula.tail();
 
                     if let Some(jet) = jets::get_jet(context, jet_name) {
-                        match jet(context, subject) {
+                        let jet_res = if context.jet_profile.is_some() {
+                            let start = Instant::now();
+                            let jet_res = jet(context, subject);
+                            if let Some(profile) = &mut context.jet_profile {
+                                profile.record_jet(jet, jet_name, &jet_res, start);
+                            }
+                            jet_res
+                        } else {
+                            jet(context, subject)
+                        };
+                        match jet_res {
                             Ok(mut jet_res) => {
                                 //  XX: simplify this by moving jet test mode into the 11 code in interpret, or into its own function?
                                 // if in test mode, check that the jet returns the same result as the raw nock
@@ -1,4 +1,6 @@
 pub mod cold;
+#[cfg(any(test, feature = "jet-fuzz"))]
+pub mod fuzz;
 pub mod hot;
 pub mod warm;
 
@@ -355,6 +357,8 @@ pub mod util {
                 cache,
                 scry_stack: D(0),
                 trace_info: None,
+                jet_profile: None,
+                sampler: None,
                 running_status: cancel,
                 test_jets,
             }
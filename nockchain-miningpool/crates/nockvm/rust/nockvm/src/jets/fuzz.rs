@@ -0,0 +1,897 @@
+//! Differential jet fuzzing.
+//!
+//! [JetFuzzer] boots a compiled Hoon standard library (or any kernel jam built on it) with no
+//! jets, so that its `%fast` hints fill the cold state. It then checks each jet of a hot state
+//! against the Hoon it stands in for: it rebuilds the jet's core from the cold state with samples
+//! mutated from the core's default sample, or random ones, runs both the jet and the raw Nock of
+//! the jetted arm, and reports every jet which disagrees with the Nock or crashes, along with the
+//! failing samples, minimised.
+//!
+//! Jets can't be cancelled, so samples larger than [FuzzConfig::max_sample_bytes] are skipped
+//! rather than risk a jet which runs for ever on them.
+//!
+//! Only built for tests and with the `jet-fuzz` feature. The `cargo test` harnesses boot the jam
+//! named by `NOCK_FUZZ_JAM`, and are skipped without it. `NOCK_FUZZ_CASES` and `NOCK_FUZZ_SEED`
+//! set the number of cases per jet and the random seed.
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicIsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::time::Duration;
+use std::{fmt, thread};
+
+use either::Either::{Left, Right};
+use ibig::UBig;
+use nockvm_macros::tas;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::hamt::Hamt;
+use crate::interpreter::{interpret, Context, Error, NockCancelToken, Slogger};
+use crate::jets::cold::Cold;
+use crate::jets::hot::{Hot, HotEntry};
+use crate::jets::warm::Warm;
+use crate::jets::{Jet, JetErr};
+use crate::mem::NockStack;
+use crate::mug::met3_usize;
+use crate::noun::{Atom, IndirectAtom, Noun, Slots, D, DIRECT_MAX, T};
+use crate::serialization::cue;
+use crate::unifying_equality::unifying_equality;
+
+/// Shrinking stops after this many candidate samples, even if it could go on
+const MAX_SHRINK_STEPS: usize = 4096;
+
+/// Longest printed noun in a [Mismatch]
+const MAX_NOUN_CHARS: usize = 256;
+
+#[derive(Debug, thiserror::Error)]
+pub enum FuzzError {
+    #[error("jet fuzzer: jam does not cue")]
+    Cue,
+    #[error("jet fuzzer: jam failed to boot: {0:?}")]
+    Boot(Error),
+}
+
+#[derive(Debug, Clone)]
+pub struct FuzzConfig {
+    /// Random cases per jet
+    pub cases: usize,
+    pub seed: u64,
+    /// Time the jet, and the Nock, get per case before the case is skipped
+    pub timeout: Duration,
+    /// Deepest cell in a random sample
+    pub depth: usize,
+    /// Largest sample, in atom bytes and cells, which is run
+    pub max_sample_bytes: usize,
+}
+
+impl Default for FuzzConfig {
+    fn default() -> Self {
+        FuzzConfig {
+            cases: 64,
+            seed: 0,
+            timeout: Duration::from_millis(100),
+            depth: 3,
+            max_sample_bytes: 1024,
+        }
+    }
+}
+
+impl FuzzConfig {
+    /// The defaults, with `NOCK_FUZZ_CASES` and `NOCK_FUZZ_SEED` applied
+    pub fn from_env() -> Self {
+        let mut config = FuzzConfig::default();
+        if let Some(cases) = std::env::var("NOCK_FUZZ_CASES")
+            .ok()
+            .and_then(|v| v.parse().ok())
+        {
+            config.cases = cases;
+        }
+        if let Some(seed) = std::env::var("NOCK_FUZZ_SEED")
+            .ok()
+            .and_then(|v| v.parse().ok())
+        {
+            config.seed = seed;
+        }
+        config
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MismatchKind {
+    /// The jet and the Nock produced different nouns
+    Differ,
+    /// The jet crashed where the Nock did not
+    JetCrashed,
+    /// The Nock crashed where the jet did not
+    NockCrashed,
+    /// The jet panicked
+    JetPanicked,
+}
+
+#[derive(Debug, Clone)]
+pub struct Mismatch {
+    /// The jet's hot state path, e.g. `/k138/one/add`
+    pub label: String,
+    pub kind: MismatchKind,
+    /// The minimised samples of the jet's core and its parents, innermost first
+    pub samples: Vec<String>,
+    pub detail: String,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: {:?} on sample {}: {}",
+            self.label,
+            self.kind,
+            self.samples.join(", "),
+            self.detail
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct FuzzReport {
+    /// Jets which were fuzzed, with the number of cases exercised: those where the jet and the
+    /// Nock both returned, so could be compared
+    pub checked: Vec<(String, usize)>,
+    /// Jets whose cores the booted jam never registered, so could not be fuzzed
+    pub unregistered: Vec<String>,
+    pub mismatches: Vec<Mismatch>,
+}
+
+enum Outcome {
+    Agree,
+    /// The case says nothing about the jet: it punted, both it and the Nock crashed, the sample
+    /// was too large, or something ran out of time or memory
+    Skip,
+    Fail(MismatchKind, String),
+}
+
+/// A jet and the arm it stands in for
+struct Target {
+    jet: Jet,
+    /// The arm's formula
+    formula: Noun,
+    root: Noun,
+    /// `(battery, parent axis)` of each core between the arm and the root, innermost first
+    layers: Vec<(Noun, u64)>,
+    /// The chum each layer was registered with, innermost first
+    chums: Vec<Noun>,
+}
+
+impl Target {
+    /// Cores whose parent is at `+7` have a sample at `+6`
+    fn sample_count(&self) -> usize {
+        self.layers.iter().filter(|(_, axis)| *axis == 7).count()
+    }
+
+    /// Rebuild the arm's core, with `samples` in the sampled layers
+    fn core(&self, stack: &mut NockStack, samples: &[Noun]) -> Option<Noun> {
+        let mut samples = samples.iter().rev();
+        let mut core = self.root;
+        for (battery, axis) in self.layers.iter().rev() {
+            let sample = if *axis == 7 {
+                samples.next().copied()
+            } else {
+                None
+            };
+            core = core_layer(stack, *battery, *axis, core, sample)?;
+        }
+        Some(core)
+    }
+}
+
+struct QuietSlogger;
+
+impl Slogger for QuietSlogger {
+    fn slog(&mut self, _stack: &mut NockStack, _pri: u64, _tank: Noun) {}
+
+    fn flog(&mut self, _stack: &mut NockStack, _cord: Noun) {}
+}
+
+pub struct JetFuzzer {
+    context: Context,
+    rng: StdRng,
+    config: FuzzConfig,
+}
+
+impl JetFuzzer {
+    /// Kick the trap in `jam`, without jets, to fill the cold state
+    pub fn boot(stack_size: usize, jam: &[u8], config: FuzzConfig) -> Result<Self, FuzzError> {
+        let mut stack = NockStack::new(stack_size, 0);
+        let cold = Cold::new(&mut stack);
+        let warm = Warm::new(&mut stack);
+        let hot = Hot::init(&mut stack, &[]);
+        let cache = Hamt::<Noun>::new(&mut stack);
+        let test_jets = Hamt::<()>::new(&mut stack);
+        let mut context = Context {
+            stack,
+            slogger: Box::pin(QuietSlogger),
+            cold,
+            warm,
+            hot,
+            cache,
+            scry_stack: D(0),
+            trace_info: None,
+            jet_profile: None,
+            sampler: None,
+            running_status: Arc::new(AtomicIsize::new(NockCancelToken::RUNNING_IDLE)),
+            test_jets,
+        };
+
+        let jam = unsafe { IndirectAtom::new_raw_bytes_ref(&mut context.stack, jam) };
+        let jam = unsafe { jam.normalize_as_atom() };
+        let trap = cue(&mut context.stack, jam).map_err(|_| FuzzError::Cue)?;
+        let kick = T(&mut context.stack, &[D(9), D(2), D(0), D(1)]);
+        interpret(&mut context, trap, kick).map_err(FuzzError::Boot)?;
+
+        Ok(JetFuzzer {
+            context,
+            rng: StdRng::seed_from_u64(config.seed),
+            config,
+        })
+    }
+
+    /// Fuzz every jet in `hot_state`, stopping at the first mismatch of each
+    pub fn fuzz(&mut self, hot_state: &[HotEntry]) -> FuzzReport {
+        let mut report = FuzzReport::default();
+        for entry in hot_state {
+            let label = hot_label(entry);
+            match self.fuzz_entry(entry, &label) {
+                None => report.unregistered.push(label),
+                Some((exercised, mismatch)) => {
+                    report.checked.push((label, exercised));
+                    report.mismatches.extend(mismatch);
+                }
+            }
+        }
+        report
+    }
+
+    /// `None` if the jet's core isn't registered, else the cases exercised and the mismatch if
+    /// there is one
+    fn fuzz_entry(&mut self, entry: &HotEntry, label: &str) -> Option<(usize, Option<Mismatch>)> {
+        let snapshot = self.context.save();
+        self.context.stack.frame_push(0);
+        let result = self.fuzz_entry_in_frame(entry, label);
+        unsafe { self.context.stack.frame_pop() };
+        self.context.restore(&snapshot);
+        result
+    }
+
+    fn fuzz_entry_in_frame(
+        &mut self,
+        entry: &HotEntry,
+        label: &str,
+    ) -> Option<(usize, Option<Mismatch>)> {
+        let stack = &mut self.context.stack;
+        let (mut path, axis, jet) = Hot::init(stack, std::slice::from_ref(entry)).next()?;
+        // Cold state paths are lists of chums, innermost first
+        let mut chums = Vec::new();
+        let mut cursor = path;
+        while let Ok(cell) = cursor.as_cell() {
+            chums.push(cell.head());
+            cursor = cell.tail();
+        }
+        let mut targets = Vec::new();
+        for batteries in self.context.cold.find(stack, &mut path) {
+            let mut layers: Vec<(Noun, u64)> = Vec::new();
+            let mut root = None;
+            for (battery, parent_axis) in batteries {
+                let battery = unsafe { *battery };
+                match parent_axis.as_direct().map(|axis| axis.data()) {
+                    Ok(0) => root = Some(battery),
+                    Ok(parent_axis) => layers.push((battery, parent_axis)),
+                    Err(_) => break,
+                }
+            }
+            let (Some(root), Some((leaf, _))) = (root, layers.first()) else {
+                continue;
+            };
+            let Ok(formula) = leaf.slot_atom(axis) else {
+                continue;
+            };
+            targets.push(Target {
+                jet,
+                formula,
+                root,
+                layers,
+                chums: chums.clone(),
+            });
+        }
+        if targets.is_empty() {
+            return None;
+        }
+
+        let mut exercised = 0;
+        for target in &targets {
+            let defaults = self.default_samples(target);
+            for case in 0..self.config.cases {
+                let samples = self.case_samples(target, defaults.as_deref(), case);
+                match self.check(target, &samples) {
+                    Outcome::Agree => exercised += 1,
+                    Outcome::Skip => {}
+                    Outcome::Fail(kind, detail) => {
+                        let (samples, detail) = self.minimise(target, samples, kind, detail);
+                        let mismatch = Mismatch {
+                            label: label.to_string(),
+                            kind,
+                            samples: samples.iter().map(|sample| print_noun(*sample)).collect(),
+                            detail,
+                        };
+                        return Some((exercised, Some(mismatch)));
+                    }
+                }
+            }
+        }
+        Some((exercised, None))
+    }
+
+    /// Samples for a case: the default samples as they are, then mostly mutations of them, with
+    /// random samples mixed in. All random without default samples.
+    fn case_samples(
+        &mut self,
+        target: &Target,
+        defaults: Option<&[Noun]>,
+        case: usize,
+    ) -> Vec<Noun> {
+        let (stack, rng, depth) = (&mut self.context.stack, &mut self.rng, self.config.depth);
+        match defaults {
+            Some(defaults) if case == 0 => defaults.to_vec(),
+            Some(defaults) if rng.gen_bool(0.75) => defaults
+                .iter()
+                .map(|sample| mutate(stack, rng, *sample, depth))
+                .collect(),
+            _ => (0..target.sample_count())
+                .map(|_| random_noun(stack, rng, depth))
+                .collect(),
+        }
+    }
+
+    /// The samples of the sampled layers of the target's core as the jam builds it, innermost
+    /// first. Each core is built by running the arm of its parent which registers it, found by
+    /// its `%fast` hint, so this is `None` if any such arm can't be found or doesn't build.
+    fn default_samples(&mut self, target: &Target) -> Option<Vec<Noun>> {
+        let mut core = target.root;
+        let mut samples = Vec::new();
+        for ((battery, axis), chum) in target.layers.iter().zip(&target.chums).rev() {
+            let arm = {
+                let parent_battery = core.slot(2).ok()?;
+                find_fast_arm(&mut self.context.stack, parent_battery, *chum)?
+            };
+            let timeout = self.config.timeout;
+            let frame = self.context.stack.get_frame_pointer();
+            let built = with_deadline(&mut self.context, timeout, |context| {
+                interpret(context, core, arm)
+            });
+            let Ok(Ok(built)) = built else {
+                // A panic can leave frames behind
+                while self.context.stack.get_frame_pointer() != frame {
+                    unsafe { self.context.stack.frame_pop() };
+                }
+                return None;
+            };
+            let mut built_battery = built.slot(2).ok()?;
+            let mut battery = *battery;
+            let same = unsafe {
+                unifying_equality(&mut self.context.stack, &mut built_battery, &mut battery)
+            };
+            if !same {
+                return None;
+            }
+            if *axis == 7 {
+                samples.push(built.slot(6).ok()?);
+            }
+            core = built;
+        }
+        samples.reverse();
+        Some(samples)
+    }
+
+    /// Run the jet and the Nock on the core with `samples`
+    fn check(&mut self, target: &Target, samples: &[Noun]) -> Outcome {
+        let budget = self.config.max_sample_bytes;
+        if samples.iter().any(|sample| !fits(*sample, budget)) {
+            return Outcome::Skip;
+        }
+        let Some(core) = target.core(&mut self.context.stack, samples) else {
+            return Outcome::Skip;
+        };
+        let (jet, formula, timeout) = (target.jet, target.formula, self.config.timeout);
+        unsafe {
+            in_frame(&mut self.context, |context| {
+                let jet_res = match with_deadline(context, timeout, |context| jet(context, core)) {
+                    Ok(res) => res,
+                    Err(panic) => return Outcome::Fail(MismatchKind::JetPanicked, panic),
+                };
+                let nock_res = match with_deadline(context, timeout, |context| {
+                    interpret(context, core, formula)
+                }) {
+                    Ok(res) => res,
+                    Err(_) => return Outcome::Skip,
+                };
+                compare(&mut context.stack, jet_res, nock_res)
+            })
+        }
+    }
+
+    /// Greedily shrink `samples` while the case still fails the same way
+    fn minimise(
+        &mut self,
+        target: &Target,
+        mut samples: Vec<Noun>,
+        kind: MismatchKind,
+        mut detail: String,
+    ) -> (Vec<Noun>, String) {
+        let mut steps = 0;
+        'shrink: while steps < MAX_SHRINK_STEPS {
+            for i in 0..samples.len() {
+                for candidate in shrink(&mut self.context.stack, samples[i]) {
+                    steps += 1;
+                    let mut trial = samples.clone();
+                    trial[i] = candidate;
+                    if let Outcome::Fail(trial_kind, trial_detail) = self.check(target, &trial) {
+                        if trial_kind == kind {
+                            samples = trial;
+                            detail = trial_detail;
+                            continue 'shrink;
+                        }
+                    }
+                    if steps >= MAX_SHRINK_STEPS {
+                        break 'shrink;
+                    }
+                }
+            }
+            break;
+        }
+        (samples, detail)
+    }
+}
+
+fn compare(
+    stack: &mut NockStack,
+    jet_res: Result<Noun, JetErr>,
+    nock_res: Result<Noun, Error>,
+) -> Outcome {
+    match (jet_res, nock_res) {
+        (Err(JetErr::Punt), _) => Outcome::Skip,
+        (Err(JetErr::Fail(Error::NonDeterministic(..))), _) => Outcome::Skip,
+        (_, Err(Error::NonDeterministic(..) | Error::ScryBlocked(_) | Error::ScryCrashed(_))) => {
+            Outcome::Skip
+        }
+        (Ok(mut jet), Ok(mut nock)) => {
+            if unsafe { unifying_equality(stack, &mut jet, &mut nock) } {
+                Outcome::Agree
+            } else {
+                let detail = format!("jet {}, nock {}", print_noun(jet), print_noun(nock));
+                Outcome::Fail(MismatchKind::Differ, detail)
+            }
+        }
+        (Ok(jet), Err(err)) => {
+            let detail = format!(
+                "jet {}, nock crashed: {}",
+                print_noun(jet),
+                print_error(&err)
+            );
+            Outcome::Fail(MismatchKind::NockCrashed, detail)
+        }
+        (Err(JetErr::Fail(err)), Ok(nock)) => {
+            let detail = format!(
+                "jet crashed: {}, nock {}",
+                print_error(&err),
+                print_noun(nock)
+            );
+            Outcome::Fail(MismatchKind::JetCrashed, detail)
+        }
+        (Err(_), Err(_)) => Outcome::Skip,
+    }
+}
+
+/// Run `f` in a new frame, then pop it and every frame a panic left above it
+unsafe fn in_frame<T>(context: &mut Context, f: impl FnOnce(&mut Context) -> T) -> T {
+    let snapshot = context.save();
+    context.stack.frame_push(0);
+    let frame = context.stack.get_frame_pointer();
+    let res = f(context);
+    while context.stack.get_frame_pointer() != frame {
+        context.stack.frame_pop();
+    }
+    context.stack.frame_pop();
+    context.restore(&snapshot);
+    res
+}
+
+/// Run `f`, cancelling any Nock it runs after `timeout`, and catch its panics
+fn with_deadline<T>(
+    context: &mut Context,
+    timeout: Duration,
+    f: impl FnOnce(&mut Context) -> T,
+) -> Result<T, String> {
+    let token = context.cancel_token();
+    let (done, watch) = mpsc::channel::<()>();
+    let watchdog = thread::spawn(move || {
+        if let Err(RecvTimeoutError::Timeout) = watch.recv_timeout(timeout) {
+            token.cancel();
+        }
+    });
+    let res = catch_unwind(AssertUnwindSafe(|| f(context)));
+    let _ = done.send(());
+    let _ = watchdog.join();
+    // A cancellation, or a panic, can leave the status anywhere
+    context
+        .running_status
+        .store(NockCancelToken::RUNNING_IDLE, Ordering::SeqCst);
+    res.map_err(|panic| {
+        panic
+            .downcast_ref::<String>()
+            .cloned()
+            .or_else(|| panic.downcast_ref::<&str>().map(|s| s.to_string()))
+            .unwrap_or_else(|| "panic".to_string())
+    })
+}
+
+/// A core with `battery` and `parent` at `axis`, and `sample` at `+6` if given
+fn core_layer(
+    stack: &mut NockStack,
+    battery: Noun,
+    axis: u64,
+    parent: Noun,
+    sample: Option<Noun>,
+) -> Option<Noun> {
+    let mut payload = parent;
+    let mut at = axis;
+    while at > 3 {
+        payload = if at & 1 == 0 {
+            T(stack, &[payload, D(0)])
+        } else {
+            T(stack, &[D(0), payload])
+        };
+        at >>= 1;
+    }
+    if at != 3 {
+        return None;
+    }
+    if let Some(sample) = sample {
+        payload = T(stack, &[sample, parent]);
+    }
+    Some(T(stack, &[battery, payload]))
+}
+
+/// A random noun, mostly small atoms so that the Nock finishes
+fn random_noun(stack: &mut NockStack, rng: &mut StdRng, depth: usize) -> Noun {
+    if depth > 0 && rng.gen_bool(0.5) {
+        let head = random_noun(stack, rng, depth - 1);
+        let tail = random_noun(stack, rng, depth - 1);
+        return T(stack, &[head, tail]);
+    }
+    match rng.gen_range(0..8) {
+        0..=3 => D(rng.gen_range(0..16)),
+        4 | 5 => D(rng.gen_range(0..1024)),
+        6 => D(rng.gen_range(0..=DIRECT_MAX)),
+        _ => {
+            let len = rng.gen_range(8..=32);
+            let bytes: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+            unsafe {
+                IndirectAtom::new_raw_bytes_ref(stack, &bytes)
+                    .normalize_as_atom()
+                    .as_noun()
+            }
+        }
+    }
+}
+
+/// `noun` with one atom nudged or one subtree replaced by a random noun
+fn mutate(stack: &mut NockStack, rng: &mut StdRng, noun: Noun, depth: usize) -> Noun {
+    match noun.as_either_atom_cell() {
+        Right(cell) if rng.gen_bool(0.8) => {
+            let (head, tail) = (cell.head(), cell.tail());
+            if rng.gen_bool(0.5) {
+                let head = mutate(stack, rng, head, depth);
+                T(stack, &[head, tail])
+            } else {
+                let tail = mutate(stack, rng, tail, depth);
+                T(stack, &[head, tail])
+            }
+        }
+        Left(atom) if rng.gen_bool(0.8) => {
+            let big = atom.as_ubig(stack);
+            let nudged = match rng.gen_range(0..4) {
+                0 => big + UBig::from(1u8),
+                1 if big != UBig::from(0u8) => big - UBig::from(1u8),
+                2 => big << 1,
+                _ => big >> 1,
+            };
+            Atom::from_ubig(stack, &nudged).as_noun()
+        }
+        _ => random_noun(stack, rng, depth),
+    }
+}
+
+/// Whether `noun` is at most `budget` atom bytes and cells
+fn fits(noun: Noun, mut budget: usize) -> bool {
+    let mut pending = vec![noun];
+    while let Some(noun) = pending.pop() {
+        let size = match noun.as_either_atom_cell() {
+            Left(atom) => met3_usize(atom),
+            Right(cell) => {
+                pending.push(cell.head());
+                pending.push(cell.tail());
+                1
+            }
+        };
+        let Some(left) = budget.checked_sub(size) else {
+            return false;
+        };
+        budget = left;
+    }
+    true
+}
+
+/// The arm formula in `battery` hinted `[11 [%fast clue] body]`, where `clue` computes
+/// `[chum parent hooks]`, either quoted whole or as an autocons with a quoted `chum`. Arms are the
+/// formulas at the leaves of the battery, which have atom heads.
+fn find_fast_arm(stack: &mut NockStack, battery: Noun, mut chum: Noun) -> Option<Noun> {
+    let mut pending = vec![battery];
+    while let Some(node) = pending.pop() {
+        let Ok(cell) = node.as_cell() else {
+            continue;
+        };
+        let Ok(op) = cell.head().as_atom() else {
+            pending.push(cell.head());
+            pending.push(cell.tail());
+            continue;
+        };
+        let fast = op.as_direct().is_ok_and(|op| op.data() == 11)
+            && node
+                .slot(12)
+                .is_ok_and(|tag| unsafe { tag.raw_equals(&D(tas!(b"fast"))) });
+        if !fast {
+            // Look through other hints, such as `%spot`, around the arm
+            if op.as_direct().is_ok_and(|op| op.data() == 11) {
+                pending.extend(node.slot(7).ok());
+            }
+            continue;
+        }
+        let chum_axis = match node.slot(26).map(|quote| quote.as_either_atom_cell()) {
+            Ok(Left(quote)) if quote.as_direct().is_ok_and(|op| op.data() == 1) => 54,
+            Ok(Right(quote)) if unsafe { quote.head().raw_equals(&D(1)) } => 53,
+            _ => continue,
+        };
+        let Ok(mut hinted) = node.slot(chum_axis) else {
+            continue;
+        };
+        if unsafe { unifying_equality(stack, &mut hinted, &mut chum) } {
+            return Some(node);
+        }
+    }
+    None
+}
+
+/// Smaller nouns to try in place of `noun`, most aggressive first
+fn shrink(stack: &mut NockStack, noun: Noun) -> Vec<Noun> {
+    match noun.as_either_atom_cell() {
+        Left(atom) => {
+            let big = atom.as_ubig(stack);
+            if big == UBig::from(0u8) {
+                return Vec::new();
+            }
+            let mut candidates = vec![D(0)];
+            let half = &big >> 1;
+            let less = &big - UBig::from(1u8);
+            if half != UBig::from(0u8) {
+                candidates.push(Atom::from_ubig(stack, &half).as_noun());
+            }
+            if less != half && less != UBig::from(0u8) {
+                candidates.push(Atom::from_ubig(stack, &less).as_noun());
+            }
+            candidates
+        }
+        Right(cell) => {
+            let (head, tail) = (cell.head(), cell.tail());
+            let mut candidates = vec![head, tail];
+            for smaller in shrink(stack, head) {
+                candidates.push(T(stack, &[smaller, tail]));
+            }
+            for smaller in shrink(stack, tail) {
+                candidates.push(T(stack, &[head, smaller]));
+            }
+            candidates
+        }
+    }
+}
+
+/// `/k138/one/add` for `&[K_138, Left(b"one"), Left(b"add")]`
+fn hot_label(entry: &HotEntry) -> String {
+    let mut label = String::new();
+    for chum in entry.0 {
+        label.push('/');
+        match chum {
+            Left(tas) => label.push_str(&String::from_utf8_lossy(tas)),
+            Right((tas, ver)) => {
+                let bytes = tas.to_le_bytes();
+                let len = bytes.iter().rposition(|b| *b != 0).map_or(0, |i| i + 1);
+                label.push_str(&String::from_utf8_lossy(&bytes[..len]));
+                label.push_str(&ver.to_string());
+            }
+        }
+    }
+    label
+}
+
+fn print_noun(noun: Noun) -> String {
+    let mut printed = format!("{:?}", noun);
+    if printed.len() > MAX_NOUN_CHARS {
+        let mut end = MAX_NOUN_CHARS;
+        while !printed.is_char_boundary(end) {
+            end -= 1;
+        }
+        printed.truncate(end);
+        printed.push_str("...");
+    }
+    printed
+}
+
+fn print_error(err: &Error) -> String {
+    match err {
+        Error::Deterministic(mote, _) => format!("deterministic {:?}", mote),
+        Error::NonDeterministic(mote, _) => format!("nondeterministic {:?}", mote),
+        Error::ScryBlocked(_) => "scry blocked".to_string(),
+        Error::ScryCrashed(_) => "scry crashed".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jets::hot::{K_138, URBIT_HOT_STATE};
+    use crate::jets::util::slot;
+    use crate::serialization::jam;
+
+    /// Head of the sample, as the battery `[0 12]` computes it
+    fn jet_first(_context: &mut Context, subject: Noun) -> Result<Noun, JetErr> {
+        Ok(slot(subject, 12)?)
+    }
+
+    /// [jet_first], except for heads of 100 and up
+    fn jet_first_wrong(_context: &mut Context, subject: Noun) -> Result<Noun, JetErr> {
+        let head = slot(subject, 12)?;
+        match head.as_direct() {
+            Ok(atom) if atom.data() >= 100 => Ok(D(0)),
+            _ => Ok(head),
+        }
+    }
+
+    /// `[11 [%fast [1 clue]] body]`
+    fn fast(stack: &mut NockStack, clue: Noun, body: Noun) -> Noun {
+        let clue = T(stack, &[D(1), clue]);
+        let hint = T(stack, &[D(tas!(b"fast")), clue]);
+        T(stack, &[D(11), hint, body])
+    }
+
+    /// A trap which registers root `%k138` and its gate `%first`, with battery `[0 12]` and
+    /// default sample `[150 7]`. The root's one arm builds the gate.
+    fn first_jam() -> Vec<u8> {
+        let mut stack = NockStack::new(8 << 10 << 10, 0);
+        let stack = &mut stack;
+        let gate_parent = T(stack, &[D(0), D(7)]);
+        let gate_clue = T(stack, &[D(tas!(b"first")), gate_parent, D(0)]);
+        let gate_battery = T(stack, &[D(0), D(12)]);
+        let gate_battery = T(stack, &[D(1), gate_battery]);
+        let gate_sample = T(stack, &[D(1), D(150), D(7)]);
+        let gate_context = T(stack, &[D(0), D(1)]);
+        let gate_core = T(stack, &[gate_battery, gate_sample, gate_context]);
+        let gate = fast(stack, gate_clue, gate_core);
+        let root_chum = T(stack, &[D(tas!(b"k")), D(138)]);
+        let root_parent = T(stack, &[D(1), D(0)]);
+        let root_clue = T(stack, &[root_chum, root_parent, D(0)]);
+        let root_core = T(stack, &[gate, D(138)]);
+        let root_body = T(stack, &[D(1), root_core]);
+        let root = fast(stack, root_clue, root_body);
+        let build_gate = T(stack, &[D(9), D(2), D(0), D(2)]);
+        let battery = T(stack, &[D(8), root, build_gate]);
+        let trap = T(stack, &[battery, D(0)]);
+        let jammed = jam(stack, trap);
+        let len = crate::mug::met3_usize(jammed);
+        jammed.as_ne_bytes()[..len].to_vec()
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_fuzz_finds_and_minimises_mismatch() {
+        let jam = first_jam();
+        let config = FuzzConfig {
+            cases: 256,
+            ..FuzzConfig::default()
+        };
+        let mut fuzzer = JetFuzzer::boot(8 << 10 << 10, &jam, config).expect("boot");
+        let first: &[HotEntry] = &[
+            (&[K_138, Left(b"first")], 1, jet_first),
+            (&[K_138, Left(b"last")], 1, jet_first),
+        ];
+        let report = fuzzer.fuzz(first);
+        let [(label, exercised)] = &report.checked[..] else {
+            panic!("expected one jet checked, got {:?}", report.checked);
+        };
+        assert_eq!(label, "/k138/first");
+        // Samples which are atoms crash both the jet and the Nock, and don't count
+        assert!(
+            *exercised > 0 && *exercised < 256,
+            "{exercised} cases exercised"
+        );
+        assert_eq!(report.unregistered, vec!["/k138/last".to_string()]);
+        assert!(report.mismatches.is_empty(), "{:?}", report.mismatches);
+
+        let wrong: &[HotEntry] = &[(&[K_138, Left(b"first")], 1, jet_first_wrong)];
+        let report = fuzzer.fuzz(wrong);
+        let [mismatch] = &report.mismatches[..] else {
+            panic!("expected one mismatch, got {:?}", report.mismatches);
+        };
+        assert_eq!(mismatch.kind, MismatchKind::Differ);
+        let minimal = T(&mut fuzzer.context.stack, &[D(100), D(0)]);
+        assert_eq!(mismatch.samples, vec![print_noun(minimal)]);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_fuzz_starts_from_default_sample() {
+        let jam = first_jam();
+        let config = FuzzConfig {
+            cases: 1,
+            ..FuzzConfig::default()
+        };
+        let mut fuzzer = JetFuzzer::boot(8 << 10 << 10, &jam, config).expect("boot");
+        // The one case is the default sample `[150 7]`, on which the wrong jet is wrong
+        let wrong: &[HotEntry] = &[(&[K_138, Left(b"first")], 1, jet_first_wrong)];
+        let report = fuzzer.fuzz(wrong);
+        assert_eq!(report.mismatches.len(), 1, "{:?}", report.mismatches);
+        assert_eq!(report.checked, vec![("/k138/first".to_string(), 0)]);
+
+        // Samples too large to run are skipped
+        let mut fuzzer = JetFuzzer::boot(
+            8 << 10 << 10,
+            &jam,
+            FuzzConfig {
+                cases: 64,
+                max_sample_bytes: 0,
+                ..FuzzConfig::default()
+            },
+        )
+        .expect("boot");
+        let report = fuzzer.fuzz(wrong);
+        assert!(report.mismatches.is_empty(), "{:?}", report.mismatches);
+        assert_eq!(report.checked, vec![("/k138/first".to_string(), 0)]);
+    }
+
+    /// Fuzz the nockvm hot state against the jam in `NOCK_FUZZ_JAM`
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn fuzz_urbit_hot_state() {
+        let Ok(path) = std::env::var("NOCK_FUZZ_JAM") else {
+            eprintln!("NOCK_FUZZ_JAM is not set, skipping jet fuzzing");
+            return;
+        };
+        let jam = std::fs::read(&path).expect("read NOCK_FUZZ_JAM");
+        let mut fuzzer =
+            JetFuzzer::boot(1 << 30, &jam, FuzzConfig::from_env()).expect("boot NOCK_FUZZ_JAM");
+        let report = fuzzer.fuzz(URBIT_HOT_STATE);
+        for label in &report.unregistered {
+            eprintln!("not registered by {}: {}", path, label);
+        }
+        for (label, exercised) in &report.checked {
+            if *exercised == 0 {
+                eprintln!("no cases exercised: {}", label);
+            }
+        }
+        for mismatch in &report.mismatches {
+            eprintln!("{}", mismatch);
+        }
+        assert!(
+            report.mismatches.is_empty(),
+            "{} jets mismatched",
+            report.mismatches.len()
+        );
+    }
+}
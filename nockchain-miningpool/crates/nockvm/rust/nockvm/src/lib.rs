@@ -12,6 +12,7 @@ pub mod jets;
 pub mod mem;
 pub mod mug;
 pub mod noun;
+pub mod profile;
 pub mod serialization;
 mod site;
 pub mod substantive;
@@ -23,6 +23,10 @@ pub(crate) const FRAME: usize = 0;
 pub(crate) const STACK: usize = 1;
 pub(crate) const ALLOC: usize = 2;
 
+/** Words a new stack keeps free past its limit by default, so a computation which runs out of
+ * memory can still unwind out to %meme. Stacks smaller than twice this keep no headroom. */
+pub const STACK_HEADROOM: usize = 1 << 20;
+
 /**  Utility function to get size in words */
 pub(crate) const fn word_size_of<T>() -> usize {
     (mem::size_of::<T>() + 7) >> 3
@@ -156,14 +160,28 @@ pub enum Direction {
 
 pub enum AllocType {
     Mmap,
+    /// Reserve address space without committing memory or swap for it: pages are only backed
+    /// once they are touched.
+    Reserve,
     Malloc,
 }
 
 pub enum Memory {
     Mmap(MmapMut),
+    Reserved(*mut u8, usize),
     Malloc(*mut u8, usize),
 }
 
+impl Drop for Memory {
+    fn drop(&mut self) {
+        if let Memory::Reserved(ptr, len) = self {
+            unsafe {
+                libc::munmap(*ptr as *mut libc::c_void, *len);
+            }
+        }
+    }
+}
+
 impl Deref for Memory {
     type Target = [u8];
 
@@ -171,6 +189,7 @@ impl Deref for Memory {
     fn deref(&self) -> &[u8] {
         match self {
             Memory::Mmap(mmap) => mmap.deref(),
+            Memory::Reserved(ptr, len) => unsafe { core::slice::from_raw_parts(*ptr, *len) },
             Memory::Malloc(ptr, size) => unsafe { core::slice::from_raw_parts(*ptr, *size) },
         }
     }
@@ -181,6 +200,7 @@ impl DerefMut for Memory {
     fn deref_mut(&mut self) -> &mut [u8] {
         match self {
             Memory::Mmap(mmap) => mmap.deref_mut(),
+            Memory::Reserved(ptr, len) => unsafe { core::slice::from_raw_parts_mut(*ptr, *len) },
             Memory::Malloc(ptr, size) => unsafe { core::slice::from_raw_parts_mut(*ptr, *size) },
         }
     }
@@ -209,6 +229,23 @@ impl Memory {
                 let mmap_mut = MmapMut::map_anon(size << 3)?;
                 Self::Mmap(mmap_mut)
             }
+            AllocType::Reserve => {
+                let len = size << 3;
+                let ptr = unsafe {
+                    libc::mmap(
+                        ptr::null_mut(),
+                        len,
+                        libc::PROT_READ | libc::PROT_WRITE,
+                        libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
+                        -1,
+                        0,
+                    )
+                };
+                if ptr == libc::MAP_FAILED {
+                    return Err(NewStackError::MmapFailed(std::io::Error::last_os_error()));
+                }
+                Self::Reserved(ptr as *mut u8, len)
+            }
             AllocType::Malloc => {
                 // Align is in terms of bytes so I'm aligning it to 64-bits / 8 bytes, word size.
                 let layout = Layout::from_size_align(size << 3, std::mem::size_of::<u64>())
@@ -241,6 +278,10 @@ pub struct NockStack {
     alloc_offset: usize,
     /// The least amount of space between the stack and alloc pointers since last reset
     least_space: usize,
+    /// The most words which may be in use at once. Allocations which would leave less than
+    /// `size - limit` words free fail as out of memory, which keeps that much headroom for
+    /// unwinding out of the failed computation.
+    limit: usize,
     /// The underlying memory allocation which must be kept alive
     memory: Memory,
     /// Whether or not [`Self::pre_copy()`] has been called on the current stack frame.
@@ -312,7 +353,7 @@ impl NockStack {
         }
         let free = size - (top_slots + RESERVED);
         #[cfg(feature = "mmap")]
-        let mut memory = Memory::allocate(AllocType::Mmap, size)?;
+        let mut memory = Memory::allocate(AllocType::Reserve, size)?;
         #[cfg(feature = "malloc")]
         let mut memory = Memory::allocate(AllocType::Malloc, size)?;
         let start = memory.as_mut_ptr() as *mut u64;
@@ -346,6 +387,11 @@ impl NockStack {
                 stack_offset,
                 alloc_offset,
                 least_space,
+                limit: if size >= STACK_HEADROOM << 1 {
+                    size - STACK_HEADROOM
+                } else {
+                    size
+                },
                 memory,
                 pc: false,
             },
@@ -432,6 +478,13 @@ impl NockStack {
         let _bytes = words * 8;
 
         // Check space availability based on offsets
+        // Space the allocation must leave free to stay under the limit. Slot pointers and top
+        // frame flips don't claim free space, so they aren't held to it.
+        let headroom = match alloc.alloc_type {
+            AllocationType::SlotPointer | AllocationType::FlipTopFrame => 0,
+            _ => self.size - self.limit,
+        };
+
         let (target_offset, limit_offset, direction) = match (alloc.alloc_type, alloc.orientation) {
             // West + Alloc, alloc is decreasing
             (AllocationType::Alloc, ArenaOrientation::West) => {
@@ -559,12 +612,12 @@ impl NockStack {
         };
         match direction {
             Direction::Increasing => {
-                if target_offset > limit_offset {
+                if target_offset + headroom > limit_offset {
                     panic_any(self.out_of_memory(alloc, Some(words)))
                 }
             }
             Direction::Decreasing => {
-                if target_offset < limit_offset {
+                if target_offset < limit_offset + headroom {
                     panic_any(self.out_of_memory(alloc, Some(words)))
                 }
             }
@@ -739,7 +792,7 @@ impl NockStack {
     }
 
     /** Size **in 64-bit words** of this NockStack */
-    pub(crate) fn size(&self) -> usize {
+    pub fn size(&self) -> usize {
         self.size
     }
 
@@ -748,6 +801,32 @@ impl NockStack {
         self.least_space
     }
 
+    /** Get the high-water-mark for words in use in this nockstack */
+    pub fn peak_usage(&self) -> usize {
+        self.size - self.least_space
+    }
+
+    /** The most words which may be in use at once */
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /** Cap the words in use at `limit`, which must not exceed the size of the stack.
+     *
+     * The stack reserves its whole size as address space up front but only commits memory as it
+     * is touched, so a large stack with a limit costs no more than a small one until it is used.
+     * A new stack is limited to its size less [STACK_HEADROOM].
+     */
+    pub fn set_limit(&mut self, limit: usize) {
+        assert!(
+            limit <= self.size,
+            "Stack limit {} exceeds stack size {}",
+            limit,
+            self.size
+        );
+        self.limit = limit;
+    }
+
     /** Check to see if an allocation is in frame */
     #[inline]
     pub(crate) unsafe fn is_in_frame<T>(&self, ptr: *const T) -> bool {
@@ -2304,4 +2383,76 @@ mod test {
             "Didn't get expected alloc error",
         );
     }
+
+    // cargo test -p nockvm test_stack_limit -- --nocapture
+    // Allocate past a limit below the stack size, then check the headroom is still free
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_stack_limit() {
+        const STACK_SIZE: usize = 512;
+        const LIMIT: usize = 256;
+        let mut stack = make_test_stack(STACK_SIZE);
+        stack.set_limit(LIMIT);
+        let used = stack.peak_usage();
+        let fits = LIMIT - used;
+        let alloc_res = catch_unwind(AssertUnwindSafe(|| unsafe {
+            stack.struct_alloc::<u64>(fits);
+        }));
+        assert!(alloc_res.is_ok());
+        assert_eq!(stack.peak_usage(), LIMIT);
+        let alloc_res = catch_unwind(AssertUnwindSafe(|| unsafe {
+            stack.struct_alloc::<u64>(1);
+        }));
+        assert!(alloc_res
+            .map_err(|err| err.is::<AllocationError>())
+            .expect_err("Expected alloc error"),);
+
+        stack.set_limit(STACK_SIZE);
+        let alloc_res = catch_unwind(AssertUnwindSafe(|| unsafe {
+            stack.struct_alloc::<u64>(STACK_SIZE - LIMIT);
+        }));
+        assert!(alloc_res.is_ok());
+        assert_eq!(stack.least_space(), 0);
+    }
+
+    // cargo test -p nockvm test_interpret_meme -- --nocapture
+    // Recursion which never returns runs out of stack, which fails the computation with %meme
+    // and leaves the stack usable, both under the default headroom and under a set limit
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_interpret_meme() {
+        use crate::jets::util::test::init_context;
+
+        let mut context = init_context();
+        let size = context.stack.size();
+        assert_eq!(context.stack.limit(), size - STACK_HEADROOM);
+        assert_interpret_meme(&mut context);
+
+        let mut context = init_context();
+        context.stack.set_limit(size / 2);
+        assert_interpret_meme(&mut context);
+    }
+
+    fn assert_interpret_meme(context: &mut crate::interpreter::Context) {
+        use crate::interpreter::{interpret, Error, Mote};
+        use crate::noun::T;
+
+        let limit = context.stack.limit();
+        let stack = &mut context.stack;
+        // [[1 0] 9 2 0 1]: cons 0 onto a recursive call to this arm
+        let konst = T(stack, &[D(1), D(0)]);
+        let arm = T(stack, &[konst, D(9), D(2), D(0), D(1)]);
+        let core = T(stack, &[arm, D(0)]);
+        let kick = T(stack, &[D(9), D(2), D(0), D(1)]);
+        let frame = context.stack.get_frame_pointer();
+
+        let res = interpret(context, core, kick);
+        assert!(matches!(res, Err(Error::NonDeterministic(Mote::Meme, _))));
+        assert_eq!(context.stack.get_frame_pointer(), frame);
+        assert_eq!(context.stack.limit(), limit);
+
+        let konst = T(&mut context.stack, &[D(1), D(42)]);
+        let res = interpret(context, D(0), konst).expect("interpret after %meme");
+        assert!(unsafe { res.raw_equals(&D(42)) });
+    }
 }
@@ -0,0 +1,245 @@
+//! Per-jet profiling.
+//!
+//! When [Context::jet_profile] is set, the interpreter counts every call it dispatches to a jet
+//! (by the jet's registered path, as in the hot state), how long the jet took, and whether it
+//! returned or punted back to raw Nock. It also counts calls to arms which were registered with
+//! the cold state by a `%fast` hint but have no jet: the unjetted hot arms. The report is written
+//! as JSON, next to the trace output.
+//!
+//! Profiling runs on every jet call, so it keeps off the NockStack: labels are built once per jet
+//! and per battery, with [path_to_string], and looked up by address after that.
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Error, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use json::{object, JsonValue};
+
+use crate::flog;
+use crate::interpreter::Context;
+use crate::jets::cold::Cold;
+use crate::jets::{Jet, JetErr};
+use crate::mem::NockStack;
+use crate::noun::{Noun, Slots};
+use crate::trace::{atom_to_string, path_to_string};
+
+crate::gdb!();
+
+/// Counters for one jet
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct JetStats {
+    /// Calls dispatched to the jet
+    pub calls: u64,
+    /// Calls where the jet computed the result
+    pub hits: u64,
+    /// Calls where the jet punted, so the arm ran as raw Nock
+    pub punts: u64,
+    /// Calls where the jet failed
+    pub errors: u64,
+    /// Time spent in the jet, over all calls
+    pub time: Duration,
+}
+
+/// Counters for one arm registered with `%fast` but without a jet
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ArmStats {
+    pub calls: u64,
+}
+
+pub struct JetProfile {
+    /// Where [JetProfile::write] puts the report
+    pub path: PathBuf,
+    /// Label and counters of each jet, by the jet's address
+    jets: HashMap<usize, (String, JetStats)>,
+    unjetted: HashMap<String, ArmStats>,
+    /// Label of the unjetted arm of each battery seen since [JetProfile::forget_batteries], by
+    /// the battery's address, or `None` if the cold state doesn't know the core
+    batteries: HashMap<u64, Option<String>>,
+    last_write: Instant,
+}
+
+/// [write_jet_profile_safe] writes the report at most this often
+pub const JET_PROFILE_WRITE_INTERVAL: Duration = Duration::from_secs(10);
+
+impl JetProfile {
+    pub fn new(path: PathBuf) -> Self {
+        JetProfile {
+            path,
+            jets: HashMap::new(),
+            unjetted: HashMap::new(),
+            batteries: HashMap::new(),
+            last_write: Instant::now(),
+        }
+    }
+
+    /// Counters of each jet, by label
+    pub fn jets(&self) -> HashMap<&str, &JetStats> {
+        self.jets
+            .values()
+            .map(|(label, stats)| (label.as_str(), stats))
+            .collect()
+    }
+
+    pub fn unjetted(&self) -> &HashMap<String, ArmStats> {
+        &self.unjetted
+    }
+
+    /// Record a call to `jet`, registered at `path`, which started at `start`
+    pub fn record_jet<T>(
+        &mut self,
+        jet: Jet,
+        path: Noun,
+        result: &Result<T, JetErr>,
+        start: Instant,
+    ) {
+        let elapsed = start.elapsed();
+        let (_, stats) = self
+            .jets
+            .entry(jet as usize)
+            .or_insert_with(|| (path_label(path), JetStats::default()));
+        stats.calls += 1;
+        stats.time += elapsed;
+        match result {
+            Ok(_) => stats.hits += 1,
+            Err(JetErr::Punt) => stats.punts += 1,
+            Err(_) => stats.errors += 1,
+        }
+    }
+
+    /// Record a call to an arm of `core` which has no jet, if the cold state knows the core
+    pub fn record_unjetted(&mut self, stack: &mut NockStack, cold: &mut Cold, core: &mut Noun) {
+        let Ok(battery) = core.slot(2) else {
+            return;
+        };
+        let label = self
+            .batteries
+            .entry(unsafe { battery.as_raw() })
+            .or_insert_with(|| cold.matches(stack, core).map(path_label));
+        if let Some(label) = label {
+            if let Some(stats) = self.unjetted.get_mut(label.as_str()) {
+                stats.calls += 1;
+            } else {
+                self.unjetted.insert(label.clone(), ArmStats { calls: 1 });
+            }
+        }
+    }
+
+    /// Forget which batteries are unjetted hot arms. Nouns may move between events, so a battery
+    /// address only means the same battery within one.
+    pub fn forget_batteries(&mut self) {
+        self.batteries.clear();
+    }
+
+    /// The report: jets by time spent, then unjetted hot arms by calls
+    pub fn to_json(&self) -> JsonValue {
+        let mut jets: Vec<&(String, JetStats)> = self.jets.values().collect();
+        jets.sort_by(|a, b| b.1.time.cmp(&a.1.time).then_with(|| a.0.cmp(&b.0)));
+        let mut unjetted: Vec<(&String, &ArmStats)> = self.unjetted.iter().collect();
+        unjetted.sort_by(|a, b| b.1.calls.cmp(&a.1.calls).then_with(|| a.0.cmp(b.0)));
+
+        let jets: Vec<JsonValue> = jets
+            .into_iter()
+            .map(|(label, stats)| {
+                object! {
+                    "name" => label.as_str(),
+                    "calls" => stats.calls,
+                    "jet" => stats.hits,
+                    "nock" => stats.punts,
+                    "error" => stats.errors,
+                    "us" => stats.time.as_micros() as f64,
+                }
+            })
+            .collect();
+        let unjetted: Vec<JsonValue> = unjetted
+            .into_iter()
+            .map(|(label, stats)| {
+                object! {
+                    "name" => label.as_str(),
+                    "calls" => stats.calls,
+                }
+            })
+            .collect();
+
+        object! {
+            "jets" => jets,
+            "unjetted" => unjetted,
+        }
+    }
+
+    /// Overwrite the report at [JetProfile::path]
+    pub fn write(&mut self) -> Result<(), Error> {
+        self.last_write = Instant::now();
+        let mut file = File::create(&self.path)?;
+        self.to_json().write_pretty(&mut file, 2)?;
+        file.write_all(b"\n")
+    }
+}
+
+/// Write the jet profile, if profiling and it wasn't written in the last
+/// [JET_PROFILE_WRITE_INTERVAL], and stop profiling if that fails. Called between events.
+pub fn write_jet_profile_safe(context: &mut Context) {
+    let Some(profile) = &mut context.jet_profile else {
+        return;
+    };
+    profile.forget_batteries();
+    if profile.last_write.elapsed() < JET_PROFILE_WRITE_INTERVAL {
+        return;
+    }
+    if let Err(e) = profile.write() {
+        flog!(context, "\rserf: error writing jet profile: {:?}", e);
+        context.jet_profile = None;
+    }
+}
+
+/// Readable label for a jet or arm path, e.g. `/k/138/one/two/add`. A bare atom is a `%sham` jet
+/// name, and labels as itself.
+pub fn path_label(path: Noun) -> String {
+    match path.as_atom() {
+        Ok(atom) => atom_to_string(atom),
+        Err(_) => path_to_string(path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nockvm_macros::tas;
+
+    use super::*;
+    use crate::jets::math::jet_add;
+    use crate::jets::util::test::init_context;
+    use crate::noun::{D, T};
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_jet_profile_report() {
+        let mut context = init_context();
+        let stack = &mut context.stack;
+        let add = T(stack, &[D(tas!(b"k")), D(tas!(b"add")), D(0)]);
+        assert_eq!(path_label(add), "/k/add");
+        assert_eq!(path_label(D(tas!(b"dec"))), "dec");
+
+        let mut profile = JetProfile::new(PathBuf::from("jet-profile.json"));
+        let start = Instant::now();
+        profile.record_jet(jet_add, add, &Ok::<(), JetErr>(()), start);
+        profile.record_jet(jet_add, add, &Err::<(), JetErr>(JetErr::Punt), start);
+        profile
+            .unjetted
+            .insert("/k/mul".into(), ArmStats { calls: 2 });
+        profile
+            .unjetted
+            .insert("/k/sub".into(), ArmStats { calls: 1 });
+
+        let stats = profile.jets()["/k/add"];
+        assert_eq!(
+            (stats.calls, stats.hits, stats.punts, stats.errors),
+            (2, 1, 1, 0)
+        );
+        let report = profile.to_json();
+        assert_eq!(report["jets"][0]["name"], "/k/add");
+        assert_eq!(report["jets"][0]["nock"], 1);
+        assert_eq!(report["unjetted"][0]["name"], "/k/mul");
+        assert_eq!(report["unjetted"][0]["calls"], 2);
+        assert_eq!(report["unjetted"][1]["name"], "/k/sub");
+    }
+}
@@ -1,8 +1,12 @@
+use std::collections::HashMap;
 use std::fs::{create_dir_all, File};
 use std::io::{Error, Write};
 use std::path::PathBuf;
 use std::result::Result;
-use std::time::Instant;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use either::Either::*;
 use json::object;
@@ -14,7 +18,7 @@ use crate::jets::bits::util::rap;
 use crate::jets::form::util::scow;
 use crate::mem::NockStack;
 use crate::mug::met3_usize;
-use crate::noun::{Atom, DirectAtom, IndirectAtom, Noun};
+use crate::noun::{Atom, DirectAtom, IndirectAtom, Noun, Slots, D};
 
 crate::gdb!();
 
@@ -141,14 +145,16 @@ pub fn write_serf_trace(info: &mut TraceInfo, name: &str, start: Instant) -> Res
     Ok(())
 }
 
+/// Write the entries of `trace_stack` down to `base`, the first entry of the parent frame
 pub unsafe fn write_nock_trace(
     stack: &mut NockStack,
     info: &mut TraceInfo,
     mut trace_stack: *const TraceStack,
+    base: *const TraceStack,
 ) -> Result<(), Error> {
     let now = Instant::now();
 
-    while !trace_stack.is_null() {
+    while !trace_stack.is_null() && trace_stack != base {
         let ts = (*trace_stack)
             .start
             .saturating_duration_since(info.process_start)
@@ -193,6 +199,165 @@ pub unsafe fn write_nock_trace(
     Ok(())
 }
 
+/// Samples the Nock stack on a timer, for flamegraphs.
+///
+/// A ticker thread counts a tick every `interval`, and the interpreter takes a sample at its next
+/// step, so sampling costs little more than keeping the trace stack. The sample is credited with
+/// every tick since the last one, so a step which outlasts several intervals, such as a long jet,
+/// weighs as much as the time it took. Samples are aggregated as folded stacks: one
+/// `root;...;leaf count` line per distinct stack, as `flamegraph.pl` and `inferno` read them.
+pub struct StackSampler {
+    /// Where [StackSampler::write] puts the folded stacks
+    pub path: PathBuf,
+    ticks: Arc<AtomicU64>,
+    /// Ticks credited to samples so far
+    taken: u64,
+    stacks: HashMap<String, u64>,
+    last_write: Instant,
+}
+
+/// [write_samples_safe] writes the samples at most this often
+pub const SAMPLE_WRITE_INTERVAL: Duration = Duration::from_secs(10);
+
+impl StackSampler {
+    pub fn new(path: PathBuf, interval: Duration) -> Self {
+        let ticks = Arc::new(AtomicU64::new(0));
+        // The ticker stops once the sampler is dropped
+        let ticker = Arc::downgrade(&ticks);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            match ticker.upgrade() {
+                Some(ticks) => {
+                    ticks.fetch_add(1, Ordering::Relaxed);
+                }
+                None => break,
+            }
+        });
+        StackSampler {
+            path,
+            ticks,
+            taken: 0,
+            stacks: HashMap::new(),
+            last_write: Instant::now(),
+        }
+    }
+
+    #[inline(always)]
+    pub fn is_due(&self) -> bool {
+        self.ticks.load(Ordering::Relaxed) != self.taken
+    }
+
+    /// Credit `folded` with the ticks since the last sample
+    pub fn record(&mut self, folded: String) {
+        let ticks = self.ticks.load(Ordering::Relaxed);
+        let elapsed = ticks.wrapping_sub(self.taken);
+        self.taken = ticks;
+        if elapsed > 0 {
+            *self.stacks.entry(folded).or_insert(0) += elapsed;
+        }
+    }
+
+    pub fn stacks(&self) -> &HashMap<String, u64> {
+        &self.stacks
+    }
+
+    /// The folded stacks, sorted
+    pub fn folded(&self) -> String {
+        let mut stacks: Vec<(&String, &u64)> = self.stacks.iter().collect();
+        stacks.sort();
+        stacks
+            .into_iter()
+            .map(|(stack, count)| format!("{} {}\n", stack, count))
+            .collect()
+    }
+
+    /// Overwrite [StackSampler::path] with the samples so far
+    pub fn write(&mut self) -> Result<(), Error> {
+        self.last_write = Instant::now();
+        let mut file = File::create(&self.path)?;
+        file.write_all(self.folded().as_bytes())
+    }
+}
+
+/// Write the stack samples, if sampling and none were written in the last
+/// [SAMPLE_WRITE_INTERVAL], and stop sampling if that fails.
+pub fn write_samples_safe(context: &mut Context) {
+    let Some(sampler) = &mut context.sampler else {
+        return;
+    };
+    if sampler.last_write.elapsed() < SAMPLE_WRITE_INTERVAL {
+        return;
+    }
+    if let Err(e) = sampler.write() {
+        flog!(context, "\rserf: error writing stack samples to file: {:?}", e);
+        context.sampler = None;
+    }
+}
+
+/// A folded stack, root first: the arms on `trace_stack`, or the `%spot`s on the `mean` stack if
+/// there are none.
+pub unsafe fn folded_stack(mut trace_stack: *const TraceStack, mean: Noun) -> String {
+    let mut frames: Vec<String> = Vec::new();
+    while !trace_stack.is_null() {
+        frames.push(path_to_string((*trace_stack).path));
+        trace_stack = (*trace_stack).next;
+    }
+    if frames.is_empty() {
+        let mut cursor = mean;
+        while let Ok(cell) = cursor.as_cell() {
+            if let Ok(entry) = cell.head().as_cell() {
+                if entry.head().raw_equals(&D(tas!(b"spot"))) {
+                    frames.extend(spot_to_string(entry.tail()));
+                }
+            }
+            cursor = cell.tail();
+        }
+    }
+    if frames.is_empty() {
+        frames.push("nock".to_string());
+    }
+    frames.reverse();
+    frames.join(";")
+}
+
+/// Like [path_to_cord], without allocating on the NockStack
+pub(crate) fn path_to_string(path: Noun) -> String {
+    let mut string = String::new();
+    let mut cursor = path;
+    while let Ok(c) = cursor.as_cell() {
+        match c.head().as_either_atom_cell() {
+            Left(a) => {
+                string.push('/');
+                string.push_str(&atom_to_string(a));
+            }
+            Right(ch) => {
+                if let (Ok(nm), Ok(kv)) = (ch.head().as_atom(), ch.tail().as_atom()) {
+                    string.push('/');
+                    string.push_str(&atom_to_string(nm));
+                    match kv.as_direct() {
+                        Ok(kv) => string.push_str(&kv.data().to_string()),
+                        Err(_) => string.push('?'),
+                    }
+                }
+            }
+        }
+        cursor = c.tail();
+    }
+    string
+}
+
+/// `/path/to/file:line` for a `$spot`, `[p=path q=pint]`
+fn spot_to_string(spot: Noun) -> Option<String> {
+    let spot = spot.as_cell().ok()?;
+    let line = spot.tail().slot(4).ok()?.as_direct().ok()?.data();
+    Some(format!("{}:{}", path_to_string(spot.head()), line))
+}
+
+pub(crate) fn atom_to_string(atom: Atom) -> String {
+    let len = met3_usize(atom);
+    String::from_utf8_lossy(&atom.as_ne_bytes()[0..len]).into_owned()
+}
+
 //  XX: Need Rust string interpolation helper that doesn't allocate
 pub fn path_to_cord(stack: &mut NockStack, path: Noun) -> Atom {
     let mut cursor = path;
@@ -266,3 +431,60 @@ pub fn path_to_cord(stack: &mut NockStack, path: Noun) -> Atom {
 
     unsafe { deres.normalize_as_atom() }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jets::util::test::init_context;
+    use crate::noun::T;
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_folded_stack_samples() {
+        let mut context = init_context();
+        let stack = &mut context.stack;
+        let k = T(stack, &[D(tas!(b"k")), D(138)]);
+        let outer_path = T(stack, &[D(tas!(b"one")), k, D(0)]);
+        let inner_path = T(stack, &[D(tas!(b"add")), D(tas!(b"one")), k, D(0)]);
+        let outer = TraceStack {
+            start: Instant::now(),
+            path: outer_path,
+            next: std::ptr::null(),
+        };
+        let inner = TraceStack {
+            start: Instant::now(),
+            path: inner_path,
+            next: &outer,
+        };
+        let folded = unsafe { folded_stack(&inner, D(0)) };
+        assert_eq!(folded, "/one/k138;/add/one/k138");
+
+        // Without arms, the %spots on the mean stack
+        let file = T(stack, &[D(tas!(b"lib")), D(tas!(b"foo")), D(0)]);
+        let start = T(stack, &[D(12), D(3)]);
+        let end = T(stack, &[D(12), D(9)]);
+        let spot = T(stack, &[file, start, end]);
+        let entry = T(stack, &[D(tas!(b"spot")), spot]);
+        let mean = T(stack, &[entry, D(0)]);
+        assert_eq!(
+            unsafe { folded_stack(std::ptr::null(), mean) },
+            "/lib/foo:12"
+        );
+
+        let mut sampler =
+            StackSampler::new(PathBuf::from("samples.folded"), Duration::from_secs(3600));
+        assert!(!sampler.is_due());
+        // A step which took two ticks counts twice
+        sampler.ticks.fetch_add(2, Ordering::Relaxed);
+        assert!(sampler.is_due());
+        sampler.record(folded.clone());
+        assert!(!sampler.is_due());
+        sampler.ticks.fetch_add(1, Ordering::Relaxed);
+        sampler.record(folded);
+        sampler.ticks.fetch_add(1, Ordering::Relaxed);
+        sampler.record("nock".to_string());
+        // Nothing is credited without a tick
+        sampler.record("idle".to_string());
+        assert_eq!(sampler.folded(), "/one/k138;/add/one/k138 3\nnock 1\n");
+    }
+}
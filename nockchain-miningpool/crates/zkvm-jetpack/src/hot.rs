@@ -1294,3 +1294,40 @@ pub const CUSTOM_LIST_JETS: &[HotEntry] = &[(
     1,
     range_jet,
 )];
+
+#[cfg(test)]
+mod tests {
+    use nockvm::jets::fuzz::{FuzzConfig, JetFuzzer};
+
+    use super::*;
+
+    /// Fuzz the prover hot state against the kernel jam in `NOCK_FUZZ_JAM`
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn fuzz_prover_hot_state() {
+        let Ok(path) = std::env::var("NOCK_FUZZ_JAM") else {
+            eprintln!("NOCK_FUZZ_JAM is not set, skipping jet fuzzing");
+            return;
+        };
+        let jam = std::fs::read(&path).expect("read NOCK_FUZZ_JAM");
+        let mut fuzzer =
+            JetFuzzer::boot(1 << 30, &jam, FuzzConfig::from_env()).expect("boot NOCK_FUZZ_JAM");
+        let report = fuzzer.fuzz(&produce_prover_hot_state());
+        for label in &report.unregistered {
+            eprintln!("not registered by {}: {}", path, label);
+        }
+        for (label, exercised) in &report.checked {
+            if *exercised == 0 {
+                eprintln!("no cases exercised: {}", label);
+            }
+        }
+        for mismatch in &report.mismatches {
+            eprintln!("{}", mismatch);
+        }
+        assert!(
+            report.mismatches.is_empty(),
+            "{} jets mismatched",
+            report.mismatches.len()
+        );
+    }
+}